@@ -0,0 +1,66 @@
+//! Backfill throughput with and without `--vm-threads`.
+//!
+//! Indexes a transactions file from genesis into a fresh database, once with
+//! blocks executing on the shared blocking pool and once on a VM pool, and
+//! prints the versions indexed per second of each. The file is laid out as
+//! `TransactionFile` describes and must start at version 0:
+//!
+//! ```text
+//! DIEM_SQLIZE_BENCH_TRANSACTIONS=/path/to/transactions \
+//!     DIEM_SQLIZE_BENCH_VM_THREADS=2 cargo bench --bench backfill
+//! ```
+
+use std::{env, path::Path, process::Command, time::Instant};
+
+fn index(transactions: &Path, extra_args: &[&str]) -> f64 {
+    let dir = tempfile::tempdir().unwrap();
+    let start = Instant::now();
+    let status = Command::new(env!("CARGO_BIN_EXE_diem-sqlize"))
+        .current_dir(dir.path())
+        .arg("--transactions-file")
+        .arg(transactions)
+        .args(extra_args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "indexing with {:?} failed", extra_args);
+    start.elapsed().as_secs_f64()
+}
+
+/// The number of transactions in a file, from the length prefixes after
+/// its first version.
+fn count_transactions(transactions: &Path) -> u64 {
+    let bytes = std::fs::read(transactions).unwrap();
+    let mut offset = 8;
+    let mut count = 0;
+    while offset < bytes.len() {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&bytes[offset..offset + 4]);
+        offset += 4 + u32::from_be_bytes(len) as usize;
+        count += 1;
+    }
+    count
+}
+
+fn main() {
+    let transactions = match env::var_os("DIEM_SQLIZE_BENCH_TRANSACTIONS") {
+        Some(path) => path,
+        None => {
+            println!("set DIEM_SQLIZE_BENCH_TRANSACTIONS to a transactions file to run this benchmark");
+            return;
+        },
+    };
+    let transactions = Path::new(&transactions);
+    let vm_threads = env::var("DIEM_SQLIZE_BENCH_VM_THREADS").unwrap_or_else(|_| "2".to_string());
+    let count = count_transactions(transactions);
+
+    let shared = index(transactions, &[]);
+    println!("shared blocking pool: {} versions in {:.1}s, {:.0}/s", count, shared, count as f64 / shared);
+    let pooled = index(transactions, &["--vm-threads", &vm_threads]);
+    println!(
+        "--vm-threads {}: {} versions in {:.1}s, {:.0}/s",
+        vm_threads,
+        count,
+        pooled,
+        count as f64 / pooled,
+    );
+}
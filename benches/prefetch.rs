@@ -0,0 +1,72 @@
+//! Read throughput of `SqlState` with and without `prefetch`.
+//!
+//! Stores a counter resource at every one of a few thousand accounts, then
+//! serves a read of each through `StateView::get` as block execution would:
+//! once straight from SQL, a query per read, and once after prefetching the
+//! whole read set, which loads it with one query per resource type.
+//!
+//! ```text
+//! cargo bench --bench prefetch --features bench
+//! ```
+
+use diem_sqlize::{
+    state::SqlState,
+    test_util::{self, TestDb},
+};
+
+use diem_state_view::StateView;
+use diem_types::{
+    access_path::{AccessPath, Path},
+    write_set::WriteOp,
+};
+use move_core_types::account_address::AccountAddress;
+use std::time::Instant;
+
+const ACCOUNTS: u16 = 4000;
+
+/// Read every path through `get` on the blocking pool, as the VM does, and
+/// report the rate, counting the time spent prefetching if any.
+async fn read(name: &str, db: &TestDb, access_paths: &[AccessPath], prefetch: bool) {
+    let start = Instant::now();
+    let mut state = SqlState::from_pool(db.pool.clone(), db.config.clone(), db.modules.clone());
+    if prefetch {
+        state.prefetch(access_paths).await.unwrap();
+    }
+    let paths = access_paths.to_vec();
+    let queries = tokio::task::spawn_blocking(move || {
+        for access_path in &paths {
+            assert!(state.get(access_path).unwrap().is_some());
+        }
+        state.queries()
+    })
+    .await
+    .unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{}: {} reads in {:.2}s with {} queries, {:.0} reads/s",
+        name,
+        access_paths.len(),
+        elapsed,
+        queries,
+        access_paths.len() as f64 / elapsed,
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let db = TestDb::new(test_util::config()).await;
+    db.publish(&test_util::counter_module()).await;
+    let path = Path::Resource(test_util::struct_tag("M", "R", vec![]));
+    let mut access_paths = vec![];
+    for account in 0..ACCOUNTS {
+        let mut bytes = [0; AccountAddress::LENGTH];
+        bytes[..2].copy_from_slice(&account.to_be_bytes());
+        let address = AccountAddress::new(bytes);
+        let op = WriteOp::Value(bcs::to_bytes(&test_util::counter(account.into())).unwrap());
+        db.write(1, address, path.clone(), op).await;
+        access_paths.push(test_util::access_path(address, &path));
+    }
+
+    read("without prefetch", &db, &access_paths, false).await;
+    read("with prefetch", &db, &access_paths, true).await;
+}
@@ -0,0 +1,156 @@
+//! Throughput of storing write sets through `DB::execute_with_annotator`,
+//! which is where replay spends its time once blocks are executed.
+//!
+//! Two fixtures are built here, the same on every run, and stored into a
+//! fresh in-memory database:
+//! - `resources`: hundreds of accounts each holding several resources with
+//!   scalar, vector and nested struct fields, written and then updated;
+//! - `modules`: a hundred modules, each declaring a handful of structs.
+//!
+//! The modules are built with the test fixtures, which the `bench` feature
+//! exposes:
+//!
+//! ```text
+//! cargo bench --bench replay --features bench
+//! ```
+
+use diem_sqlize::{
+    annotator::MoveValueAnnotator,
+    db::DB,
+    resolver::{ModuleCache, Resolver, DEFAULT_MODULE_CACHE_SIZE},
+    test_util::{self, TestStruct},
+};
+
+use diem_types::{
+    access_path::{AccessPath, Path},
+    write_set::WriteOp,
+};
+use move_core_types::{
+    account_address::AccountAddress,
+    identifier::Identifier,
+    language_storage::ModuleId,
+    value::{MoveStruct, MoveValue},
+};
+use sqlx::sqlite::SqlitePoolOptions;
+use std::{sync::Arc, time::Instant};
+use vm::file_format::SignatureToken;
+
+/// The writes of one version.
+type Writes = Vec<(AccessPath, WriteOp)>;
+
+const ACCOUNTS: u8 = 250;
+const UPDATES: u64 = 3;
+/// The resources every account holds.
+const RESOURCES: [&str; 8] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7"];
+const MODULES: usize = 100;
+/// The structs every module declares.
+const STRUCTS: [&str; 6] = ["S0", "S1", "S2", "S3", "S4", "S5"];
+
+fn publish(module_name: &str, structs: &[TestStruct]) -> (AccessPath, WriteOp) {
+    let id = ModuleId::new(test_util::ADDRESS, Identifier::new(module_name).unwrap());
+    let path = bcs::to_bytes(&Path::Code(id)).unwrap();
+    let module = test_util::module_bytes(module_name, structs);
+    (AccessPath::new(test_util::ADDRESS, path), WriteOp::Value(module))
+}
+
+/// Publishes `Bench`, declaring `Item { value: u64, flag: bool }` and
+/// resources `R0` and up of `{ balance: u64, key: vector<u8>, owners:
+/// vector<address>, items: vector<Item> }`, at version 0. Each later version
+/// writes every resource at every account, the first creating them and the
+/// rest updating them with one more item.
+fn resources_fixture() -> Vec<(u64, Writes)> {
+    let mut structs = vec![TestStruct::plain("Item", vec![
+        ("value", SignatureToken::U64),
+        ("flag", SignatureToken::Bool),
+    ])];
+    for &name in &RESOURCES {
+        structs.push(TestStruct {
+            is_resource: true,
+            ..TestStruct::plain(name, vec![
+                ("balance", SignatureToken::U64),
+                ("key", test_util::vector_token(SignatureToken::U8)),
+                ("owners", test_util::vector_token(SignatureToken::Address)),
+                ("items", test_util::vector_token(test_util::struct_token(0))),
+            ])
+        });
+    }
+    let mut versions = vec![(0, vec![publish("Bench", &structs)])];
+    for update in 0..UPDATES {
+        let mut writes = vec![];
+        for account in 0..ACCOUNTS {
+            let address = AccountAddress::new([account; AccountAddress::LENGTH]);
+            for (r, name) in RESOURCES.iter().enumerate() {
+                let tag = test_util::struct_tag("Bench", name, vec![]);
+                let items = (0..update + 1)
+                    .map(|i| MoveValue::Struct(MoveStruct::new(vec![MoveValue::U64(i), MoveValue::Bool(i % 2 == 0)])))
+                    .collect();
+                let value = MoveStruct::new(vec![
+                    MoveValue::U64(update * 1000 + r as u64),
+                    MoveValue::Vector(vec![MoveValue::U8(account); 32]),
+                    MoveValue::Vector(vec![MoveValue::Address(address); r % 3]),
+                    MoveValue::Vector(items),
+                ]);
+                let path = bcs::to_bytes(&Path::Resource(tag)).unwrap();
+                writes.push((AccessPath::new(address, path), WriteOp::Value(bcs::to_bytes(&value).unwrap())));
+            }
+        }
+        versions.push((update + 1, writes));
+    }
+    versions
+}
+
+/// `MODULES` modules, one per version, each declaring the `STRUCTS`, every
+/// one nesting the one before. The last is a resource.
+fn modules_fixture() -> Vec<(u64, Writes)> {
+    (0..MODULES)
+        .map(|m| {
+            let structs = STRUCTS
+                .iter()
+                .enumerate()
+                .map(|(s, &name)| {
+                    let mut fields = vec![("value", SignatureToken::U64), ("owner", SignatureToken::Address)];
+                    if s > 0 {
+                        fields.push(("inner", test_util::struct_token(s - 1)));
+                    }
+                    TestStruct { is_resource: s + 1 == STRUCTS.len(), ..TestStruct::plain(name, fields) }
+                })
+                .collect::<Vec<_>>();
+            (m as u64, vec![publish(&format!("M{}", m), &structs)])
+        })
+        .collect()
+}
+
+/// Store every write into a new in-memory database and report the rate.
+async fn store(name: &str, versions: &[(u64, Writes)]) {
+    // one connection, since each would open a database of its own in memory
+    let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+    let config = Arc::new(test_util::config());
+    let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+    let db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+    db.initialize().await;
+    let annotator = MoveValueAnnotator::new(Resolver::from_shared_cache(pool, config, modules));
+
+    let start = Instant::now();
+    let mut writes = 0;
+    for (version, write_set) in versions {
+        for (access_path, op) in write_set {
+            db.execute_with_annotator(*version, access_path, op, &annotator).await.unwrap();
+            writes += 1;
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{}: {} writes over {} versions in {:.2}s, {:.0} writes/s",
+        name,
+        writes,
+        versions.len(),
+        elapsed,
+        writes as f64 / elapsed,
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    store("resources", &resources_fixture()).await;
+    store("modules", &modules_fixture()).await;
+}
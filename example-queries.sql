@@ -3,7 +3,7 @@
 -- QUERY: find total # of accounts
 
 SELECT COUNT(*)
-FROM __root__x1__DiemAccount__DiemAccount
+FROM __root__x00000000000000000000000000000001__DiemAccount__DiemAccount
 ;
 
 -- OUTPUT:
@@ -12,8 +12,8 @@ FROM __root__x1__DiemAccount__DiemAccount
 -- count parent vasps
 
 SELECT COUNT(*)
-FROM __root__x1__Roles__RoleId rr
-INNER JOIN x1__Roles__RoleId r ON rr.id = r.__id
+FROM __root__x00000000000000000000000000000001__Roles__RoleId rr
+INNER JOIN x00000000000000000000000000000001__Roles__RoleId r ON rr.id = r.__id
 WHERE
   r.role_id = 5
 ;
@@ -24,11 +24,11 @@ WHERE
 -- QUERY: count child vasps per parent
 
 SELECT lower(quote(rr.address)), pv.num_children, COUNT(cv.__id)
-FROM __root__x1__Roles__RoleId rr
-INNER JOIN x1__Roles__RoleId r ON rr.id = r.__id
-INNER JOIN __root__x1__VASP__ParentVASP rpv ON rr.address = rpv.address
-INNER JOIN x1__VASP__ParentVASP pv ON rpv.id == pv.__id
-LEFT JOIN x1__VASP__ChildVASP cv ON rr.address = cv.parent_vasp_addr
+FROM __root__x00000000000000000000000000000001__Roles__RoleId rr
+INNER JOIN x00000000000000000000000000000001__Roles__RoleId r ON rr.id = r.__id
+INNER JOIN __root__x00000000000000000000000000000001__VASP__ParentVASP rpv ON rr.address = rpv.address
+INNER JOIN x00000000000000000000000000000001__VASP__ParentVASP pv ON rpv.id == pv.__id
+LEFT JOIN x00000000000000000000000000000001__VASP__ChildVASP cv ON rr.address = cv.parent_vasp_addr
 WHERE
   r.role_id = 5
 GROUP BY
@@ -52,8 +52,8 @@ ORDER BY
 -- QUERY: count txs per account
 
 SELECT lower(quote(ra.address)), a.sequence_number
-FROM __root__x1__DiemAccount__DiemAccount ra
-INNER JOIN x1__DiemAccount__DiemAccount a ON ra.id = a.__id
+FROM __root__x00000000000000000000000000000001__DiemAccount__DiemAccount ra
+INNER JOIN x00000000000000000000000000000001__DiemAccount__DiemAccount a ON ra.id = a.__id
 ORDER BY a.sequence_number
 ;
 
@@ -70,9 +70,9 @@ ORDER BY a.sequence_number
 -- QUERY: get specific account's balance
 
 SELECT lower(quote(rb.address)), c.value
-FROM __root__x1__DiemAccount__Balance__t_x1__XUS__XUS_t rb
-INNER JOIN x1__DiemAccount__Balance__t_x1__XUS__XUS_t b ON rb.id = b.__id
-INNER JOIN x1__Diem__Diem__t_x1__XUS__XUS_t c ON b.coin = c.__id
+FROM __root__x00000000000000000000000000000001__DiemAccount__Balance__t_x00000000000000000000000000000001__XUS__XUS_t rb
+INNER JOIN x00000000000000000000000000000001__DiemAccount__Balance__t_x00000000000000000000000000000001__XUS__XUS_t b ON rb.id = b.__id
+INNER JOIN x00000000000000000000000000000001__Diem__Diem__t_x00000000000000000000000000000001__XUS__XUS_t c ON b.coin = c.__id
 WHERE
   rb.address = x'455fb150ba3a5228618a108e3b8992f0'
 ;
@@ -83,9 +83,9 @@ WHERE
 -- QUERY: tresurance compliances tx fee balance
 
 SELECT lower(quote(rtf.address)), c.value
-FROM __root__x1__TransactionFee__TransactionFee__t_x1__XUS__XUS_t rtf
-INNER JOIN x1__TransactionFee__TransactionFee__t_x1__XUS__XUS_t tf ON rtf.id = tf.__id
-INNER JOIN x1__Diem__Diem__t_x1__XUS__XUS_t c ON tf.balance = c.__id
+FROM __root__x00000000000000000000000000000001__TransactionFee__TransactionFee__t_x00000000000000000000000000000001__XUS__XUS_t rtf
+INNER JOIN x00000000000000000000000000000001__TransactionFee__TransactionFee__t_x00000000000000000000000000000001__XUS__XUS_t tf ON rtf.id = tf.__id
+INNER JOIN x00000000000000000000000000000001__Diem__Diem__t_x00000000000000000000000000000001__XUS__XUS_t c ON tf.balance = c.__id
 WHERE
   rtf.address = x'0000000000000000000000000b1e55ed'
 ;
@@ -96,7 +96,7 @@ WHERE
 -- QUERY: list currencies in the system
 
 SElECT slot
-FROM x1__RegisteredCurrencies__RegisteredCurrencies__currency_codes__elements
+FROM x00000000000000000000000000000001__RegisteredCurrencies__RegisteredCurrencies__currency_codes__elements
 ;
 
 -- OUTPUT:
@@ -106,7 +106,7 @@ FROM x1__RegisteredCurrencies__RegisteredCurrencies__currency_codes__elements
 -- QUERY: query total value of each currency
 
 SELECT lower(quote(total_value))
-FROM x1__Diem__CurrencyInfo__t_x1__XUS__XUS_t
+FROM x00000000000000000000000000000001__Diem__CurrencyInfo__t_x00000000000000000000000000000001__XUS__XUS_t
 ;
 
 -- OUTPUT:
@@ -116,8 +116,8 @@ FROM x1__Diem__CurrencyInfo__t_x1__XUS__XUS_t
 -- QUERY: query total value stored in all balances
 
 SELECT SUM(c.value)
-FROM x1__DiemAccount__Balance__t_x1__XUS__XUS_t b
-INNER JOIN x1__Diem__Diem__t_x1__XUS__XUS_t c ON b.coin = c.__id
+FROM x00000000000000000000000000000001__DiemAccount__Balance__t_x00000000000000000000000000000001__XUS__XUS_t b
+INNER JOIN x00000000000000000000000000000001__Diem__Diem__t_x00000000000000000000000000000001__XUS__XUS_t c ON b.coin = c.__id
 ;
 
 -- OUTPUT:
@@ -126,8 +126,8 @@ INNER JOIN x1__Diem__Diem__t_x1__XUS__XUS_t c ON b.coin = c.__id
 -- QUERY: query total value stored in all preburn balances
 
 SELECT SUM(c.value)
-FROM x1__Diem__Preburn__t_x1__XUS__XUS_t pb
-INNER JOIN x1__Diem__Diem__t_x1__XUS__XUS_t c ON pb.to_burn = c.__id
+FROM x00000000000000000000000000000001__Diem__Preburn__t_x00000000000000000000000000000001__XUS__XUS_t pb
+INNER JOIN x00000000000000000000000000000001__Diem__Diem__t_x00000000000000000000000000000001__XUS__XUS_t c ON pb.to_burn = c.__id
 ;
 
 -- OUTPUT:
@@ -136,10 +136,10 @@ INNER JOIN x1__Diem__Diem__t_x1__XUS__XUS_t c ON pb.to_burn = c.__id
 -- QUERY: validators by system info
 
 SELECT COUNT(dsve.slot)
-FROM __root__x1__DiemConfig__DiemConfig__t_x1__DiemSystem__DiemSystem_t rdcds
-INNER JOIN x1__DiemConfig__DiemConfig__t_x1__DiemSystem__DiemSystem_t dcds ON rdcds.id = dcds.__id
-INNER JOIN x1__DiemSystem__DiemSystem ds ON dcds.payload = ds.__id
-LEFT JOIN x1__DiemSystem__DiemSystem__validators__elements dsve ON dsve.parent_id = ds.__id
+FROM __root__x00000000000000000000000000000001__DiemConfig__DiemConfig__t_x00000000000000000000000000000001__DiemSystem__DiemSystem_t rdcds
+INNER JOIN x00000000000000000000000000000001__DiemConfig__DiemConfig__t_x00000000000000000000000000000001__DiemSystem__DiemSystem_t dcds ON rdcds.id = dcds.__id
+INNER JOIN x00000000000000000000000000000001__DiemSystem__DiemSystem ds ON dcds.payload = ds.__id
+LEFT JOIN x00000000000000000000000000000001__DiemSystem__DiemSystem__validators__elements dsve ON dsve.parent_id = ds.__id
 WHERE                                      
   rdcds.address = x'0000000000000000000000000a550c18'
 ;
@@ -150,8 +150,8 @@ WHERE
 -- QUERY: validators by role
 
 SELECT lower(quote(rr.address))
-FROM __root__x1__Roles__RoleId rr
-INNER JOIN x1__Roles__RoleId r ON rr.id = r.__id
+FROM __root__x00000000000000000000000000000001__Roles__RoleId rr
+INNER JOIN x00000000000000000000000000000001__Roles__RoleId r ON rr.id = r.__id
 WHERE r.role_id = 3
 ;
 
@@ -161,4 +161,21 @@ WHERE r.role_id = 3
 -- x'd4c4fb4956d899e55289083f45ac5d84'
 -- x'57208e640c623b27c6bba704380825ab'
 
-
+-- QUERY: largest sequence numbers, read as unsigned
+--
+-- u64 values are stored as the signed 64 bit integer with the same bits, so
+-- anything above 2^63 - 1 comes back negative. Adding 2^64 recovers the
+-- intended value (as a floating point number). Indexing with
+-- --numeric-encoding text stores zero padded decimals that compare correctly
+-- as they are.
+
+SELECT lower(quote(ra.address)),
+  CASE WHEN a.sequence_number < 0
+    THEN a.sequence_number + 18446744073709551616.0
+    ELSE a.sequence_number
+  END AS sequence_number
+FROM __root__x00000000000000000000000000000001__DiemAccount__DiemAccount ra
+INNER JOIN x00000000000000000000000000000001__DiemAccount__DiemAccount a ON ra.id = a.__id
+ORDER BY 2 DESC
+LIMIT 10
+;
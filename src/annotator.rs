@@ -2,14 +2,16 @@ use anyhow::{anyhow, Result};
 use diem_types::{
     account_address::AccountAddress,
     contract_event::ContractEvent,
+    event::EventKey,
 };
 use move_core_types::{
     identifier::Identifier,
-    language_storage::{StructTag, TypeTag},
+    language_storage::{StructTag, TypeTag, CORE_CODE_ADDRESS},
     value::{MoveStruct, MoveValue},
 };
+use serde::{Serialize, Serializer};
 use std::{
-    convert::TryInto,
+    convert::{TryFrom, TryInto},
     fmt::{Display, Formatter},
     future::Future,
     pin::Pin,
@@ -23,7 +25,7 @@ use crate::{
     resolver::Resolver,
 };
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Serialize)]
 pub struct AnnotatedMoveStruct {
     pub is_resource: bool,
     pub type_: StructTag,
@@ -34,7 +36,7 @@ pub struct AnnotatedMoveStruct {
 /// for debugging/client purpose right now and just for a better visualization of on chain data. In
 /// the long run, we would like to transform this struct to a Json value so that we can have a cross
 /// platform interpretation of the on chain data.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Serialize)]
 pub enum AnnotatedMoveValue {
     U8(u8),
     U64(u64),
@@ -42,10 +44,25 @@ pub enum AnnotatedMoveValue {
     Bool(bool),
     Address(AccountAddress),
     Vector(TypeTag, Vec<AnnotatedMoveValue>),
+    #[serde(serialize_with = "serialize_hex")]
     Bytes(Vec<u8>),
     Struct(AnnotatedMoveStruct),
 }
 
+/// The fields of a resource whose type could only be partly resolved. Fields
+/// without a value are listed in `unresolved` with the reason.
+#[derive(Debug)]
+pub struct PartialMoveStruct {
+    pub type_: StructTag,
+    pub value: Vec<(Identifier, Option<AnnotatedMoveValue>)>,
+    pub unresolved: Vec<(Identifier, String)>,
+}
+
+/// Bytes are serialized as hex, as they are displayed.
+fn serialize_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
 pub struct MoveValueAnnotator {
     resolver: Resolver,
 }
@@ -57,6 +74,10 @@ impl MoveValueAnnotator {
         }
     }
 
+    pub fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
     pub async fn view_resource(&self, tag: &StructTag, blob: &[u8]) -> Result<AnnotatedMoveStruct> {
         let ty = self.resolver.resolve_struct(tag).await?;
         let struct_def = (&ty)
@@ -66,7 +87,47 @@ impl MoveValueAnnotator {
         self.annotate_struct(&move_struct, &ty).await
     }
 
-    #[allow(dead_code)]
+    /// Decode as many fields of a resource as possible when its type can't be
+    /// fully resolved. Fields are encoded one after another, so once a
+    /// field's type is unknown, none of the fields after it can be found
+    /// either.
+    pub async fn view_resource_partial(&self, tag: &StructTag, blob: &[u8]) -> Result<PartialMoveStruct> {
+        let mut value = vec![];
+        let mut unresolved = vec![];
+        let mut rest = Some(blob);
+        for (name, ty) in self.resolver.resolve_struct_fields(tag).await? {
+            let (bytes, ty) = match (rest, ty) {
+                (Some(bytes), Ok(ty)) => (bytes, ty),
+                (Some(_), Err(e)) => {
+                    rest = None;
+                    unresolved.push((name.clone(), e.to_string()));
+                    value.push((name, None));
+                    continue;
+                },
+                (None, _) => {
+                    unresolved.push((name.clone(), "follows a field that could not be resolved".to_string()));
+                    value.push((name, None));
+                    continue;
+                },
+            };
+            let len = encoded_len(&ty, bytes).map_err(|e| anyhow!("field {}: {}", name, e))?;
+            let layout = (&ty)
+                .try_into()
+                .map_err(|e: PartialVMError| e.finish(Location::Undefined).into_vm_status())?;
+            let move_value = MoveValue::simple_deserialize(&bytes[..len], &layout)?;
+            value.push((name, Some(self.annotate_value(&move_value, &ty, 1).await?)));
+            rest = Some(&bytes[len..]);
+        }
+        if rest.map_or(false, |bytes| !bytes.is_empty()) {
+            return Err(anyhow!("{} has bytes left over after its last field", tag));
+        }
+        Ok(PartialMoveStruct {
+            type_: tag.clone(),
+            value,
+            unresolved,
+        })
+    }
+
     pub async fn view_contract_event(&self, event: &ContractEvent) -> Result<AnnotatedMoveValue> {
         let ty = self.resolver.resolve_type(event.type_tag()).await?;
         let move_ty = (&ty)
@@ -74,58 +135,99 @@ impl MoveValueAnnotator {
             .map_err(|e: PartialVMError| e.finish(Location::Undefined).into_vm_status())?;
 
         let move_value = MoveValue::simple_deserialize(event.event_data(), &move_ty)?;
-        self.annotate_value(&move_value, &ty).await
+        self.annotate_value(&move_value, &ty, 0).await
     }
 
     pub async fn annotate_struct(
         &self,
         move_struct: &MoveStruct,
         ty: &FatStructType,
+    ) -> Result<AnnotatedMoveStruct> {
+        self.annotate_struct_at_depth(move_struct, ty, 0).await
+    }
+
+    /// Values are only written and read back after being annotated, so
+    /// limiting the depth here keeps every later recursive pass over them
+    /// from overflowing the stack too.
+    async fn annotate_struct_at_depth(
+        &self,
+        move_struct: &MoveStruct,
+        ty: &FatStructType,
+        depth: usize,
     ) -> Result<AnnotatedMoveStruct> {
         let struct_tag = ty
             .struct_tag()
             .map_err(|e| e.finish(Location::Undefined).into_vm_status())?;
+        // a layout that no longer matches the data, e.g. after an upgrade,
+        // must not quietly drop the fields zip would leave out
+        if ty.fields.len() != move_struct.fields().len() {
+            return Err(anyhow!(
+                "{} has {} fields but the value has {}",
+                struct_tag,
+                ty.fields.len(),
+                move_struct.fields().len(),
+            ));
+        }
         let mut annotated_fields = vec![];
         for ((id, ty), v) in ty.fields.iter().zip(move_struct.fields().iter()) {
-            annotated_fields.push((id.clone(), self.annotate_value(v, ty).await?));
+            annotated_fields.push((id.clone(), self.annotate_value(v, ty, depth + 1).await?));
         }
-        Ok(AnnotatedMoveStruct {
+        let mut annotated = AnnotatedMoveStruct {
             is_resource: ty.is_resource,
             type_: struct_tag,
             value: annotated_fields,
-        })
+        };
+        if self.resolver.config().expand_event_handles {
+            expand_event_handle(&mut annotated);
+        }
+        Ok(annotated)
     }
 
-    fn annotate_value<'a>(&'a self, value: &'a MoveValue, ty: &'a FatType) -> Pin<Box<dyn Future<Output=Result<AnnotatedMoveValue>> + 'a>> {
+    fn annotate_value<'a>(
+        &'a self,
+        value: &'a MoveValue,
+        ty: &'a FatType,
+        depth: usize,
+    ) -> Pin<Box<dyn Future<Output=Result<AnnotatedMoveValue>> + 'a>> {
         Box::pin(async move {
+            let max_depth = self.resolver.config().max_value_depth;
+            if depth > max_depth {
+                return Err(anyhow!("value is nested more than {} levels deep", max_depth));
+            }
             Ok(match (value, ty) {
                 (MoveValue::Bool(b), FatType::Bool) => AnnotatedMoveValue::Bool(*b),
                 (MoveValue::U8(i), FatType::U8) => AnnotatedMoveValue::U8(*i),
                 (MoveValue::U64(i), FatType::U64) => AnnotatedMoveValue::U64(*i),
                 (MoveValue::U128(i), FatType::U128) => AnnotatedMoveValue::U128(*i),
                 (MoveValue::Address(a), FatType::Address) => AnnotatedMoveValue::Address(*a),
+                // every element must match the declared element type, so a
+                // corrupted value fails as a whole instead of being partially
+                // annotated
                 (MoveValue::Vector(a), FatType::Vector(ty)) => match ty.as_ref() {
                     FatType::U8 => AnnotatedMoveValue::Bytes(
                         a.iter()
-                            .map(|v| match v {
-                                MoveValue::U8(i) => Ok(*i),
-                                _ => Err(anyhow!("unexpected value type")),
+                            .enumerate()
+                            .map(|(i, v)| match v {
+                                MoveValue::U8(b) => Ok(*b),
+                                _ => Err(anyhow!("element {} of vector<u8> is {:?}", i, v)),
                             })
                             .collect::<Result<_>>()?,
                     ),
-                    _ => AnnotatedMoveValue::Vector(
-                        ty.type_tag().unwrap(),
-                        {
-                            let mut values = vec![];
-                            for v in a {
-                                values.push(self.annotate_value(v, ty.as_ref()).await?);
-                            }
-                            values
-                        },
-                    ),
+                    _ => {
+                        let elem_tag = ty.type_tag().map_err(|e| e.finish(Location::Undefined).into_vm_status())?;
+                        let mut values = vec![];
+                        for (i, v) in a.iter().enumerate() {
+                            let value = self
+                                .annotate_value(v, ty.as_ref(), depth + 1)
+                                .await
+                                .map_err(|e| anyhow!("element {} of vector<{}>: {}", i, elem_tag, e))?;
+                            values.push(value);
+                        }
+                        AnnotatedMoveValue::Vector(elem_tag, values)
+                    },
                 },
                 (MoveValue::Struct(s), FatType::Struct(ty)) => {
-                    AnnotatedMoveValue::Struct(self.annotate_struct(s, ty.as_ref()).await?)
+                    AnnotatedMoveValue::Struct(self.annotate_struct_at_depth(s, ty.as_ref(), depth).await?)
                 }
                 _ => {
                     return Err(anyhow!(
@@ -139,6 +241,31 @@ impl MoveValueAnnotator {
     }
 }
 
+/// The guid of a `0x1::Event::EventHandle` is the key its events are
+/// emitted under. Add it as an `event_key` field so it can be queried as such.
+fn expand_event_handle(struct_: &mut AnnotatedMoveStruct) {
+    let tag = &struct_.type_;
+    if tag.address != CORE_CODE_ADDRESS
+        || tag.module.as_str() != "Event"
+        || tag.name.as_str() != "EventHandle"
+    {
+        return;
+    }
+
+    let key = struct_.value.iter().find_map(|(name, value)| match value {
+        AnnotatedMoveValue::Bytes(guid) if name.as_str() == "guid" => {
+            EventKey::try_from(guid.as_slice()).ok()
+        },
+        _ => None,
+    });
+    if let Some(key) = key {
+        struct_.value.push((
+            Identifier::new("event_key").unwrap(),
+            AnnotatedMoveValue::Bytes(key.as_bytes().to_vec()),
+        ));
+    }
+}
+
 fn write_indent(f: &mut Formatter, indent: u64) -> std::fmt::Result {
     for _i in 0..indent {
         write!(f, " ")?;
@@ -204,3 +331,159 @@ impl Display for AnnotatedMoveStruct {
         pretty_print_struct(f, self, 0)
     }
 }
+
+/// How many bytes the bcs encoding of a value of type `ty` takes at the start
+/// of `bytes`.
+fn encoded_len(ty: &FatType, bytes: &[u8]) -> Result<usize> {
+    let len = match ty {
+        FatType::Bool | FatType::U8 => 1,
+        FatType::U64 => 8,
+        FatType::U128 => 16,
+        FatType::Address => AccountAddress::LENGTH,
+        FatType::Vector(elem_ty) => {
+            let (count, mut len) = read_uleb128(bytes)?;
+            for _ in 0..count {
+                let elem_bytes = bytes.get(len..).ok_or_else(|| anyhow!("value is truncated"))?;
+                len += encoded_len(elem_ty, elem_bytes)?;
+            }
+            len
+        },
+        FatType::Struct(struct_ty) => {
+            let mut len = 0;
+            for (_, field_ty) in &struct_ty.fields {
+                let field_bytes = bytes.get(len..).ok_or_else(|| anyhow!("value is truncated"))?;
+                len += encoded_len(field_ty, field_bytes)?;
+            }
+            len
+        },
+        FatType::TyParam(_) => return Err(anyhow!("unexpected type parameter")),
+    };
+    if len > bytes.len() {
+        return Err(anyhow!("value is truncated"));
+    }
+    Ok(len)
+}
+
+/// A vector's length prefix and the number of bytes it takes.
+fn read_uleb128(bytes: &[u8]) -> Result<(usize, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().take(5).enumerate() {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value as usize, i + 1));
+        }
+    }
+    Err(anyhow!("malformed length prefix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::{
+        db::Config,
+        test_util::{self, TestDb, TestStruct, ADDRESS},
+    };
+
+    #[tokio::test]
+    async fn vectors_mixing_element_types_are_rejected() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![
+            ("values", test_util::vector_token(SignatureToken::U64)),
+            ("bytes", test_util::vector_token(SignatureToken::U8)),
+        ])])).await;
+        let annotator = db.annotator();
+        let ty = annotator.resolver().resolve_struct(&tag).await.unwrap();
+        let resource = |values, bytes| MoveStruct::new(vec![MoveValue::Vector(values), MoveValue::Vector(bytes)]);
+
+        let consistent = resource(vec![MoveValue::U64(1), MoveValue::U64(2)], vec![MoveValue::U8(3)]);
+        annotator.annotate_struct(&consistent, &ty).await.unwrap();
+        let mixed = resource(vec![MoveValue::U64(1), MoveValue::Bool(true)], vec![]);
+        let error = annotator.annotate_struct(&mixed, &ty).await.unwrap_err().to_string();
+        assert!(error.starts_with("element 1 of vector<u64>: Cannot annotate value Bool(true)"), "{}", error);
+        let mixed = resource(vec![], vec![MoveValue::U8(3), MoveValue::U64(4)]);
+        let error = annotator.annotate_struct(&mixed, &ty).await.unwrap_err().to_string();
+        assert_eq!(error, "element 1 of vector<u8> is U64(4)");
+    }
+
+    #[tokio::test]
+    async fn values_with_a_different_field_count_are_rejected() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![
+            ("a", SignatureToken::U64),
+            ("b", SignatureToken::Bool),
+        ])])).await;
+        let annotator = db.annotator();
+        let ty = annotator.resolver().resolve_struct(&tag).await.unwrap();
+
+        for fields in vec![vec![MoveValue::U64(1)], vec![MoveValue::U64(1), MoveValue::Bool(true), MoveValue::U64(2)]] {
+            let count = fields.len();
+            let error = annotator.annotate_struct(&MoveStruct::new(fields), &ty).await.unwrap_err().to_string();
+            assert_eq!(error, format!("{} has 2 fields but the value has {}", tag, count));
+        }
+    }
+
+    #[tokio::test]
+    async fn event_handles_are_given_their_key() {
+        let tag = StructTag {
+            address: CORE_CODE_ADDRESS,
+            module: Identifier::new("Event").unwrap(),
+            name: Identifier::new("R").unwrap(),
+            type_params: vec![],
+        };
+        let key = EventKey::new_from_address(&ADDRESS, 7);
+        let value = MoveStruct::new(vec![MoveValue::Struct(MoveStruct::new(vec![
+            MoveValue::U64(3),
+            MoveValue::Vector(key.as_bytes().iter().copied().map(MoveValue::U8).collect()),
+        ]))]);
+        let (tag, value) = (&tag, &value);
+        let handle_fields = |expand_event_handles| async move {
+            let db = TestDb::new(Config { expand_event_handles, ..test_util::config() }).await;
+            db.publish(&test_util::module_bytes_at(CORE_CODE_ADDRESS, "Event", &[
+                TestStruct::resource("R", vec![("handle", test_util::struct_token(1))]),
+                TestStruct::resource("EventHandle", vec![
+                    ("counter", SignatureToken::U64),
+                    ("guid", test_util::vector_token(SignatureToken::U8)),
+                ]),
+            ])).await;
+            let annotator = db.annotator();
+            let ty = annotator.resolver().resolve_struct(tag).await.unwrap();
+            let annotated = annotator.annotate_struct(value, &ty).await.unwrap();
+            match annotated.value.into_iter().next() {
+                Some((_, AnnotatedMoveValue::Struct(handle))) => handle.value,
+                other => panic!("unexpected field {:?}", other),
+            }
+        };
+
+        let names = |fields: &[(Identifier, AnnotatedMoveValue)]| {
+            fields.iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&handle_fields(false).await), vec!["counter", "guid"]);
+        let fields = handle_fields(true).await;
+        assert_eq!(names(&fields), vec!["counter", "guid", "event_key"]);
+        assert_eq!(fields[2].1, AnnotatedMoveValue::Bytes(key.as_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn values_nested_past_the_limit_are_rejected() {
+        let db = TestDb::new(Config { max_value_depth: 16, ..test_util::config() }).await;
+        // a field is one level below its struct, and each vector's elements
+        // one below it
+        let nested = |levels| (0..levels).fold(SignatureToken::U64, |ty, _| test_util::vector_token(ty));
+        db.publish(&test_util::module_bytes("M", &[
+            TestStruct::resource("Shallow", vec![("v", nested(15))]),
+            TestStruct::resource("Deep", vec![("v", nested(16))]),
+        ])).await;
+        let value = |levels| MoveStruct::new(vec![(0..levels).fold(MoveValue::U64(1), |v, _| MoveValue::Vector(vec![v]))]);
+        let annotator = db.annotator();
+
+        let shallow = annotator.resolver().resolve_struct(&test_util::struct_tag("M", "Shallow", vec![])).await.unwrap();
+        annotator.annotate_struct(&value(15), &shallow).await.unwrap();
+        let deep = annotator.resolver().resolve_struct(&test_util::struct_tag("M", "Deep", vec![])).await.unwrap();
+        let error = annotator.annotate_struct(&value(16), &deep).await.unwrap_err().to_string();
+        assert!(error.ends_with("value is nested more than 16 levels deep"), "{}", error);
+    }
+}
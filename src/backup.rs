@@ -1,55 +1,261 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use diem_crypto::HashValue;
 use diem_types::{
     account_state::AccountState,
     account_state_blob::AccountStateBlob,
 };
 use libflate::gzip::Decoder;
+use sha2::{Digest, Sha256};
 use std::{
-    cell::RefCell,
-    convert::{TryFrom, TryInto},
-    fs::File,
-    io::{BufReader, Read},
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, BufReader, Read},
     path::PathBuf,
 };
 
+/// The largest account state blob a backup is trusted to declare unless told
+/// otherwise. Real account states are a few kilobytes; the length prefix is
+/// read from the file, and a corrupted one could otherwise ask for gigabytes.
+pub const DEFAULT_MAX_BLOB_SIZE: usize = 64 << 20;
+
 pub struct Backup {
     reader: RefCell<Decoder<BufReader<File>>>,
     buffer: RefCell<Vec<u8>>,
+    offset: Cell<u64>,
+    max_blob_size: usize,
 }
 
 impl Backup {
     pub fn from_file(path: &PathBuf) -> Result<Self> {
-        let reader = RefCell::new(Decoder::new(BufReader::new(File::open(path)?)).unwrap());
+        let reader = RefCell::new(Decoder::new(BufReader::new(File::open(path)?))?);
         let buffer = RefCell::new(Vec::with_capacity(4096*4));
         Ok(Self {
             reader,
             buffer,
+            offset: Cell::new(0),
+            max_blob_size: DEFAULT_MAX_BLOB_SIZE,
         })
     }
+
+    /// Reject blobs declared longer than `max_blob_size` bytes as corrupt.
+    pub fn with_max_blob_size(mut self, max_blob_size: usize) -> Self {
+        self.max_blob_size = max_blob_size;
+        self
+    }
+
+    /// The offset into the decompressed stream just past the last account
+    /// state read. Backups are gzipped, so this is not a position in the file
+    /// itself.
+    pub fn offset(&self) -> u64 {
+        self.offset.get()
+    }
+
+    /// Skip ahead to an offset previously returned by `offset`, discarding
+    /// the account states before it.
+    pub fn skip_to(&self, offset: u64) -> Result<()> {
+        let current = self.offset.get();
+        if offset < current {
+            return Err(anyhow!("cannot skip backwards from offset {} to {}", current, offset));
+        }
+        let mut reader = self.reader.borrow_mut();
+        let skipped = io::copy(&mut reader.by_ref().take(offset - current), &mut io::sink())?;
+        if skipped != offset - current {
+            return Err(anyhow!("backup ended before offset {}", offset));
+        }
+        self.offset.set(offset);
+        Ok(())
+    }
+
+    /// Read the next length prefixed blob. Returns `Ok(None)` only when the
+    /// backup ends cleanly between blobs; anything else that goes wrong is an
+    /// error so that a corrupted backup is not mistaken for a short one.
+    fn read_account_state(&self) -> Result<Option<AccountState>> {
+        let mut reader = self.reader.borrow_mut();
+
+        let mut len_buf = [0u8; 4];
+        let read = reader.read(&mut len_buf)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        reader
+            .read_exact(&mut len_buf[read..])
+            .map_err(|e| anyhow!("backup truncated in blob length: {}", e))?;
+        let blob_len = u32::from_be_bytes(len_buf) as usize;
+        if blob_len > self.max_blob_size {
+            return Err(anyhow!(
+                "backup blob at offset {} declares {} bytes, more than the maximum of {}; the backup is likely corrupt",
+                self.offset.get(),
+                blob_len,
+                self.max_blob_size,
+            ));
+        }
+
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.resize(blob_len, 0);
+        reader
+            .read_exact(&mut buffer.as_mut_slice()[..blob_len])
+            .map_err(|e| anyhow!("backup truncated in blob of {} bytes: {}", blob_len, e))?;
+
+        let (_, asb): (HashValue, AccountStateBlob) = bcs::from_bytes(&buffer[0..blob_len])
+            .map_err(|e| anyhow!("backup blob failed deserialization: {}", e))?;
+        self.offset.set(self.offset.get() + (len_buf.len() + blob_len) as u64);
+
+        Ok(Some(AccountState::try_from(&asb)?))
+    }
 }
 
 impl Iterator for Backup {
-    type Item = AccountState;
+    type Item = Result<AccountState>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut len_buf = vec![0u8; 4];
-        if let Err(_) = self.reader.borrow_mut().read_exact(len_buf.as_mut_slice()) {
-            return None;
+        self.read_account_state().transpose()
+    }
+}
+
+/// Check the SHA-256 digest of every backup file against a manifest of
+/// `<hex digest>  <file name>` lines, as written by `sha256sum`. Files are
+/// matched by name, ignoring directories, and every file must be listed.
+pub fn verify_manifest(manifest: &PathBuf, files: &[PathBuf]) -> Result<()> {
+    let manifest_text = fs::read_to_string(manifest)
+        .map_err(|e| anyhow!("failed reading manifest {}: {}", manifest.display(), e))?;
+    let mut digests = HashMap::new();
+    for line in manifest_text.lines().filter(|line| !line.trim().is_empty()) {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            // sha256sum marks files hashed in binary mode with a `*`
+            (Some(digest), Some(name)) => {
+                let name = name.trim_start_matches('*');
+                let name = name.rsplit('/').next().unwrap_or(name);
+                digests.insert(name.to_string(), digest.to_lowercase());
+            },
+            _ => return Err(anyhow!("malformed line in manifest {}: {:?}", manifest.display(), line)),
         }
-        let blob_len = u32::from_be_bytes(len_buf.try_into().unwrap()) as usize;
-        let mut buffer = self.buffer.borrow_mut();
-        buffer.resize(blob_len, 0);
+    }
+
+    for file in files {
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let expected = digests
+            .get(&name)
+            .ok_or_else(|| anyhow!("backup {} is not listed in manifest {}", file.display(), manifest.display()))?;
 
-        if let Err(_) = self.reader.borrow_mut().read_exact(&mut buffer.as_mut_slice()[..blob_len]) {
-            return None;
+        tracing::info!(backup = %file.display(), "checking digest");
+        let mut reader = BufReader::new(File::open(file)?);
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1 << 16];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        let actual = hex::encode(hasher.finalize());
+        if &actual != expected {
+            return Err(anyhow!("backup {} has digest {} but the manifest lists {}", file.display(), actual, expected));
         }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libflate::gzip::Encoder;
+    use std::{collections::BTreeMap, io::Write};
+
+    use super::*;
+
+    /// A blob as a backup frames it, of an account with no resources.
+    fn framed_blob() -> Vec<u8> {
+        let state: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+        let blob = AccountStateBlob::from(bcs::to_bytes(&state).unwrap());
+        let bytes = bcs::to_bytes(&(HashValue::zero(), blob)).unwrap();
+        let mut framed = (bytes.len() as u32).to_be_bytes().to_vec();
+        framed.extend(bytes);
+        framed
+    }
+
+    fn backup_of(dir: &tempfile::TempDir, bytes: &[u8]) -> Backup {
+        let path = dir.path().join("backup.gz");
+        let mut encoder = Encoder::new(File::create(&path).unwrap()).unwrap();
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().into_result().unwrap();
+        Backup::from_file(&path).unwrap()
+    }
+
+    #[test]
+    fn whole_blobs_are_read_to_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob = framed_blob();
+        let backup = backup_of(&dir, &[blob.clone(), blob.clone()].concat());
+        let states = backup.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(states.len(), 2);
+    }
+
+    #[test]
+    fn truncated_blobs_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob = framed_blob();
+
+        // cut off in the length prefix of the second blob
+        let mut backup = backup_of(&dir, &[&blob[..], &blob[..2]].concat());
+        assert!(backup.next().unwrap().is_ok());
+        let error = backup.next().unwrap().unwrap_err().to_string();
+        assert!(error.contains("truncated in blob length"), "{}", error);
+
+        // cut off in the blob itself
+        let mut backup = backup_of(&dir, &blob[..blob.len() - 1]);
+        let error = backup.next().unwrap().unwrap_err().to_string();
+        assert!(error.contains("truncated in blob of"), "{}", error);
+    }
+
+    #[test]
+    fn oversized_blobs_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob = framed_blob();
+        let mut backup = backup_of(&dir, &blob).with_max_blob_size(blob.len() - 5);
+        let error = backup.next().unwrap().unwrap_err().to_string();
+        assert!(error.contains("more than the maximum"), "{}", error);
+    }
+
+    /// Write `contents` to `name` in `dir`, returning its path.
+    fn file_of(dir: &tempfile::TempDir, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn digest(contents: &[u8]) -> String {
+        hex::encode(Sha256::digest(contents))
+    }
+
+    #[test]
+    fn manifests_are_checked_by_file_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = file_of(&dir, "first.gz", b"first");
+        let second = file_of(&dir, "second.gz", b"second");
+        // as sha256sum writes it, binary mode and directories included
+        let manifest = file_of(&dir, "SHA256SUMS", format!(
+            "{}  first.gz\n{} *backups/second.gz\n",
+            digest(b"first"),
+            digest(b"second"),
+        ).as_bytes());
+        verify_manifest(&manifest, &[first.clone(), second.clone()]).unwrap();
 
-        let (_, asb): (HashValue, AccountStateBlob) = match bcs::from_bytes(&buffer[0..blob_len]) {
-            Err(_) => return None,
-            Ok(r) => r,
-        };
+        let mismatched = file_of(&dir, "SHA256SUMS.bad", format!(
+            "{}  first.gz\n{}  second.gz\n",
+            digest(b"first"),
+            digest(b"other"),
+        ).as_bytes());
+        let error = verify_manifest(&mismatched, &[first.clone(), second]).unwrap_err().to_string();
+        assert!(error.contains(&format!("has digest {} but the manifest lists {}", digest(b"second"), digest(b"other"))), "{}", error);
 
-        AccountState::try_from(&asb).ok()
+        let unlisted = file_of(&dir, "third.gz", b"third");
+        let error = verify_manifest(&manifest, &[first, unlisted]).unwrap_err().to_string();
+        assert!(error.contains("third.gz is not listed in manifest"), "{}", error);
     }
 }
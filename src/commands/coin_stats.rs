@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use move_core_types::{
+    identifier::Identifier,
+    language_storage::{StructTag, TypeTag, CORE_CODE_ADDRESS},
+    value::MoveValue,
+};
+use sqlx::{sqlite::SqlitePool, Row};
+use std::{io::Write, sync::Arc};
+
+use crate::{
+    db::{self, Config},
+    fat_type::{FatStructType, FatType},
+    resolver::Resolver,
+};
+
+/// Print how many accounts hold `coin` and their total balance, read from
+/// the latest `0x1::DiemAccount::Balance<coin>` of each address. Values are
+/// read back through the resolver, so the sum is exact whatever numeric
+/// encoding or layout the database was indexed with.
+pub async fn run(pool: SqlitePool, config: Arc<Config>, coin: &StructTag, out: &mut impl Write) -> Result<()> {
+    let balance_tag = StructTag {
+        address: CORE_CODE_ADDRESS,
+        module: Identifier::new("DiemAccount")?,
+        name: Identifier::new("Balance")?,
+        type_params: vec![TypeTag::Struct(coin.clone())],
+    };
+    let table = db::root_table_name(&config, &balance_tag);
+    let mut conn = pool.acquire().await?;
+    let exists_sql = "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?";
+    if sqlx::query(exists_sql).bind(db::unquote(&table)).fetch_optional(&mut conn).await?.is_none() {
+        return Err(anyhow!("no {} is stored", balance_tag));
+    }
+
+    // Balance<C> holds a Diem<C> in `coin`, which holds the amount in `value`
+    let resolver = Resolver::from_pool(pool.clone(), config.clone());
+    let balance = resolver.resolve_struct(&balance_tag).await?;
+    let (coin_idx, coin_type) = field(&balance, "coin")?;
+    let coin_struct = match coin_type {
+        FatType::Struct(s) => s,
+        _ => return Err(anyhow!("{}::coin is not a struct", balance_tag)),
+    };
+    let (value_idx, _) = field(coin_struct, "value")?;
+
+    // with history, an address has a row per version
+    let select_sql = format!(
+        "SELECT id FROM {table} AS r WHERE last_modified_version = (SELECT MAX(last_modified_version) FROM {table} AS h WHERE h.address = r.address)",
+        table = table,
+    );
+    let ids = sqlx::query(&select_sql)
+        .fetch_all(&mut conn)
+        .await?
+        .iter()
+        .map(|row| row.get::<i64, _>(0))
+        .collect::<Vec<_>>();
+
+    let mut holders = 0u64;
+    let mut total = 0u128;
+    for id in &ids {
+        let value = db::fetch_struct(&config, &balance_tag, *id, &resolver, &mut conn).await?;
+        let amount = match value {
+            Some(MoveValue::Struct(balance)) => match balance.fields().get(coin_idx) {
+                Some(MoveValue::Struct(coin)) => match coin.fields().get(value_idx) {
+                    Some(MoveValue::U64(amount)) => *amount,
+                    _ => return Err(anyhow!("{} row {} has no u64 value", balance_tag, id)),
+                },
+                _ => return Err(anyhow!("{} row {} has no coin", balance_tag, id)),
+            },
+            _ => return Err(anyhow!("{} row {} is missing its struct row", balance_tag, id)),
+        };
+        if amount > 0 {
+            holders += 1;
+        }
+        total += amount as u128;
+    }
+
+    writeln!(out, "{}: {} holders of {} accounts, total supply {}", coin, holders, ids.len(), total)?;
+    Ok(())
+}
+
+/// The position and type of a named field.
+fn field<'a>(struct_: &'a FatStructType, name: &str) -> Result<(usize, &'a FatType)> {
+    struct_
+        .fields
+        .iter()
+        .enumerate()
+        .find(|(_, (field_name, _))| field_name.as_str() == name)
+        .map(|(i, (_, ty))| (i, ty))
+        .ok_or_else(|| anyhow!("{}::{} has no field {}", struct_.module, struct_.name, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use diem_types::{access_path::Path, write_set::WriteOp};
+    use move_core_types::{account_address::AccountAddress, value::MoveStruct};
+    use vm::file_format::{Kind, SignatureToken, StructHandleIndex};
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct};
+
+    #[tokio::test]
+    async fn the_latest_balance_of_each_account_is_summed() {
+        let db = TestDb::new(Config { history: true, ..test_util::config() }).await;
+        let generic = |name, fields| TestStruct { name, is_resource: true, type_parameters: vec![Kind::All], fields };
+        let diem_of_t = SignatureToken::StructInstantiation(StructHandleIndex(1), vec![SignatureToken::TypeParameter(0)]);
+        db.publish(&test_util::module_bytes_at(CORE_CODE_ADDRESS, "DiemAccount", &[
+            generic("Balance", vec![("coin", diem_of_t)]),
+            generic("Diem", vec![("value", SignatureToken::U64)]),
+            TestStruct::plain("XUS", vec![("dummy", SignatureToken::Bool)]),
+            TestStruct::plain("XDX", vec![("dummy", SignatureToken::Bool)]),
+        ])).await;
+        let coin = |name: &str| StructTag {
+            address: CORE_CODE_ADDRESS,
+            module: Identifier::new("DiemAccount").unwrap(),
+            name: Identifier::new(name).unwrap(),
+            type_params: vec![],
+        };
+        let balance = StructTag {
+            address: CORE_CODE_ADDRESS,
+            module: Identifier::new("DiemAccount").unwrap(),
+            name: Identifier::new("Balance").unwrap(),
+            type_params: vec![TypeTag::Struct(coin("XUS"))],
+        };
+        let write = |version, byte, amount| {
+            let value = MoveStruct::new(vec![MoveValue::Struct(test_util::counter(amount))]);
+            let op = WriteOp::Value(bcs::to_bytes(&value).unwrap());
+            db.write(version, AccountAddress::new([byte; AccountAddress::LENGTH]), Path::Resource(balance.clone()), op)
+        };
+        write(1, 1, 10).await;
+        write(2, 1, 7).await;
+        write(2, 2, 5).await;
+        write(3, 3, 0).await;
+
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &coin("XUS"), &mut out).await.unwrap();
+        let expected = format!("{}: 2 holders of 3 accounts, total supply 12\n", coin("XUS"));
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let error = run(db.pool.clone(), db.config.clone(), &coin("XDX"), &mut vec![]).await.unwrap_err();
+        let missing = StructTag { type_params: vec![TypeTag::Struct(coin("XDX"))], ..balance };
+        assert_eq!(error.to_string(), format!("no {} is stored", missing));
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Result};
+use move_core_types::language_storage::StructTag;
+use sqlx::sqlite::SqlitePool;
+use std::{io::Write, sync::Arc};
+use vm::errors::Location;
+
+use crate::{
+    db::{self, Config},
+    fat_type::{FatStructType, FatType},
+    resolver::Resolver,
+};
+
+pub async fn run(pool: SqlitePool, config: Arc<Config>, tag: &StructTag, out: &mut impl Write) -> Result<()> {
+    let resolver = Resolver::from_pool(pool, config.clone());
+    let struct_ = resolver.resolve_struct(tag).await?;
+    writeln!(out, "{} in table {}", tag, db::struct_tag_to_sql(&config, tag))?;
+    writeln!(out, "    top level instances in table {}", db::root_table_name(&config, tag))?;
+    print_fields(&config, &struct_, 4, out)
+}
+
+fn print_fields(config: &Config, struct_: &FatStructType, indent: usize, out: &mut impl Write) -> Result<()> {
+    let tag = struct_tag(struct_)?;
+    for (field_name, field_type) in &struct_.fields {
+        writeln!(out, "{:indent$}{}: {}", "", field_name, type_name(field_type)?, indent = indent)?;
+        match field_type {
+            FatType::Vector(elem_type)
+                if matches!(**elem_type, FatType::U8) && db::is_auth_key_field(config, field_name.as_str()) => {
+                writeln!(out, "{:indent$}stored as hex text", "", indent = indent + 4)?;
+            },
+            // vectors other than those of primitives have their own table
+            FatType::Vector(elem_type) if !db::is_inline_vector(elem_type) => {
+                print_elements(config, db::vector_table_name(config, &tag, field_name), elem_type, indent + 4, out)?;
+            },
+            FatType::Struct(sub_struct) => {
+                let sub_tag = struct_tag(sub_struct)?;
+                if db::scalar_option_type(&sub_tag).is_some() {
+                    writeln!(out, "{:indent$}stored inline as a nullable column", "", indent = indent + 4)?;
+                } else if db::is_json_struct(config, sub_struct) {
+                    writeln!(out, "{:indent$}stored inline as a JSON column", "", indent = indent + 4)?;
+                } else {
+                    print_struct(config, sub_struct, indent + 4, out)?;
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+fn print_elements(config: &Config, table_name: String, elem_type: &FatType, indent: usize, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "{:indent$}elements in table {}", "", table_name, indent = indent)?;
+    match elem_type {
+        FatType::Vector(sub_type) if !db::is_inline_vector(sub_type) => {
+            print_elements(config, db::elements_table_name(&table_name), sub_type, indent + 4, out)
+        },
+        FatType::Struct(sub_struct) => print_struct(config, sub_struct, indent + 4, out),
+        _ => Ok(()),
+    }
+}
+
+fn print_struct(config: &Config, struct_: &FatStructType, indent: usize, out: &mut impl Write) -> Result<()> {
+    let tag = struct_tag(struct_)?;
+    writeln!(out, "{:indent$}{} in table {}", "", tag, db::struct_tag_to_sql(config, &tag), indent = indent)?;
+    print_fields(config, struct_, indent + 4, out)
+}
+
+fn struct_tag(struct_: &FatStructType) -> Result<StructTag> {
+    struct_
+        .struct_tag()
+        .map_err(|e| anyhow!("{}", e.finish(Location::Undefined).into_vm_status()))
+}
+
+fn type_name(ty: &FatType) -> Result<String> {
+    ty.type_tag()
+        .map(|tag| tag.to_string())
+        .map_err(|e| anyhow!("{}", e.finish(Location::Undefined).into_vm_status()))
+}
+
+#[cfg(test)]
+mod tests {
+    use move_core_types::{identifier::Identifier, language_storage::TypeTag};
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct};
+
+    #[tokio::test]
+    async fn fields_are_listed_with_their_tables() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::module_bytes("D", &[
+            TestStruct::resource("R", vec![
+                ("value", SignatureToken::U64),
+                ("items", test_util::vector_token(test_util::struct_token(1))),
+            ]),
+            TestStruct::plain("Item", vec![("owner", SignatureToken::Address)]),
+        ])).await;
+        let tag = test_util::struct_tag("D", "R", vec![]);
+        let item_tag = test_util::struct_tag("D", "Item", vec![]);
+
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &tag, &mut out).await.unwrap();
+        let items = db::vector_table_name(&db.config, &tag, &Identifier::new("items").unwrap());
+        let expected = [
+            format!("{} in table {}", tag, db::struct_tag_to_sql(&db.config, &tag)),
+            format!("    top level instances in table {}", db::root_table_name(&db.config, &tag)),
+            "    value: u64".to_string(),
+            format!("    items: {}", TypeTag::Vector(Box::new(TypeTag::Struct(item_tag.clone())))),
+            format!("        elements in table {}", items),
+            format!("            {} in table {}", item_tag, db::struct_tag_to_sql(&db.config, &item_tag)),
+            "                owner: address".to_string(),
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n") + "\n");
+
+        let missing = test_util::struct_tag("D", "Missing", vec![]);
+        assert!(run(db.pool.clone(), db.config.clone(), &missing, &mut vec![]).await.is_err());
+    }
+}
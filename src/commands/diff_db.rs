@@ -0,0 +1,223 @@
+use anyhow::{anyhow, Result};
+use sqlx::{
+    pool::PoolConnection,
+    sqlite::{Sqlite, SqlitePool, SqlitePoolOptions},
+    Row,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    db::{self, Config},
+    resolver::Resolver,
+};
+
+/// One of the databases being compared.
+struct Side {
+    name: String,
+    pool: SqlitePool,
+    resolver: Resolver,
+}
+
+impl Side {
+    async fn open(path: &Path, config: &Arc<Config>) -> Result<Side> {
+        if !path.exists() {
+            return Err(anyhow!("{} does not exist", path.display()));
+        }
+        let pool = SqlitePoolOptions::new()
+            .connect(&format!("sqlite:{}", path.display()))
+            .await?;
+        Ok(Side::from_pool(path.display().to_string(), pool, config))
+    }
+
+    fn from_pool(name: String, pool: SqlitePool, config: &Arc<Config>) -> Side {
+        Side { name, resolver: Resolver::from_pool(pool.clone(), config.clone()), pool }
+    }
+
+    /// The `__root__` tables, keyed by the table name without the prefix.
+    async fn root_tables(&self, config: &Config) -> Result<BTreeSet<String>> {
+        let root_prefix = format!("{}__root__", config.table_prefix);
+        Ok(sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| row.get::<String, _>(0))
+            .filter_map(|name| name.strip_prefix(root_prefix.as_str()).map(str::to_string))
+            .collect())
+    }
+
+    /// The struct id of the latest value of each address in a root table.
+    async fn roots(&self, table: &str, db: &mut PoolConnection<Sqlite>) -> Result<BTreeMap<Vec<u8>, i64>> {
+        // with history, an address has a row per version
+        let select_sql = format!(
+            "SELECT address, id FROM {table} AS r WHERE last_modified_version = (SELECT MAX(last_modified_version) FROM {table} AS h WHERE h.address = r.address)",
+            table = table,
+        );
+        Ok(sqlx::query(&select_sql)
+            .fetch_all(db)
+            .await?
+            .iter()
+            .map(|row| (row.get::<Vec<u8>, _>(0), row.get::<i64, _>(1)))
+            .collect())
+    }
+}
+
+/// Compare the resources stored in two databases written with the same
+/// settings, printing every type and address stored in only one of them and
+/// every resource whose value differs. Values are reconstructed before being
+/// compared, so only differences in the data count, not in how it is laid
+/// out.
+pub async fn run(config: Arc<Config>, a: &Path, b: &Path, out: &mut impl Write) -> Result<()> {
+    let a = Side::open(a, &config).await?;
+    let b = Side::open(b, &config).await?;
+    compare(&config, &a, &b, out).await
+}
+
+async fn compare(config: &Config, a: &Side, b: &Side, out: &mut impl Write) -> Result<()> {
+    let mut a_db = a.pool.acquire().await?;
+    let mut b_db = b.pool.acquire().await?;
+
+    let a_tables = a.root_tables(config).await?;
+    let b_tables = b.root_tables(config).await?;
+    let mut differences = 0;
+    for name in a_tables.union(&b_tables) {
+        let tag = match db::struct_tag_from_name(name) {
+            Some(tag) => tag,
+            None => {
+                let table = format!("{}__root__{}", config.table_prefix, name);
+                tracing::warn!(table = %table, "cannot tell the type stored in the table; not comparing it");
+                continue;
+            },
+        };
+        if !a_tables.contains(name) || !b_tables.contains(name) {
+            let only_in = if a_tables.contains(name) { &a.name } else { &b.name };
+            writeln!(out, "{} is only stored in {}", tag, only_in)?;
+            differences += 1;
+            continue;
+        }
+        // reading a value back needs its type, and would panic without it
+        let mut resolved = true;
+        for side in &[a, b] {
+            if let Err(e) = side.resolver.resolve_struct(&tag).await {
+                tracing::warn!(resource = %tag, database = %side.name, "cannot resolve the type; not comparing it: {}", e);
+                resolved = false;
+            }
+        }
+        if !resolved {
+            continue;
+        }
+
+        let table = db::root_table_name(config, &tag);
+        let a_roots = a.roots(&table, &mut a_db).await?;
+        let b_roots = b.roots(&table, &mut b_db).await?;
+        for address in a_roots.keys().filter(|address| !b_roots.contains_key(*address)) {
+            writeln!(out, "{} at {} is only stored in {}", tag, hex::encode(address), a.name)?;
+            differences += 1;
+        }
+        for address in b_roots.keys().filter(|address| !a_roots.contains_key(*address)) {
+            writeln!(out, "{} at {} is only stored in {}", tag, hex::encode(address), b.name)?;
+            differences += 1;
+        }
+
+        for (address, a_id) in &a_roots {
+            let b_id = match b_roots.get(address) {
+                Some(b_id) => *b_id,
+                None => continue,
+            };
+            let a_value = db::fetch_struct(config, &tag, *a_id, &a.resolver, &mut a_db).await?;
+            let b_value = db::fetch_struct(config, &tag, b_id, &b.resolver, &mut b_db).await?;
+            if a_value != b_value {
+                writeln!(out, "{} at {} differs", tag, hex::encode(address))?;
+                differences += 1;
+            }
+        }
+    }
+
+    writeln!(out, "compared {} types: {} differences", a_tables.union(&b_tables).count(), differences)?;
+    if differences > 0 {
+        return Err(anyhow!("{} and {} differ", a.name, b.name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use diem_types::{access_path::Path, write_set::WriteOp};
+    use move_core_types::{account_address::AccountAddress, language_storage::StructTag};
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct};
+
+    async fn write(db: &TestDb, address: AccountAddress, tag: &StructTag, value: u64) {
+        let op = WriteOp::Value(bcs::to_bytes(&test_util::counter(value)).unwrap());
+        db.write(1, address, Path::Resource(tag.clone()), op).await;
+    }
+
+    #[tokio::test]
+    async fn types_addresses_and_values_that_differ_are_listed() {
+        let module = test_util::module_bytes("M", &[
+            TestStruct::resource("R", vec![("value", SignatureToken::U64)]),
+            TestStruct::resource("S", vec![("value", SignatureToken::U64)]),
+        ]);
+        let r = test_util::struct_tag("M", "R", vec![]);
+        let s = test_util::struct_tag("M", "S", vec![]);
+        let only_in_a = AccountAddress::new([1; AccountAddress::LENGTH]);
+        let same = AccountAddress::new([2; AccountAddress::LENGTH]);
+
+        let a = TestDb::new(test_util::config()).await;
+        let b = TestDb::new(test_util::config()).await;
+        for db in &[&a, &b] {
+            db.publish(&module).await;
+            write(db, same, &r, 3).await;
+        }
+        write(&a, test_util::ADDRESS, &r, 1).await;
+        write(&b, test_util::ADDRESS, &r, 2).await;
+        write(&a, only_in_a, &r, 4).await;
+        write(&a, test_util::ADDRESS, &s, 5).await;
+
+        let a_side = Side::from_pool("a".to_string(), a.pool.clone(), &a.config);
+        let b_side = Side::from_pool("b".to_string(), b.pool.clone(), &b.config);
+        let mut out = vec![];
+        let error = compare(&a.config, &a_side, &b_side, &mut out).await.unwrap_err();
+        assert_eq!(error.to_string(), "a and b differ");
+        let expected = [
+            format!("{} at {} is only stored in a", r, hex::encode(only_in_a)),
+            format!("{} at {} differs", r, hex::encode(test_util::ADDRESS)),
+            format!("{} is only stored in a", s),
+            "compared 2 types: 3 differences".to_string(),
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n") + "\n");
+
+        let mut out = vec![];
+        compare(&a.config, &a_side, &a_side, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "compared 2 types: 0 differences\n");
+    }
+
+    #[tokio::test]
+    async fn tables_of_unknown_types_are_skipped_with_a_warning() {
+        let db = TestDb::new(test_util::config()).await;
+        // `M` is never published, so `R` doesn't resolve
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let unparsed = db::quote(&format!("{}__root__bogus", db.config.table_prefix));
+        for table in &[db::root_table_name(&db.config, &tag), unparsed] {
+            let create_sql = format!("CREATE TABLE {} (address BLOB NOT NULL, id INTEGER NOT NULL)", table);
+            sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+        }
+
+        let side = Side::from_pool("a".to_string(), db.pool.clone(), &db.config);
+        let (logs, _guard) = test_util::Logs::capture();
+        let mut out = vec![];
+        compare(&db.config, &side, &side, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "compared 2 types: 0 differences\n");
+        let contents = logs.contents();
+        assert!(contents.contains("cannot tell the type stored in the table; not comparing it"), "{}", contents);
+        assert!(contents.contains("table=__root__bogus"), "{}", contents);
+        assert!(contents.contains(&format!("resource={} database=a", tag)), "{}", contents);
+        assert!(contents.contains("cannot resolve the type; not comparing it"), "{}", contents);
+    }
+}
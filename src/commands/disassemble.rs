@@ -0,0 +1,175 @@
+use anyhow::Result;
+use move_core_types::{account_address::AccountAddress, identifier::Identifier};
+use sqlx::sqlite::SqlitePool;
+use std::{io::Write, sync::Arc};
+use vm::{
+    access::ModuleAccess,
+    file_format::{Bytecode, CompiledModule, SignatureToken, StructFieldInformation, StructHandleIndex},
+};
+
+use crate::{
+    db::Config,
+    resolver::Resolver,
+};
+
+/// Print a stored module's dependencies, structs, and functions, with the
+/// bytecode of each function that has any.
+pub async fn run(
+    pool: SqlitePool,
+    config: Arc<Config>,
+    address: &AccountAddress,
+    name: &Identifier,
+    out: &mut impl Write,
+) -> Result<()> {
+    let resolver = Resolver::from_pool(pool, config);
+    let module = resolver.get_module(address, name).await?;
+
+    writeln!(out, "module {}", module.self_id())?;
+    for dependency in module.immediate_dependencies() {
+        writeln!(out, "    use {}", dependency)?;
+    }
+
+    for struct_def in module.struct_defs() {
+        let handle = module.struct_handle_at(struct_def.struct_handle);
+        writeln!(out)?;
+        writeln!(
+            out,
+            "    {}struct {}{} {{",
+            if handle.is_nominal_resource { "resource " } else { "" },
+            module.identifier_at(handle.name),
+            type_params(handle.type_parameters.len()),
+        )?;
+        match &struct_def.field_information {
+            StructFieldInformation::Native => writeln!(out, "        native")?,
+            StructFieldInformation::Declared(fields) => {
+                for field in fields {
+                    writeln!(
+                        out,
+                        "        {}: {},",
+                        module.identifier_at(field.name),
+                        token_name(&module, &field.signature.0),
+                    )?;
+                }
+            },
+        }
+        writeln!(out, "    }}")?;
+    }
+
+    for function_def in module.function_defs() {
+        let handle = module.function_handle_at(function_def.function);
+        let signature = |idx| {
+            module
+                .signature_at(idx)
+                .0
+                .iter()
+                .map(|token| token_name(&module, token))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        writeln!(out)?;
+        writeln!(
+            out,
+            "    {}fun {}{}({}): ({})",
+            if function_def.is_native() { "native " } else { "" },
+            module.identifier_at(handle.name),
+            type_params(handle.type_parameters.len()),
+            signature(handle.parameters),
+            signature(handle.return_),
+        )?;
+        if let Some(code) = &function_def.code {
+            writeln!(out, "        locals: ({})", signature(code.locals))?;
+            for (offset, instruction) in code.code.iter().enumerate() {
+                match instruction {
+                    // calls are the one thing worth naming to follow the code
+                    Bytecode::Call(idx) => {
+                        let callee = module.function_handle_at(*idx);
+                        let callee_module = module.module_handle_at(callee.module);
+                        writeln!(
+                            out,
+                            "        {}: Call {}::{}",
+                            offset,
+                            module.identifier_at(callee_module.name),
+                            module.identifier_at(callee.name),
+                        )?;
+                    },
+                    _ => writeln!(out, "        {}: {:?}", offset, instruction)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn type_params(count: usize) -> String {
+    if count == 0 {
+        return "".to_string();
+    }
+    let names = (0..count).map(|i| format!("T{}", i)).collect::<Vec<_>>();
+    format!("<{}>", names.join(", "))
+}
+
+fn token_name(module: &CompiledModule, token: &SignatureToken) -> String {
+    match token {
+        SignatureToken::Bool => "bool".to_string(),
+        SignatureToken::U8 => "u8".to_string(),
+        SignatureToken::U64 => "u64".to_string(),
+        SignatureToken::U128 => "u128".to_string(),
+        SignatureToken::Address => "address".to_string(),
+        SignatureToken::Signer => "signer".to_string(),
+        SignatureToken::Vector(elem) => format!("vector<{}>", token_name(module, elem)),
+        SignatureToken::Struct(idx) => struct_name(module, *idx),
+        SignatureToken::StructInstantiation(idx, ty_args) => format!(
+            "{}<{}>",
+            struct_name(module, *idx),
+            ty_args.iter().map(|ty| token_name(module, ty)).collect::<Vec<_>>().join(", "),
+        ),
+        SignatureToken::Reference(inner) => format!("&{}", token_name(module, inner)),
+        SignatureToken::MutableReference(inner) => format!("&mut {}", token_name(module, inner)),
+        SignatureToken::TypeParameter(idx) => format!("T{}", idx),
+    }
+}
+
+fn struct_name(module: &CompiledModule, idx: StructHandleIndex) -> String {
+    let handle = module.struct_handle_at(idx);
+    let module_handle = module.module_handle_at(handle.module);
+    format!("{}::{}", module.identifier_at(module_handle.name), module.identifier_at(handle.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use move_core_types::language_storage::ModuleId;
+    use vm::file_format::Kind;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct, ADDRESS};
+
+    #[tokio::test]
+    async fn structs_are_printed_with_their_fields() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::module_bytes("M", &[
+            TestStruct::resource("R", vec![
+                ("value", SignatureToken::U64),
+                ("owners", test_util::vector_token(SignatureToken::Address)),
+                ("item", SignatureToken::StructInstantiation(StructHandleIndex(1), vec![SignatureToken::U64])),
+            ]),
+            TestStruct {
+                name: "Box",
+                is_resource: false,
+                type_parameters: vec![Kind::All],
+                fields: vec![("item", SignatureToken::TypeParameter(0))],
+            },
+        ])).await;
+
+        let mut out = vec![];
+        let name = Identifier::new("M").unwrap();
+        run(db.pool.clone(), db.config.clone(), &ADDRESS, &name, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!(
+            "module {}\n\n    resource struct R {{\n        value: u64,\n        owners: vector<address>,\n        item: M::Box<u64>,\n    }}\n\n    struct Box<T0> {{\n        item: T0,\n    }}\n",
+            ModuleId::new(ADDRESS, name.clone()),
+        ));
+
+        // a module that was never published
+        let missing = Identifier::new("Missing").unwrap();
+        assert!(run(db.pool.clone(), db.config.clone(), &ADDRESS, &missing, &mut vec![]).await.is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use sqlx::sqlite::SqlitePool;
+use std::{collections::BTreeMap, fs, io::Write, path::Path, sync::Arc};
+
+use crate::{
+    db::{Config, DB},
+    resolver::{ModuleCache, Resolver, DEFAULT_MODULE_CACHE_SIZE},
+};
+
+/// Write the resolved layout of every stored resource type to `path` as one
+/// JSON object keyed by struct tag. Each generic instantiation is its own
+/// entry, with its type arguments substituted into the fields.
+pub async fn run(pool: SqlitePool, config: Arc<Config>, path: &Path, out: &mut impl Write) -> Result<()> {
+    let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+    let db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+    let resolver = Resolver::from_shared_cache(pool, config, modules);
+
+    let mut layouts = BTreeMap::new();
+    for tag in db.stored_types().await?.values() {
+        match resolver.resolve_struct(tag).await {
+            Ok(layout) => {
+                layouts.insert(tag.to_string(), serde_json::to_value(layout)?);
+            },
+            Err(e) => tracing::warn!(resource = %tag, "cannot resolve the type; leaving it out: {}", e),
+        }
+    }
+    fs::write(path, serde_json::to_string_pretty(&layouts)?)?;
+    writeln!(out, "wrote {} types to {}", layouts.len(), path.display())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use move_core_types::{language_storage::TypeTag, value::{MoveStruct, MoveValue}};
+    use vm::file_format::{Kind, SignatureToken};
+
+    use super::*;
+    use crate::{
+        db,
+        test_util::{self, TestDb, TestStruct},
+    };
+
+    #[tokio::test]
+    async fn each_instantiation_is_written_with_its_layout() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::module_bytes("G", &[TestStruct {
+            name: "Box",
+            is_resource: true,
+            type_parameters: vec![Kind::All],
+            fields: vec![("item", SignatureToken::TypeParameter(0))],
+        }])).await;
+        let of_u64 = test_util::struct_tag("G", "Box", vec![TypeTag::U64]);
+        let of_bool = test_util::struct_tag("G", "Box", vec![TypeTag::Bool]);
+        db.store(1, &of_u64, test_util::counter(1)).await;
+        db.store(1, &of_bool, MoveStruct::new(vec![MoveValue::Bool(true)])).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("types.json");
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &path, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("wrote 2 types to {}\n", path.display()));
+        let written: BTreeMap<String, serde_json::Value> = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let resolver = db.resolver();
+        let mut expected = BTreeMap::new();
+        for tag in &[&of_u64, &of_bool] {
+            let layout = resolver.resolve_struct(tag).await.unwrap();
+            expected.insert(tag.to_string(), serde_json::to_value(layout).unwrap());
+        }
+        assert_eq!(written, expected);
+    }
+
+    #[tokio::test]
+    async fn types_that_do_not_resolve_are_left_out_with_a_warning() {
+        let db = TestDb::new(test_util::config()).await;
+        // `M` is never published, so `R` doesn't resolve
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let create_sql = format!("CREATE TABLE {} (address BLOB NOT NULL, id INTEGER NOT NULL)", db::root_table_name(&db.config, &tag));
+        sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("types.json");
+        let (logs, _guard) = test_util::Logs::capture();
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &path, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("wrote 0 types to {}\n", path.display()));
+        let contents = logs.contents();
+        assert!(contents.contains("cannot resolve the type; leaving it out"), "{}", contents);
+        assert!(contents.contains(&format!("resource={}", tag)), "{}", contents);
+    }
+}
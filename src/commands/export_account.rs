@@ -0,0 +1,62 @@
+use anyhow::Result;
+use move_core_types::account_address::AccountAddress;
+use sqlx::sqlite::SqlitePool;
+use std::{fs, io::Write, path::Path, sync::Arc};
+
+use crate::{
+    db::{Config, DB},
+    resolver::{ModuleCache, DEFAULT_MODULE_CACHE_SIZE},
+};
+
+/// Write every stored resource of an account to `out` as one JSON object
+/// keyed by struct tag. An account with no resources is written as `{}`.
+pub async fn run(
+    pool: SqlitePool,
+    config: Arc<Config>,
+    address: &AccountAddress,
+    path: &Path,
+    out: &mut impl Write,
+) -> Result<()> {
+    let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+    let db = DB::from_pool(pool, config, modules);
+    let resources = db.read_account(address).await?;
+
+    let mut object = serde_json::Map::new();
+    for resource in &resources {
+        object.insert(resource.type_.to_string(), serde_json::to_value(resource)?);
+    }
+    fs::write(path, serde_json::to_string_pretty(&serde_json::Value::Object(object))?)?;
+    writeln!(out, "wrote {} resources of {} to {}", resources.len(), address, path.display())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, counter, counter_module, TestDb, ADDRESS};
+
+    #[tokio::test]
+    async fn resources_are_written_keyed_by_struct_tag() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        db.store(1, &tag, counter(7)).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("account.json");
+
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &ADDRESS, &path, &mut out).await.unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let expected = db.annotator().view_resource(&tag, &bcs::to_bytes(&counter(7)).unwrap()).await.unwrap();
+        assert_eq!(written, serde_json::json!({ tag.to_string(): serde_json::to_value(&expected).unwrap() }));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("wrote 1 resources of {} to {}\n", ADDRESS, path.display()),
+        );
+
+        // an account without resources is an empty object
+        let other = AccountAddress::new([1; AccountAddress::LENGTH]);
+        run(db.pool.clone(), db.config.clone(), &other, &path, &mut vec![]).await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+    }
+}
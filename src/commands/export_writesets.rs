@@ -0,0 +1,174 @@
+use anyhow::{anyhow, Result};
+use diem_types::{
+    access_path::AccessPath,
+    transaction::{Transaction, TransactionOutput},
+    write_set::{WriteOp, WriteSet},
+};
+use diem_vm::{DiemVM, VMExecutor};
+use sqlx::sqlite::SqlitePool;
+use std::{cmp, collections::HashMap, io::Write, path::Path, sync::Arc};
+use url::Url;
+
+use crate::{
+    db::{Config, DB},
+    resolver::{ModuleCache, DEFAULT_MODULE_CACHE_SIZE},
+    state::{GenesisState, SqlState},
+    transactions::{FramedWriter, TransactionSource},
+};
+
+/// How many transactions are fetched and executed together.
+const CHUNK_SIZE: u64 = 100;
+
+/// Execute versions `from` to `to` against the state right before `from`
+/// and write each one's write set to `file`, framed like a transactions file:
+/// the version of the first as a big-endian u64, then each bcs encoded and
+/// prefixed with its length as a big-endian u32.
+///
+/// Nothing is written to the database. Each chunk reads what the chunks
+/// before it wrote from memory, so without history the database must be
+/// synced to exactly `from`.
+pub async fn run(
+    pool: SqlitePool,
+    config: Arc<Config>,
+    endpoint: Url,
+    from: u64,
+    to: u64,
+    file: &Path,
+    out: &mut impl Write,
+) -> Result<()> {
+    let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+    let db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+    check_range(&config, from, to, db.sync_state().await.next_version)?;
+
+    let source = TransactionSource::from_endpoints(&[endpoint]).await?;
+    let mut writer = FramedWriter::create(file, from)?;
+    // every write so far, read in place of the database's older values
+    let mut pending = HashMap::new();
+    let mut first_version = from;
+    while first_version <= to {
+        let count = chunk_size(first_version, to);
+        let txs = source
+            .get_transactions(first_version, count, false)
+            .await?
+            .into_iter()
+            .map(|(tx, _)| tx)
+            .collect::<Vec<_>>();
+        if txs.len() as u64 != count {
+            return Err(anyhow!("the endpoint returned {} of versions {} to {}", txs.len(), first_version, first_version + count - 1));
+        }
+        let outputs = execute(&pool, &config, &modules, first_version, &pending, txs).await?;
+        for output in &outputs {
+            writer.append(output.write_set())?;
+            record_writes(&mut pending, output.write_set());
+        }
+        writeln!(out, "exported {} to {}", first_version, first_version + count - 1)?;
+        first_version += count;
+    }
+    writer.finish()?;
+    writeln!(out, "wrote the write sets of {} to {} to {}", from, to, file.display())?;
+    Ok(())
+}
+
+/// Whether versions `from` to `to` can be executed against a database whose
+/// next version is `next_version`.
+fn check_range(config: &Config, from: u64, to: u64, next_version: u64) -> Result<()> {
+    if from > to {
+        return Err(anyhow!("--from must not be after --to"));
+    }
+    if config.history && from > next_version {
+        return Err(anyhow!("the database is only synced up to version {}", next_version.saturating_sub(1)));
+    }
+    if !config.history && from != next_version {
+        return Err(anyhow!(
+            "without --history, the database must be synced to right before version {}, but its next version is {}",
+            from,
+            next_version,
+        ));
+    }
+    Ok(())
+}
+
+/// How many transactions to execute from `first_version`, up to `to`.
+/// Genesis executes against empty state, on its own.
+fn chunk_size(first_version: u64, to: u64) -> u64 {
+    if first_version == 0 {
+        1
+    } else {
+        cmp::min(CHUNK_SIZE, to - first_version + 1)
+    }
+}
+
+/// Record the writes of `write_set` in `pending`, with deletions as `None`.
+fn record_writes(pending: &mut HashMap<AccessPath, Option<Vec<u8>>>, write_set: &WriteSet) {
+    for (access_path, write_op) in write_set {
+        let value = match write_op {
+            WriteOp::Value(v) => Some(v.clone()),
+            WriteOp::Deletion => None,
+        };
+        pending.insert(access_path.clone(), value);
+    }
+}
+
+async fn execute(
+    pool: &SqlitePool,
+    config: &Arc<Config>,
+    modules: &Arc<ModuleCache>,
+    first_version: u64,
+    pending: &HashMap<AccessPath, Option<Vec<u8>>>,
+    txs: Vec<Transaction>,
+) -> Result<Vec<TransactionOutput>> {
+    let outputs = if first_version == 0 {
+        tokio::task::spawn_blocking(move || DiemVM::execute_block(txs, &GenesisState)).await?
+    } else {
+        let sql_state = SqlState::from_pool(pool.clone(), config.clone(), modules.clone())
+            .at_version(first_version - 1)
+            .with_pending(pending.clone());
+        tokio::task::spawn_blocking(move || DiemVM::execute_block(txs, &sql_state)).await?
+    };
+    outputs.map_err(|status| anyhow!("executing from version {} failed: {:?}", first_version, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use diem_types::write_set::WriteSetMut;
+
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn ranges_must_start_where_the_database_allows() {
+        let config = test_util::config();
+        assert!(check_range(&config, 5, 9, 5).is_ok());
+        assert!(check_range(&config, 5, 5, 5).is_ok());
+        assert!(check_range(&config, 6, 5, 5).unwrap_err().to_string().contains("--from"));
+        // without history, only the next version has its state before it
+        let error = check_range(&config, 4, 9, 5).unwrap_err().to_string();
+        assert!(error.contains("its next version is 5"), "{}", error);
+        assert!(check_range(&config, 6, 9, 5).is_err());
+
+        let config = Config { history: true, ..test_util::config() };
+        assert!(check_range(&config, 0, 9, 5).is_ok());
+        assert!(check_range(&config, 5, 9, 5).is_ok());
+        let error = check_range(&config, 6, 9, 5).unwrap_err().to_string();
+        assert!(error.contains("only synced up to version 4"), "{}", error);
+    }
+
+    #[test]
+    fn genesis_executes_alone_and_the_last_chunk_stops_at_to() {
+        assert_eq!(chunk_size(0, 500), 1);
+        assert_eq!(chunk_size(1, 500), CHUNK_SIZE);
+        assert_eq!(chunk_size(450, 500), 51);
+        assert_eq!(chunk_size(500, 500), 1);
+    }
+
+    #[test]
+    fn later_writes_replace_earlier_ones() {
+        let path = |i| AccessPath::new(test_util::ADDRESS, vec![i]);
+        let write_set = |ops: Vec<(AccessPath, WriteOp)>| WriteSetMut::new(ops).freeze().unwrap();
+        let mut pending = HashMap::new();
+        record_writes(&mut pending, &write_set(vec![(path(1), WriteOp::Value(vec![1])), (path(2), WriteOp::Value(vec![2]))]));
+        record_writes(&mut pending, &write_set(vec![(path(1), WriteOp::Deletion), (path(3), WriteOp::Value(vec![3]))]));
+        let expected = vec![(path(1), None), (path(2), Some(vec![2])), (path(3), Some(vec![3]))];
+        assert_eq!(pending, expected.into_iter().collect());
+    }
+}
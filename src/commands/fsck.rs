@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Result};
+use sqlx::{sqlite::SqlitePool, Row};
+use std::{collections::HashSet, io::Write, sync::Arc};
+use vm::errors::Location;
+
+use crate::{
+    db::{self, Config},
+    fat_type::{FatStructType, FatType},
+    resolver::Resolver,
+};
+
+/// How many dangling references of one column are listed before the rest are
+/// only counted.
+const MAX_LISTED: usize = 20;
+
+/// An integer column holding the id of a row in another table.
+#[derive(PartialEq, Eq, Hash)]
+struct Reference {
+    table: String,
+    column: String,
+    target: String,
+    target_key: &'static str,
+}
+
+/// Check that every id stored in a `__root__` table, a struct table's
+/// sub-struct column, or an element table's `parent_id` or struct `slot`
+/// names an existing row, printing each one that doesn't. The references are
+/// found from the types stored, so every root table's type must resolve.
+pub async fn run(pool: SqlitePool, config: Arc<Config>, out: &mut impl Write) -> Result<()> {
+    let resolver = Resolver::from_pool(pool.clone(), config.clone());
+    let mut db = pool.acquire().await?;
+    let tables = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'")
+        .fetch_all(&mut db)
+        .await?
+        .iter()
+        .map(|row| db::quote(&row.get::<String, _>(0)))
+        .collect::<HashSet<_>>();
+
+    let root_prefix = format!("{}__root__", config.table_prefix);
+    let mut root_tables = tables.iter().filter(|name| db::unquote(name).starts_with(&root_prefix)).collect::<Vec<_>>();
+    root_tables.sort();
+    let mut references = vec![];
+    let mut seen = HashSet::new();
+    for root_table in root_tables {
+        let tag = match db::struct_tag_from_name(&db::unquote(root_table)[root_prefix.len()..]) {
+            Some(tag) => tag,
+            None => {
+                tracing::warn!(table = %root_table, "cannot tell the type stored in the table; not checking it");
+                continue;
+            },
+        };
+        let struct_ = match resolver.resolve_struct(&tag).await {
+            Ok(struct_) => struct_,
+            Err(e) => {
+                tracing::warn!(resource = %tag, table = %root_table, "cannot resolve the type; not checking the table: {}", e);
+                continue;
+            },
+        };
+        let table = db::struct_tag_to_sql(&config, &tag);
+        add_reference(&mut references, &mut seen, root_table.clone(), "id", table.clone(), "__id");
+        struct_references(&config, &struct_, table, &mut references, &mut seen)?;
+    }
+
+    let mut dangling = 0;
+    for reference in &references {
+        // nothing refers to anything from a table that was never created
+        if !tables.contains(&reference.table) {
+            continue;
+        }
+        let select_sql = if tables.contains(&reference.target) {
+            format!(
+                "SELECT p.rowid, p.{column} FROM {table} p WHERE p.{column} IS NOT NULL AND NOT EXISTS (SELECT 1 FROM {target} t WHERE t.{key} = p.{column})",
+                column = db::quote(&reference.column),
+                table = reference.table,
+                target = reference.target,
+                key = reference.target_key,
+            )
+        } else {
+            format!(
+                "SELECT rowid, {column} FROM {table} WHERE {column} IS NOT NULL",
+                column = db::quote(&reference.column),
+                table = reference.table,
+            )
+        };
+        let rows = sqlx::query(&select_sql).fetch_all(&mut db).await?;
+        for row in rows.iter().take(MAX_LISTED) {
+            writeln!(
+                out,
+                "dangling: {}.{} of row {} is {}, which is not in {}",
+                reference.table,
+                reference.column,
+                row.get::<i64, _>(0),
+                row.get::<i64, _>(1),
+                reference.target,
+            )?;
+        }
+        if rows.len() > MAX_LISTED {
+            writeln!(out, "dangling: {} more in {}.{}", rows.len() - MAX_LISTED, reference.table, reference.column)?;
+        }
+        dangling += rows.len();
+    }
+
+    writeln!(out, "checked {} references: {} dangling", references.len(), dangling)?;
+    if dangling > 0 {
+        return Err(anyhow!("{} dangling references found", dangling));
+    }
+    Ok(())
+}
+
+fn add_reference(
+    references: &mut Vec<Reference>,
+    seen: &mut HashSet<(String, String)>,
+    table: String,
+    column: &str,
+    target: String,
+    target_key: &'static str,
+) -> bool {
+    if !seen.insert((table.clone(), column.to_string())) {
+        return false;
+    }
+    references.push(Reference {
+        table,
+        column: column.to_string(),
+        target,
+        target_key,
+    });
+    true
+}
+
+/// The references out of a struct's table and the tables below it, laid out
+/// the way `db::struct_to_sql` writes them.
+fn struct_references(
+    config: &Config,
+    struct_: &FatStructType,
+    table: String,
+    references: &mut Vec<Reference>,
+    seen: &mut HashSet<(String, String)>,
+) -> Result<()> {
+    let tag = struct_
+        .struct_tag()
+        .map_err(|e| anyhow!("{}", e.finish(Location::Undefined).into_vm_status()))?;
+    for (field_name, field_type) in &struct_.fields {
+        match field_type {
+            FatType::Struct(sub_struct) => {
+                let sub_tag = sub_struct
+                    .struct_tag()
+                    .map_err(|e| anyhow!("{}", e.finish(Location::Undefined).into_vm_status()))?;
+                // these are stored inline rather than referenced
+                if db::scalar_option_type(&sub_tag).is_some() || db::is_json_struct(config, sub_struct) {
+                    continue;
+                }
+                let sub_table = db::struct_tag_to_sql(config, &sub_tag);
+                if add_reference(references, seen, table.clone(), field_name.as_str(), sub_table.clone(), "__id") {
+                    struct_references(config, sub_struct, sub_table, references, seen)?;
+                }
+            },
+            FatType::Vector(elem_type) if !db::is_inline_vector(elem_type) => {
+                let elements = db::vector_table_name(config, &tag, field_name);
+                if add_reference(references, seen, elements.clone(), "parent_id", table.clone(), "__id") {
+                    element_references(config, elem_type, elements, references, seen)?;
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// The references out of an element table, whose own ids are referred to by
+/// the `parent_id` of its nested vectors' tables.
+fn element_references(
+    config: &Config,
+    elem_type: &FatType,
+    table: String,
+    references: &mut Vec<Reference>,
+    seen: &mut HashSet<(String, String)>,
+) -> Result<()> {
+    match elem_type {
+        FatType::Struct(elem_struct) => {
+            let elem_tag = elem_struct
+                .struct_tag()
+                .map_err(|e| anyhow!("{}", e.finish(Location::Undefined).into_vm_status()))?;
+            let elem_table = db::struct_tag_to_sql(config, &elem_tag);
+            if add_reference(references, seen, table, "slot", elem_table.clone(), "__id") {
+                struct_references(config, elem_struct, elem_table, references, seen)?;
+            }
+        },
+        FatType::Vector(sub_type) if !db::is_inline_vector(sub_type) => {
+            let child = db::elements_table_name(&table);
+            if add_reference(references, seen, child.clone(), "parent_id", table, "id") {
+                element_references(config, sub_type, child, references, seen)?;
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use move_core_types::{
+        account_address::AccountAddress,
+        identifier::Identifier,
+        value::{MoveStruct, MoveValue},
+    };
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct, ADDRESS};
+
+    #[tokio::test]
+    async fn dangling_references_are_listed() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let inner_tag = test_util::struct_tag("M", "Inner", vec![]);
+        db.publish(&test_util::module_bytes("M", &[
+            TestStruct::resource("R", vec![
+                ("inner", test_util::struct_token(1)),
+                ("owners", test_util::vector_token(SignatureToken::Address)),
+            ]),
+            TestStruct::plain("Inner", vec![("value", SignatureToken::U64)]),
+        ])).await;
+        db.store(1, &tag, MoveStruct::new(vec![
+            MoveValue::Struct(MoveStruct::new(vec![MoveValue::U64(1)])),
+            MoveValue::Vector(vec![MoveValue::Address(ADDRESS), MoveValue::Address(AccountAddress::new([1; AccountAddress::LENGTH]))]),
+        ])).await;
+
+        // the root row, the sub-struct and the vector's parent
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "checked 3 references: 0 dangling\n");
+
+        let struct_table = db::struct_tag_to_sql(&db.config, &tag);
+        let inner_table = db::struct_tag_to_sql(&db.config, &inner_tag);
+        sqlx::query(&format!("DELETE FROM {}", inner_table)).execute(&db.pool).await.unwrap();
+        let mut out = vec![];
+        let error = run(db.pool.clone(), db.config.clone(), &mut out).await.unwrap_err().to_string();
+        assert_eq!(error, "1 dangling references found");
+        assert_eq!(String::from_utf8(out).unwrap(), format!(
+            "dangling: {}.inner of row 1 is 1, which is not in {}\nchecked 3 references: 1 dangling\n",
+            struct_table,
+            inner_table,
+        ));
+
+        // with the struct row gone too, everything pointing at it dangles
+        // instead
+        sqlx::query(&format!("DELETE FROM {}", struct_table)).execute(&db.pool).await.unwrap();
+        let mut out = vec![];
+        let error = run(db.pool.clone(), db.config.clone(), &mut out).await.unwrap_err().to_string();
+        assert_eq!(error, "3 dangling references found");
+        let out = String::from_utf8(out).unwrap();
+        let owners = db::vector_table_name(&db.config, &tag, &Identifier::new("owners").unwrap());
+        for line in &[
+            format!("dangling: {}.id of row 1 is 1, which is not in {}", db::root_table_name(&db.config, &tag), struct_table),
+            format!("dangling: {}.parent_id of row 1 is 1, which is not in {}", owners, struct_table),
+            format!("dangling: {}.parent_id of row 2 is 1, which is not in {}", owners, struct_table),
+            "checked 3 references: 3 dangling".to_string(),
+        ] {
+            assert!(out.lines().any(|l| l == line), "{} not in {}", line, out);
+        }
+    }
+
+    #[tokio::test]
+    async fn tables_of_unknown_types_are_skipped_with_a_warning() {
+        let db = TestDb::new(test_util::config()).await;
+        // `M` is never published, so `R` doesn't resolve
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let unparsed = db::quote(&format!("{}__root__bogus", db.config.table_prefix));
+        for table in &[db::root_table_name(&db.config, &tag), unparsed] {
+            let create_sql = format!("CREATE TABLE {} (address BLOB NOT NULL, id INTEGER NOT NULL)", table);
+            sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+        }
+
+        let (logs, _guard) = test_util::Logs::capture();
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "checked 0 references: 0 dangling\n");
+        let contents = logs.contents();
+        assert!(contents.contains("cannot tell the type stored in the table; not checking it"), "{}", contents);
+        assert!(contents.contains("__root__bogus"), "{}", contents);
+        assert!(contents.contains(&format!("resource={}", tag)), "{}", contents);
+        assert!(contents.contains("cannot resolve the type; not checking the table"), "{}", contents);
+    }
+}
@@ -0,0 +1,180 @@
+#[cfg(feature = "replay")]
+use anyhow::anyhow;
+use anyhow::Result;
+use move_core_types::{account_address::AccountAddress, identifier::Identifier, language_storage::StructTag};
+use sqlx::sqlite::SqlitePool;
+use std::{
+    io,
+    path::{Path as FilePath, PathBuf},
+    sync::Arc,
+};
+use structopt::StructOpt;
+use url::Url;
+
+use crate::{
+    db::Config,
+    migrate,
+    parser,
+};
+
+mod coin_stats;
+mod describe_type;
+mod diff_db;
+mod disassemble;
+mod dump_types;
+mod export_account;
+#[cfg(feature = "replay")]
+mod export_writesets;
+mod fsck;
+#[cfg(feature = "replay")]
+mod replay_one;
+mod selfcheck;
+mod stats;
+#[cfg(feature = "replay")]
+mod verify;
+
+/// Commands that inspect an existing database instead of indexing a chain.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum Command {
+    /// Count the accounts holding a coin and their total balance, e.g.
+    /// `--coin 0x1::XUS::XUS`
+    CoinStats {
+        #[structopt(long, parse(try_from_str = parser::parse_struct_tag))]
+        coin: StructTag,
+    },
+    /// Print the fields of a struct type and the tables they are stored in
+    DescribeType {
+        #[structopt(long, parse(try_from_str = parser::parse_struct_tag))]
+        struct_tag: StructTag,
+    },
+    /// Compare the resources stored in two databases indexed with the same
+    /// options, listing every one stored in only one of them or whose value
+    /// differs, and exiting with an error if there are any
+    DiffDb {
+        #[structopt(long, parse(from_os_str))]
+        a: PathBuf,
+        #[structopt(long, parse(from_os_str))]
+        b: PathBuf,
+    },
+    /// Print the structs, functions, and bytecode of a stored module
+    Disassemble {
+        #[structopt(long, parse(try_from_str = parser::parse_address))]
+        address: AccountAddress,
+        #[structopt(long, parse(try_from_str = Identifier::new))]
+        module: Identifier,
+    },
+    /// Write the resolved layout of every stored resource type to a JSON
+    /// file, keyed by struct tag
+    DumpTypes {
+        #[structopt(long, parse(from_os_str))]
+        out: PathBuf,
+    },
+    /// Write every resource stored for an account to a JSON file, keyed by
+    /// struct tag
+    ExportAccount {
+        #[structopt(long, parse(try_from_str = parser::parse_address))]
+        address: AccountAddress,
+        #[structopt(long, parse(from_os_str))]
+        out: PathBuf,
+    },
+    /// Execute the transactions from one version to another against the
+    /// stored state and write their write sets to a file, framed like a
+    /// --transactions-file, without applying them
+    #[cfg(feature = "replay")]
+    ExportWritesets {
+        #[structopt(long)]
+        from: u64,
+        #[structopt(long)]
+        to: u64,
+        #[structopt(long, parse(from_os_str))]
+        out: PathBuf,
+    },
+    /// Check that every stored reference between generated tables names an
+    /// existing row, exiting with an error if any doesn't
+    Fsck,
+    /// Rewrite every stored resource written under an earlier schema version
+    /// with the current layout, instead of indexing the chain again. Takes
+    /// the same layout options the database was indexed with, except that
+    /// the numbers of a database at schema version 0, all stored as with
+    /// `--numeric-encoding blob`, are rewritten under --numeric-encoding
+    MigrateData {
+        /// The schema version the database was written under, as reported
+        /// when indexing resumes
+        #[structopt(long)]
+        from_schema: u64,
+    },
+    /// Execute the transaction at one version against the current state and
+    /// print its writes, applying them only to a scratch copy of the database
+    #[cfg(feature = "replay")]
+    ReplayOne {
+        #[structopt(long)]
+        version: u64,
+        /// Execute against the state right after this version instead, e.g.
+        /// to see how the transaction would have gone earlier. Requires a
+        /// database indexed with --history
+        #[structopt(long)]
+        as_of: Option<u64>,
+        /// Only print the writes, without applying them to the copy
+        #[structopt(long)]
+        dry_run: bool,
+        /// Print the SQL statements the writes run
+        #[structopt(long, conflicts_with("dry-run"))]
+        show_sql: bool,
+    },
+    /// Read back and annotate every stored resource, listing each that
+    /// can't be and exiting with an error if there are any
+    Selfcheck,
+    /// List every generated table with its row count and size, grouped by
+    /// the type stored and largest first
+    Stats,
+    /// Compare the stored state of accounts that sent transactions between
+    /// two versions with the endpoint's state, exiting with an error on any
+    /// mismatch
+    #[cfg(feature = "replay")]
+    Verify {
+        #[structopt(long)]
+        from: u64,
+        #[structopt(long)]
+        to: u64,
+    },
+}
+
+impl Command {
+    pub async fn run(self, pool: SqlitePool, config: Arc<Config>, database: &FilePath, endpoint: Option<Url>) -> Result<()> {
+        // only the commands that execute transactions, which need the VM,
+        // read these
+        #[cfg(not(feature = "replay"))]
+        let _ = (database, endpoint);
+        match self {
+            Command::CoinStats { coin } => coin_stats::run(pool, config, &coin, &mut io::stdout()).await,
+            Command::DescribeType { struct_tag } => describe_type::run(pool, config, &struct_tag, &mut io::stdout()).await,
+            Command::DiffDb { a, b } => {
+                drop(pool);
+                diff_db::run(config, &a, &b, &mut io::stdout()).await
+            },
+            Command::Disassemble { address, module } => disassemble::run(pool, config, &address, &module, &mut io::stdout()).await,
+            Command::DumpTypes { out } => dump_types::run(pool, config, &out, &mut io::stdout()).await,
+            Command::ExportAccount { address, out } => export_account::run(pool, config, &address, &out, &mut io::stdout()).await,
+            #[cfg(feature = "replay")]
+            Command::ExportWritesets { from, to, out } => {
+                let endpoint = endpoint.ok_or_else(|| anyhow!("export-writesets requires --endpoint"))?;
+                export_writesets::run(pool, config, endpoint, from, to, &out, &mut io::stdout()).await
+            },
+            Command::Fsck => fsck::run(pool, config, &mut io::stdout()).await,
+            Command::MigrateData { from_schema } => migrate::run(pool, config, from_schema, &mut io::stdout()).await,
+            #[cfg(feature = "replay")]
+            Command::ReplayOne { version, as_of, dry_run, show_sql } => {
+                let endpoint = endpoint.ok_or_else(|| anyhow!("replay-one requires --endpoint"))?;
+                replay_one::run(pool, config, database, endpoint, version, as_of, dry_run, show_sql, &mut io::stdout()).await
+            },
+            Command::Selfcheck => selfcheck::run(pool, config, &mut io::stdout()).await,
+            Command::Stats => stats::run(pool, config, &mut io::stdout()).await,
+            #[cfg(feature = "replay")]
+            Command::Verify { from, to } => {
+                let endpoint = endpoint.ok_or_else(|| anyhow!("verify requires --endpoint"))?;
+                verify::run(pool, config, endpoint, from, to, &mut io::stdout()).await
+            },
+        }
+    }
+}
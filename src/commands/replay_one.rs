@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Result};
+use diem_json_rpc_client::async_client::{Client, Retry};
+use diem_types::{
+    access_path::Path,
+    transaction::{Transaction, TransactionOutput},
+    write_set::{WriteOp, WriteSet},
+};
+use diem_vm::{DiemVM, VMExecutor};
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use std::{
+    fs,
+    io::Write,
+    path::{Path as FilePath, PathBuf},
+    sync::Arc,
+};
+use url::Url;
+
+use crate::{
+    annotator::MoveValueAnnotator,
+    db::{Config, DB},
+    resolver::{ModuleCache, Resolver, DEFAULT_MODULE_CACHE_SIZE},
+    state::{GenesisState, SqlState},
+    util,
+};
+
+/// Execute the transaction at `version` against the current state, or the
+/// state right after version `as_of`, and print its writes. Unless
+/// `dry_run` is set, the writes are also applied to a throwaway copy of the
+/// database, optionally printing the SQL they run.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    pool: SqlitePool,
+    config: Arc<Config>,
+    database: &FilePath,
+    endpoint: Url,
+    version: u64,
+    as_of: Option<u64>,
+    dry_run: bool,
+    show_sql: bool,
+    out: &mut impl Write,
+) -> Result<()> {
+    let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+    let db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+    let next_version = db.sync_state().await.next_version;
+    if let Some(as_of) = as_of {
+        if version == 0 {
+            return Err(anyhow!("genesis executes against empty state, not a snapshot"));
+        }
+        if as_of >= next_version {
+            return Err(anyhow!("the database is only synced up to version {}", next_version.saturating_sub(1)));
+        }
+    } else if next_version > version && !config.history {
+        tracing::warn!(
+            version,
+            next_version,
+            "the database is synced past the transaction, so it reads newer state than it originally did",
+        );
+    }
+
+    let client = Client::from_url(endpoint, Retry::default()).unwrap();
+    let txs = client.get_transactions(version, 1, false).await?;
+    let bytes = hex::decode(&txs.get(0).ok_or_else(|| anyhow!("version {} not found", version))?.bytes)?;
+    let tx: Transaction = bcs::from_bytes(&bytes)?;
+
+    let outputs = if version == 0 {
+        tokio::task::spawn_blocking(move || DiemVM::execute_block(vec![tx], &GenesisState)).await?
+    } else {
+        let sql_state = match as_of {
+            Some(as_of) => SqlState::as_of(pool.clone(), config.clone(), modules.clone(), as_of)?,
+            None => SqlState::from_pool(pool.clone(), config.clone(), modules.clone()).at_version(version - 1),
+        };
+        tokio::task::spawn_blocking(move || DiemVM::execute_block(vec![tx], &sql_state)).await?
+    };
+    let output: TransactionOutput = outputs
+        .map_err(|status| anyhow!("execution failed: {:?}", status))?
+        .remove(0);
+    writeln!(out, "status: {:?}", output.status())?;
+
+    let resolver = if version == 0 {
+        Resolver::from_pool_and_genesis_write_set(pool.clone(), config.clone(), output.write_set())
+    } else {
+        Resolver::from_shared_cache(pool.clone(), config.clone(), modules.clone())
+    };
+    let annotator = MoveValueAnnotator::new(resolver);
+    print_writes(&annotator, output.write_set(), out).await?;
+    if dry_run {
+        return Ok(());
+    }
+
+    let copy = replay_copy(database);
+    let copy_config = Arc::new(Config {
+        log_sql: show_sql,
+        ..(*config).clone()
+    });
+    let copy_pool = apply_to_copy(&pool, copy_config, modules, &copy, version, output.write_set(), &annotator, out).await?;
+    copy_pool.close().await;
+    fs::remove_file(&copy)?;
+    Ok(())
+}
+
+/// The scratch copy of `database` that writes are applied to, next to it.
+fn replay_copy(database: &FilePath) -> PathBuf {
+    let mut copy = database.as_os_str().to_owned();
+    copy.push(".replay");
+    PathBuf::from(copy)
+}
+
+/// Print every write in `write_set`, annotating the resources written.
+async fn print_writes(annotator: &MoveValueAnnotator, write_set: &WriteSet, out: &mut impl Write) -> Result<()> {
+    for (access_path, write_op) in write_set {
+        let (address, path) = match util::decode_access_path(access_path) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                writeln!(out, "write to {} ({})", access_path.address, e)?;
+                continue;
+            },
+        };
+        match (path, write_op) {
+            (Path::Code(id), WriteOp::Value(_)) => writeln!(out, "publish module {}", id)?,
+            (Path::Code(id), WriteOp::Deletion) => writeln!(out, "delete module {}", id)?,
+            (Path::Resource(tag), WriteOp::Deletion) => writeln!(out, "delete {} at {}", tag, address)?,
+            (Path::Resource(tag), WriteOp::Value(v)) => match annotator.view_resource(&tag, v).await {
+                Ok(resource) => writeln!(out, "write {} at {}:\n{}", tag, address, resource)?,
+                Err(e) => writeln!(out, "write {} at {} (cannot annotate: {})", tag, address, e)?,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Copy the database behind `pool` to `copy`, replacing any earlier copy,
+/// and apply `write_set` to it at `version`. Returns a pool on the copy,
+/// which is left for the caller to remove.
+#[allow(clippy::too_many_arguments)]
+async fn apply_to_copy(
+    pool: &SqlitePool,
+    config: Arc<Config>,
+    modules: Arc<ModuleCache>,
+    copy: &FilePath,
+    version: u64,
+    write_set: &WriteSet,
+    annotator: &MoveValueAnnotator,
+    out: &mut impl Write,
+) -> Result<SqlitePool> {
+    // the copy must not exist beforehand
+    if copy.exists() {
+        fs::remove_file(copy)?;
+    }
+    let vacuum_sql = format!("VACUUM INTO '{}'", copy.display().to_string().replace('\'', "''"));
+    sqlx::query(&vacuum_sql).execute(pool).await?;
+    let copy_pool = SqlitePoolOptions::new()
+        .connect(&format!("sqlite:{}", copy.display())).await?;
+    let copy_db = DB::from_pool(copy_pool.clone(), config, modules);
+    writeln!(out, "applying writes to {}", copy.display())?;
+    for (access_path, write_op) in write_set {
+        // modules can only be published once, and the database likely has
+        // this one already
+        if let Ok((_, Path::Code(id))) = util::decode_access_path(access_path) {
+            if copy_db.is_published(&id).await {
+                writeln!(out, "module {} is already published; not applying", id)?;
+                continue;
+            }
+        }
+        copy_db.execute_with_annotator(version, access_path, write_op, annotator).await?;
+    }
+    Ok(copy_pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use diem_types::write_set::WriteSetMut;
+    use move_core_types::account_address::AccountAddress;
+    use sqlx::Row;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, ADDRESS};
+
+    const OTHER: AccountAddress = AccountAddress::new([1; AccountAddress::LENGTH]);
+
+    /// Publishes the counter module again, writes a counter of 7 at `ADDRESS`
+    /// and deletes the one at `OTHER`.
+    fn write_set() -> WriteSet {
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let counter = bcs::to_bytes(&test_util::counter(7)).unwrap();
+        WriteSetMut::new(vec![
+            (test_util::access_path(ADDRESS, &Path::Code(tag.module_id())), WriteOp::Value(test_util::counter_module())),
+            (test_util::access_path(ADDRESS, &Path::Resource(tag.clone())), WriteOp::Value(counter)),
+            (test_util::access_path(OTHER, &Path::Resource(tag)), WriteOp::Deletion),
+        ])
+        .freeze()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_are_printed_annotated() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+
+        let mut out = vec![];
+        print_writes(&db.annotator(), &write_set(), &mut out).await.unwrap();
+        let counter = bcs::to_bytes(&test_util::counter(7)).unwrap();
+        let resource = db.annotator().view_resource(&tag, &counter).await.unwrap();
+        let expected = format!(
+            "publish module {}\nwrite {} at {}:\n{}\ndelete {} at {}\n",
+            tag.module_id(),
+            tag,
+            ADDRESS,
+            resource,
+            tag,
+            OTHER,
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn writes_are_applied_to_a_copy_only() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.store(1, &tag, test_util::counter(1)).await;
+        let counter = bcs::to_bytes(&test_util::counter(2)).unwrap();
+        db.write(1, OTHER, Path::Resource(tag.clone()), WriteOp::Value(counter)).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let copy = replay_copy(&dir.path().join("chain.db"));
+        assert_eq!(copy, dir.path().join("chain.db.replay"));
+        let mut out = vec![];
+        let copy_pool = apply_to_copy(
+            &db.pool,
+            db.config.clone(),
+            db.modules.clone(),
+            &copy,
+            2,
+            &write_set(),
+            &db.annotator(),
+            &mut out,
+        )
+        .await
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("module {} is already published; not applying", tag.module_id())), "{}", out);
+
+        let copy_db = DB::from_pool(copy_pool.clone(), db.config.clone(), db.modules.clone());
+        assert_eq!(copy_db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(7));
+        // deleting resources is not supported, so the deletion is only
+        // recorded as skipped
+        assert!(copy_db.read_resource(&OTHER, &tag).await.unwrap().is_some());
+        let select_sql = format!("SELECT version, address FROM {}", db.config.skipped_writes_table_name());
+        let rows = sqlx::query(&select_sql).fetch_all(&copy_pool).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<i64, _>(0), 2);
+        assert_eq!(rows[0].get::<Vec<u8>, _>(1), OTHER.as_ref().to_vec());
+        // the database itself is untouched
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(1));
+        assert!(db.db.read_resource(&OTHER, &tag).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{anyhow, Result};
+use move_core_types::{language_storage::StructTag, value::MoveValue};
+use sqlx::{
+    pool::PoolConnection,
+    sqlite::{Sqlite, SqlitePool},
+    Row,
+};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    io::Write,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use crate::{
+    annotator::MoveValueAnnotator,
+    db::{self, Config, DB},
+    fat_type::FatStructType,
+    resolver::{ModuleCache, Resolver, DEFAULT_MODULE_CACHE_SIZE},
+};
+
+/// How many failures of one type are listed before the rest are only
+/// counted.
+const MAX_LISTED: usize = 20;
+
+/// Read back every row of every `__root__` table, with history every
+/// version, and annotate it with its resolved type, printing each one that
+/// can't be and a count of failures per type. Reads that would panic while
+/// indexing, e.g. a column a layout option no longer writes, are caught and
+/// reported like any other failure.
+pub async fn run(pool: SqlitePool, config: Arc<Config>, out: &mut impl Write) -> Result<()> {
+    let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+    let db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+    let annotator = MoveValueAnnotator::new(Resolver::from_shared_cache(pool.clone(), config.clone(), modules));
+    let types = db
+        .stored_types()
+        .await?
+        .into_iter()
+        .map(|(root_table, tag)| (tag.to_string(), (root_table, tag)))
+        .collect::<BTreeMap<_, _>>();
+
+    // failures are reported with their panic message, so the default hook
+    // printing each one as well would only repeat them
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = check_types(&pool, &config, &annotator, types.values(), out).await;
+    panic::set_hook(hook);
+    let (checked, failures) = result?;
+
+    writeln!(out, "checked {} resources of {} types: {} failed", checked, types.len(), failures.values().sum::<usize>())?;
+    for (tag, count) in &failures {
+        writeln!(out, "  {}: {} failed", tag, count)?;
+    }
+    if !failures.is_empty() {
+        return Err(anyhow!("{} types have resources that cannot be read back", failures.len()));
+    }
+    Ok(())
+}
+
+/// The number of rows checked, and of failures keyed by struct tag.
+async fn check_types<'a>(
+    pool: &SqlitePool,
+    config: &Config,
+    annotator: &MoveValueAnnotator,
+    types: impl Iterator<Item = &'a (String, StructTag)>,
+    out: &mut impl Write,
+) -> Result<(usize, BTreeMap<String, usize>)> {
+    let mut conn = pool.acquire().await?;
+    let mut checked = 0;
+    let mut failures = BTreeMap::new();
+    for (root_table, tag) in types {
+        let select_sql = format!("SELECT id, address FROM {} ORDER BY id", root_table);
+        let rows = sqlx::query(&select_sql)
+            .fetch_all(&mut conn)
+            .await?
+            .iter()
+            .map(|row| (row.get::<i64, _>(0), row.get::<Vec<u8>, _>(1)))
+            .collect::<Vec<_>>();
+        checked += rows.len();
+
+        let struct_ = match annotator.resolver().resolve_struct(tag).await {
+            Ok(struct_) => struct_,
+            Err(e) => {
+                writeln!(out, "failed: cannot resolve {}, so none of its {} rows can be read: {}", tag, rows.len(), e)?;
+                failures.insert(tag.to_string(), rows.len());
+                continue;
+            },
+        };
+        let mut failed = 0;
+        for (id, address) in rows {
+            let outcome = CatchPanic(Box::pin(check_resource(config, tag, &struct_, id, annotator, &mut conn))).await;
+            let reason = match outcome {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => e.to_string(),
+                Err(message) => {
+                    // the connection may have been left mid-statement
+                    conn = pool.acquire().await?;
+                    format!("panicked: {}", message)
+                },
+            };
+            if failed < MAX_LISTED {
+                let address = db::address_from_bytes(&address)
+                    .map(|address| address.to_string())
+                    .unwrap_or_else(|_| hex::encode(&address));
+                writeln!(out, "failed: {} at {} (row {}): {}", tag, address, id, reason)?;
+            }
+            failed += 1;
+        }
+        if failed > MAX_LISTED {
+            writeln!(out, "failed: {} more of {}", failed - MAX_LISTED, tag)?;
+        }
+        if failed > 0 {
+            failures.insert(tag.to_string(), failed);
+        }
+    }
+    Ok((checked, failures))
+}
+
+async fn check_resource(
+    config: &Config,
+    tag: &StructTag,
+    struct_: &FatStructType,
+    id: i64,
+    annotator: &MoveValueAnnotator,
+    conn: &mut PoolConnection<Sqlite>,
+) -> Result<()> {
+    match db::fetch_struct(config, tag, id, annotator.resolver(), conn).await? {
+        Some(MoveValue::Struct(value)) => {
+            annotator.annotate_struct(&value, struct_).await?;
+            Ok(())
+        },
+        Some(_) => Err(anyhow!("read back as something other than a struct")),
+        None => Err(anyhow!("missing its struct row")),
+    }
+}
+
+/// Resolves to the output of a future, or to the message of a panic while
+/// polling it. `fetch_struct` panics on values it can't decode, and its future
+/// isn't `Send`, so it can't be caught by spawning it as a task.
+struct CatchPanic<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchPanic<F> {
+    type Output = std::result::Result<F::Output, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.0.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(payload) => Poll::Ready(Err(panic_message(payload))),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "no message".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use diem_types::{access_path::Path, write_set::WriteOp};
+    use move_core_types::{account_address::AccountAddress, value::MoveStruct};
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct};
+
+    #[tokio::test]
+    async fn resources_that_cannot_be_read_back_are_listed() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![("owner", SignatureToken::Address)])])).await;
+        let addresses = (1..=3).map(|i| AccountAddress::new([i; AccountAddress::LENGTH])).collect::<Vec<_>>();
+        for address in &addresses {
+            let op = WriteOp::Value(bcs::to_bytes(&MoveStruct::new(vec![MoveValue::Address(*address)])).unwrap());
+            db.write(1, *address, Path::Resource(tag.clone()), op).await;
+        }
+
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "checked 3 resources of 1 types: 0 failed\n");
+
+        // the second loses its struct row, and the third's address is cut short
+        let table = db::struct_tag_to_sql(&db.config, &tag);
+        sqlx::query(&format!("DELETE FROM {} WHERE __id = 2", table)).execute(&db.pool).await.unwrap();
+        sqlx::query(&format!("UPDATE {} SET owner = x'0102' WHERE __id = 3", table)).execute(&db.pool).await.unwrap();
+        let mut out = vec![];
+        let error = run(db.pool.clone(), db.config.clone(), &mut out).await.unwrap_err();
+        assert_eq!(error.to_string(), "1 types have resources that cannot be read back");
+        let expected = [
+            format!("failed: {} at {} (row 2): missing its struct row", tag, addresses[1]),
+            format!(
+                "failed: {} at {} (row 3): {} column owner: stored address 0102 is 2 bytes wide, but addresses are {} bytes",
+                tag,
+                addresses[2],
+                table,
+                db::ADDRESS_LENGTH,
+            ),
+            "checked 3 resources of 1 types: 2 failed".to_string(),
+            format!("  {}: 2 failed", tag),
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n") + "\n");
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::Result;
+use sqlx::{sqlite::SqlitePool, Row};
+use std::{collections::BTreeMap, io::Write, sync::Arc};
+
+use crate::db::{self, Config};
+
+struct TableStats {
+    name: String,
+    rows: i64,
+    bytes: Option<i64>,
+}
+
+/// Print the row count, and size when SQLite reports it, of every generated
+/// table. Tables are grouped by the type they store and sorted by rows.
+pub async fn run(pool: SqlitePool, config: Arc<Config>, out: &mut impl Write) -> Result<()> {
+    let mut db = pool.acquire().await?;
+    let names = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+        .fetch_all(&mut db)
+        .await?
+        .iter()
+        .map(|row| row.get::<String, _>(0))
+        .filter(|name| config.is_generated_table(name))
+        .map(|name| db::quote(&name))
+        .collect::<Vec<_>>();
+
+    let mut groups: BTreeMap<String, Vec<TableStats>> = BTreeMap::new();
+    for name in names {
+        let count_sql = format!("SELECT COUNT(*) FROM {}", name);
+        let rows = sqlx::query(&count_sql).fetch_one(&mut db).await?.get(0);
+        // dbstat is only present when SQLite is built with it
+        let bytes = sqlx::query("SELECT SUM(pgsize) FROM dbstat WHERE name = ?")
+            .bind(db::unquote(&name))
+            .fetch_one(&mut db)
+            .await
+            .ok()
+            .and_then(|row| row.get::<Option<i64>, _>(0));
+        groups
+            .entry(group_name(&config, &name))
+            .or_default()
+            .push(TableStats { name, rows, bytes });
+    }
+
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    for (_, tables) in &mut groups {
+        tables.sort_by(|a, b| b.rows.cmp(&a.rows));
+    }
+    groups.sort_by_key(|(_, tables)| -tables.iter().map(|t| t.rows).sum::<i64>());
+
+    for (group, tables) in groups {
+        let rows = tables.iter().map(|t| t.rows).sum::<i64>();
+        let bytes = tables.iter().map(|t| t.bytes).sum::<Option<i64>>();
+        writeln!(out, "{}: {} rows{}", group, rows, size_suffix(bytes))?;
+        for table in tables {
+            writeln!(out, "    {}: {} rows{}", table.name, table.rows, size_suffix(table.bytes))?;
+        }
+    }
+    Ok(())
+}
+
+/// The struct table a generated table belongs to. `__root__` and vector
+/// element tables are grouped with their struct's table, and bookkeeping
+/// tables with each other. Names are quoted like the tables they group.
+fn group_name(config: &Config, name: &str) -> String {
+    let name = db::unquote(name);
+    let rest = &name[config.table_prefix.len()..];
+    if let Some(struct_name) = rest.strip_prefix("__root__") {
+        return db::quote(&format!("{}{}", config.table_prefix, struct_name));
+    }
+    if rest.starts_with("__") {
+        return "bookkeeping".to_string();
+    }
+    // nested vectors add one `__elements` per level
+    let field_table = name.trim_end_matches("__elements");
+    if field_table.len() < name.len() {
+        if let Some((struct_table, _field)) = field_table.rsplit_once("__") {
+            return db::quote(struct_table);
+        }
+    }
+    db::quote(name)
+}
+
+fn size_suffix(bytes: Option<i64>) -> String {
+    match bytes {
+        Some(bytes) => format!(", {} KiB", (bytes + 1023) / 1024),
+        None => "".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use move_core_types::{
+        account_address::AccountAddress,
+        identifier::Identifier,
+        value::{MoveStruct, MoveValue},
+    };
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct};
+
+    #[tokio::test]
+    async fn tables_are_grouped_by_their_struct() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![
+            ("owners", test_util::vector_token(SignatureToken::Address)),
+        ])])).await;
+        // the owners are stored in a table of their own
+        let owners = (1..=3).map(|i| MoveValue::Address(AccountAddress::new([i; AccountAddress::LENGTH]))).collect();
+        db.store(1, &tag, MoveStruct::new(vec![MoveValue::Vector(owners)])).await;
+
+        let mut out = vec![];
+        run(db.pool.clone(), db.config.clone(), &mut out).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // sizes depend on whether SQLite was built with dbstat
+        let lines = out.lines().map(|line| line.split(" rows").next().unwrap().to_string()).collect::<Vec<_>>();
+        let struct_table = db::struct_tag_to_sql(&db.config, &tag);
+        let owners = db::vector_table_name(&db.config, &tag, &Identifier::new("owners").unwrap());
+        let group = lines.iter().position(|line| *line == format!("{}: 5", struct_table)).expect(&out);
+        assert_eq!(lines[group + 1..group + 4], [
+            format!("    {}: 3", owners),
+            format!("    {}: 1", db::root_table_name(&db.config, &tag)),
+            format!("    {}: 1", struct_table),
+        ]);
+        assert!(lines.iter().any(|line| line.starts_with("bookkeeping: ")), "{}", out);
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use diem_json_rpc_client::async_client::{Client, Retry};
+use diem_types::{
+    access_path::{AccessPath, Path},
+    account_address::AccountAddress,
+    account_state::AccountState,
+    account_state_blob::AccountStateBlob,
+    transaction::Transaction,
+};
+use itertools::Itertools;
+use sqlx::sqlite::SqlitePool;
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    io::Write,
+    sync::Arc,
+};
+use url::Url;
+
+use crate::{
+    db::{Config, DB},
+    resolver::{ModuleCache, DEFAULT_MODULE_CACHE_SIZE},
+    state::SqlState,
+    util,
+};
+
+/// Compare the stored state of every account that sent a transaction between
+/// `from` and `to` with the endpoint's state at the version the database is
+/// synced to.
+pub async fn run(
+    pool: SqlitePool,
+    config: Arc<Config>,
+    endpoint: Url,
+    from: u64,
+    to: u64,
+    out: &mut impl Write,
+) -> Result<()> {
+    let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+    let db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+    let sync_state = db.sync_state().await;
+    if sync_state.next_version == 0 {
+        return Err(anyhow!("database has not synced any transactions"));
+    }
+    let synced_version = sync_state.next_version - 1;
+    if from > to || to > synced_version {
+        return Err(anyhow!("versions must satisfy from <= to <= {} (the synced version)", synced_version));
+    }
+
+    let client = Client::from_url(endpoint, Retry::default()).unwrap();
+    let mut accounts = BTreeSet::new();
+    for versions in &(from..=to).chunks(100) {
+        let versions = versions.collect::<Vec<_>>();
+        let txs = client.get_transactions(versions[0], versions.len() as u64, false).await?;
+        for t in txs {
+            let bytes = hex::decode(&t.bytes)?;
+            if let Transaction::UserTransaction(tx) = bcs::from_bytes::<Transaction>(&bytes)? {
+                accounts.insert(tx.sender());
+            }
+        }
+    }
+
+    let sql_state = SqlState::from_pool(pool, config.clone(), modules).at_version(synced_version);
+    let mut checked = 0;
+    let mut mismatches = 0;
+    for address in &accounts {
+        let view = client.get_account_state_with_proof(address, Some(synced_version), Some(synced_version)).await?;
+        let state = match view.blob {
+            Some(blob) => Some(AccountState::try_from(&AccountStateBlob::from(blob.into_bytes()?))?),
+            None => None,
+        };
+        let (account_checked, account_mismatches) = verify_account(&sql_state, &config, address, state, out).await?;
+        checked += account_checked;
+        mismatches += account_mismatches;
+    }
+
+    writeln!(
+        out,
+        "checked {} values in {} accounts at version {}: {} mismatches",
+        checked,
+        accounts.len(),
+        synced_version,
+        mismatches,
+    )?;
+    if mismatches > 0 {
+        return Err(anyhow!("{} mismatches found", mismatches));
+    }
+    Ok(())
+}
+
+/// Compare the stored values of one account with its state on chain, `None`
+/// if it doesn't exist there, printing each mismatch. Returns the number of
+/// values checked and of mismatches.
+async fn verify_account(
+    sql_state: &SqlState,
+    config: &Config,
+    address: &AccountAddress,
+    state: Option<AccountState>,
+    out: &mut impl Write,
+) -> Result<(usize, usize)> {
+    let state = match state {
+        Some(state) => state,
+        None => {
+            writeln!(out, "mismatch: account {} does not exist on chain", address)?;
+            return Ok((0, 1));
+        },
+    };
+    let mut checked = 0;
+    let mut mismatches = 0;
+    for (key, value) in state.iter() {
+        let access_path = AccessPath::new(*address, key.clone());
+        let description = match util::decode_access_path(&access_path).map(|(_, path)| path) {
+            // unknown paths are never stored
+            Err(_) => continue,
+            Ok(Path::Resource(tag)) if !config.is_indexed(&tag) => continue,
+            Ok(Path::Resource(tag)) => format!("{} at {}", tag, address),
+            Ok(Path::Code(id)) => format!("module {}", id),
+        };
+        checked += 1;
+        if let Some(problem) = compare(sql_state, &access_path, value).await {
+            writeln!(out, "mismatch: {}: {}", description, problem)?;
+            mismatches += 1;
+        }
+    }
+    Ok((checked, mismatches))
+}
+
+async fn compare(sql_state: &SqlState, access_path: &AccessPath, expected: &[u8]) -> Option<String> {
+    match sql_state.fetch(access_path).await {
+        Err(e) => Some(format!("failed to read from database: {}", e)),
+        Ok(None) => Some("missing from database".to_string()),
+        Ok(Some(stored)) if stored != expected => Some(format!(
+            "database has {}, endpoint has {}",
+            hex::encode(&stored),
+            hex::encode(expected),
+        )),
+        Ok(Some(_)) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, ADDRESS};
+
+    /// An account state holding these values, keyed by their paths.
+    fn account_state(values: &[(&Path, Vec<u8>)]) -> AccountState {
+        let state = values
+            .iter()
+            .map(|(path, value)| (bcs::to_bytes(path).unwrap(), value.clone()))
+            .collect::<BTreeMap<_, _>>();
+        AccountState::try_from(&AccountStateBlob::from(bcs::to_bytes(&state).unwrap())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn accounts_are_compared_value_by_value() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::counter_module()).await;
+        db.store(1, &tag, test_util::counter(1)).await;
+        let sql_state = SqlState::from_pool(db.pool.clone(), db.config.clone(), db.modules.clone());
+        let code = Path::Code(tag.module_id());
+        let resource = Path::Resource(tag.clone());
+        let counter = |value| bcs::to_bytes(&test_util::counter(value)).unwrap();
+
+        let mut out = vec![];
+        let state = account_state(&[(&code, test_util::counter_module()), (&resource, counter(1))]);
+        let found = verify_account(&sql_state, &db.config, &ADDRESS, Some(state), &mut out).await.unwrap();
+        assert_eq!(found, (2, 0));
+        assert!(out.is_empty());
+
+        let state = account_state(&[(&resource, counter(2))]);
+        let found = verify_account(&sql_state, &db.config, &ADDRESS, Some(state), &mut out).await.unwrap();
+        assert_eq!(found, (1, 1));
+        let expected = format!(
+            "mismatch: {} at {}: database has {}, endpoint has {}\n",
+            tag,
+            ADDRESS,
+            hex::encode(counter(1)),
+            hex::encode(counter(2)),
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let other = AccountAddress::new([1; AccountAddress::LENGTH]);
+        let mut out = vec![];
+        let state = account_state(&[(&resource, counter(1))]);
+        let found = verify_account(&sql_state, &db.config, &other, Some(state), &mut out).await.unwrap();
+        assert_eq!(found, (1, 1));
+        assert_eq!(String::from_utf8(out).unwrap(), format!("mismatch: {} at {}: missing from database\n", tag, other));
+
+        let mut out = vec![];
+        assert_eq!(verify_account(&sql_state, &db.config, &other, None, &mut out).await.unwrap(), (0, 1));
+        assert_eq!(String::from_utf8(out).unwrap(), format!("mismatch: account {} does not exist on chain\n", other));
+    }
+}
@@ -1,712 +1,5174 @@
+use anyhow::{anyhow, Result};
 use diem_types::{
     access_path::{AccessPath, Path},
+    contract_event::ContractEvent,
+    event::EventKey,
     write_set::WriteOp,
 };
 use move_core_types::{
     account_address::AccountAddress,
     identifier::Identifier,
-    language_storage::{ModuleId, StructTag, TypeTag},
+    language_storage::{CORE_CODE_ADDRESS, ModuleId, StructTag, TypeTag},
     value::{MoveStruct, MoveValue},
 };
 use sqlx::{
-    Row,
+    Column, Row,
     pool::PoolConnection,
-    sqlite::{Sqlite, SqlitePool},
+    sqlite::{Sqlite, SqlitePool, SqliteRow},
 };
+use lru::LruCache;
 use std::{
-    cell::RefCell,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     future::Future,
+    mem,
     pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use url::Url;
+use vm::{
+    access::ModuleAccess,
+    errors::Location,
+    file_format::{CompiledModule, Kind},
 };
 
 use crate::{
-    annotator::{AnnotatedMoveStruct, AnnotatedMoveValue, MoveValueAnnotator},
-    fat_type::{FatStructType, FatType},
-    resolver::Resolver,
+    annotator::{AnnotatedMoveStruct, AnnotatedMoveValue, MoveValueAnnotator, PartialMoveStruct},
+    fat_type::{self, FatStructType, FatType},
+    parser,
+    resolver::{MissingStructDef, ModuleCache, Resolver},
     util,
 };
 
-thread_local! {
-    static CREATED_CACHE: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+/// The tables of one database that have been created or checked since it was
+/// opened. Each `DB` keeps its own, since what exists depends on the database
+/// its pool connects to. The lock is held while a table is being created, so
+/// that concurrent writers of a new type don't race to create the same table.
+#[derive(Default)]
+pub struct CreatedTables(Mutex<HashSet<String>>);
+
+impl CreatedTables {
+    /// Forget a table that was dropped, so that it is created again if used.
+    async fn forget(&self, name: &str) {
+        self.0.lock().await.remove(name);
+    }
+
+    /// Forget every table, e.g. after a rollback dropped those created since
+    /// a savepoint, so that each is created or checked again when next used.
+    async fn forget_all(&self) {
+        self.0.lock().await.clear();
+    }
 }
 
-pub struct DB {
-    pool: SqlitePool,
+/// The number of `__root__` ids a `RootIdCache` keeps unless told otherwise.
+pub const DEFAULT_ROOT_ID_CACHE_SIZE: usize = 100_000;
+
+/// A size bounded cache of the latest `__root__` id of resources looked up or
+/// written through one `DB`, keyed by root table and address, with None where
+/// the address is known to have no row. Every write to a `__root__` table goes
+/// through `DB::store`, which keeps this current, so lookups of a resource
+/// seen before can skip SQL. A `SqlState` reading alongside the `DB` shares it
+/// via `SqlState::with_root_ids`.
+pub struct RootIdCache(std::sync::Mutex<LruCache<(String, AccountAddress), Option<i64>>>);
+
+impl RootIdCache {
+    pub fn new(capacity: usize) -> Self {
+        RootIdCache(std::sync::Mutex::new(LruCache::new(capacity)))
+    }
+
+    /// The cached id of a resource: `Some(None)` if the address is known to
+    /// have none, and None if it hasn't been seen or has been evicted.
+    pub fn get(&self, root_table: &str, address: &AccountAddress) -> Option<Option<i64>> {
+        self.0.lock().unwrap().get(&(root_table.to_string(), *address)).copied()
+    }
+
+    /// Record what a lookup of, or write to, a `__root__` table found.
+    pub fn insert(&self, root_table: &str, address: AccountAddress, id: Option<i64>) {
+        self.0.lock().unwrap().put((root_table.to_string(), address), id);
+    }
+
+    /// Forget every id, e.g. after rows were deleted or tables dropped.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
 }
 
-impl DB {
-    pub fn from_pool(pool: SqlitePool) -> DB {
-        DB {
-            pool,
+/// Settings that control how Move data is laid out in SQL. These must be the
+/// same for every reader and writer of a given set of tables.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Prepended to the name of every generated table, so that several
+    /// chains can share one database.
+    pub table_prefix: String,
+    /// Also store the original bcs bytes of each resource in a `raw` column
+    /// of its `__root__` row.
+    pub keep_raw: bool,
+    /// Statements taking at least this long are logged with their text.
+    pub slow_query_threshold: Option<Duration>,
+    /// Add the derived `event_key` to every `0x1::Event::EventHandle`.
+    pub expand_event_handles: bool,
+    /// If not empty, only resources of these types are stored. A tag without
+    /// type parameters also matches every instantiation of a generic type.
+    pub only_structs: HashSet<StructTag>,
+    /// How u64 and u128 fields are stored.
+    pub numeric_encoding: NumericEncoding,
+    /// Read u64 INTEGERs as the u64 with the same bits, which is how values
+    /// above `i64::MAX` are written. Otherwise they are read as signed, and a
+    /// negative value is an error instead of a large u64.
+    pub u64_bitcast: bool,
+    /// Keep every version of a resource instead of updating it in place.
+    /// `__root__` rows are then keyed by address and `last_modified_version`.
+    pub history: bool,
+    /// Where to fetch modules that are missing from the database, if anywhere.
+    pub module_endpoint: Option<Url>,
+    /// Values nested deeper than this are rejected rather than risking a
+    /// stack overflow in the recursive passes that write and read them.
+    pub max_value_depth: usize,
+    /// Log every SQL statement as it is run.
+    pub log_sql: bool,
+    /// Also keep the validator set in a flat `__validators` table, one row
+    /// per validator, whenever the on-chain `DiemSystem` config is written.
+    pub flatten_validators: bool,
+    /// Store sub-structs whose fields are all scalars as a JSON column of
+    /// their parent's table instead of in a table of their own.
+    pub nested_as_json: bool,
+    /// When a resource's type only partly resolves, keep the fields that can
+    /// be decoded in `__partial_resources` instead of skipping the write.
+    pub best_effort_resolution: bool,
+    /// Record the block timestamp in effect at every version in
+    /// `__transactions`.
+    pub with_timestamps: bool,
+    /// Also give every `__root__` row the block timestamp of its version as
+    /// `last_modified_time`.
+    pub resource_timestamps: bool,
+    /// Store `vector<u8>` fields named `authentication_key` as hex text
+    /// rather than blobs, whichever struct declares them.
+    pub auth_keys_as_hex: bool,
+    /// Halt on any write that would otherwise be skipped or only partly
+    /// stored, so that a finished run is known to be complete.
+    pub strict: bool,
+    /// Keep the layout of every resolved type in `__layouts`, so that later
+    /// runs can skip deserializing and walking the modules behind it.
+    pub persist_layouts: bool,
+}
+
+/// The settings of the command line's defaults.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            table_prefix: String::new(),
+            keep_raw: false,
+            slow_query_threshold: None,
+            expand_event_handles: false,
+            only_structs: HashSet::new(),
+            numeric_encoding: NumericEncoding::default(),
+            u64_bitcast: true,
+            history: false,
+            module_endpoint: None,
+            max_value_depth: 256,
+            log_sql: false,
+            flatten_validators: false,
+            nested_as_json: false,
+            best_effort_resolution: false,
+            with_timestamps: false,
+            resource_timestamps: false,
+            auth_keys_as_hex: false,
+            strict: false,
+            persist_layouts: false,
         }
     }
+}
 
-    pub async fn initialize(&self) {
-        let mut db = self.pool.acquire().await.unwrap();
+impl Config {
+    pub fn module_table_name(&self) -> String {
+        quote(&format!("{}__module", self.table_prefix))
+    }
 
-        let create_sql = format!(
-            "CREATE TABLE __module (address BLOB NOT NULL, name STRING NOT NULL, data BLOB NOT NULL, CONSTRAINT __module_pkey PRIMARY KEY (address, name))",
-        );
-        sqlx::query(&create_sql).execute(&mut db).await.unwrap();
+    pub fn sync_state_table_name(&self) -> String {
+        quote(&format!("{}__sync_state", self.table_prefix))
     }
 
-    pub async fn execute_with_annotator(
-        &self,
-        access_path: &AccessPath,
-        op: &WriteOp,
-        annotator: &MoveValueAnnotator,
-    ) {
-        let (address, path) = util::decode_access_path(access_path);
-        match (&path, op) {
-            (Path::Code(id), WriteOp::Deletion) => self.unpublish(id).await,
-            (Path::Code(id), WriteOp::Value(v)) => self.publish(id, v).await,
-            (Path::Resource(tag), WriteOp::Deletion) => self.delete(&address, tag).await,
-            (Path::Resource(tag), WriteOp::Value(v)) => {
-                let resource = annotator.view_resource(tag, v).await.unwrap();
-                self.store(&address, tag, resource).await
-            },
-        }
+    pub fn events_table_name(&self) -> String {
+        quote(&format!("{}__events", self.table_prefix))
     }
 
-    async fn unpublish(&self, _id: &ModuleId) {
-        //println!("unpublishing {}", id);
-        todo!();
+    pub fn skipped_writes_table_name(&self) -> String {
+        quote(&format!("{}__skipped_writes", self.table_prefix))
     }
 
-    async fn publish(&self, id: &ModuleId, data: &[u8]) {
-        //println!("publishing {}", id);
-        let address = id.address();
-        let name = id.name().as_str();
-        let create_sql = format!(
-            "INSERT INTO __module VALUES (?, ?, ?)",
-        );
-        let mut db = self.pool.acquire().await.unwrap();
-        sqlx::query(&create_sql)
-            .bind(address.as_ref())
-            .bind(name)
-            .bind(data)
-            .execute(&mut db)
-            .await
-            .unwrap();
+    pub fn skipped_transactions_table_name(&self) -> String {
+        quote(&format!("{}__skipped_transactions", self.table_prefix))
     }
 
-    async fn delete(&self, _address: &AccountAddress, _tag: &StructTag) {
-        //println!("deleting {}::{}", address, tag);
-        todo!();
+    pub fn unknown_paths_table_name(&self) -> String {
+        quote(&format!("{}__unknown_paths", self.table_prefix))
     }
 
-    async fn store(&self, address: &AccountAddress, tag: &StructTag, data: AnnotatedMoveStruct) {
-        //println!("storing {}::{}", address, tag);
-        //println!("{}", data);
-        let mut db = self.pool.acquire().await.unwrap();
+    pub fn validators_table_name(&self) -> String {
+        quote(&format!("{}__validators", self.table_prefix))
+    }
 
-        // see if global object already exists
-        let sql_tag = struct_tag_to_sql(tag);
-        let select_sql = format!(
-            "SELECT id FROM __root__{} WHERE address = ?",
-            sql_tag,
-        );
-        //println!("QUERY: {}\nPARAM: {}", select_sql, address.short_str());
-        let result = sqlx::query(&select_sql)
-            .bind(address.as_ref())
-            .fetch_optional(&mut db)
-            .await
-            .unwrap_or(None);
-        match result {
-            None => {
-                generate_sql(&address, Some(&data), &mut db).await;
-            },
-            Some(row) => {
-                let id = row.get(0);
-                let resolver = Resolver::from_pool(self.pool.clone());
-                let old_struct = match fetch_struct(tag, id, &resolver, &mut db).await.unwrap() {
-                    MoveValue::Struct(s) => s,
-                    _ => unreachable!(),
-                };
-                let fat_type = resolver.resolve_struct(tag).await.unwrap();
-                let annotator = MoveValueAnnotator::new(resolver);
-                let old_struct = annotator.annotate_struct(&old_struct, &fat_type).await.unwrap();
-                generate_diff_sql(&old_struct, &data, id, &mut db).await;
+    pub fn transactions_table_name(&self) -> String {
+        quote(&format!("{}__transactions", self.table_prefix))
+    }
+
+    pub fn partial_resources_table_name(&self) -> String {
+        quote(&format!("{}__partial_resources", self.table_prefix))
+    }
+
+    pub fn module_versions_table_name(&self) -> String {
+        quote(&format!("{}__module_versions", self.table_prefix))
+    }
+
+    pub fn module_deps_table_name(&self) -> String {
+        quote(&format!("{}__module_deps", self.table_prefix))
+    }
+
+    pub fn struct_meta_table_name(&self) -> String {
+        quote(&format!("{}__struct_meta", self.table_prefix))
+    }
+
+    pub fn layouts_table_name(&self) -> String {
+        quote(&format!("{}__layouts", self.table_prefix))
+    }
+
+    pub fn unindexed_resources_table_name(&self) -> String {
+        quote(&format!("{}__unindexed_resources", self.table_prefix))
+    }
+
+    /// Whether resources of this type are stored in tables of their own.
+    /// Those that aren't are kept as bcs bytes in `__unindexed_resources`,
+    /// where only replay reads them.
+    pub fn is_indexed(&self, tag: &StructTag) -> bool {
+        if self.only_structs.is_empty() || self.only_structs.contains(tag) {
+            return true;
+        }
+        let generic = StructTag {
+            type_params: vec![],
+            ..tag.clone()
+        };
+        self.only_structs.contains(&generic)
+    }
+
+    /// Whether a table is one generated under this configuration's prefix:
+    /// one of the fixed bookkeeping tables, or a struct, vector or `__root__`
+    /// table named after a struct's full-width address. Another prefix that
+    /// merely starts with this one, or a table that happens to start with
+    /// `__`, doesn't match. The name may be quoted or as `sqlite_master`
+    /// lists it.
+    pub fn is_generated_table(&self, name: &str) -> bool {
+        let rest = match unquote(name).strip_prefix(self.table_prefix.as_str()) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        if BOOKKEEPING_TABLES.contains(&rest) {
+            return true;
+        }
+        let rest = rest.strip_prefix("__root__").unwrap_or(rest);
+        let address_len = ADDRESS_LENGTH * 2;
+        match (rest.get(..1), rest.get(1..address_len + 1), rest.get(address_len + 1..)) {
+            (Some("x"), Some(address), Some(rest)) => {
+                address.chars().all(|c| c.is_ascii_hexdigit()) && rest.starts_with("__")
             },
+            _ => false,
         }
     }
 }
 
-pub fn generate_diff_sql<'a>(
-    old_value: &'a  AnnotatedMoveStruct,
-    value: &'a AnnotatedMoveStruct,
-    id: i64,
-    db: &'a mut PoolConnection<Sqlite>
-) -> Pin<Box<dyn Future<Output=()> + 'a>>
-{
-    Box::pin(async move {
-        assert_eq!(old_value.type_, value.type_, "struct types must match");
+/// The tables every database has besides those generated for structs, as
+/// named after the table prefix.
+const BOOKKEEPING_TABLES: [&str; 14] = [
+    "__module",
+    "__sync_state",
+    "__events",
+    "__skipped_writes",
+    "__skipped_transactions",
+    "__unknown_paths",
+    "__validators",
+    "__transactions",
+    "__partial_resources",
+    "__module_versions",
+    "__module_deps",
+    "__struct_meta",
+    "__layouts",
+    "__unindexed_resources",
+];
 
-        let changed_fields = old_value
-            .value
-            .iter()
-            .zip(value.value.iter())
-            .filter_map(|((name, ov), (_, nv))| {
-                if ov == nv {
-                    None
-                } else {
-                    Some((name, ov, nv))
-                }
-            })
-            .collect::<Vec<_>>();
-        if changed_fields.is_empty() {
-            return;
+/// How u64 and u128 fields are stored. `Blob` is the most compact, while
+/// `Text` and `Split` let SQL compare values in numeric order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NumericEncoding {
+    /// u64 as a (wrapping) INTEGER, u128 as a 16 byte big-endian BLOB. This
+    /// is the layout of databases written before encodings were selectable.
+    Blob,
+    /// Decimal TEXT, zero padded to the type's maximum width.
+    Text,
+    /// u64 as a (wrapping) INTEGER, u128 as INTEGER `_hi` and `_lo` halves.
+    /// Each half is offset by 2^63 so that signed comparison of the halves
+    /// matches unsigned comparison of the value.
+    Split,
+}
+
+const SPLIT_OFFSET: u64 = 1 << 63;
+
+impl NumericEncoding {
+    pub const VARIANTS: &'static [&'static str] = &["blob", "text", "split"];
+
+    fn u64_column_type(self) -> &'static str {
+        match self {
+            NumericEncoding::Text => "TEXT",
+            _ => "INTEGER",
         }
+    }
 
-        let sql_tag = struct_tag_to_sql(&value.type_);
-        let mut updated = vec![];
-        for (field_name, old_field_value, field_value) in changed_fields {
-            match field_value {
-                AnnotatedMoveValue::U8(v) => {
-                    updated.push(format!("{} = {}", field_name, v));
-                },
-                AnnotatedMoveValue::U64(v) => {
-                    updated.push(format!("{} = {}", field_name, v));
-                },
-                AnnotatedMoveValue::U128(v) => {
-                    updated.push(format!("{} = x'{}'", field_name, hex::encode(v.to_be_bytes())));
-                },
-                AnnotatedMoveValue::Bool(v) => {
-                    updated.push(format!("{} = {}", field_name, v));
-                },
-                AnnotatedMoveValue::Address(v) => {
-                    updated.push(format!("{} = x'{}'", field_name, hex::encode(v)));
-                },
-                AnnotatedMoveValue::Bytes(v) => {
-                    updated.push(format!("{} = x'{}'", field_name, hex::encode(v)));
-                },
-                AnnotatedMoveValue::Vector(ty, v) => {
-                    // delete old entries
-                    let name = vector_table_name(&value.type_, field_name);
-                    let delete_sql = format!(
-                        "DELETE FROM {} WHERE parent_id = {}",
-                        name,
-                        id,
-                    );
-                    sqlx::query(&delete_sql).execute(&mut *db).await.unwrap();
+    fn u128_columns(self, name: &str) -> Vec<(String, &'static str)> {
+        match self {
+            NumericEncoding::Blob => vec![(name.to_string(), "BLOB")],
+            NumericEncoding::Text => vec![(name.to_string(), "TEXT")],
+            NumericEncoding::Split => vec![
+                (format!("{}_hi", name), "INTEGER"),
+                (format!("{}_lo", name), "INTEGER"),
+            ],
+        }
+    }
 
-                    // populate new entries
-                    vector_to_sql(name, id, &ty, &v, &mut *db).await;
-                },
-                AnnotatedMoveValue::Struct(v) => {
-                    // this will generate no changes here, but will recursively update the struct
-                    let ov = match old_field_value {
-                        AnnotatedMoveValue::Struct(o) => o,
-                        _ => unreachable!(),
-                    };
+    fn u64_to_sql(self, v: u64) -> String {
+        match self {
+            NumericEncoding::Text => format!("'{:020}'", v),
+            _ => format!("{}", v as i64),
+        }
+    }
 
-                    let select_sql = format!(
-                        "SELECT {} FROM {} WHERE __id = ?",
-                        field_name,
-                        sql_tag,
-                    );
-                    let sub_id = sqlx::query(&select_sql)
-                        .bind(id)
-                        .fetch_one(&mut *db)
-                        .await
-                        .unwrap()
-                        .get(0);
-                    
-                    generate_diff_sql(&ov, &v, sub_id, &mut *db).await;
+    fn u128_to_sql(self, v: u128) -> Vec<String> {
+        match self {
+            NumericEncoding::Blob => vec![format!("x'{}'", hex::encode(v.to_be_bytes()))],
+            NumericEncoding::Text => vec![format!("'{:039}'", v)],
+            NumericEncoding::Split => vec![
+                format!("{}", ((v >> 64) as u64 ^ SPLIT_OFFSET) as i64),
+                format!("{}", (v as u64 ^ SPLIT_OFFSET) as i64),
+            ],
+        }
+    }
+
+    /// Read a u64 stored at `index`, or None if the column is NULL.
+    ///
+    /// SQLite integers are signed, so u64s above `i64::MAX` are stored as the
+    /// negative i64 with the same bits, and with `bitcast` reading casts the
+    /// bits back. Old databases are therefore read correctly as-is. Without
+    /// it, negative values are rejected, for tables whose u64s were written
+    /// as plain signed numbers. To compare such values in SQL instead, either
+    /// convert on the fly as in `example-queries.sql` or re-index with
+    /// `--reset --numeric-encoding text`.
+    fn u64_from_row(self, row: &SqliteRow, index: usize, bitcast: bool) -> Result<Option<u64>> {
+        match self {
+            NumericEncoding::Text => row
+                .get::<Option<String>, _>(index)
+                .map(|v| v.parse().map_err(|e| anyhow!("u64 column holds {:?}: {}", v, e)))
+                .transpose(),
+            _ => match row.get::<Option<i64>, _>(index) {
+                Some(v) if v < 0 && !bitcast => {
+                    Err(anyhow!("u64 column holds {}, which is negative, under --no-u64-bitcast", v))
                 },
-            }
+                v => Ok(v.map(|v| v as u64)),
+            },
         }
+    }
 
-        if !updated.is_empty() {
-            let update_sql = format!(
-                "UPDATE {} SET {} WHERE __id = ?",
-                sql_tag,
-                updated.join(", "),
-            );
-            //println!("{}", update_sql);
-            sqlx::query(&update_sql)
-                .bind(id)
-                .execute(&mut *db)
-                .await
-                .unwrap();
+    /// Read a u128 stored starting at `index`, or None if the columns are NULL.
+    fn u128_from_row(self, row: &SqliteRow, index: usize) -> Result<Option<u128>> {
+        match self {
+            NumericEncoding::Blob => row.get::<Option<Vec<u8>>, _>(index)
+                .map(u128_from_bytes)
+                .transpose(),
+            NumericEncoding::Text => row
+                .get::<Option<String>, _>(index)
+                .map(|v| v.parse().map_err(|e| anyhow!("u128 column holds {:?}: {}", v, e)))
+                .transpose(),
+            NumericEncoding::Split => {
+                let (hi, lo) = match (row.get::<Option<i64>, _>(index), row.get::<Option<i64>, _>(index + 1)) {
+                    (Some(hi), Some(lo)) => (hi, lo),
+                    _ => return Ok(None),
+                };
+                let hi = (hi as u64 ^ SPLIT_OFFSET) as u128;
+                let lo = (lo as u64 ^ SPLIT_OFFSET) as u128;
+                Ok(Some(hi << 64 | lo))
+            },
         }
-    })
+    }
 }
 
-pub async fn generate_sql(address: &AccountAddress, value: Option<&AnnotatedMoveStruct>, db: &mut PoolConnection<Sqlite>) {
-    // post order traversal of the struct to write it
-    match value {
-        Some(struct_) => {
-            let id = struct_to_sql(struct_, db).await;
+impl Default for NumericEncoding {
+    fn default() -> Self {
+        NumericEncoding::Blob
+    }
+}
 
-            let table_name = format!("__root__{}", struct_tag_to_sql(&struct_.type_));
-            if !hit_created_cache(&table_name) {
-                // attach struct to global storage
-                let create_sql = format!(
-                    "CREATE TABLE IF NOT EXISTS {} (address BLOB UNIQUE NOT NULL, id INTEGER NOT NULL)",
-                    table_name,
-                );
-                //println!("{}", create_sql);
-                sqlx::query(&create_sql).execute(&mut *db).await.unwrap();
-            }
+impl FromStr for NumericEncoding {
+    type Err = anyhow::Error;
 
-            let insert_sql = format!(
-                "INSERT INTO {} VALUES (x'{}', {})",
-                table_name,
-                hex::encode(address),
-                id,
-            );
-            //println!("{}", insert_sql);
-            sqlx::query(&insert_sql).execute(&mut *db).await.unwrap();
-        },
-        None => {
-            todo!();
-        },
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "blob" => Ok(NumericEncoding::Blob),
+            "text" => Ok(NumericEncoding::Text),
+            "split" => Ok(NumericEncoding::Split),
+            _ => Err(anyhow!("unknown numeric encoding {}", s)),
+        }
     }
 }
 
-fn struct_to_sql<'a>(struct_: &'a AnnotatedMoveStruct, db: &'a mut PoolConnection<Sqlite>) -> Pin<Box<dyn Future<Output=i64> + 'a>> {
-    Box::pin(async move {
-        // handle fields
-        let mut field_names = vec![];
-        let mut fields = vec![];
-        let mut values = vec![];
+/// A comparison `DB::query_resources` applies to a top-level field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
 
-        fields.push("__id INTEGER PRIMARY KEY".to_string());
+impl FilterOp {
+    pub const VARIANTS: &'static [&'static str] = &["=", "!=", "<", "<=", ">", ">="];
 
-        for (ident, val) in &struct_.value {
-            match val {
-                AnnotatedMoveValue::U8(i) => {
-                    field_names.push(format!("{}", ident));
-                    fields.push(format!("{} INTEGER NOT NULL", ident));
-                    values.push(format!("{}", i));
-                },
-                AnnotatedMoveValue::U64(i) => {
-                    field_names.push(format!("{}", ident));
-                    fields.push(format!("{} INTEGER NOT NULL", ident));
-                    values.push(format!("{}", *i as i64));
-                },
-                AnnotatedMoveValue::U128(i) => {
-                    field_names.push(format!("{}", ident));
-                    fields.push(format!("{} BLOB NOT NULL", ident));
-                    values.push(format!("x'{}'", hex::encode(i.to_be_bytes())));
-                },
-                AnnotatedMoveValue::Bool(i) => {
-                    field_names.push(format!("{}", ident));
-                    fields.push(format!("{} BOOLEAN NOT NULL", ident));
-                    values.push(format!("{}", i));
-                },
-                AnnotatedMoveValue::Address(i) => {
-                    field_names.push(format!("{}", ident));
-                    fields.push(format!("{} BLOB NOT NULL", ident));
-                    values.push(format!("x'{}'", hex::encode(i)));
-                },
-                AnnotatedMoveValue::Bytes(i) => {
-                    field_names.push(format!("{}", ident));
-                    fields.push(format!("{} BLOB NOT NULL", ident));
-                    values.push(format!("x'{}'", hex::encode(&i)));
-                },
-                AnnotatedMoveValue::Struct(s) => {
-                    let id = struct_to_sql(s, db).await;
-                    field_names.push(format!("{}", ident));
-                    fields.push(format!("{} INTEGER NOT NULL", ident));
-                    values.push(format!("{}", id));
-                },
-                AnnotatedMoveValue::Vector(ty, v) => {
-                    match ty {
-                        TypeTag::Bool |
-                        TypeTag::U8 |
-                        TypeTag::U64 |
-                        TypeTag::U128 => {
-                            // primitive vectors are stored inline
-                            let bytes = vector_to_bytes(v);
-                            field_names.push(format!("{}", ident));
-                            fields.push(format!("{} BLOB NOT NULL", ident));
-                            values.push(format!("x'{}'", hex::encode(&bytes)));
-                        },
+    fn sql(self) -> &'static str {
+        match self {
+            FilterOp::Eq => "=",
+            FilterOp::Ne => "!=",
+            FilterOp::Lt => "<",
+            FilterOp::Le => "<=",
+            FilterOp::Gt => ">",
+            FilterOp::Ge => ">=",
+        }
+    }
+}
 
-                        TypeTag::Signer => unreachable!(),
+impl FromStr for FilterOp {
+    type Err = anyhow::Error;
 
-                        TypeTag::Address |
-                        TypeTag::Vector(_) |
-                        TypeTag::Struct(_) => {
-                            // complex vectors are stored in a separate table named after the field
-                            // these generate no fields in this struct, and are handled later
-                        },
-                    }
-                },
-            }
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "=" => Ok(FilterOp::Eq),
+            "!=" => Ok(FilterOp::Ne),
+            "<" => Ok(FilterOp::Lt),
+            "<=" => Ok(FilterOp::Le),
+            ">" => Ok(FilterOp::Gt),
+            ">=" => Ok(FilterOp::Ge),
+            _ => Err(anyhow!("unknown comparison {}", s)),
         }
+    }
+}
 
-        let table_name = struct_tag_to_sql(&struct_.type_);
-        if !struct_.value.is_empty() {
-            if !hit_created_cache(&table_name) {
-                let create_sql = format!(
-                    "CREATE TABLE IF NOT EXISTS {} ({})",
-                    table_name,
-                    fields.join(", "),
-                );
-                //println!("{}", create_sql);
-                sqlx::query(&create_sql).execute(&mut *db).await.unwrap();
-            }            
-
-            let insert_sql = if !field_names.is_empty() {
-                format!(
-                    "INSERT INTO {} ({}) VALUES ({})",
-                    table_name,
-                    field_names.join(", "),
-                    values.join(", "),
-                )
-            } else {
-                format!("INSERT INTO {} DEFAULT VALUES", table_name)
-            };
-            //println!("{}", insert_sql);
-            let result = sqlx::query(&insert_sql).execute(&mut *db).await.unwrap();
-            let id = result.last_insert_rowid();
-
-            // handle complex vectors inside the struct
-            for (ident, val) in &struct_.value {
-                match val {
-                    AnnotatedMoveValue::Vector(ty, v) => {
-                        match ty {
-                            TypeTag::Address |
-                            TypeTag::Vector(_) |
-                            TypeTag::Struct(_) => {
-                                let name = vector_table_name(&struct_.type_, ident);
-                                vector_to_sql(name, id, &ty, &v, &mut *db).await;
-                            },
-                            _ => {},
-                        }
-                    },
-                    _ => {},
-                }
-            }
+/// A value bound to one of a filter's placeholders.
+enum FilterValue {
+    Integer(i64),
+    Text(String),
+    Blob(Vec<u8>),
+}
 
-            id
-        } else {
-            if !hit_created_cache(&table_name) {
-                let create_sql = format!(
-                    "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY)",
-                    table_name,
-                );
-                //println!("{}", create_sql);
-                sqlx::query(&create_sql).execute(&mut *db).await.unwrap();
-            }            
+/// Progress of the indexer, persisted so that a restarted run picks up where
+/// the previous one stopped.
+#[derive(Clone, Debug, Default)]
+pub struct SyncState {
+    /// The next transaction version to replay.
+    pub next_version: u64,
+    /// While a backup is being ingested, the backup file in progress and the
+    /// `Backup::offset` of the next account state to read from it.
+    pub backup_position: Option<(String, u64)>,
+    /// Whether the genesis modules have been published, either by replaying
+    /// genesis or after ingesting a backup.
+    pub genesis_applied: bool,
+}
 
-            let insert_sql = format!("INSERT INTO {} DEFAULT VALUES;", table_name);
-            //println!("{}", insert_sql);
-            let result = sqlx::query(&insert_sql).execute(&mut *db).await.unwrap();
+/// The version of the rules for laying resources out in tables, recorded in
+/// `__sync_state` and bumped whenever they change, so that `migrate-data` can
+/// rewrite a database written under earlier ones. 0 is every database written
+/// before the version was recorded, all of which stored numbers as
+/// `NumericEncoding::Blob` does.
+pub const SCHEMA_VERSION: u64 = 1;
 
-            result.last_insert_rowid()
-        }
-    })
+/// The settings that read resources written under an earlier schema version,
+/// given those of the current one.
+pub fn schema_config(version: u64, config: &Config) -> Result<Config> {
+    match version {
+        // the numeric encoding was only chosen by --numeric-encoding from
+        // version 1 on
+        0 => Ok(Config { numeric_encoding: NumericEncoding::Blob, ..config.clone() }),
+        SCHEMA_VERSION => Ok(config.clone()),
+        _ => Err(anyhow!("unknown schema version {}", version)),
+    }
 }
 
-async fn vector_to_sql(name: String, pid: i64, ty: &TypeTag, v: &[AnnotatedMoveValue], db: &mut PoolConnection<Sqlite>) {
-    // create table for this vector
+/// The most SELECTs SQLite joins into one compound statement by default.
+const MAX_COMPOUND_SELECT: usize = 500;
 
-    let field = match ty {
-        TypeTag::Address => "slot BLOB NOT NULL".to_string(),
-        TypeTag::Vector(vty) => {
-            match **vty {
-                // this is Vector<u8> aka Bytes
-                TypeTag::U8 => "slot BLOB NOT NULL".to_string(),
-                // other vectors generate no field
-                _ => "".to_string(),
-            }
-        },
-        TypeTag::Struct(_) => "slot INTEGER NOT NULL".to_string(),
-        _ => unreachable!(),
-    };
+/// How much of a slow statement's text to include in the warning.
+const SLOW_QUERY_MAX_LEN: usize = 200;
 
-    if !hit_created_cache(&name) {
-        let create_sql = format!(
-            "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, parent_id INTEGER NOT NULL, {})",
-            name,
-            field,
-        );
-        //println!("{}", create_sql);
-        sqlx::query(&create_sql).execute(&mut *db).await.unwrap();
-    }    
-
-    // populate table
-    for e in v {
-        match e {
-            AnnotatedMoveValue::Address(a) => {
-                let insert_sql = format!(
-                    "INSERT INTO {} (parent_id, slot) VALUES ({}, x'{}')",
-                    name,
-                    pid,
-                    hex::encode(a),
-                );
-                //println!("{}", insert_sql);
-                sqlx::query(&insert_sql).execute(&mut *db).await.unwrap();
-            },
-            AnnotatedMoveValue::Struct(s) => {
-                let id = struct_to_sql(s, db).await;
-                let insert_sql = format!(
-                    "INSERT INTO {} (parent_id, slot) VALUES ({}, {})",
-                    name,
-                    pid,
-                    id,
-                );
-                //println!("{}", insert_sql);
-                sqlx::query(&insert_sql).execute(&mut *db).await.unwrap();
-            },
-            AnnotatedMoveValue::Bytes(b) => {
-                let insert_sql = format!(
-                    "INSERT INTO {} (parent_id, slot) VALUES ({}, x'{}')",
-                    name,
-                    pid,
-                    hex::encode(b),
+/// Times query futures against the configured slow query threshold, and logs
+/// them if asked to. This costs nothing beyond the boxing when neither is set.
+trait Timed: Future + Sized {
+    fn timed<'a>(self, config: &'a Config, sql: &'a str) -> Pin<Box<dyn Future<Output=Self::Output> + 'a>>
+    where
+        Self: 'a,
+    {
+        Box::pin(async move {
+            if config.log_sql {
+                tracing::info!("{}", sql);
+            }
+            let threshold = match config.slow_query_threshold {
+                None => return self.await,
+                Some(t) => t,
+            };
+            let start = Instant::now();
+            let output = self.await;
+            let elapsed = start.elapsed();
+            if elapsed >= threshold {
+                let text: String = sql.chars().take(SLOW_QUERY_MAX_LEN).collect();
+                tracing::warn!(
+                    "slow query took {} ms: {}{}",
+                    elapsed.as_millis(),
+                    text,
+                    if text.len() < sql.len() { "..." } else { "" },
                 );
-                //println!("{}", insert_sql);
-                sqlx::query(&insert_sql).execute(&mut *db).await.unwrap();
-            },
-
-            AnnotatedMoveValue::Vector(_vty, _vval) => todo!(),
-            _ => unreachable!(),
-        }
+            }
+            output
+        })
     }
-    
 }
 
-fn vector_to_bytes(v: &[AnnotatedMoveValue]) -> Vec<u8> {
-    v.iter().flat_map(|value| {
-        match value {
-            AnnotatedMoveValue::Bool(b) => vec![if *b { 1u8 } else { 0u8 }],
-            AnnotatedMoveValue::U8(i) => vec![*i],
-            AnnotatedMoveValue::U64(i) => i.to_be_bytes().to_vec(),
-            AnnotatedMoveValue::U128(i) => i.to_be_bytes().to_vec(),
-            _ => unreachable!(),
-        }
-    }).collect()
+impl<F: Future> Timed for F {}
+
+/// What a write did to the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteKind {
+    CreateResource,
+    UpdateResource,
+    DeleteResource,
+    PublishModule,
+    UnpublishModule,
 }
 
-fn type_param_to_sql(param: &TypeTag) -> String {
-    match param {
-        TypeTag::Bool => "Bool".to_string(),
-        TypeTag::U8 => "U8".to_string(),
-        TypeTag::U64 => "U64".to_string(),
-        TypeTag::U128 => "U128".to_string(),
-        TypeTag::Address => "Address".to_string(),
-        TypeTag::Signer => unreachable!(),
-        TypeTag::Vector(type_tag) => format!("Vector__t_{}_t", type_param_to_sql(&type_tag)),
-        TypeTag::Struct(struct_tag) => struct_tag_to_sql(struct_tag),
+/// Custom processing of each write, e.g. metrics or side indexes, run after
+/// `DB` has applied it. `kind` is `None` for writes that weren't applied:
+/// resource values whose type isn't indexed or failed to resolve, and
+/// deletions, which are only recorded as skipped.
+pub trait WriteObserver: Send + Sync {
+    fn on_write(&self, version: u64, address: &AccountAddress, path: &Path, op: &WriteOp, kind: Option<WriteKind>);
+
+    /// Called with the decoded value of every resource stored, before
+    /// `on_write` for the same write.
+    fn on_store(&self, _version: u64, _address: &AccountAddress, _resource: &AnnotatedMoveStruct) {}
+
+    /// Called before the sync state is saved, so that anything buffered is
+    /// kept at least as far as indexing has got. An error keeps the sync
+    /// state from being saved.
+    fn flush(&self) -> Result<()> {
+        Ok(())
     }
 }
 
-fn type_params_to_sql(params: &[TypeTag]) -> String {
-    let result: Vec<_> = params.iter().map(|tt| type_param_to_sql(tt)).collect();
-    result.join("__")
+/// Writes held back to be applied together, set up with `DB::buffer_writes`.
+/// Only the latest write to each path is kept unless history is, and the
+/// sync state is only saved once the writes before it are applied.
+struct WriteBuffer {
+    interval: Option<Duration>,
+    max_rows: Option<usize>,
+    last_flush: Instant,
+    /// Each write with its version and block timestamp, in order. A write
+    /// replaced by a later one to the same path leaves `None` behind.
+    writes: Vec<Option<(u64, Option<u64>, AccessPath, WriteOp)>>,
+    latest: HashMap<AccessPath, usize>,
+    sync_state: Option<SyncState>,
 }
 
-pub fn struct_tag_to_sql(tag: &StructTag) -> String {
-    let type_params_str = if !tag.type_params.is_empty() {
-        format!("__t_{}_t", type_params_to_sql(&tag.type_params))
-    } else {
-        "".to_string()
-    };
-    format!("x{}__{}__{}{}",
-            tag.address.short_str_lossless(),
-            tag.module,
-            tag.name,
-            type_params_str)
+impl WriteBuffer {
+    fn is_due(&self) -> bool {
+        self.max_rows.map_or(false, |max_rows| self.latest.len() >= max_rows)
+            || self.interval.map_or(false, |interval| self.last_flush.elapsed() >= interval)
+    }
 }
 
-fn vector_table_name(tag: &StructTag, field_name: &Identifier) -> String {
-    format!("{}__{}__elements", struct_tag_to_sql(tag), field_name)
+/// Writes and reads the generated tables. Addresses are always stored, bound,
+/// and put in table names at their full width; the shortened forms are only
+/// for messages.
+pub struct DB {
+    pool: SqlitePool,
+    config: Arc<Config>,
+    modules: Arc<ModuleCache>,
+    tables: Arc<CreatedTables>,
+    root_ids: Arc<RootIdCache>,
+    observers: Vec<Box<dyn WriteObserver>>,
+    block_timestamp: std::sync::Mutex<Option<u64>>,
+    write_buffer: std::sync::Mutex<Option<WriteBuffer>>,
 }
 
-pub fn fetch_struct<'a>(
-    tag: &'a StructTag,
-    id: i64,
-    resolver: &'a Resolver,
-    db: &'a mut PoolConnection<Sqlite>,
-) -> Pin<Box<dyn Future<Output=Option<MoveValue>> + 'a>> {
-    Box::pin(async move {
-        // Find the fields to query for the struct
-        let struct_ = resolver.resolve_struct(tag).await.unwrap();
-        let columns = struct_columns(&struct_);
-        let columns = if columns.is_empty() {
-            vec!["__id"]
-        } else {
-            columns
+impl DB {
+    pub fn from_pool(pool: SqlitePool, config: Arc<Config>, modules: Arc<ModuleCache>) -> DB {
+        DB {
+            pool,
+            config,
+            modules,
+            tables: Arc::new(CreatedTables::default()),
+            root_ids: Arc::new(RootIdCache::new(DEFAULT_ROOT_ID_CACHE_SIZE)),
+            observers: vec![],
+            block_timestamp: std::sync::Mutex::new(None),
+            write_buffer: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// The root ids this `DB` has looked up or written, for a `SqlState`
+    /// reading the same tables to share.
+    pub fn root_ids(&self) -> Arc<RootIdCache> {
+        self.root_ids.clone()
+    }
+
+    /// A resolver over this `DB`'s module cache that shares the tables it
+    /// has created, e.g. for storing layouts with `--persist-layouts`.
+    pub fn resolver(&self) -> Resolver {
+        Resolver::from_shared_cache(self.pool.clone(), self.config.clone(), self.modules.clone())
+            .with_tables(self.tables.clone())
+    }
+
+    /// Hold writes back, coalescing repeated writes to the same path, until
+    /// `max_rows` paths have pending writes or `interval` has passed since the
+    /// last flush, checked whenever the sync state is saved. Executing a
+    /// block must then see the `pending_writes`.
+    ///
+    /// Buffered writes are applied with an annotator over the shared module
+    /// cache, so this is only for replaying after genesis.
+    pub fn buffer_writes(&self, interval: Option<Duration>, max_rows: Option<usize>) {
+        *self.write_buffer.lock().unwrap() = Some(WriteBuffer {
+            interval,
+            max_rows,
+            last_flush: Instant::now(),
+            writes: vec![],
+            latest: HashMap::new(),
+            sync_state: None,
+        });
+    }
+
+    /// The value of every path with a buffered write, or `None` if it is
+    /// deleted.
+    pub fn pending_writes(&self) -> HashMap<AccessPath, Option<Vec<u8>>> {
+        let buffer = self.write_buffer.lock().unwrap();
+        let writes = match &*buffer {
+            Some(buffer) => &buffer.writes,
+            None => return HashMap::new(),
         };
-        let select_sql = format!(
-            "SELECT {} FROM {} WHERE __id = {}",
-            columns.join(", "),
-            struct_tag_to_sql(tag),
-            id,
-        );
-        //println!("{}", select_sql);
-        let row = sqlx::query(&select_sql)
-            .fetch_optional(&mut *db)
-            .await
-            .unwrap();
-        let row = match row {
-            None => return None,
-            Some(r) => r,
+        writes
+            .iter()
+            .flatten()
+            .map(|(_, _, access_path, op)| {
+                let value = match op {
+                    WriteOp::Value(v) => Some(v.clone()),
+                    WriteOp::Deletion => None,
+                };
+                (access_path.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Apply every buffered write, then save the latest sync state given
+    /// while they were buffered.
+    pub async fn flush(&self) -> Result<()> {
+        let (writes, sync_state) = match &mut *self.write_buffer.lock().unwrap() {
+            Some(buffer) => {
+                buffer.latest.clear();
+                buffer.last_flush = Instant::now();
+                (mem::take(&mut buffer.writes), buffer.sync_state.take())
+            },
+            None => return Ok(()),
         };
+        let resolver = self.resolver();
+        let annotator = MoveValueAnnotator::new(resolver);
+        let block_timestamp = *self.block_timestamp.lock().unwrap();
+        for (version, time, access_path, op) in writes.into_iter().flatten() {
+            *self.block_timestamp.lock().unwrap() = time;
+            self.apply(version, &access_path, &op, &annotator).await?;
+        }
+        *self.block_timestamp.lock().unwrap() = block_timestamp;
+        if let Some(sync_state) = sync_state {
+            self.write_sync_state(&sync_state).await?;
+        }
+        Ok(())
+    }
 
-        let mut fields = vec![];
-        let mut column_index = 0;
-        for (field_name, field_type) in struct_.fields {
-            match field_type {
-                // vectors (other than Vector<u8>) have no corresponding column in the struct's table
-                FatType::Vector(ref sub_type) => {
-                    match **sub_type {
-                        FatType::U8 => {
-                            let bytes: Vec<u8> = row.get(column_index);
-                            let v: Vec<MoveValue> = bytes.into_iter().map(|b| MoveValue::U8(b)).collect();
-                            fields.push(MoveValue::Vector(v));
-                            column_index += 1;
-                        },
+    /// Call `observer` for every write applied with `execute_with_annotator`,
+    /// after any observers added before it.
+    pub fn with_observer(mut self, observer: Box<dyn WriteObserver>) -> DB {
+        self.observers.push(observer);
+        self
+    }
 
-                        _ => {
-                            let v = fetch_vector(tag, &field_name, &*sub_type, id, resolver, db).await;
-                            fields.push(MoveValue::Vector(v));
-                            // don't change column index
-                        },
-                    }
-                },
+    /// Whether the tables for the configured prefix have already been
+    /// created in this database.
+    pub async fn is_initialized(&self) -> bool {
+        let mut db = self.pool.acquire().await.unwrap();
+        let select_sql = "SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?";
+        sqlx::query(select_sql)
+            .bind(unquote(&self.config.module_table_name()))
+            .fetch_optional(&mut db)
+            .timed(&self.config, select_sql)
+            .await
+            .unwrap()
+            .is_some()
+    }
 
-                // type parameters can be ignored as they are already expanded
-                FatType::TyParam(_) => {}
+    /// Drop every table generated under the configured prefix, leaving the
+    /// database ready to be initialized again.
+    pub async fn reset(&self) -> Result<()> {
+        let mut db = self.pool.acquire().await?;
+        let select_sql = "SELECT name FROM sqlite_master WHERE type = 'table'";
+        let tables = sqlx::query(select_sql)
+            .fetch_all(&mut db)
+            .timed(&self.config, select_sql)
+            .await?
+            .iter()
+            .map(|row| row.get::<String, _>(0))
+            .filter(|name| self.config.is_generated_table(name))
+            .collect::<Vec<_>>();
+        for table in tables {
+            let table = quote(&table);
+            let drop_sql = format!("DROP TABLE {}", table);
+            sqlx::query(&drop_sql).execute(&mut db).timed(&self.config, &drop_sql).await?;
+            self.tables.forget(&table).await;
+        }
+        self.root_ids.clear();
+        Ok(())
+    }
 
-                // these types all have fields
-                FatType::Bool => {
-                    fields.push(MoveValue::Bool(row.get(column_index)));
-                    column_index += 1;
-                },
-                FatType::U8 => {
-                    fields.push(MoveValue::U8(row.get::<i64, _>(column_index) as u8));
-                    column_index += 1;
-                },
-                FatType::U64 => {
-                    fields.push(MoveValue::U64(row.get::<i64, _>(column_index) as u64));
-                    column_index += 1;
-                },
-                FatType::U128 => {
-                    let bytes: Vec<u8> = row.get(column_index);
-                    let v = u128::from_be_bytes(bytes.try_into().unwrap());
-                    fields.push(MoveValue::U128(v));
-                    column_index += 1;
-                },
-                FatType::Address => {
-                    let bytes: Vec<u8> = row.get(column_index);
-                    fields.push(MoveValue::Address(AccountAddress::try_from(bytes).unwrap()));
-                    column_index += 1;
-                },
-                FatType::Struct(ref sub_struct) => {
-                    let sub_tag = sub_struct.struct_tag().unwrap();
-                    let sub_id = row.get(column_index);
-                    let value = fetch_struct(&sub_tag, sub_id, resolver, &mut *db).await.unwrap();
-                    fields.push(value);
-                    column_index += 1;
-                },
+    pub async fn initialize(&self) {
+        let mut db = self.pool.acquire().await.unwrap();
+
+        let module_table = self.config.module_table_name();
+        let create_sql = format!(
+            "CREATE TABLE {} (address BLOB NOT NULL, name STRING NOT NULL, data BLOB NOT NULL, CONSTRAINT {} PRIMARY KEY (address, name))",
+            module_table,
+            quote(&format!("{}_pkey", unquote(&module_table))),
+        );
+        sqlx::query(&create_sql).execute(&mut db).timed(&self.config, &create_sql).await.unwrap();
+
+        let sync_state_table = self.config.sync_state_table_name();
+        let create_sql = format!(
+            "CREATE TABLE {} (id INTEGER PRIMARY KEY CHECK (id = 0), next_version INTEGER NOT NULL, backup_file STRING, backup_offset INTEGER, genesis_applied BOOLEAN NOT NULL DEFAULT 0, schema_version INTEGER NOT NULL DEFAULT 0)",
+            sync_state_table,
+        );
+        sqlx::query(&create_sql).execute(&mut db).timed(&self.config, &create_sql).await.unwrap();
+        let insert_sql = format!(
+            "INSERT INTO {} (id, next_version, schema_version) VALUES (0, 0, {})",
+            sync_state_table,
+            SCHEMA_VERSION,
+        );
+        sqlx::query(&insert_sql).execute(&mut db).timed(&self.config, &insert_sql).await.unwrap();
+    }
+
+    /// Add what later releases keep in the sync state to a database created
+    /// by an earlier one.
+    pub async fn upgrade(&self) {
+        let mut db = self.pool.acquire().await.unwrap();
+        let sync_state_table = self.config.sync_state_table_name();
+        let pragma_sql = format!("PRAGMA table_info({})", sync_state_table);
+        let columns = sqlx::query(&pragma_sql)
+            .fetch_all(&mut db)
+            .timed(&self.config, &pragma_sql)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(1))
+            .collect::<Vec<_>>();
+        if !columns.iter().any(|name| name == "genesis_applied") {
+            let alter_sql = format!(
+                "ALTER TABLE {} ADD COLUMN genesis_applied BOOLEAN NOT NULL DEFAULT 0",
+                sync_state_table,
+            );
+            sqlx::query(&alter_sql).execute(&mut db).timed(&self.config, &alter_sql).await.unwrap();
+            // until then, genesis was always applied before any version
+            // counted as synced
+            let update_sql = format!("UPDATE {} SET genesis_applied = next_version > 0", sync_state_table);
+            sqlx::query(&update_sql).execute(&mut db).timed(&self.config, &update_sql).await.unwrap();
+        }
+        // the data stays at schema version 0 until migrate-data rewrites it
+        if !columns.iter().any(|name| name == "schema_version") {
+            let alter_sql = format!(
+                "ALTER TABLE {} ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 0",
+                sync_state_table,
+            );
+            sqlx::query(&alter_sql).execute(&mut db).timed(&self.config, &alter_sql).await.unwrap();
+        }
+        // the tables of structs without fields were keyed by `id` rather
+        // than `__id`, and are the only generated tables with no other column
+        let table_names = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .fetch_all(&mut db)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(0))
+            .filter(|name| self.config.is_generated_table(name))
+            .collect::<Vec<_>>();
+        for table_name in table_names.iter().map(|name| quote(name)) {
+            let pragma_sql = format!("PRAGMA table_info({})", table_name);
+            let columns = sqlx::query(&pragma_sql)
+                .fetch_all(&mut db)
+                .timed(&self.config, &pragma_sql)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| row.get::<String, _>(1))
+                .collect::<Vec<_>>();
+            if columns == ["id"] {
+                let alter_sql = format!("ALTER TABLE {} RENAME COLUMN id TO __id", table_name);
+                sqlx::query(&alter_sql).execute(&mut db).timed(&self.config, &alter_sql).await.unwrap();
             }
         }
+    }
 
-        Some(MoveValue::Struct(MoveStruct::new(fields)))
-    })
-}
+    /// Check that the database was written with this build's address width,
+    /// judging by a published module, since every table name and address
+    /// column depends on it.
+    pub async fn check_address_width(&self) -> Result<()> {
+        let mut db = self.pool.acquire().await?;
+        let select_sql = format!("SELECT address FROM {} LIMIT 1", self.config.module_table_name());
+        let row = sqlx::query(&select_sql).fetch_optional(&mut db).timed(&self.config, &select_sql).await?;
+        match row {
+            Some(row) => address_from_bytes(&row.get::<Vec<u8>, _>(0)).map(|_| ()),
+            None => Ok(()),
+        }
+    }
 
-/// Return the set of columns in a struct's table. This will be a subset of
-/// columns as Vector fields do not have a column.
-fn struct_columns<'a>(struct_: &'a FatStructType) -> Vec<&'a str> {
-    struct_.fields.iter().filter_map(|(field_name, field_type)| {
-        match field_type {
-            // vectors (other than Vector<u8>) have no corresponding column in the struct's table
-            FatType::Vector(ref sub_type) => {
-                match **sub_type {
-                    FatType::U8 => Some(field_name.as_str()),
-                    _ => None,
-                }
+    /// The schema version the stored resources were written under.
+    pub async fn schema_version(&self) -> u64 {
+        let mut db = self.pool.acquire().await.unwrap();
+        let select_sql = format!("SELECT schema_version FROM {} WHERE id = 0", self.config.sync_state_table_name());
+        let row = sqlx::query(&select_sql).fetch_one(&mut db).timed(&self.config, &select_sql).await.unwrap();
+        row.get::<i64, _>(0) as u64
+    }
+
+    pub async fn sync_state(&self) -> SyncState {
+        self.try_sync_state().await.unwrap()
+    }
+
+    /// `sync_state`, failing rather than panicking when the database can't
+    /// be opened or hasn't been initialized, e.g. for queries answered while
+    /// indexing has yet to start.
+    pub async fn try_sync_state(&self) -> Result<SyncState> {
+        let mut db = self.pool.acquire().await?;
+        let select_sql = format!(
+            "SELECT next_version, backup_file, backup_offset, genesis_applied FROM {} WHERE id = 0",
+            self.config.sync_state_table_name(),
+        );
+        let row = sqlx::query(&select_sql).fetch_one(&mut db).timed(&self.config, &select_sql).await?;
+        let backup_file: Option<String> = row.get(1);
+        let backup_offset: Option<i64> = row.get(2);
+        Ok(SyncState {
+            next_version: row.get::<i64, _>(0) as u64,
+            backup_position: backup_file.map(|file| (file, backup_offset.unwrap_or(0) as u64)),
+            genesis_applied: row.get(3),
+        })
+    }
+
+    /// Save the indexer's progress, or with writes buffered, hold it until
+    /// they are applied.
+    pub async fn save_sync_state(&self, state: &SyncState) -> Result<()> {
+        let buffered = match &mut *self.write_buffer.lock().unwrap() {
+            Some(buffer) => {
+                buffer.sync_state = Some(state.clone());
+                Some(buffer.is_due())
             },
+            None => None,
+        };
+        match buffered {
+            Some(true) => self.flush().await,
+            Some(false) => Ok(()),
+            None => self.write_sync_state(state).await,
+        }
+    }
 
-            // type parameters can be ignored as they are expanded already
-            FatType::TyParam(_) => None,
+    async fn write_sync_state(&self, state: &SyncState) -> Result<()> {
+        for observer in &self.observers {
+            observer.flush()?;
+        }
+        let mut db = self.pool.acquire().await?;
+        let update_sql = format!(
+            "UPDATE {} SET next_version = ?, backup_file = ?, backup_offset = ?, genesis_applied = ? WHERE id = 0",
+            self.config.sync_state_table_name(),
+        );
+        let (backup_file, backup_offset) = match &state.backup_position {
+            Some((file, offset)) => (Some(file.as_str()), Some(*offset as i64)),
+            None => (None, None),
+        };
+        sqlx::query(&update_sql)
+            .bind(state.next_version as i64)
+            .bind(backup_file)
+            .bind(backup_offset)
+            .bind(state.genesis_applied)
+            .execute(&mut db)
+            .timed(&self.config, &update_sql)
+            .await?;
+        Ok(())
+    }
 
-            // these types all have fields
-            FatType::Bool |
-            FatType::U8 |
-            FatType::U64 |
-            FatType::U128 |
-            FatType::Address |
-            FatType::Struct(_) => Some(field_name.as_str()),
+    /// Use `usecs` as the block timestamp of the writes applied from now on,
+    /// until a later block sets it again.
+    pub fn set_block_timestamp(&self, usecs: u64) {
+        *self.block_timestamp.lock().unwrap() = Some(usecs);
+    }
+
+    /// Pick up the block timestamp recorded for the last version stored, so
+    /// that a resumed run doesn't leave the versions before its first block
+    /// without one.
+    pub async fn load_block_timestamp(&self) -> Result<()> {
+        let mut db = self.pool.acquire().await?;
+        let select_sql = format!(
+            "SELECT timestamp_usecs FROM {} WHERE timestamp_usecs IS NOT NULL ORDER BY version DESC LIMIT 1",
+            self.config.transactions_table_name(),
+        );
+        // the table doesn't exist until the first version is stored
+        let row = absent_if_missing_table(
+            sqlx::query(&select_sql)
+                .fetch_optional(&mut db)
+                .timed(&self.config, &select_sql)
+                .await,
+        )?;
+        if let Some(row) = row {
+            self.set_block_timestamp(row.get::<i64, _>(0) as u64);
+        }
+        Ok(())
+    }
+
+    /// Record the block timestamp in effect at `version`. Versions before the
+    /// first block seen, e.g. those ingested from a backup, have none.
+    pub async fn store_transaction(&self, version: u64) -> Result<()> {
+        let mut db = self.pool.acquire().await?;
+
+        let table_name = self.config.transactions_table_name();
+        let columns = [
+            "version INTEGER PRIMARY KEY".to_string(),
+            "timestamp_usecs INTEGER".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &table_name, &columns, &mut db).await?;
+
+        let insert_sql = format!(
+            "INSERT INTO {} (version, timestamp_usecs) VALUES (?, ?) ON CONFLICT(version) DO UPDATE SET timestamp_usecs = excluded.timestamp_usecs",
+            table_name,
+        );
+        sqlx::query(&insert_sql)
+            .bind(version as i64)
+            .bind(self.block_timestamp.lock().unwrap().map(|usecs| usecs as i64))
+            .execute(&mut db)
+            .timed(&self.config, &insert_sql)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn store_events(&self, version: u64, events: &[ContractEvent]) -> Result<()> {
+        let mut db = self.pool.acquire().await?;
+
+        let table_name = self.config.events_table_name();
+        let columns = [
+            "version INTEGER NOT NULL".to_string(),
+            "key BLOB NOT NULL".to_string(),
+            "sequence_number INTEGER NOT NULL".to_string(),
+            "type_tag STRING NOT NULL".to_string(),
+            "data BLOB NOT NULL".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &table_name, &columns, &mut db).await?;
+
+        let insert_sql = format!(
+            "INSERT INTO {} (version, key, sequence_number, type_tag, data) VALUES (?, ?, ?, ?, ?)",
+            table_name,
+        );
+        for event in events {
+            sqlx::query(&insert_sql)
+                .bind(version as i64)
+                .bind(event.key().as_bytes())
+                .bind(event.sequence_number() as i64)
+                .bind(event.type_tag().to_string())
+                .bind(event.event_data())
+                .execute(&mut db)
+                .timed(&self.config, &insert_sql)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The events emitted to one event handle, in sequence order, as stored
+    /// with `--with-events`. A key nothing was emitted to has no events.
+    pub async fn events_for_key(&self, key: &EventKey) -> Result<Vec<AnnotatedMoveValue>> {
+        let mut db = self.pool.acquire().await?;
+        let table_name = self.config.events_table_name();
+        let exists_sql = "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?";
+        let exists = sqlx::query(exists_sql)
+            .bind(unquote(&table_name))
+            .fetch_optional(&mut db)
+            .timed(&self.config, exists_sql)
+            .await?
+            .is_some();
+        if !exists {
+            return Err(anyhow!("no events are stored; index with --with-events to store them"));
         }
-    }).collect()
-}
 
-fn fetch_vector<'a>(
-    tag: &'a StructTag,
-    field_name: &'a Identifier,
-    elem_type: &'a FatType,
-    id: i64,
-    resolver: &'a Resolver,
-    db: &'a mut PoolConnection<Sqlite>,
-) -> Pin<Box<dyn Future<Output=Vec<MoveValue>> + 'a>> {
-    Box::pin(async move {
-        let table_name = vector_table_name(tag, field_name);
         let select_sql = format!(
-            "SELECT slot FROM {} WHERE parent_id = {} ORDER BY rowid",
+            "SELECT sequence_number, type_tag, data FROM {} WHERE key = ? ORDER BY sequence_number",
             table_name,
-            id,
         );
-        //println!("ELEMENTS QUERY: {}", select_sql);
         let rows = sqlx::query(&select_sql)
-            .fetch_all(&mut *db)
-            .await
-            .unwrap();
-        let mut elements = vec![];
+            .bind(key.as_bytes())
+            .fetch_all(&mut db)
+            .timed(&self.config, &select_sql)
+            .await?;
+        let resolver = self.resolver();
+        let annotator = MoveValueAnnotator::new(resolver);
+        let mut events = vec![];
         for row in rows {
-            let element = match elem_type {
-                FatType::Bool => MoveValue::Bool(row.get(0)),
-                FatType::U8 => MoveValue::U8(row.get::<i64,_>(0) as u8),
-                FatType::U64 => MoveValue::U64(row.get::<i64,_>(0) as u64),
-                FatType::U128 => {
-                    let bytes: Vec<u8> = row.get(0);
-                    let v = u128::from_be_bytes(bytes.try_into().unwrap());
-                    MoveValue::U128(v)
-                },
-                FatType::Address => {
-                    let bytes: Vec<u8> = row.get(0);
-                    MoveValue::Address(AccountAddress::try_from(bytes).unwrap())
-                },
-                FatType::Vector(ref sub_type) => {
-                    match **sub_type {
-                        FatType::U8 => {
-                            let bytes: Vec<u8> = row.get(0);
-                            let v: Vec<MoveValue> = bytes
-                                .into_iter()
-                                .map(|b| MoveValue::U8(b)).collect();
-                            MoveValue::Vector(v)
-                        },
-                        _ => todo!(),
-                    }
-                },
-                FatType::Struct(sty) => {
-                    let sub_tag = sty.struct_tag().unwrap();
-                    let sub_id = row.get(0);
-                    fetch_struct(&sub_tag, sub_id, resolver, db).await.unwrap()
+            let event = ContractEvent::new(
+                *key,
+                row.get::<i64, _>(0) as u64,
+                parser::parse_type_tag(row.get(1))?,
+                row.get(2),
+            );
+            events.push(annotator.view_contract_event(&event).await?);
+        }
+        Ok(events)
+    }
+
+    /// Read a single u64 field of the resource at `address`. `field` may be a
+    /// dotted path through nested structs, e.g. `coin.value`.
+    pub async fn read_field_u64(&self, address: &AccountAddress, tag: &StructTag, field: &str) -> Result<Option<u64>> {
+        let row = self.read_field(address, tag, field, &FatType::U64).await?;
+        match row {
+            Some(row) => self.config.numeric_encoding.u64_from_row(&row, 0, self.config.u64_bitcast),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn read_field_u128(&self, address: &AccountAddress, tag: &StructTag, field: &str) -> Result<Option<u128>> {
+        let row = self.read_field(address, tag, field, &FatType::U128).await?;
+        match row {
+            Some(row) => self.config.numeric_encoding.u128_from_row(&row, 0),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn read_field_bool(&self, address: &AccountAddress, tag: &StructTag, field: &str) -> Result<Option<bool>> {
+        let row = self.read_field(address, tag, field, &FatType::Bool).await?;
+        Ok(row.map(|row| row.get::<i64, _>(0) != 0))
+    }
+
+    pub async fn read_field_address(&self, address: &AccountAddress, tag: &StructTag, field: &str) -> Result<Option<AccountAddress>> {
+        let row = self.read_field(address, tag, field, &FatType::Address).await?;
+        row.map(|row| address_from_bytes(&row.get::<Vec<u8>, _>(0)))
+            .transpose()
+    }
+
+    /// The type of every stored resource, keyed by its quoted `__root__`
+    /// table, as `root_table_name` names it.
+    pub async fn stored_types(&self) -> Result<HashMap<String, StructTag>> {
+        let mut db = self.pool.acquire().await?;
+        let root_prefix = format!("{}__root__", self.config.table_prefix);
+        let select_sql = "SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name";
+        let root_tables = sqlx::query(select_sql)
+            .fetch_all(&mut db)
+            .timed(&self.config, select_sql)
+            .await?
+            .iter()
+            .map(|row| row.get::<String, _>(0))
+            .filter(|name| name.starts_with(&root_prefix))
+            .collect::<Vec<_>>();
+
+        let mut tags = HashMap::new();
+        for root_table in root_tables {
+            match struct_tag_from_name(&root_table[root_prefix.len()..]) {
+                Some(tag) => {
+                    tags.insert(quote(&root_table), tag);
                 },
-                FatType::TyParam(_) => unreachable!(),
+                None => tracing::warn!(table = %root_table, "cannot tell the type stored in the table; skipping it"),
+            }
+        }
+        Ok(tags)
+    }
+
+    /// The resource of one type stored at `address`, if there is one. With
+    /// history, the latest version is read.
+    pub async fn read_resource(&self, address: &AccountAddress, tag: &StructTag) -> Result<Option<AnnotatedMoveStruct>> {
+        let resolver = self.resolver();
+        let fat_type = resolver.resolve_struct(tag).await?;
+        let mut db = self.pool.acquire().await?;
+
+        let select_sql = format!(
+            "SELECT id FROM {} WHERE address = ? ORDER BY last_modified_version DESC LIMIT 1",
+            root_table_name(&self.config, tag),
+        );
+        // a type that was never stored has no table
+        let row = absent_if_missing_table(
+            sqlx::query(&select_sql)
+                .bind(address.as_ref())
+                .fetch_optional(&mut db)
+                .timed(&self.config, &select_sql)
+                .await,
+        )?;
+        let id = match row {
+            None => return Ok(None),
+            Some(row) => row.get(0),
+        };
+        let struct_ = match fetch_struct(&self.config, tag, id, &resolver, &mut db).await? {
+            Some(MoveValue::Struct(s)) => s,
+            _ => return Err(anyhow!("{} at {} is missing its struct row", tag, address)),
+        };
+        let annotator = MoveValueAnnotator::new(resolver);
+        Ok(Some(annotator.annotate_struct(&struct_, &fat_type).await?))
+    }
+
+    /// Every resource stored at `address`, found by looking it up in every
+    /// `__root__` table at once. With history, the latest version of each is
+    /// read.
+    pub async fn read_account(&self, address: &AccountAddress) -> Result<Vec<AnnotatedMoveStruct>> {
+        let resolver = self.resolver();
+        let tags = self.stored_types().await?;
+        let mut db = self.pool.acquire().await?;
+
+        // the ids of every resource are found with one query per batch of
+        // tables, each table's row tagged with the table's name
+        let mut ids = vec![];
+        let table_names = tags.keys().collect::<Vec<_>>();
+        for batch in table_names.chunks(MAX_COMPOUND_SELECT) {
+            let select_sql = batch
+                .iter()
+                .map(|root_table| if self.config.history {
+                    format!(
+                        "SELECT '{}' AS root_table, id FROM (SELECT id FROM {} WHERE address = x'{}' ORDER BY last_modified_version DESC LIMIT 1)",
+                        root_table,
+                        root_table,
+                        hex::encode(address),
+                    )
+                } else {
+                    format!(
+                        "SELECT '{}' AS root_table, id FROM {} WHERE address = x'{}'",
+                        root_table,
+                        root_table,
+                        hex::encode(address),
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" UNION ALL ");
+            let rows = sqlx::query(&select_sql)
+                .fetch_all(&mut db)
+                .timed(&self.config, &select_sql)
+                .await?;
+            ids.extend(rows.iter().map(|row| (row.get::<String, _>(0), row.get::<i64, _>(1))));
+        }
+        ids.sort();
+
+        let mut values = vec![];
+        for (root_table, id) in ids {
+            let tag = &tags[&root_table];
+            let value = fetch_struct(&self.config, tag, id, &resolver, &mut db)
+                .await?
+                .ok_or_else(|| anyhow!("{} at {} is missing its struct row", tag, address))?;
+            let fat_type = resolver.resolve_struct(tag).await?;
+            values.push((value, fat_type));
+        }
+
+        let annotator = MoveValueAnnotator::new(resolver);
+        let mut resources = vec![];
+        for (value, fat_type) in values {
+            let struct_ = match value {
+                MoveValue::Struct(s) => s,
+                _ => unreachable!(),
             };
-            elements.push(element);
+            resources.push(annotator.annotate_struct(&struct_, &fat_type).await?);
         }
-        elements
-    })
-}
+        Ok(resources)
+    }
 
-fn hit_created_cache(name: &String) -> bool {
-    CREATED_CACHE.with(|cache| {
-        let exists = cache.borrow().contains(name);
-        if !exists {
-            cache.borrow_mut().insert(name.clone());
+    /// Every resource of type `tag` whose top-level fields pass all of
+    /// `filters`, with the address it is stored at, ordered by address. Only
+    /// bool, u8, u64, u128, and address fields can be filtered on, with a
+    /// value of the same type, and the values are bound as parameters rather
+    /// than written into the SQL. With history, only the latest version of
+    /// each resource is considered.
+    pub async fn query_resources(
+        &self,
+        tag: &StructTag,
+        filters: &[(&str, FilterOp, MoveValue)],
+    ) -> Result<Vec<(AccountAddress, AnnotatedMoveStruct)>> {
+        let annotator = MoveValueAnnotator::new(self.resolver());
+        let fat_type = annotator.resolver().resolve_struct(tag).await?;
+        let root_table = root_table_name(&self.config, tag);
+
+        let mut conditions = vec![];
+        let mut values = vec![];
+        for (field, op, value) in filters {
+            let field_type = fat_type
+                .fields
+                .iter()
+                .find(|(name, _)| name.as_str() == *field)
+                .map(|(_, ty)| ty)
+                .ok_or_else(|| anyhow!("{} has no field {}", tag, field))?;
+            let (lhs, binds) = filter_operands(&self.config, field, field_type, value)?;
+            let placeholders = vec!["?"; binds.len()].join(", ");
+            let rhs = if binds.len() > 1 { format!("({})", placeholders) } else { placeholders };
+            conditions.push(format!("{} {} {}", lhs, op.sql(), rhs));
+            values.extend(binds);
         }
-        exists
-    })
+        if self.config.history {
+            conditions.push(format!(
+                "r.last_modified_version = (SELECT MAX(h.last_modified_version) FROM {} AS h WHERE h.address = r.address)",
+                root_table,
+            ));
+        }
+        let select_sql = format!(
+            "SELECT r.address, r.id FROM {} AS r JOIN {} AS s ON s.__id = r.id WHERE {} ORDER BY r.address",
+            root_table,
+            struct_tag_to_sql(&self.config, tag),
+            if conditions.is_empty() { "1".to_string() } else { conditions.join(" AND ") },
+        );
+        let mut query = sqlx::query(&select_sql);
+        for value in values {
+            query = match value {
+                FilterValue::Integer(v) => query.bind(v),
+                FilterValue::Text(v) => query.bind(v),
+                FilterValue::Blob(v) => query.bind(v),
+            };
+        }
+        let mut db = self.pool.acquire().await?;
+        let rows = match query.fetch_all(&mut db).timed(&self.config, &select_sql).await {
+            Ok(rows) => rows,
+            // a type that was never stored has no table
+            Err(e) if is_missing_table(&e) => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut resources = vec![];
+        for row in rows {
+            let address = address_from_bytes(&row.get::<Vec<u8>, _>(0))?;
+            let id = row.get::<i64, _>(1);
+            let struct_ = match fetch_struct(&self.config, tag, id, annotator.resolver(), &mut db).await? {
+                Some(MoveValue::Struct(s)) => s,
+                _ => return Err(anyhow!("{} at {} is missing its struct row", tag, address)),
+            };
+            resources.push((address, annotator.annotate_struct(&struct_, &fat_type).await?));
+        }
+        Ok(resources)
+    }
+
+    /// Select just the columns of one field, following sub-struct references
+    /// along the way, after checking the field has the expected type. Returns
+    /// None if there is no such resource at `address`.
+    async fn read_field(
+        &self,
+        address: &AccountAddress,
+        tag: &StructTag,
+        field: &str,
+        expected: &FatType,
+    ) -> Result<Option<SqliteRow>> {
+        let resolver = self.resolver();
+        let mut struct_ = resolver.resolve_struct(tag).await?;
+        let mut db = self.pool.acquire().await?;
+
+        let select_sql = if self.config.history {
+            format!(
+                "SELECT id FROM {} WHERE address = ? ORDER BY last_modified_version DESC LIMIT 1",
+                root_table_name(&self.config, tag),
+            )
+        } else {
+            format!("SELECT id FROM {} WHERE address = ?", root_table_name(&self.config, tag))
+        };
+        let row = absent_if_missing_table(
+            sqlx::query(&select_sql)
+                .bind(address.as_ref())
+                .fetch_optional(&mut db)
+                .timed(&self.config, &select_sql)
+                .await,
+        )?;
+        let mut id: i64 = match row {
+            None => return Ok(None),
+            Some(row) => row.get(0),
+        };
+
+        let path = field.split('.').collect::<Vec<_>>();
+        for (i, name) in path.iter().enumerate() {
+            let struct_tag = struct_
+                .struct_tag()
+                .map_err(|e| anyhow!("{}", e.finish(Location::Undefined).into_vm_status()))?;
+            let field_type = struct_
+                .fields
+                .iter()
+                .find(|(field_name, _)| field_name.as_str() == *name)
+                .map(|(_, field_type)| field_type.clone())
+                .ok_or_else(|| anyhow!("{} has no field {}", struct_tag, name))?;
+            let table_name = struct_tag_to_sql(&self.config, &struct_tag);
+
+            if i + 1 < path.len() {
+                let sub_struct = match field_type {
+                    FatType::Struct(sub_struct) => sub_struct,
+                    _ => return Err(anyhow!("field {} of {} is not a struct", name, struct_tag)),
+                };
+                let sub_tag = sub_struct
+                    .struct_tag()
+                    .map_err(|e| anyhow!("{}", e.finish(Location::Undefined).into_vm_status()))?;
+                if scalar_option_type(&sub_tag).is_some() {
+                    return Err(anyhow!("field {} of {} is an Option stored inline", name, struct_tag));
+                }
+                if is_json_struct(&self.config, &sub_struct) {
+                    return Err(anyhow!("field {} of {} is stored inline as JSON", name, struct_tag));
+                }
+                let select_sql = format!("SELECT {} FROM {} WHERE __id = ?", quote(name), table_name);
+                id = sqlx::query(&select_sql)
+                    .bind(id)
+                    .fetch_one(&mut db)
+                    .timed(&self.config, &select_sql)
+                    .await?
+                    .get(0);
+                struct_ = *sub_struct;
+                continue;
+            }
+
+            if mem::discriminant(&field_type) != mem::discriminant(expected) {
+                return Err(anyhow!("field {} of {} is a {:?}, not a {:?}", name, struct_tag, field_type, expected));
+            }
+            let type_tag = field_type
+                .type_tag()
+                .map_err(|e| anyhow!("{}", e.finish(Location::Undefined).into_vm_status()))?;
+            let columns = column_names(scalar_columns(&self.config, name, &type_tag))
+                .iter()
+                .map(|column| quote(column))
+                .collect::<Vec<_>>();
+            let select_sql = format!("SELECT {} FROM {} WHERE __id = ?", columns.join(", "), table_name);
+            let row = sqlx::query(&select_sql)
+                .bind(id)
+                .fetch_one(&mut db)
+                .timed(&self.config, &select_sql)
+                .await?;
+            return Ok(Some(row));
+        }
+        unreachable!("split always yields at least one field name")
+    }
+
+    /// Forget everything written at or after `version`, so those versions
+    /// can be indexed again. Only history keeps enough to do this: the root
+    /// rows of those versions are dropped, uncovering the earlier ones,
+    /// along with the struct rows they pointed to. Modules stay as last
+    /// published, since `__module` keeps only the latest bytes of each: one
+    /// upgraded in the versions rolled back decodes the earlier resources
+    /// with its new layout until those versions are replayed.
+    pub async fn rollback(&self, version: u64) -> Result<()> {
+        if !self.config.history {
+            return Err(anyhow!("rolling back requires history"));
+        }
+        self.flush().await?;
+        let stored_types = self.stored_types().await?;
+        let mut db = self.pool.acquire().await?;
+
+        // with history every version of a resource is its own tree of rows,
+        // so the rows below a dropped root row are reachable from no other
+        let annotator = MoveValueAnnotator::new(self.resolver());
+        for (root_table, tag) in &stored_types {
+            let select_sql = format!("SELECT id FROM {} WHERE last_modified_version >= ?", root_table);
+            let ids = sqlx::query(&select_sql)
+                .bind(version as i64)
+                .fetch_all(&mut db)
+                .timed(&self.config, &select_sql)
+                .await?
+                .iter()
+                .map(|row| row.get::<i64, _>(0))
+                .collect::<Vec<_>>();
+            for id in ids {
+                let struct_ = match fetch_struct(&self.config, tag, id, annotator.resolver(), &mut db).await? {
+                    Some(MoveValue::Struct(s)) => s,
+                    Some(_) => unreachable!(),
+                    // the struct rows are already gone, or the type no
+                    // longer resolves
+                    None => continue,
+                };
+                let fat_type = annotator.resolver().resolve_struct(tag).await?;
+                let struct_ = annotator.annotate_struct(&struct_, &fat_type).await?;
+                delete_struct_rows(&self.config, &struct_, id, &mut db).await?;
+            }
+        }
+
+        let select_sql = "SELECT name FROM sqlite_master WHERE type = 'table'";
+        let tables = sqlx::query(select_sql)
+            .fetch_all(&mut db)
+            .timed(&self.config, select_sql)
+            .await?
+            .iter()
+            .map(|row| quote(&row.get::<String, _>(0)))
+            .collect::<Vec<_>>();
+
+        // rows are deleted, so the latest id of any address may change
+        self.root_ids.clear();
+
+        let root_prefix = format!("{}__root__", self.config.table_prefix);
+        let unindexed_table = self.config.unindexed_resources_table_name();
+        let versioned_tables = [
+            self.config.events_table_name(),
+            self.config.skipped_writes_table_name(),
+            self.config.unknown_paths_table_name(),
+            self.config.validators_table_name(),
+            self.config.partial_resources_table_name(),
+            self.config.transactions_table_name(),
+            self.config.skipped_transactions_table_name(),
+        ];
+        for table in tables {
+            let delete_sql = if unquote(&table).starts_with(&root_prefix) || table == unindexed_table {
+                format!("DELETE FROM {} WHERE last_modified_version >= ?", table)
+            } else if versioned_tables.contains(&table) {
+                format!("DELETE FROM {} WHERE version >= ?", table)
+            } else {
+                continue;
+            };
+            sqlx::query(&delete_sql)
+                .bind(version as i64)
+                .execute(&mut db)
+                .timed(&self.config, &delete_sql)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the database file without its free pages.
+    pub async fn vacuum(&self) {
+        let mut db = self.pool.acquire().await.unwrap();
+        sqlx::query("VACUUM").execute(&mut db).timed(&self.config, "VACUUM").await.unwrap();
+    }
+
+    /// Whether the file was created with `auto_vacuum = INCREMENTAL`, the
+    /// only mode in which `incremental_vacuum` frees anything.
+    pub async fn is_incremental_vacuum(&self) -> bool {
+        let mut db = self.pool.acquire().await.unwrap();
+        let row = sqlx::query("PRAGMA auto_vacuum").fetch_one(&mut db).timed(&self.config, "PRAGMA auto_vacuum").await.unwrap();
+        row.get::<i64, _>(0) == 2
+    }
+
+    /// Truncate up to `pages` free pages off the end of the file. Unlike
+    /// `vacuum`, this takes time proportional to the pages freed rather than
+    /// to the size of the database.
+    pub async fn incremental_vacuum(&self, pages: u32) {
+        let mut db = self.pool.acquire().await.unwrap();
+        let vacuum_sql = format!("PRAGMA incremental_vacuum({})", pages);
+        sqlx::query(&vacuum_sql).execute(&mut db).timed(&self.config, &vacuum_sql).await.unwrap();
+    }
+
+    /// Apply one write of the transaction at `version`, then pass it to the
+    /// observers, whether or not it was stored. With writes buffered, it is
+    /// only applied, and observed, when the buffer is flushed, and not at all
+    /// if a later write to the same path replaces it first.
+    pub async fn execute_with_annotator(
+        &self,
+        version: u64,
+        access_path: &AccessPath,
+        op: &WriteOp,
+        annotator: &MoveValueAnnotator,
+    ) -> Result<()> {
+        let time = *self.block_timestamp.lock().unwrap();
+        if let Some(buffer) = &mut *self.write_buffer.lock().unwrap() {
+            // with history every version of a resource is stored
+            if !self.config.history {
+                if let Some(earlier) = buffer.latest.get(access_path) {
+                    buffer.writes[*earlier] = None;
+                }
+            }
+            buffer.latest.insert(access_path.clone(), buffer.writes.len());
+            buffer.writes.push(Some((version, time, access_path.clone(), op.clone())));
+            return Ok(());
+        }
+        self.apply(version, access_path, op, annotator).await
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, access_path, op, annotator), fields(address = %access_path.address))]
+    async fn apply(&self, version: u64, access_path: &AccessPath, op: &WriteOp, annotator: &MoveValueAnnotator) -> Result<()> {
+        let (address, path) = match util::decode_access_path(access_path) {
+            Ok(decoded) => decoded,
+            Err(e) => return self.record_unknown_path(version, access_path, op, &e.to_string()).await,
+        };
+        let kind = match (&path, op) {
+            // deletions are only recorded as skipped, so they did nothing
+            (Path::Code(id), WriteOp::Deletion) => {
+                self.unpublish(version, access_path, id).await?;
+                None
+            },
+            (Path::Code(id), WriteOp::Value(v)) => {
+                self.publish(id, v).await?;
+                Some(WriteKind::PublishModule)
+            },
+            // modules are always published so that every type can be resolved
+            (Path::Resource(tag), _) if !self.config.is_indexed(tag) => {
+                self.store_unindexed(version, &address, tag, op).await?;
+                None
+            },
+            (Path::Resource(tag), WriteOp::Deletion) => {
+                self.delete(version, access_path, tag).await?;
+                None
+            },
+            (Path::Resource(tag), WriteOp::Value(v)) => {
+                // a type may legitimately fail to resolve, e.g. in a partial
+                // snapshot, and shouldn't abort the whole write set
+                match annotator.view_resource(tag, v).await {
+                    Ok(resource) => Some(self.store(&address, tag, resource, v, version).await?),
+                    Err(e) if e.is::<MissingStructDef>() => {
+                        self.skip(version, access_path, &format!("struct def removed: {}", e)).await?;
+                        None
+                    },
+                    Err(e) if self.config.best_effort_resolution => {
+                        match annotator.view_resource_partial(tag, v).await {
+                            Ok(partial) => self.store_partial(version, &address, &partial).await?,
+                            Err(_) => self.skip(version, access_path, &format!("{}: {}", tag, e)).await?,
+                        }
+                        None
+                    },
+                    Err(e) => {
+                        self.skip(version, access_path, &format!("{}: {}", tag, e)).await?;
+                        None
+                    },
+                }
+            },
+        };
+        for observer in &self.observers {
+            observer.on_write(version, &address, &path, op, kind);
+        }
+        Ok(())
+    }
+
+    /// Record a write that can't be applied yet, so that the replay can
+    /// continue and the write can be processed later.
+    async fn skip(&self, version: u64, access_path: &AccessPath, reason: &str) -> Result<()> {
+        if self.config.strict {
+            return Err(strict_error(version, access_path, reason));
+        }
+        tracing::warn!(version, address = %access_path.address, "skipping write: {}", reason);
+        let mut db = self.pool.acquire().await?;
+
+        let table_name = self.config.skipped_writes_table_name();
+        let columns = [
+            "version INTEGER NOT NULL".to_string(),
+            "address BLOB NOT NULL".to_string(),
+            "path BLOB NOT NULL".to_string(),
+            "reason STRING NOT NULL".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &table_name, &columns, &mut db).await?;
+
+        let insert_sql = format!(
+            "INSERT INTO {} (version, address, path, reason) VALUES (?, ?, ?, ?)",
+            table_name,
+        );
+        sqlx::query(&insert_sql)
+            .bind(version as i64)
+            .bind(access_path.address.as_ref())
+            .bind(&access_path.path)
+            .bind(reason)
+            .execute(&mut db)
+            .timed(&self.config, &insert_sql)
+            .await?;
+        Ok(())
+    }
+
+    /// Record a transaction whose execution failed and was skipped under
+    /// `--on-error skip`. None of its writes are applied, so later state may
+    /// differ from the chain's.
+    pub async fn skip_transaction(&self, version: u64, reason: &str) -> Result<()> {
+        tracing::warn!(version, "skipping transaction: {}", reason);
+        let mut db = self.pool.acquire().await?;
+
+        let table_name = self.config.skipped_transactions_table_name();
+        let columns = [
+            "version INTEGER NOT NULL".to_string(),
+            "reason STRING NOT NULL".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &table_name, &columns, &mut db).await?;
+
+        let insert_sql = format!("INSERT INTO {} (version, reason) VALUES (?, ?)", table_name);
+        sqlx::query(&insert_sql)
+            .bind(version as i64)
+            .bind(reason)
+            .execute(&mut db)
+            .timed(&self.config, &insert_sql)
+            .await?;
+        Ok(())
+    }
+
+    /// Skip a write to a path that can't be decoded, keeping its raw bytes
+    /// so it can be inspected later. Deletions have no value.
+    async fn record_unknown_path(&self, version: u64, access_path: &AccessPath, op: &WriteOp, reason: &str) -> Result<()> {
+        self.skip(version, access_path, reason).await?;
+        let mut db = self.pool.acquire().await?;
+
+        let table_name = self.config.unknown_paths_table_name();
+        let columns = [
+            "version INTEGER NOT NULL".to_string(),
+            "address BLOB NOT NULL".to_string(),
+            "path BLOB NOT NULL".to_string(),
+            "value BLOB".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &table_name, &columns, &mut db).await?;
+
+        let insert_sql = format!("INSERT INTO {} (version, address, path, value) VALUES (?, ?, ?, ?)", table_name);
+        sqlx::query(&insert_sql)
+            .bind(version as i64)
+            .bind(access_path.address.as_ref())
+            .bind(&access_path.path)
+            .bind(match op {
+                WriteOp::Value(v) => Some(v.as_slice()),
+                WriteOp::Deletion => None,
+            })
+            .execute(&mut db)
+            .timed(&self.config, &insert_sql)
+            .await?;
+        Ok(())
+    }
+
+    /// Keep the decodable fields of a resource whose type only partly
+    /// resolved as a JSON object, with the fields that couldn't be decoded
+    /// set to null and listed with the reason. Without history, only the
+    /// latest partial value of each resource is kept.
+    async fn store_partial(&self, version: u64, address: &AccountAddress, partial: &PartialMoveStruct) -> Result<()> {
+        tracing::warn!(
+            version,
+            %address,
+            resource = %partial.type_,
+            "storing the resource without fields {}",
+            partial.unresolved.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", "),
+        );
+        let mut db = self.pool.acquire().await?;
+
+        let table_name = self.config.partial_resources_table_name();
+        let columns = [
+            "version INTEGER NOT NULL".to_string(),
+            "address BLOB NOT NULL".to_string(),
+            "type_tag STRING NOT NULL".to_string(),
+            "value STRING NOT NULL".to_string(),
+            "unresolved STRING NOT NULL".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &table_name, &columns, &mut db).await?;
+
+        let type_tag = partial.type_.to_string();
+        if !self.config.history {
+            let delete_sql = format!("DELETE FROM {} WHERE address = ? AND type_tag = ?", table_name);
+            sqlx::query(&delete_sql)
+                .bind(address.as_ref())
+                .bind(&type_tag)
+                .execute(&mut db)
+                .timed(&self.config, &delete_sql)
+                .await?;
+        }
+
+        let value = partial.value
+            .iter()
+            .map(|(name, value)| (name.to_string(), serde_json::to_value(value).unwrap()))
+            .collect::<serde_json::Map<_, _>>();
+        let unresolved = partial.unresolved
+            .iter()
+            .map(|(name, reason)| (name.to_string(), serde_json::Value::from(reason.as_str())))
+            .collect::<serde_json::Map<_, _>>();
+        let insert_sql = format!(
+            "INSERT INTO {} (version, address, type_tag, value, unresolved) VALUES (?, ?, ?, ?, ?)",
+            table_name,
+        );
+        sqlx::query(&insert_sql)
+            .bind(version as i64)
+            .bind(address.as_ref())
+            .bind(&type_tag)
+            .bind(serde_json::Value::Object(value).to_string())
+            .bind(serde_json::Value::Object(unresolved).to_string())
+            .execute(&mut db)
+            .timed(&self.config, &insert_sql)
+            .await?;
+        Ok(())
+    }
+
+    async fn unpublish(&self, version: u64, access_path: &AccessPath, id: &ModuleId) -> Result<()> {
+        self.skip(version, access_path, &format!("unpublishing module {} is not supported", id)).await
+    }
+
+    /// Whether the module is in the module table.
+    pub async fn is_published(&self, id: &ModuleId) -> bool {
+        let mut db = self.pool.acquire().await.unwrap();
+        let select_sql = format!(
+            "SELECT 1 FROM {} WHERE address = ? AND name = ?",
+            self.config.module_table_name(),
+        );
+        sqlx::query(&select_sql)
+            .bind(id.address().as_ref())
+            .bind(id.name().as_str())
+            .fetch_optional(&mut db)
+            .timed(&self.config, &select_sql)
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    /// Store a module's bytes. Publishing a module again, either because it
+    /// was upgraded or because an interrupted run is resumed, replaces them.
+    async fn publish(&self, id: &ModuleId, data: &[u8]) -> Result<()> {
+        let address = id.address();
+        let name = id.name().as_str();
+        let create_sql = format!(
+            "INSERT INTO {} VALUES (?, ?, ?) ON CONFLICT(address, name) DO UPDATE SET data = excluded.data",
+            self.config.module_table_name(),
+        );
+        let mut db = self.pool.acquire().await?;
+        sqlx::query(&create_sql)
+            .bind(address.as_ref())
+            .bind(name)
+            .bind(data)
+            .execute(&mut db)
+            .timed(&self.config, &create_sql)
+            .await?;
+        self.modules.invalidate(id);
+        self.store_module_info(id, data, &mut db).await
+    }
+
+    /// Record the bytecode version, immediate dependencies, and struct
+    /// abilities of a published module, replacing those of any earlier
+    /// publication of it.
+    async fn store_module_info(&self, id: &ModuleId, data: &[u8], db: &mut PoolConnection<Sqlite>) -> Result<()> {
+        let module_id = id.to_string();
+        let module = match CompiledModule::deserialize(data) {
+            Ok(module) => module,
+            Err(e) => {
+                tracing::warn!(module = %id, "not recording the dependencies of the module: {}", e);
+                return Ok(());
+            },
+        };
+
+        let versions_table = self.config.module_versions_table_name();
+        let columns = [
+            "module_id STRING NOT NULL PRIMARY KEY".to_string(),
+            "bytecode_version INTEGER NOT NULL".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &versions_table, &columns, db).await?;
+        let insert_sql = format!(
+            "INSERT INTO {} (module_id, bytecode_version) VALUES (?, ?) ON CONFLICT(module_id) DO UPDATE SET bytecode_version = excluded.bytecode_version",
+            versions_table,
+        );
+        sqlx::query(&insert_sql)
+            .bind(&module_id)
+            .bind(bytecode_version(data) as i64)
+            .execute(&mut *db)
+            .timed(&self.config, &insert_sql)
+            .await?;
+
+        let deps_table = self.config.module_deps_table_name();
+        let columns = [
+            "module_id STRING NOT NULL".to_string(),
+            "depends_on_module_id STRING NOT NULL".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &deps_table, &columns, db).await?;
+        let delete_sql = format!("DELETE FROM {} WHERE module_id = ?", deps_table);
+        sqlx::query(&delete_sql)
+            .bind(&module_id)
+            .execute(&mut *db)
+            .timed(&self.config, &delete_sql)
+            .await?;
+        let insert_sql = format!("INSERT INTO {} (module_id, depends_on_module_id) VALUES (?, ?)", deps_table);
+        for dependency in module.immediate_dependencies() {
+            sqlx::query(&insert_sql)
+                .bind(&module_id)
+                .bind(dependency.to_string())
+                .execute(&mut *db)
+                .timed(&self.config, &insert_sql)
+                .await?;
+        }
+
+        // abilities are stored comma separated, e.g. `key,store`, so that
+        // `',' || abilities || ',' LIKE '%,key,%'` finds the types with `key`.
+        // they are those of the definition: a type parameter constrained to
+        // resources makes every instantiation one, while those of any kind
+        // only do when instantiated with one
+        let meta_table = self.config.struct_meta_table_name();
+        let columns = [
+            "module_id STRING NOT NULL".to_string(),
+            "name STRING NOT NULL".to_string(),
+            "is_resource BOOLEAN NOT NULL".to_string(),
+            "abilities STRING NOT NULL".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &meta_table, &columns, db).await?;
+        let delete_sql = format!("DELETE FROM {} WHERE module_id = ?", meta_table);
+        sqlx::query(&delete_sql)
+            .bind(&module_id)
+            .execute(&mut *db)
+            .timed(&self.config, &delete_sql)
+            .await?;
+        let insert_sql = format!("INSERT INTO {} (module_id, name, is_resource, abilities) VALUES (?, ?, ?, ?)", meta_table);
+        for struct_def in module.struct_defs() {
+            let handle = module.struct_handle_at(struct_def.struct_handle);
+            let has_resource_params = handle.type_parameters.iter().any(|kind| matches!(kind, Kind::Resource));
+            sqlx::query(&insert_sql)
+                .bind(&module_id)
+                .bind(module.identifier_at(handle.name).as_str())
+                .bind(handle.is_nominal_resource)
+                .bind(fat_type::abilities(handle.is_nominal_resource, has_resource_params).join(","))
+                .execute(&mut *db)
+                .timed(&self.config, &insert_sql)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Keep the bytes of a resource whose type isn't indexed, so that
+    /// transactions reading it can still be replayed. Without history only
+    /// the latest value is kept; with it, a deletion is a row without one.
+    async fn store_unindexed(&self, version: u64, address: &AccountAddress, tag: &StructTag, op: &WriteOp) -> Result<()> {
+        let mut db = self.pool.acquire().await?;
+        let table_name = self.config.unindexed_resources_table_name();
+        let columns = [
+            "address BLOB NOT NULL".to_string(),
+            "struct_tag STRING NOT NULL".to_string(),
+            "last_modified_version INTEGER NOT NULL".to_string(),
+            "value BLOB".to_string(),
+        ];
+        ensure_table(&self.config, &self.tables, &table_name, &columns, &mut db).await?;
+
+        let value = match op {
+            WriteOp::Value(v) => Some(v.as_slice()),
+            WriteOp::Deletion => None,
+        };
+        if !self.config.history {
+            let delete_sql = format!("DELETE FROM {} WHERE address = ? AND struct_tag = ?", table_name);
+            sqlx::query(&delete_sql)
+                .bind(address.as_ref())
+                .bind(tag.to_string())
+                .execute(&mut db)
+                .timed(&self.config, &delete_sql)
+                .await?;
+            if value.is_none() {
+                return Ok(());
+            }
+        }
+        let insert_sql = format!(
+            "INSERT INTO {} (address, struct_tag, last_modified_version, value) VALUES (?, ?, ?, ?)",
+            table_name,
+        );
+        sqlx::query(&insert_sql)
+            .bind(address.as_ref())
+            .bind(tag.to_string())
+            .bind(version as i64)
+            .bind(value)
+            .execute(&mut db)
+            .timed(&self.config, &insert_sql)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, version: u64, access_path: &AccessPath, tag: &StructTag) -> Result<()> {
+        self.skip(version, access_path, &format!("deleting {} is not supported", tag)).await
+    }
+
+    /// Store a resource value, reporting whether the account already had
+    /// one of this type.
+    async fn store(&self, address: &AccountAddress, tag: &StructTag, data: AnnotatedMoveStruct, raw: &[u8], version: u64) -> Result<WriteKind> {
+        if self.config.flatten_validators && is_validator_set(tag) {
+            self.store_validators(&data, version).await?;
+        }
+
+        let time = *self.block_timestamp.lock().unwrap();
+        let mut db = self.pool.acquire().await?;
+
+        // see if global object already exists
+        let root_table = root_table_name(&self.config, tag);
+        let existing = match self.root_ids.get(&root_table, address) {
+            Some(id) => id,
+            None => {
+                let select_sql = format!(
+                    "SELECT id FROM {} WHERE address = ?",
+                    root_table,
+                );
+                absent_if_missing_table(
+                    sqlx::query(&select_sql)
+                        .bind(address.as_ref())
+                        .fetch_optional(&mut db)
+                        .timed(&self.config, &select_sql)
+                        .await,
+                )?
+                .map(|row| row.get::<i64, _>(0))
+            },
+        };
+        let kind = match existing {
+            None => {
+                let id = generate_sql(&self.config, &self.tables, &address, &data, raw, version, time, &mut db).await?;
+                self.root_ids.insert(&root_table, *address, Some(id));
+                WriteKind::CreateResource
+            },
+            // with history, every version is a new tree of rows
+            Some(_) if self.config.history => {
+                let id = generate_sql(&self.config, &self.tables, &address, &data, raw, version, time, &mut db).await?;
+                self.root_ids.insert(&root_table, *address, Some(id));
+                WriteKind::UpdateResource
+            },
+            Some(id) => {
+                self.root_ids.insert(&root_table, *address, Some(id));
+                let resolver = self.resolver();
+                match fetch_struct(&self.config, tag, id, &resolver, &mut db).await? {
+                    Some(MoveValue::Struct(old_struct)) => {
+                        let fat_type = resolver.resolve_struct(tag).await?;
+                        let annotator = MoveValueAnnotator::new(resolver);
+                        let old_struct = annotator.annotate_struct(&old_struct, &fat_type).await?;
+                        generate_diff_sql(&self.config, &self.tables, &old_struct, &data, id, &mut db).await?;
+
+                        let update_sql = format!(
+                            "UPDATE {} SET last_modified_version = ?{}{} WHERE address = ?",
+                            root_table,
+                            if self.config.keep_raw { ", raw = ?" } else { "" },
+                            if self.config.resource_timestamps { ", last_modified_time = ?" } else { "" },
+                        );
+                        let mut query = sqlx::query(&update_sql).bind(version as i64);
+                        if self.config.keep_raw {
+                            query = query.bind(raw);
+                        }
+                        if self.config.resource_timestamps {
+                            query = query.bind(time.map(|usecs| usecs as i64));
+                        }
+                        query
+                            .bind(address.as_ref())
+                            .execute(&mut db)
+                            .timed(&self.config, &update_sql)
+                            .await?;
+                    },
+                    Some(_) => unreachable!(),
+                    // the root row outlived its struct rows, so there is
+                    // nothing to diff against; write the value afresh
+                    None => {
+                        let id = self.rewrite(address, &root_table, &data, raw, version, time, &mut db).await?;
+                        self.root_ids.insert(&root_table, *address, Some(id));
+                    },
+                }
+                WriteKind::UpdateResource
+            },
+        };
+
+        for observer in &self.observers {
+            observer.on_store(version, address, &data);
+        }
+        Ok(kind)
+    }
+
+    /// Replace a resource's root row with a fresh tree of rows, returning
+    /// the id of the new struct row. The old root row is only deleted if the
+    /// new one is written.
+    #[allow(clippy::too_many_arguments)]
+    async fn rewrite(
+        &self,
+        address: &AccountAddress,
+        root_table: &str,
+        data: &AnnotatedMoveStruct,
+        raw: &[u8],
+        version: u64,
+        time: Option<u64>,
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<i64> {
+        sqlx::query("SAVEPOINT rewrite").execute(&mut *db).timed(&self.config, "SAVEPOINT rewrite").await?;
+        let written: Result<i64> = async {
+            let delete_sql = format!("DELETE FROM {} WHERE address = ?", root_table);
+            sqlx::query(&delete_sql)
+                .bind(address.as_ref())
+                .execute(&mut *db)
+                .timed(&self.config, &delete_sql)
+                .await?;
+            generate_sql(&self.config, &self.tables, address, data, raw, version, time, db).await
+        }.await;
+        if written.is_err() {
+            let rollback_sql = "ROLLBACK TO rewrite";
+            sqlx::query(rollback_sql).execute(&mut *db).timed(&self.config, rollback_sql).await?;
+        }
+        sqlx::query("RELEASE rewrite").execute(&mut *db).timed(&self.config, "RELEASE rewrite").await?;
+        written
+    }
+
+    /// Replace the rows of `__validators` with the set in a newly written
+    /// `DiemConfig<DiemSystem>`. With history, earlier sets are kept and are
+    /// told apart by `version`.
+    async fn store_validators(&self, config: &AnnotatedMoveStruct, version: u64) -> Result<()> {
+        let validators = match validator_set(config) {
+            Some(validators) => validators,
+            None => {
+                tracing::warn!(version, "the validator set has an unexpected layout; not flattening it");
+                return Ok(());
+            },
+        };
+        let mut db = self.pool.acquire().await?;
+
+        let table_name = self.config.validators_table_name();
+        let u64_type = self.config.numeric_encoding.u64_column_type();
+        let columns = [
+            "version INTEGER NOT NULL".to_string(),
+            "address BLOB NOT NULL".to_string(),
+            "consensus_pubkey BLOB NOT NULL".to_string(),
+            format!("consensus_voting_power {} NOT NULL", u64_type),
+            "validator_network_addresses BLOB NOT NULL".to_string(),
+            "fullnode_network_addresses BLOB NOT NULL".to_string(),
+            format!("last_config_update_time {} NOT NULL", u64_type),
+        ];
+        ensure_table(&self.config, &self.tables, &table_name, &columns, &mut db).await?;
+
+        if !self.config.history {
+            let delete_sql = format!("DELETE FROM {}", table_name);
+            sqlx::query(&delete_sql).execute(&mut db).timed(&self.config, &delete_sql).await?;
+        }
+        for validator in validators {
+            let insert_sql = format!(
+                "INSERT INTO {} (version, address, consensus_pubkey, consensus_voting_power, validator_network_addresses, fullnode_network_addresses, last_config_update_time) VALUES (?, ?, ?, {}, ?, ?, {})",
+                table_name,
+                self.config.numeric_encoding.u64_to_sql(validator.consensus_voting_power),
+                self.config.numeric_encoding.u64_to_sql(validator.last_config_update_time),
+            );
+            sqlx::query(&insert_sql)
+                .bind(version as i64)
+                .bind(validator.address.as_ref())
+                .bind(validator.consensus_pubkey)
+                .bind(validator.validator_network_addresses)
+                .bind(validator.fullnode_network_addresses)
+                .execute(&mut db)
+                .timed(&self.config, &insert_sql)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+pub fn generate_diff_sql<'a>(
+    config: &'a Config,
+    tables: &'a CreatedTables,
+    old_value: &'a  AnnotatedMoveStruct,
+    value: &'a AnnotatedMoveStruct,
+    id: i64,
+    db: &'a mut PoolConnection<Sqlite>
+) -> Pin<Box<dyn Future<Output=Result<()>> + 'a>>
+{
+    Box::pin(async move {
+        assert_eq!(old_value.type_, value.type_, "struct types must match");
+
+        let changed_fields = old_value
+            .value
+            .iter()
+            .zip(value.value.iter())
+            .filter_map(|((name, ov), (_, nv))| {
+                if ov == nv {
+                    None
+                } else {
+                    Some((name, ov, nv))
+                }
+            })
+            .collect::<Vec<_>>();
+        if changed_fields.is_empty() {
+            return Ok(());
+        }
+
+        let sql_tag = struct_tag_to_sql(config, &value.type_);
+        let mut updated = vec![];
+        for (field_name, old_field_value, field_value) in changed_fields {
+            match field_value {
+                AnnotatedMoveValue::U8(v) => {
+                    updated.push(format!("{} = {}", quote(field_name.as_str()), v));
+                },
+                AnnotatedMoveValue::U64(v) => {
+                    updated.push(format!("{} = {}", quote(field_name.as_str()), config.numeric_encoding.u64_to_sql(*v)));
+                },
+                AnnotatedMoveValue::U128(v) => {
+                    let columns = config.numeric_encoding.u128_columns(field_name.as_str());
+                    let values = config.numeric_encoding.u128_to_sql(*v);
+                    for ((column, _), value) in columns.into_iter().zip(values) {
+                        updated.push(format!("{} = {}", quote(&column), value));
+                    }
+                },
+                AnnotatedMoveValue::Bool(v) => {
+                    updated.push(format!("{} = {}", quote(field_name.as_str()), u8::from(*v)));
+                },
+                AnnotatedMoveValue::Address(v) => {
+                    updated.push(format!("{} = x'{}'", quote(field_name.as_str()), hex::encode(v)));
+                },
+                AnnotatedMoveValue::Bytes(v) if is_auth_key_field(config, field_name.as_str()) => {
+                    let key = auth_key_to_sql(&value.type_, field_name.as_str(), v)?;
+                    updated.push(format!("{} = {}", quote(field_name.as_str()), key));
+                },
+                AnnotatedMoveValue::Bytes(v) => {
+                    updated.push(format!("{} = x'{}'", quote(field_name.as_str()), hex::encode(v)));
+                },
+                AnnotatedMoveValue::Vector(ty, v) if is_inline_vector_tag(ty) => {
+                    let bytes = vector_to_bytes(v).map_err(|e| anyhow!("{} field {}: {}", sql_tag, field_name, e))?;
+                    updated.push(format!("{} = x'{}'", quote(field_name.as_str()), hex::encode(&bytes)));
+                },
+                AnnotatedMoveValue::Vector(ty, v) => {
+                    // delete old entries, and whatever they refer to
+                    let name = vector_table_name(config, &value.type_, field_name);
+                    let old_elements = match old_field_value {
+                        AnnotatedMoveValue::Vector(_, o) => o,
+                        other => {
+                            return Err(anyhow!("{} field {} was {:?} but is now a vector", sql_tag, field_name, other));
+                        },
+                    };
+                    delete_vector_rows(config, name.clone(), id, old_elements, &mut *db).await?;
+
+                    // populate new entries
+                    vector_to_sql(config, tables, name, id, &ty, &v, &mut *db).await?;
+                },
+                AnnotatedMoveValue::Struct(v) if scalar_option_type(&v.type_).is_some() => {
+                    let elem_ty = scalar_option_type(&v.type_).unwrap();
+                    let columns = scalar_columns(config, field_name.as_str(), elem_ty);
+                    for ((column, _), value) in columns.into_iter().zip(option_to_sql(config, v)) {
+                        updated.push(format!("{} = {}", quote(&column), value));
+                    }
+                },
+                AnnotatedMoveValue::Struct(v) if stores_as_json(config, v) => {
+                    updated.push(format!("{} = {}", quote(field_name.as_str()), struct_to_json_sql(v)));
+                },
+                AnnotatedMoveValue::Struct(v) => {
+                    // this will generate no changes here, but will recursively update the struct
+                    let ov = match old_field_value {
+                        AnnotatedMoveValue::Struct(o) => o,
+                        other => {
+                            return Err(anyhow!("{} field {} was {:?} but is now a struct", sql_tag, field_name, other));
+                        },
+                    };
+
+                    let select_sql = format!(
+                        "SELECT {} FROM {} WHERE __id = ?",
+                        quote(field_name.as_str()),
+                        sql_tag,
+                    );
+                    let sub_id = sqlx::query(&select_sql)
+                        .bind(id)
+                        .fetch_one(&mut *db)
+                        .timed(config, &select_sql)
+                        .await?
+                        .get(0);
+                    
+                    generate_diff_sql(config, tables, &ov, &v, sub_id, &mut *db).await?;
+                },
+            }
+        }
+
+        if !updated.is_empty() {
+            let update_sql = format!(
+                "UPDATE {} SET {} WHERE __id = ?",
+                sql_tag,
+                updated.join(", "),
+            );
+            sqlx::query(&update_sql)
+                .bind(id)
+                .execute(&mut *db)
+                .timed(config, &update_sql)
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+/// Delete the rows `vector_to_sql` wrote for the elements `old` under `pid`,
+/// along with the rows of nested vectors and of struct elements, so that
+/// nothing is left behind unreferenced.
+fn delete_vector_rows<'a>(
+    config: &'a Config,
+    name: String,
+    pid: i64,
+    old: &'a [AnnotatedMoveValue],
+    db: &'a mut PoolConnection<Sqlite>,
+) -> Pin<Box<dyn Future<Output=Result<()>> + 'a>> {
+    Box::pin(async move {
+        // struct elements point at their rows from the slot, while nested
+        // vectors not stored inline are the parent of their own elements
+        let column = match old.first() {
+            Some(AnnotatedMoveValue::Struct(_)) => Some("slot"),
+            Some(AnnotatedMoveValue::Vector(ty, _)) if !is_inline_vector_tag(ty) => Some("id"),
+            _ => None,
+        };
+        if let Some(column) = column {
+            let select_sql = format!("SELECT {} FROM {} WHERE parent_id = {} ORDER BY rowid", column, name, pid);
+            let rows = sqlx::query(&select_sql).fetch_all(&mut *db).timed(config, &select_sql).await?;
+            for (element, row) in old.iter().zip(rows) {
+                match element {
+                    AnnotatedMoveValue::Struct(s) => delete_struct_rows(config, s, row.get(0), &mut *db).await?,
+                    AnnotatedMoveValue::Vector(_, v) => {
+                        delete_vector_rows(config, elements_table_name(&name), row.get(0), v, &mut *db).await?;
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        let delete_sql = format!("DELETE FROM {} WHERE parent_id = {}", name, pid);
+        sqlx::query(&delete_sql).execute(&mut *db).timed(config, &delete_sql).await?;
+        Ok(())
+    })
+}
+
+/// Delete the row `struct_to_sql` wrote for `struct_` as `id`, and the rows
+/// of its sub-structs and vectors.
+fn delete_struct_rows<'a>(
+    config: &'a Config,
+    struct_: &'a AnnotatedMoveStruct,
+    id: i64,
+    db: &'a mut PoolConnection<Sqlite>,
+) -> Pin<Box<dyn Future<Output=Result<()>> + 'a>> {
+    Box::pin(async move {
+        let table_name = struct_tag_to_sql(config, &struct_.type_);
+        for (ident, val) in &struct_.value {
+            match val {
+                AnnotatedMoveValue::Struct(s) if scalar_option_type(&s.type_).is_none() && !stores_as_json(config, s) => {
+                    let select_sql = format!("SELECT {} FROM {} WHERE __id = {}", quote(ident.as_str()), table_name, id);
+                    let sub_id = sqlx::query(&select_sql).fetch_one(&mut *db).timed(config, &select_sql).await?.get(0);
+                    delete_struct_rows(config, s, sub_id, &mut *db).await?;
+                },
+                AnnotatedMoveValue::Vector(ty, v) if !is_inline_vector_tag(ty) => {
+                    let name = vector_table_name(config, &struct_.type_, ident);
+                    delete_vector_rows(config, name, id, v, &mut *db).await?;
+                },
+                _ => {},
+            }
+        }
+
+        let delete_sql = format!("DELETE FROM {} WHERE __id = {}", table_name, id);
+        sqlx::query(&delete_sql).execute(&mut *db).timed(config, &delete_sql).await?;
+        Ok(())
+    })
+}
+
+/// Write a resource and its `__root__` row, returning the id of the struct
+/// row the root row points at.
+pub async fn generate_sql(
+    config: &Config,
+    tables: &CreatedTables,
+    address: &AccountAddress,
+    struct_: &AnnotatedMoveStruct,
+    raw: &[u8],
+    version: u64,
+    time: Option<u64>,
+    db: &mut PoolConnection<Sqlite>,
+) -> Result<i64> {
+    // the struct tables and the `__root__` table are created lazily, and
+    // must never exist without each other
+    sqlx::query("SAVEPOINT generate_sql").execute(&mut *db).timed(config, "SAVEPOINT generate_sql").await?;
+    let written: Result<i64> = async {
+        // post order traversal of the struct to write it
+        let id = struct_to_sql(config, tables, struct_, db).await?;
+
+        let table_name = root_table_name(config, &struct_.type_);
+        // attach struct to global storage
+        let mut columns = vec![
+            if config.history {
+                "address BLOB NOT NULL".to_string()
+            } else {
+                "address BLOB UNIQUE NOT NULL".to_string()
+            },
+            "id INTEGER NOT NULL".to_string(),
+            "last_modified_version INTEGER NOT NULL".to_string(),
+        ];
+        let mut names = vec!["address", "id", "last_modified_version"];
+        let mut values = vec![format!("x'{}'", hex::encode(address)), id.to_string(), version.to_string()];
+        if config.keep_raw {
+            columns.push("raw BLOB NOT NULL".to_string());
+            names.push("raw");
+            values.push(format!("x'{}'", hex::encode(raw)));
+        }
+        if config.resource_timestamps {
+            columns.push("last_modified_time INTEGER".to_string());
+            names.push("last_modified_time");
+            values.push(time.map_or("NULL".to_string(), |usecs| usecs.to_string()));
+        }
+        ensure_table(config, tables, &table_name, &columns, db).await?;
+
+        let insert_sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            table_name,
+            names.join(", "),
+            values.join(", "),
+        );
+        sqlx::query(&insert_sql).execute(&mut *db).timed(config, &insert_sql).await?;
+        Ok(id)
+    }.await;
+    // a failed write leaves nothing behind, and the connection usable.
+    // the tables it created are dropped again, and which ones they were
+    // isn't tracked, so every table is checked again on its next use.
+    if written.is_err() {
+        let rollback_sql = "ROLLBACK TO generate_sql";
+        sqlx::query(rollback_sql).execute(&mut *db).timed(config, rollback_sql).await?;
+        tables.forget_all().await;
+    }
+    sqlx::query("RELEASE generate_sql").execute(&mut *db).timed(config, "RELEASE generate_sql").await?;
+    written
+}
+
+fn struct_to_sql<'a>(
+    config: &'a Config,
+    tables: &'a CreatedTables,
+    struct_: &'a AnnotatedMoveStruct,
+    db: &'a mut PoolConnection<Sqlite>,
+) -> Pin<Box<dyn Future<Output=Result<i64>> + 'a>> {
+    Box::pin(async move {
+        // handle fields
+        let mut field_names = vec![];
+        let mut fields = vec![];
+        let mut values = vec![];
+
+        fields.push("__id INTEGER PRIMARY KEY".to_string());
+
+        for (ident, val) in &struct_.value {
+            match val {
+                AnnotatedMoveValue::U8(i) => {
+                    field_names.push(quote(ident.as_str()));
+                    fields.push(format!("{} INTEGER NOT NULL", quote(ident.as_str())));
+                    values.push(format!("{}", i));
+                },
+                AnnotatedMoveValue::U64(i) => {
+                    field_names.push(quote(ident.as_str()));
+                    fields.push(format!("{} {} NOT NULL", quote(ident.as_str()), config.numeric_encoding.u64_column_type()));
+                    values.push(config.numeric_encoding.u64_to_sql(*i));
+                },
+                AnnotatedMoveValue::U128(i) => {
+                    let columns = config.numeric_encoding.u128_columns(ident.as_str());
+                    for ((column, ty), value) in columns.into_iter().zip(config.numeric_encoding.u128_to_sql(*i)) {
+                        fields.push(format!("{} {} NOT NULL", quote(&column), ty));
+                        field_names.push(quote(&column));
+                        values.push(value);
+                    }
+                },
+                AnnotatedMoveValue::Bool(i) => {
+                    // stored as 0 or 1, so that `WHERE flag = 1` matches
+                    let column = quote(ident.as_str());
+                    field_names.push(column.clone());
+                    fields.push(format!("{} BOOLEAN NOT NULL CHECK ({} IN (0, 1))", column, column));
+                    values.push(u8::from(*i).to_string());
+                },
+                AnnotatedMoveValue::Address(i) => {
+                    field_names.push(quote(ident.as_str()));
+                    fields.push(format!("{} BLOB NOT NULL", quote(ident.as_str())));
+                    values.push(format!("x'{}'", hex::encode(i)));
+                },
+                AnnotatedMoveValue::Bytes(i) if is_auth_key_field(config, ident.as_str()) => {
+                    field_names.push(quote(ident.as_str()));
+                    fields.push(format!("{} STRING NOT NULL", quote(ident.as_str())));
+                    values.push(auth_key_to_sql(&struct_.type_, ident.as_str(), i)?);
+                },
+                AnnotatedMoveValue::Bytes(i) => {
+                    field_names.push(quote(ident.as_str()));
+                    fields.push(format!("{} BLOB NOT NULL", quote(ident.as_str())));
+                    values.push(format!("x'{}'", hex::encode(&i)));
+                },
+                AnnotatedMoveValue::Struct(s) => {
+                    if let Some(elem_ty) = scalar_option_type(&s.type_) {
+                        // options of scalars are stored inline as nullable columns
+                        let columns = scalar_columns(config, ident.as_str(), elem_ty);
+                        for ((column, ty), value) in columns.into_iter().zip(option_to_sql(config, s)) {
+                            fields.push(format!("{} {}", quote(&column), ty));
+                            field_names.push(quote(&column));
+                            values.push(value);
+                        }
+                    } else if stores_as_json(config, s) {
+                        field_names.push(quote(ident.as_str()));
+                        fields.push(format!("{} TEXT NOT NULL", quote(ident.as_str())));
+                        values.push(struct_to_json_sql(s));
+                    } else {
+                        let id = struct_to_sql(config, tables, s, db).await?;
+                        field_names.push(quote(ident.as_str()));
+                        fields.push(format!("{} INTEGER NOT NULL", quote(ident.as_str())));
+                        values.push(format!("{}", id));
+                    }
+                },
+                AnnotatedMoveValue::Vector(ty, v) => {
+                    match ty {
+                        TypeTag::Bool |
+                        TypeTag::U8 |
+                        TypeTag::U64 |
+                        TypeTag::U128 => {
+                            // primitive vectors are stored inline
+                            let bytes = vector_to_bytes(v)
+                                .map_err(|e| anyhow!("{} field {}: {}", struct_.type_, ident, e))?;
+                            field_names.push(quote(ident.as_str()));
+                            fields.push(format!("{} BLOB NOT NULL", quote(ident.as_str())));
+                            values.push(format!("x'{}'", hex::encode(&bytes)));
+                        },
+
+                        TypeTag::Signer => {
+                            return Err(anyhow!("{} field {} is a vector of signers, which cannot be stored", struct_.type_, ident));
+                        },
+
+                        TypeTag::Address |
+                        TypeTag::Vector(_) |
+                        TypeTag::Struct(_) => {
+                            // complex vectors are stored in a separate table named after the field
+                            // these generate no fields in this struct, and are handled later
+                        },
+                    }
+                },
+            }
+        }
+
+        let table_name = struct_tag_to_sql(config, &struct_.type_);
+        ensure_table(config, tables, &table_name, &fields, db).await?;
+
+        let insert_sql = if !field_names.is_empty() {
+            format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                table_name,
+                field_names.join(", "),
+                values.join(", "),
+            )
+        } else {
+            format!("INSERT INTO {} DEFAULT VALUES", table_name)
+        };
+        let result = sqlx::query(&insert_sql).execute(&mut *db).timed(config, &insert_sql).await?;
+        let id = result.last_insert_rowid();
+
+        // handle complex vectors inside the struct
+        for (ident, val) in &struct_.value {
+            match val {
+                AnnotatedMoveValue::Vector(ty, v) => {
+                    match ty {
+                        TypeTag::Address |
+                        TypeTag::Vector(_) |
+                        TypeTag::Struct(_) => {
+                            let name = vector_table_name(config, &struct_.type_, ident);
+                            vector_to_sql(config, tables, name, id, &ty, &v, &mut *db).await?;
+                        },
+                        _ => {},
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        Ok(id)
+    })
+}
+
+/// Write the elements of a vector to its table. Elements that are themselves
+/// vectors of primitives are stored inline like struct fields are, while
+/// other nested vectors get a row with no slot whose own elements go in the
+/// child table `{name}__elements`.
+fn vector_to_sql<'a>(
+    config: &'a Config,
+    tables: &'a CreatedTables,
+    name: String,
+    pid: i64,
+    ty: &'a TypeTag,
+    v: &'a [AnnotatedMoveValue],
+    db: &'a mut PoolConnection<Sqlite>,
+) -> Pin<Box<dyn Future<Output=Result<()>> + 'a>> {
+    Box::pin(async move {
+        // create table for this vector
+
+        let field = match ty {
+            TypeTag::Address => "slot BLOB NOT NULL".to_string(),
+            TypeTag::Vector(vty) => {
+                match **vty {
+                    // this includes Vector<u8> aka Bytes
+                    TypeTag::Bool |
+                    TypeTag::U8 |
+                    TypeTag::U64 |
+                    TypeTag::U128 => "slot BLOB NOT NULL".to_string(),
+                    // other vectors generate no field
+                    _ => "".to_string(),
+                }
+            },
+            TypeTag::Struct(_) => "slot INTEGER NOT NULL".to_string(),
+            _ => return Err(anyhow!("{} cannot hold elements of type {:?}", name, ty)),
+        };
+
+        let mut columns = vec![
+            "id INTEGER PRIMARY KEY".to_string(),
+            "parent_id INTEGER NOT NULL".to_string(),
+        ];
+        if !field.is_empty() {
+            columns.push(field);
+        }
+        ensure_table(config, tables, &name, &columns, db).await?;
+
+        // populate table
+        for e in v {
+            match e {
+                AnnotatedMoveValue::Address(a) => {
+                    let insert_sql = format!(
+                        "INSERT INTO {} (parent_id, slot) VALUES ({}, x'{}')",
+                        name,
+                        pid,
+                        hex::encode(a),
+                    );
+                    sqlx::query(&insert_sql).execute(&mut *db).timed(config, &insert_sql).await?;
+                },
+                AnnotatedMoveValue::Struct(s) => {
+                    let id = struct_to_sql(config, tables, s, db).await?;
+                    let insert_sql = format!(
+                        "INSERT INTO {} (parent_id, slot) VALUES ({}, {})",
+                        name,
+                        pid,
+                        id,
+                    );
+                    sqlx::query(&insert_sql).execute(&mut *db).timed(config, &insert_sql).await?;
+                },
+                AnnotatedMoveValue::Bytes(b) => {
+                    let insert_sql = format!(
+                        "INSERT INTO {} (parent_id, slot) VALUES ({}, x'{}')",
+                        name,
+                        pid,
+                        hex::encode(b),
+                    );
+                    sqlx::query(&insert_sql).execute(&mut *db).timed(config, &insert_sql).await?;
+                },
+
+                AnnotatedMoveValue::Vector(vty, vval) => match vty {
+                    TypeTag::Bool |
+                    TypeTag::U64 |
+                    TypeTag::U128 => {
+                        let insert_sql = format!(
+                            "INSERT INTO {} (parent_id, slot) VALUES ({}, x'{}')",
+                            name,
+                            pid,
+                            hex::encode(vector_to_bytes(vval).map_err(|e| anyhow!("{}: {}", name, e))?),
+                        );
+                        sqlx::query(&insert_sql).execute(&mut *db).timed(config, &insert_sql).await?;
+                    },
+                    _ => {
+                        let insert_sql = format!("INSERT INTO {} (parent_id) VALUES ({})", name, pid);
+                        let result = sqlx::query(&insert_sql).execute(&mut *db).timed(config, &insert_sql).await?;
+                        let id = result.last_insert_rowid();
+                        vector_to_sql(config, tables, elements_table_name(&name), id, vty, vval, &mut *db).await?;
+                    },
+                },
+                other => return Err(anyhow!("{} cannot hold the element {:?}", name, other)),
+            }
+        }
+        Ok(())
+    })
+}
+
+fn vector_to_bytes(v: &[AnnotatedMoveValue]) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for value in v {
+        match value {
+            AnnotatedMoveValue::Bool(b) => bytes.push(if *b { 1u8 } else { 0u8 }),
+            AnnotatedMoveValue::U8(i) => bytes.push(*i),
+            AnnotatedMoveValue::U64(i) => bytes.extend_from_slice(&i.to_be_bytes()),
+            AnnotatedMoveValue::U128(i) => bytes.extend_from_slice(&i.to_be_bytes()),
+            other => return Err(anyhow!("{:?} is not a primitive vector element", other)),
+        }
+    }
+    Ok(bytes)
+}
+
+fn type_param_to_sql(param: &TypeTag) -> String {
+    match param {
+        TypeTag::Bool => "Bool".to_string(),
+        TypeTag::U8 => "U8".to_string(),
+        TypeTag::U64 => "U64".to_string(),
+        TypeTag::U128 => "U128".to_string(),
+        TypeTag::Address => "Address".to_string(),
+        // signers can't be stored, but naming them keeps this total
+        TypeTag::Signer => "Signer".to_string(),
+        TypeTag::Vector(type_tag) => format!("Vector__t_{}_t", type_param_to_sql(&type_tag)),
+        TypeTag::Struct(struct_tag) => struct_tag_name(struct_tag),
+    }
+}
+
+fn type_params_to_sql(params: &[TypeTag]) -> String {
+    let result: Vec<_> = params.iter().map(|tt| type_param_to_sql(tt)).collect();
+    result.join("__")
+}
+
+/// One `0x1::DiemSystem::ValidatorInfo` with its config flattened.
+struct Validator<'a> {
+    address: &'a AccountAddress,
+    consensus_pubkey: &'a [u8],
+    consensus_voting_power: u64,
+    validator_network_addresses: &'a [u8],
+    fullnode_network_addresses: &'a [u8],
+    last_config_update_time: u64,
+}
+
+/// Whether this is the on-chain config holding the validator set,
+/// `0x1::DiemConfig::DiemConfig<0x1::DiemSystem::DiemSystem>`.
+fn is_validator_set(tag: &StructTag) -> bool {
+    let is_core = |tag: &StructTag, module: &str, name: &str| {
+        tag.address == CORE_CODE_ADDRESS && tag.module.as_str() == module && tag.name.as_str() == name
+    };
+    match tag.type_params.as_slice() {
+        [TypeTag::Struct(payload)] => {
+            is_core(tag, "DiemConfig", "DiemConfig") && is_core(payload, "DiemSystem", "DiemSystem")
+        },
+        _ => false,
+    }
+}
+
+/// The value of a struct's field by name.
+fn field<'a>(struct_: &'a AnnotatedMoveStruct, name: &str) -> Option<&'a AnnotatedMoveValue> {
+    struct_.value.iter().find(|(ident, _)| ident.as_str() == name).map(|(_, value)| value)
+}
+
+fn bytes_field<'a>(struct_: &'a AnnotatedMoveStruct, name: &str) -> Option<&'a [u8]> {
+    match field(struct_, name)? {
+        AnnotatedMoveValue::Bytes(b) => Some(b),
+        _ => None,
+    }
+}
+
+fn u64_field(struct_: &AnnotatedMoveStruct, name: &str) -> Option<u64> {
+    match field(struct_, name)? {
+        AnnotatedMoveValue::U64(i) => Some(*i),
+        _ => None,
+    }
+}
+
+/// The validators in a `DiemConfig<DiemSystem>`, or None if its layout isn't
+/// the one this was written for.
+fn validator_set(config: &AnnotatedMoveStruct) -> Option<Vec<Validator>> {
+    let system = match field(config, "payload")? {
+        AnnotatedMoveValue::Struct(s) => s,
+        _ => return None,
+    };
+    let infos = match field(system, "validators")? {
+        AnnotatedMoveValue::Vector(_, infos) => infos,
+        _ => return None,
+    };
+    infos.iter().map(|info| {
+        let info = match info {
+            AnnotatedMoveValue::Struct(s) => s,
+            _ => return None,
+        };
+        let validator_config = match field(info, "config")? {
+            AnnotatedMoveValue::Struct(s) => s,
+            _ => return None,
+        };
+        Some(Validator {
+            address: match field(info, "addr")? {
+                AnnotatedMoveValue::Address(a) => a,
+                _ => return None,
+            },
+            consensus_pubkey: bytes_field(validator_config, "consensus_pubkey")?,
+            consensus_voting_power: u64_field(info, "consensus_voting_power")?,
+            validator_network_addresses: bytes_field(validator_config, "validator_network_addresses")?,
+            fullnode_network_addresses: bytes_field(validator_config, "fullnode_network_addresses")?,
+            last_config_update_time: u64_field(info, "last_config_update_time")?,
+        })
+    }).collect()
+}
+
+/// The width of the addresses stored in BLOB columns and written in hex in
+/// table names, which is that of the chain this is built for: 16 bytes, or
+/// 32 with the `address32` feature.
+pub const ADDRESS_LENGTH: usize = AccountAddress::LENGTH;
+
+/// Read back a stored address, failing clearly rather than misreading one
+/// written for a chain with a different address width.
+pub fn address_from_bytes(bytes: &[u8]) -> Result<AccountAddress> {
+    if bytes.len() != ADDRESS_LENGTH {
+        return Err(anyhow!(
+            "stored address {} is {} bytes wide, but addresses are {} bytes",
+            hex::encode(bytes),
+            bytes.len(),
+            ADDRESS_LENGTH,
+        ));
+    }
+    AccountAddress::try_from(bytes).map_err(|e| anyhow!("{}", e))
+}
+
+/// Read back a u128 stored as a big-endian blob.
+fn u128_from_bytes(bytes: Vec<u8>) -> Result<u128> {
+    let bytes: [u8; 16] = bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| anyhow!("u128 column holds {} bytes, not 16", bytes.len()))?;
+    Ok(u128::from_be_bytes(bytes))
+}
+
+/// The table name for a struct type, including the configured prefix.
+pub fn struct_tag_to_sql(config: &Config, tag: &StructTag) -> String {
+    quote(&format!("{}{}", config.table_prefix, struct_tag_name(tag)))
+}
+
+/// The version in a serialized module's header, which follows the 4 byte
+/// magic as a little-endian u32.
+fn bytecode_version(data: &[u8]) -> u32 {
+    data.get(4..8)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .unwrap_or(0)
+}
+
+/// The table mapping addresses to top level structs of this type.
+pub fn root_table_name(config: &Config, tag: &StructTag) -> String {
+    quote(&format!("{}__root__{}", config.table_prefix, struct_tag_name(tag)))
+}
+
+/// The unprefixed name for a struct type. This is also used to name type
+/// parameters inside other table names.
+pub fn struct_tag_name(tag: &StructTag) -> String {
+    let type_params_str = if !tag.type_params.is_empty() {
+        format!("__t_{}_t", type_params_to_sql(&tag.type_params))
+    } else {
+        "".to_string()
+    };
+    // the address is written at full width so its length is fixed and the
+    // name never depends on how the address happened to be written
+    format!("x{}__{}__{}{}",
+            hex::encode(tag.address),
+            tag.module,
+            tag.name,
+            type_params_str)
+}
+
+/// The inverse of `struct_tag_name`. Identifiers containing the separators
+/// could make a name ambiguous, so the result is only returned if it names
+/// the same table.
+pub fn struct_tag_from_name(name: &str) -> Option<StructTag> {
+    match parse_struct_tag_name(name) {
+        Some((tag, "")) if struct_tag_name(&tag) == name => Some(tag),
+        _ => None,
+    }
+}
+
+fn parse_struct_tag_name(s: &str) -> Option<(StructTag, &str)> {
+    let s = s.strip_prefix('x')?;
+    let address_len = ADDRESS_LENGTH * 2;
+    let address = address_from_bytes(&hex::decode(s.get(..address_len)?).ok()?).ok()?;
+    let s = s[address_len..].strip_prefix("__")?;
+    let (module, s) = parse_identifier(s)?;
+    let s = s.strip_prefix("__")?;
+    let (name, mut s) = parse_identifier(s)?;
+
+    let mut type_params = vec![];
+    if let Some(rest) = s.strip_prefix("__t_") {
+        s = rest;
+        loop {
+            let (param, rest) = parse_type_param_name(s)?;
+            type_params.push(param);
+            if let Some(rest) = rest.strip_prefix("_t") {
+                s = rest;
+                break;
+            }
+            s = rest.strip_prefix("__")?;
+        }
+    }
+    Some((StructTag { address, module, name, type_params }, s))
+}
+
+fn parse_type_param_name(s: &str) -> Option<(TypeTag, &str)> {
+    for (name, tag) in &[
+        ("Bool", TypeTag::Bool),
+        ("U128", TypeTag::U128),
+        ("U64", TypeTag::U64),
+        ("U8", TypeTag::U8),
+        ("Address", TypeTag::Address),
+        ("Signer", TypeTag::Signer),
+    ] {
+        if let Some(rest) = s.strip_prefix(name) {
+            return Some((tag.clone(), rest));
+        }
+    }
+    if let Some(rest) = s.strip_prefix("Vector__t_") {
+        let (elem, rest) = parse_type_param_name(rest)?;
+        return Some((TypeTag::Vector(Box::new(elem)), rest.strip_prefix("_t")?));
+    }
+    let (tag, rest) = parse_struct_tag_name(s)?;
+    Some((TypeTag::Struct(tag), rest))
+}
+
+/// An identifier runs until a separator: `__`, or the `_t` that closes a
+/// list of type parameters.
+fn parse_identifier(s: &str) -> Option<(Identifier, &str)> {
+    let mut end = 0;
+    while end < s.len() {
+        let rest = &s[end..];
+        let closes = rest.starts_with("_t") && (rest.len() == 2 || rest[2..].starts_with('_'));
+        if rest.starts_with("__") || closes || !(rest.as_bytes()[0].is_ascii_alphanumeric() || rest.as_bytes()[0] == b'_') {
+            break;
+        }
+        end += 1;
+    }
+    let identifier = Identifier::new(&s[..end]).ok()?;
+    Some((identifier, &s[end..]))
+}
+
+pub fn vector_table_name(config: &Config, tag: &StructTag, field_name: &Identifier) -> String {
+    quote(&format!("{}__{}__elements", unquote(&struct_tag_to_sql(config, tag)), field_name))
+}
+
+/// The table of the elements of the nested vectors stored in `table`.
+pub fn elements_table_name(table: &str) -> String {
+    quote(&format!("{}__elements", unquote(table)))
+}
+
+/// If `tag` is `0x1::Option::Option<T>` for a scalar `T`, return `T`. Such
+/// fields are stored as a single nullable column instead of a struct table
+/// and an element table.
+pub fn scalar_option_type(tag: &StructTag) -> Option<&TypeTag> {
+    if tag.address != CORE_CODE_ADDRESS
+        || tag.module.as_str() != "Option"
+        || tag.name.as_str() != "Option"
+        || tag.type_params.len() != 1
+    {
+        return None;
+    }
+
+    let ty = &tag.type_params[0];
+    match ty {
+        TypeTag::Bool |
+        TypeTag::U8 |
+        TypeTag::U64 |
+        TypeTag::U128 |
+        TypeTag::Address => Some(ty),
+        TypeTag::Vector(inner) if **inner == TypeTag::U8 => Some(ty),
+        _ => None,
+    }
+}
+
+/// The columns, and their types, that store a scalar field named `name`.
+fn scalar_columns(config: &Config, name: &str, ty: &TypeTag) -> Vec<(String, &'static str)> {
+    match ty {
+        TypeTag::Bool => vec![(name.to_string(), "BOOLEAN")],
+        TypeTag::U8 => vec![(name.to_string(), "INTEGER")],
+        TypeTag::U64 => vec![(name.to_string(), config.numeric_encoding.u64_column_type())],
+        TypeTag::U128 => config.numeric_encoding.u128_columns(name),
+        _ => vec![(name.to_string(), "BLOB")],
+    }
+}
+
+/// Whether a sub-struct is stored as a JSON column, which is the case for
+/// structs with only scalar fields when `nested_as_json` is set.
+pub fn is_json_struct(config: &Config, struct_: &FatStructType) -> bool {
+    config.nested_as_json
+        && struct_.struct_tag().map_or(false, |tag| scalar_option_type(&tag).is_none())
+        && struct_.fields.iter().all(|(_, field_type)| match field_type {
+            FatType::Bool | FatType::U8 | FatType::U64 | FatType::U128 | FatType::Address => true,
+            FatType::Vector(elem_type) => matches!(**elem_type, FatType::U8),
+            // type parameters are already expanded and have no value
+            FatType::TyParam(_) => true,
+            FatType::Struct(_) => false,
+        })
+}
+
+/// `is_json_struct` for a value being written.
+fn stores_as_json(config: &Config, struct_: &AnnotatedMoveStruct) -> bool {
+    config.nested_as_json
+        && scalar_option_type(&struct_.type_).is_none()
+        && struct_.value.iter().all(|(_, value)| match value {
+            AnnotatedMoveValue::Bool(_)
+            | AnnotatedMoveValue::U8(_)
+            | AnnotatedMoveValue::U64(_)
+            | AnnotatedMoveValue::U128(_)
+            | AnnotatedMoveValue::Address(_)
+            | AnnotatedMoveValue::Bytes(_) => true,
+            AnnotatedMoveValue::Vector(..) | AnnotatedMoveValue::Struct(_) => false,
+        })
+}
+
+/// Render a struct of scalars as a JSON object literal. u64 and u128 are
+/// decimal strings, since JSON numbers can't hold them exactly, and
+/// addresses and bytes are hex strings.
+fn struct_to_json_sql(struct_: &AnnotatedMoveStruct) -> String {
+    let object = struct_.value.iter().map(|(ident, value)| {
+        let value = match value {
+            AnnotatedMoveValue::Bool(b) => serde_json::Value::from(*b),
+            AnnotatedMoveValue::U8(i) => serde_json::Value::from(*i),
+            AnnotatedMoveValue::U64(i) => serde_json::Value::from(i.to_string()),
+            AnnotatedMoveValue::U128(i) => serde_json::Value::from(i.to_string()),
+            AnnotatedMoveValue::Address(a) => serde_json::Value::from(hex::encode(a)),
+            AnnotatedMoveValue::Bytes(b) => serde_json::Value::from(hex::encode(b)),
+            _ => unreachable!(),
+        };
+        (ident.to_string(), value)
+    }).collect::<serde_json::Map<_, _>>();
+    // none of the values can contain a quote, but keys are escaped anyway
+    format!("'{}'", serde_json::Value::Object(object).to_string().replace('\'', "''"))
+}
+
+/// The inverse of `struct_to_json_sql`. Keys are looked up by field name, so
+/// extra keys such as an expanded `event_key` are ignored.
+fn json_to_struct(struct_: &FatStructType, json: &str) -> Result<MoveValue> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)?;
+    let mut fields = vec![];
+    for (field_name, field_type) in &struct_.fields {
+        let invalid = || anyhow!("field {} of {} is not a valid {:?}: {}", field_name, struct_.name, field_type, json);
+        let value = object.get(field_name.as_str()).ok_or_else(invalid)?;
+        let field = match field_type {
+            FatType::Bool => MoveValue::Bool(value.as_bool().ok_or_else(invalid)?),
+            FatType::U8 => MoveValue::U8(value.as_u64().and_then(|v| u8::try_from(v).ok()).ok_or_else(invalid)?),
+            FatType::U64 => MoveValue::U64(value.as_str().and_then(|v| v.parse().ok()).ok_or_else(invalid)?),
+            FatType::U128 => MoveValue::U128(value.as_str().and_then(|v| v.parse().ok()).ok_or_else(invalid)?),
+            FatType::Address => {
+                let bytes = value.as_str().and_then(|v| hex::decode(v).ok()).ok_or_else(invalid)?;
+                MoveValue::Address(address_from_bytes(&bytes).map_err(|_| invalid())?)
+            },
+            FatType::Vector(_) => {
+                let bytes = value.as_str().and_then(|v| hex::decode(v).ok()).ok_or_else(invalid)?;
+                MoveValue::Vector(bytes.into_iter().map(MoveValue::U8).collect())
+            },
+            FatType::TyParam(_) => continue,
+            FatType::Struct(_) => return Err(anyhow!("field {} of {} is a struct, which is never stored as JSON", field_name, struct_.name)),
+        };
+        fields.push(field);
+    }
+    Ok(MoveValue::Struct(MoveStruct::new(fields)))
+}
+
+/// Render an Option of a scalar as its element's SQL literals, or NULLs if empty.
+fn option_to_sql(config: &Config, option: &AnnotatedMoveStruct) -> Vec<String> {
+    let elem = match &option.value[0].1 {
+        // Option<u8> holds a vector<u8>, which is annotated as bytes
+        AnnotatedMoveValue::Bytes(b) => match b.first() {
+            Some(i) => return vec![format!("{}", i)],
+            None => None,
+        },
+        AnnotatedMoveValue::Vector(_, v) => v.first(),
+        _ => unreachable!(),
+    };
+
+    match elem {
+        None => {
+            let elem_ty = scalar_option_type(&option.type_).unwrap();
+            vec!["NULL".to_string(); scalar_columns(config, "", elem_ty).len()]
+        },
+        Some(AnnotatedMoveValue::U8(i)) => vec![format!("{}", i)],
+        Some(AnnotatedMoveValue::U64(i)) => vec![config.numeric_encoding.u64_to_sql(*i)],
+        Some(AnnotatedMoveValue::U128(i)) => config.numeric_encoding.u128_to_sql(*i),
+        Some(AnnotatedMoveValue::Bool(i)) => vec![u8::from(*i).to_string()],
+        Some(AnnotatedMoveValue::Address(i)) => vec![format!("x'{}'", hex::encode(i))],
+        Some(AnnotatedMoveValue::Bytes(i)) => vec![format!("x'{}'", hex::encode(i))],
+        Some(_) => unreachable!(),
+    }
+}
+
+pub fn fetch_struct<'a>(
+    config: &'a Config,
+    tag: &'a StructTag,
+    id: i64,
+    resolver: &'a Resolver,
+    db: &'a mut PoolConnection<Sqlite>,
+) -> Pin<Box<dyn Future<Output=Result<Option<MoveValue>>> + 'a>> {
+    Box::pin(async move {
+        // Find the fields to query for the struct
+        let struct_ = match resolver.resolve_struct(tag).await {
+            Ok(struct_) => struct_,
+            // resources of a struct removed by an upgrade can't be read back
+            Err(e) if e.is::<MissingStructDef>() => {
+                tracing::warn!(resource = %tag, "{}; treating the resource as absent", e);
+                return Ok(None);
+            },
+            Err(e) => return Err(anyhow!("cannot resolve {}: {}", tag, e)),
+        };
+        // the key is selected too, so that a struct stored without columns
+        // still has something to select
+        let columns = std::iter::once("__id".to_string())
+            .chain(struct_columns(config, &struct_).iter().map(|column| quote(column)))
+            .collect::<Vec<_>>();
+        let table = struct_tag_to_sql(config, tag);
+        let select_sql = format!("SELECT {} FROM {} WHERE __id = {}", columns.join(", "), table, id);
+        let row = match sqlx::query(&select_sql).fetch_optional(&mut *db).timed(config, &select_sql).await {
+            Ok(Some(row)) => row,
+            Ok(None) => return Ok(None),
+            // a crash between creating a `__root__` table and the struct
+            // tables below it, before the two were written atomically, can
+            // leave a root row pointing at a table that doesn't exist
+            Err(e) if is_missing_table(&e) => {
+                tracing::warn!(resource = %tag, table = %table, "no such table; treating the resource as absent");
+                return Ok(None);
+            },
+            Err(e) => return Err(anyhow!("{}: {}", select_sql, e)),
+        };
+
+        let mut fields = vec![];
+        for (field_name, field_type) in struct_.fields {
+            // columns are found by name, so reads don't depend on the order
+            // they were created in
+            let name = field_name.as_str();
+            let in_column = |e: anyhow::Error| anyhow!("{} column {}: {}", table, name, e);
+            let not_null = || anyhow!("{} column {} is NULL", table, name);
+            match field_type {
+                // vectors other than those of primitives have no corresponding column in the struct's table
+                FatType::Vector(ref sub_type) => {
+                    match **sub_type {
+                        FatType::U8 if is_auth_key_field(config, name) => {
+                            let text = row.try_get::<String, _>(name).map_err(|e| in_column(e.into()))?;
+                            let bytes = hex::decode(text)
+                                .map_err(|e| anyhow!("{} field {} is not hex: {}", tag, name, e))?;
+                            fields.push(MoveValue::Vector(bytes_to_vector(&FatType::U8, bytes).map_err(in_column)?));
+                        },
+                        ref elem_type if is_inline_vector(elem_type) => {
+                            let bytes: Vec<u8> = row.get(name);
+                            fields.push(MoveValue::Vector(bytes_to_vector(elem_type, bytes).map_err(in_column)?));
+                        },
+
+                        _ => {
+                            let v = fetch_vector(config, tag, &field_name, &*sub_type, id, resolver, db).await?;
+                            fields.push(MoveValue::Vector(v));
+                        },
+                    }
+                },
+
+                // type parameters can be ignored as they are already expanded
+                FatType::TyParam(_) => {}
+
+                // these types all have fields
+                FatType::Bool => {
+                    fields.push(MoveValue::Bool(row.get::<i64, _>(name) != 0));
+                },
+                FatType::U8 => {
+                    fields.push(MoveValue::U8(row.get::<i64, _>(name) as u8));
+                },
+                FatType::U64 => {
+                    let v = config.numeric_encoding
+                        .u64_from_row(&row, column_ordinal(&row, name), config.u64_bitcast)
+                        .map_err(in_column)?
+                        .ok_or_else(not_null)?;
+                    fields.push(MoveValue::U64(v));
+                },
+                FatType::U128 => {
+                    let column_index = first_column_ordinal(config, &row, name, &TypeTag::U128);
+                    let v = config.numeric_encoding
+                        .u128_from_row(&row, column_index)
+                        .map_err(in_column)?
+                        .ok_or_else(not_null)?;
+                    fields.push(MoveValue::U128(v));
+                },
+                FatType::Address => {
+                    let bytes: Vec<u8> = row.get(name);
+                    fields.push(MoveValue::Address(address_from_bytes(&bytes).map_err(in_column)?));
+                },
+                FatType::Struct(ref sub_struct) => {
+                    let sub_tag = sub_struct.struct_tag().unwrap();
+                    if let Some(elem_ty) = scalar_option_type(&sub_tag) {
+                        let column_index = first_column_ordinal(config, &row, name, elem_ty);
+                        let elem = match elem_ty {
+                            TypeTag::Bool => row.get::<Option<i64>, _>(column_index).map(|b| MoveValue::Bool(b != 0)),
+                            TypeTag::U8 => row.get::<Option<i64>, _>(column_index).map(|v| MoveValue::U8(v as u8)),
+                            TypeTag::U64 => config.numeric_encoding
+                                .u64_from_row(&row, column_index, config.u64_bitcast)
+                                .map_err(in_column)?
+                                .map(MoveValue::U64),
+                            TypeTag::U128 => config.numeric_encoding
+                                .u128_from_row(&row, column_index)
+                                .map_err(in_column)?
+                                .map(MoveValue::U128),
+                            TypeTag::Address => row.get::<Option<Vec<u8>>, _>(column_index)
+                                .map(|bytes| address_from_bytes(&bytes).map_err(in_column))
+                                .transpose()?
+                                .map(MoveValue::Address),
+                            TypeTag::Vector(_) => row.get::<Option<Vec<u8>>, _>(column_index)
+                                .map(|bytes| MoveValue::Vector(bytes.into_iter().map(MoveValue::U8).collect())),
+                            _ => unreachable!(),
+                        };
+                        // an Option is a struct holding a vector of zero or one elements
+                        fields.push(MoveValue::Struct(MoveStruct::new(vec![
+                            MoveValue::Vector(elem.into_iter().collect()),
+                        ])));
+                        continue;
+                    }
+                    if is_json_struct(config, sub_struct) {
+                        let json: String = row.get(name);
+                        fields.push(json_to_struct(sub_struct, &json)?);
+                        continue;
+                    }
+                    let sub_id = row.get(name);
+                    // a resource missing any of its rows is as absent as
+                    // one missing its top-level row
+                    match fetch_struct(config, &sub_tag, sub_id, resolver, &mut *db).await? {
+                        Some(value) => fields.push(value),
+                        None => return Ok(None),
+                    }
+                },
+            }
+        }
+
+        Ok(Some(MoveValue::Struct(MoveStruct::new(fields))))
+    })
+}
+
+/// The position of a column in a row, which is how the numeric encodings
+/// read their values.
+fn column_ordinal(row: &SqliteRow, name: &str) -> usize {
+    row.column(name).ordinal()
+}
+
+/// The position of the first of the columns storing a scalar field, of which
+/// there may be several.
+fn first_column_ordinal(config: &Config, row: &SqliteRow, name: &str, ty: &TypeTag) -> usize {
+    let (first, _) = scalar_columns(config, name, ty).remove(0);
+    column_ordinal(row, &first)
+}
+
+/// Return the set of columns in a struct's table. This will be a subset of
+/// columns as Vector fields do not have a column.
+fn struct_columns(config: &Config, struct_: &FatStructType) -> Vec<String> {
+    struct_.fields.iter().flat_map(|(field_name, field_type)| {
+        let name = field_name.as_str();
+        match field_type {
+            // vectors other than those of primitives have no corresponding column in the struct's table
+            FatType::Vector(ref sub_type) if is_inline_vector(sub_type) => vec![name.to_string()],
+            FatType::Vector(_) => vec![],
+
+            // type parameters can be ignored as they are expanded already
+            FatType::TyParam(_) => vec![],
+
+            // wide integers and options of scalars may span several columns
+            FatType::U128 => column_names(scalar_columns(config, name, &TypeTag::U128)),
+            FatType::Struct(ref sub_struct) => {
+                let sub_tag = sub_struct.struct_tag().unwrap();
+                // a sub-struct stored as JSON also has a single column
+                match scalar_option_type(&sub_tag) {
+                    Some(elem_ty) => column_names(scalar_columns(config, name, elem_ty)),
+                    None => vec![name.to_string()],
+                }
+            },
+
+            // these types all have fields
+            FatType::Bool |
+            FatType::U8 |
+            FatType::U64 |
+            FatType::Address => vec![name.to_string()],
+        }
+    }).collect()
+}
+
+fn column_names(columns: Vec<(String, &'static str)>) -> Vec<String> {
+    columns.into_iter().map(|(name, _)| name).collect()
+}
+
+fn fetch_vector<'a>(
+    config: &'a Config,
+    tag: &'a StructTag,
+    field_name: &'a Identifier,
+    elem_type: &'a FatType,
+    id: i64,
+    resolver: &'a Resolver,
+    db: &'a mut PoolConnection<Sqlite>,
+) -> Pin<Box<dyn Future<Output=Result<Vec<MoveValue>>> + 'a>> {
+    let table_name = vector_table_name(config, tag, field_name);
+    fetch_elements(config, table_name, elem_type, id, resolver, db)
+}
+
+/// Read the elements of one vector from its table, following nested vectors
+/// into their child tables the way `vector_to_sql` wrote them.
+fn fetch_elements<'a>(
+    config: &'a Config,
+    table_name: String,
+    elem_type: &'a FatType,
+    parent_id: i64,
+    resolver: &'a Resolver,
+    db: &'a mut PoolConnection<Sqlite>,
+) -> Pin<Box<dyn Future<Output=Result<Vec<MoveValue>>> + 'a>> {
+    Box::pin(async move {
+        // nested vectors other than those stored inline have no slot
+        let has_slot = match elem_type {
+            FatType::Vector(sub_type) => is_inline_vector(sub_type),
+            _ => true,
+        };
+        let select_sql = format!(
+            "SELECT id{} FROM {} WHERE parent_id = {} ORDER BY rowid",
+            if has_slot { ", slot" } else { "" },
+            table_name,
+            parent_id,
+        );
+        let rows = sqlx::query(&select_sql)
+            .fetch_all(&mut *db)
+            .timed(config, &select_sql)
+            .await?;
+        let mut elements = vec![];
+        for row in rows {
+            let element = match elem_type {
+                FatType::Bool => MoveValue::Bool(row.get::<i64, _>(1) != 0),
+                FatType::U8 => MoveValue::U8(row.get::<i64,_>(1) as u8),
+                FatType::U64 => MoveValue::U64(row.get::<i64,_>(1) as u64),
+                FatType::U128 => {
+                    let v = u128_from_bytes(row.get(1)).map_err(|e| anyhow!("{} column slot: {}", table_name, e))?;
+                    MoveValue::U128(v)
+                },
+                FatType::Address => {
+                    let bytes: Vec<u8> = row.get(1);
+                    let address = address_from_bytes(&bytes).map_err(|e| anyhow!("{} column slot: {}", table_name, e))?;
+                    MoveValue::Address(address)
+                },
+                FatType::Vector(ref sub_type) if has_slot => {
+                    let v = bytes_to_vector(sub_type, row.get(1)).map_err(|e| anyhow!("{} column slot: {}", table_name, e))?;
+                    MoveValue::Vector(v)
+                },
+                FatType::Vector(ref sub_type) => {
+                    let child_table = elements_table_name(&table_name);
+                    let v = fetch_elements(config, child_table, sub_type, row.get(0), resolver, &mut *db).await?;
+                    MoveValue::Vector(v)
+                },
+                FatType::Struct(sty) => {
+                    let sub_tag = sty.struct_tag().unwrap();
+                    let sub_id = row.get(1);
+                    fetch_struct(config, &sub_tag, sub_id, resolver, &mut *db)
+                        .await?
+                        .ok_or_else(|| anyhow!("{} row {} in {} is missing", sub_tag, sub_id, table_name))?
+                },
+                FatType::TyParam(_) => unreachable!(),
+            };
+            elements.push(element);
+        }
+        Ok(elements)
+    })
+}
+
+/// The length of an authentication key, in bytes.
+const AUTH_KEY_LENGTH: usize = 32;
+
+/// The names of the `vector<u8>` fields that hold authentication keys, in
+/// whichever struct they are declared.
+const AUTH_KEY_FIELDS: &[&str] = &["authentication_key"];
+
+/// Whether a `vector<u8>` field holds an authentication key to be stored as
+/// hex text, going by its name alone. Only the field's type and name are
+/// considered, so that a column's type never depends on the value written
+/// first.
+pub fn is_auth_key_field(config: &Config, name: &str) -> bool {
+    config.auth_keys_as_hex && AUTH_KEY_FIELDS.contains(&name)
+}
+
+/// The hex text literal of an authentication key, which must be 32 bytes.
+fn auth_key_to_sql(tag: &StructTag, name: &str, bytes: &[u8]) -> Result<String> {
+    if bytes.len() != AUTH_KEY_LENGTH {
+        return Err(anyhow!(
+            "{} field {} is {} bytes, not the {} of an authentication key",
+            tag,
+            name,
+            bytes.len(),
+            AUTH_KEY_LENGTH,
+        ));
+    }
+    Ok(format!("'{}'", hex::encode(bytes)))
+}
+
+/// `is_inline_vector` for a value being written.
+fn is_inline_vector_tag(elem_type: &TypeTag) -> bool {
+    matches!(elem_type, TypeTag::Bool | TypeTag::U8 | TypeTag::U64 | TypeTag::U128)
+}
+
+/// Whether a vector with these elements is stored as a single blob.
+pub fn is_inline_vector(elem_type: &FatType) -> bool {
+    match elem_type {
+        FatType::Bool | FatType::U8 | FatType::U64 | FatType::U128 => true,
+        _ => false,
+    }
+}
+
+/// The inverse of `vector_to_bytes`. Fails if `bytes` is not a whole number
+/// of elements, as a stored vector is only by corruption.
+fn bytes_to_vector(elem_type: &FatType, bytes: Vec<u8>) -> Result<Vec<MoveValue>> {
+    let not_whole = |width: usize| anyhow!("vector of {} byte elements is stored in {} bytes", width, bytes.len());
+    match elem_type {
+        FatType::Bool => Ok(bytes.iter().map(|b| MoveValue::Bool(*b != 0)).collect()),
+        FatType::U8 => Ok(bytes.iter().copied().map(MoveValue::U8).collect()),
+        FatType::U64 => {
+            let chunks = bytes.chunks_exact(8);
+            if !chunks.remainder().is_empty() {
+                return Err(not_whole(8));
+            }
+            Ok(chunks.map(|c| MoveValue::U64(u64::from_be_bytes(c.try_into().unwrap()))).collect())
+        },
+        FatType::U128 => {
+            let chunks = bytes.chunks_exact(16);
+            if !chunks.remainder().is_empty() {
+                return Err(not_whole(16));
+            }
+            Ok(chunks.map(|c| MoveValue::U128(u128::from_be_bytes(c.try_into().unwrap()))).collect())
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// A resolved type as kept in `__layouts`, along with the modules its layout
+/// was derived from and a hash of their bytes at the time.
+pub struct StoredLayout {
+    pub modules: Vec<ModuleId>,
+    pub hash: Vec<u8>,
+    pub layout: FatStructType,
+}
+
+/// Every layout kept by earlier runs, keyed by the struct tag they resolve.
+/// Rows that no longer decode, e.g. after `FatType` changed, are ignored and
+/// replaced once the type is resolved again.
+pub async fn load_layouts(config: &Config, pool: &SqlitePool) -> Result<HashMap<String, StoredLayout>> {
+    let table_name = config.layouts_table_name();
+    let exists_sql = "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?";
+    if sqlx::query(exists_sql).bind(unquote(&table_name)).fetch_optional(pool).timed(config, exists_sql).await?.is_none() {
+        return Ok(HashMap::new());
+    }
+    let select_sql = format!("SELECT struct_tag, modules, hash, layout FROM {}", table_name);
+    let rows = sqlx::query(&select_sql).fetch_all(pool).timed(config, &select_sql).await?;
+    let mut layouts = HashMap::new();
+    for row in rows {
+        let modules = serde_json::from_str(row.get::<&str, _>(1));
+        let layout = serde_json::from_str(row.get::<&str, _>(3));
+        if let (Ok(modules), Ok(layout)) = (modules, layout) {
+            let hash = row.get::<Vec<u8>, _>(2);
+            layouts.insert(row.get::<String, _>(0), StoredLayout { modules, hash, layout });
+        }
+    }
+    Ok(layouts)
+}
+
+/// Keep a resolved layout for later runs, replacing any older one.
+pub async fn store_layout(
+    config: &Config,
+    tables: &CreatedTables,
+    pool: &SqlitePool,
+    struct_tag: &str,
+    stored: &StoredLayout,
+) -> Result<()> {
+    let table_name = config.layouts_table_name();
+    let columns = [
+        "struct_tag STRING PRIMARY KEY".to_string(),
+        "modules STRING NOT NULL".to_string(),
+        "hash BLOB NOT NULL".to_string(),
+        "layout STRING NOT NULL".to_string(),
+    ];
+    let mut db = pool.acquire().await?;
+    ensure_table(config, tables, &table_name, &columns, &mut db).await?;
+    let insert_sql = format!(
+        "INSERT INTO {} (struct_tag, modules, hash, layout) VALUES (?, ?, ?, ?) ON CONFLICT(struct_tag) DO UPDATE SET modules = excluded.modules, hash = excluded.hash, layout = excluded.layout",
+        table_name,
+    );
+    sqlx::query(&insert_sql)
+        .bind(struct_tag)
+        .bind(serde_json::to_string(&stored.modules).unwrap())
+        .bind(&stored.hash)
+        .bind(serde_json::to_string(&stored.layout).unwrap())
+        .execute(&mut db)
+        .timed(config, &insert_sql)
+        .await?;
+    Ok(())
+}
+
+/// Quote a column name taken from a Move field, which may be an SQL keyword
+/// such as `order` or `index`, or a generated table name. Move identifiers
+/// and table prefixes never contain quotes, so no escaping is needed.
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name)
+}
+
+/// A table name as `sqlite_master` lists it, without the quotes `quote`
+/// adds. Names that aren't quoted are returned as they are.
+pub fn unquote(name: &str) -> &str {
+    name.strip_prefix('"').and_then(|name| name.strip_suffix('"')).unwrap_or(name)
+}
+
+/// The left-hand side of a filter on the field `name` of the struct table
+/// aliased `s`, and the values its right-hand side binds. Where one column
+/// doesn't sort like the Move value, the two sides are row values that do.
+fn filter_operands(config: &Config, name: &str, field_type: &FatType, value: &MoveValue) -> Result<(String, Vec<FilterValue>)> {
+    let column = format!("s.{}", quote(name));
+    let encoding = config.numeric_encoding;
+    Ok(match (field_type, value) {
+        (FatType::Bool, MoveValue::Bool(b)) => (column, vec![FilterValue::Integer(i64::from(*b))]),
+        (FatType::U8, MoveValue::U8(v)) => (column, vec![FilterValue::Integer(i64::from(*v))]),
+        (FatType::U64, MoveValue::U64(v)) if encoding == NumericEncoding::Text => {
+            (column, vec![FilterValue::Text(format!("{:020}", v))])
+        },
+        // u64s above i64::MAX wrap to negative integers, which sort after
+        // the others once ordered by sign first
+        (FatType::U64, MoveValue::U64(v)) => (
+            format!("({} < 0, {})", column, column),
+            vec![FilterValue::Integer(i64::from((*v as i64) < 0)), FilterValue::Integer(*v as i64)],
+        ),
+        (FatType::U128, MoveValue::U128(v)) => match encoding {
+            NumericEncoding::Blob => (column, vec![FilterValue::Blob(v.to_be_bytes().to_vec())]),
+            NumericEncoding::Text => (column, vec![FilterValue::Text(format!("{:039}", v))]),
+            NumericEncoding::Split => (
+                format!("(s.{}, s.{})", quote(&format!("{}_hi", name)), quote(&format!("{}_lo", name))),
+                vec![
+                    FilterValue::Integer(((*v >> 64) as u64 ^ SPLIT_OFFSET) as i64),
+                    FilterValue::Integer((*v as u64 ^ SPLIT_OFFSET) as i64),
+                ],
+            ),
+        },
+        (FatType::Address, MoveValue::Address(a)) => (column, vec![FilterValue::Blob(a.as_ref().to_vec())]),
+        (FatType::Bool, _) | (FatType::U8, _) | (FatType::U64, _) | (FatType::U128, _) | (FatType::Address, _) => {
+            return Err(anyhow!("field {} is a {:?}, but is compared with {:?}", name, field_type, value));
+        },
+        _ => return Err(anyhow!("field {} is not a bool, integer, or address, so it can't be filtered on", name)),
+    })
+}
+
+/// The error `--strict` stops with instead of skipping a write.
+fn strict_error(version: u64, access_path: &AccessPath, reason: &str) -> anyhow::Error {
+    anyhow!(
+        "cannot apply write at version {} to {} at path {} under --strict: {}",
+        version,
+        access_path.address,
+        hex::encode(&access_path.path),
+        reason,
+    )
+}
+
+/// The row a query found, with a table that doesn't exist yet read as no
+/// row. Other errors are returned, so that they aren't mistaken for absence.
+pub fn absent_if_missing_table<T>(result: std::result::Result<Option<T>, sqlx::Error>) -> Result<Option<T>> {
+    match result {
+        Ok(row) => Ok(row),
+        Err(e) if is_missing_table(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a query failed because a table it reads doesn't exist.
+pub fn is_missing_table(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(e) => e.message().starts_with("no such table"),
+        _ => false,
+    }
+}
+
+/// Create a generated table the first time it is used through `tables`, and
+/// check that it has the columns this code would create, since reading a
+/// table of a different shape silently returns wrong data. The table may
+/// already exist from an earlier run, or be created at the same time by
+/// another `DB` on the same database, so it is created if missing and then
+/// checked either way. All table creation goes through here, so each table
+/// is created or checked at most once per `DB`.
+async fn ensure_table(
+    config: &Config,
+    tables: &CreatedTables,
+    name: &String,
+    columns: &[String],
+    db: &mut PoolConnection<Sqlite>,
+) -> Result<()> {
+    let mut created = tables.0.lock().await;
+    if created.contains(name) {
+        return Ok(());
+    }
+
+    let create_sql = format!(
+        "CREATE TABLE IF NOT EXISTS {} ({})",
+        name,
+        columns.join(", "),
+    );
+    sqlx::query(&create_sql).execute(&mut *db).timed(config, &create_sql).await?;
+
+    let pragma_sql = format!("PRAGMA table_info({})", name);
+    let existing = sqlx::query(&pragma_sql)
+        .fetch_all(&mut *db)
+        .timed(config, &pragma_sql)
+        .await?
+        .iter()
+        .map(|row| format!("{} {}", row.get::<String, _>(1), row.get::<String, _>(2)))
+        .collect::<Vec<_>>();
+
+    // only the name and type of each column are compared, which is what
+    // reads depend on. order doesn't matter since columns are always named,
+    // e.g. after a module upgrade reorders a struct's fields.
+    let expected = columns
+        .iter()
+        .map(|column| column.split_whitespace().take(2).collect::<Vec<_>>().join(" "))
+        // PRAGMA table_info gives names without the quotes they were created with
+        .map(|column| column.replacen('"', "", 2))
+        .collect::<Vec<_>>();
+    let mut sorted_existing = existing.clone();
+    sorted_existing.sort();
+    let mut sorted_expected = expected.clone();
+    sorted_expected.sort();
+    if sorted_existing != sorted_expected {
+        let missing = expected.iter().filter(|c| !existing.contains(c)).collect::<Vec<_>>();
+        let unexpected = existing.iter().filter(|c| !expected.contains(c)).collect::<Vec<_>>();
+        return Err(anyhow!(
+            "table {} does not match the current schema\n  expected: {}\n  found: {}\n  missing: {:?}\n  unexpected: {:?}",
+            name,
+            expected.join(", "),
+            existing.join(", "),
+            missing,
+            unexpected,
+        ));
+    }
+
+    // constraints aren't listed by PRAGMA table_info, and a table created
+    // before a column had its CHECK still reads correctly, so one missing
+    // is only reported
+    let checks = columns
+        .iter()
+        .filter_map(|column| column.find(" CHECK ").map(|i| &column[i + 1..]))
+        .collect::<Vec<_>>();
+    if !checks.is_empty() {
+        let select_sql = "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?";
+        let table_sql: String = sqlx::query(select_sql)
+            .bind(unquote(name))
+            .fetch_one(&mut *db)
+            .timed(config, select_sql)
+            .await?
+            .get(0);
+        for check in checks.iter().filter(|check| !table_sql.contains(*check)) {
+            tracing::warn!(table = %name, "the table was created without {}; reset to add it", check);
+        }
+    }
+    created.insert(name.clone());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use move_core_types::value::{MoveStruct, MoveValue};
+    use proptest::prelude::*;
+    use vm::file_format::{
+        AddressIdentifierIndex, IdentifierIndex, ModuleHandle, SignatureToken, StructHandleIndex, TableIndex,
+    };
+
+    use super::*;
+    use crate::{
+        resolver::DEFAULT_MODULE_CACHE_SIZE,
+        test_util::{self, counter, counter_module, TestDb, TestStruct, ADDRESS},
+    };
+
+    /// The value of a counter stored at `ADDRESS`.
+    async fn read_counter(db: &TestDb, tag: &StructTag) -> Option<u64> {
+        let resource = db.db.read_resource(&ADDRESS, tag).await.unwrap()?;
+        match &resource.value[0].1 {
+            AnnotatedMoveValue::U64(v) => Some(*v),
+            other => panic!("unexpected value {:?}", other),
+        }
+    }
+
+    /// Another database in the same file as `db`, e.g. under another table
+    /// prefix.
+    async fn sharing_pool(db: &TestDb, config: Config) -> TestDb {
+        let config = Arc::new(config);
+        let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+        let shared = DB::from_pool(db.pool.clone(), config.clone(), modules.clone());
+        shared.initialize().await;
+        TestDb { _dir: db._dir.clone(), pool: db.pool.clone(), config, modules, db: shared }
+    }
+
+    #[test]
+    fn root_id_cache_evicts_least_recently_used() {
+        let cache = RootIdCache::new(2);
+        let other = AccountAddress::new([1; AccountAddress::LENGTH]);
+        cache.insert("t", ADDRESS, Some(1));
+        cache.insert("t", other, None);
+        // reading the first makes the second the least recently used
+        assert_eq!(cache.get("t", &ADDRESS), Some(Some(1)));
+        cache.insert("u", ADDRESS, Some(2));
+        assert_eq!(cache.get("t", &other), None);
+        assert_eq!(cache.get("t", &ADDRESS), Some(Some(1)));
+        assert_eq!(cache.get("u", &ADDRESS), Some(Some(2)));
+        cache.clear();
+        assert_eq!(cache.get("t", &ADDRESS), None);
+    }
+
+    #[tokio::test]
+    async fn store_caches_root_ids_per_db() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.store(1, &tag, counter(7)).await;
+
+        let root_table = root_table_name(&db.config, &tag);
+        assert!(matches!(db.db.root_ids().get(&root_table, &ADDRESS), Some(Some(_))));
+        // another database of the same prefix has its own cache
+        let other = TestDb::new(test_util::config()).await;
+        assert_eq!(other.db.root_ids().get(&root_table, &ADDRESS), None);
+    }
+
+    #[tokio::test]
+    async fn rollback_forgets_root_ids() {
+        let db = TestDb::new(Config { history: true, ..test_util::config() }).await;
+        db.publish(&counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.store(1, &tag, counter(1)).await;
+        db.store(2, &tag, counter(2)).await;
+        assert_eq!(read_counter(&db, &tag).await, Some(2));
+
+        db.db.rollback(2).await.unwrap();
+        let root_table = root_table_name(&db.config, &tag);
+        assert_eq!(db.db.root_ids().get(&root_table, &ADDRESS), None);
+        assert_eq!(read_counter(&db, &tag).await, Some(1));
+        // writing again builds on the version rolled back to
+        db.store(2, &tag, counter(3)).await;
+        assert_eq!(read_counter(&db, &tag).await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn root_ids_of_rows_deleted_for_missing_struct_rows_are_replaced() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.store(1, &tag, counter(1)).await;
+        // a later struct row, so that writing afresh can't reuse the id
+        let other = AccountAddress::new([1; AccountAddress::LENGTH]);
+        let op = WriteOp::Value(bcs::to_bytes(&counter(2)).unwrap());
+        db.write(1, other, Path::Resource(tag.clone()), op).await;
+
+        let root_table = root_table_name(&db.config, &tag);
+        let stale = db.db.root_ids().get(&root_table, &ADDRESS).unwrap().unwrap();
+        let delete_sql = format!("DELETE FROM {} WHERE __id = {}", struct_tag_to_sql(&db.config, &tag), stale);
+        sqlx::query(&delete_sql).execute(&db.pool).await.unwrap();
+        db.store(2, &tag, counter(3)).await;
+
+        let select_sql = format!("SELECT id FROM {} WHERE address = ?", root_table);
+        let rows = sqlx::query(&select_sql).bind(ADDRESS.as_ref()).fetch_all(&db.pool).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        let id: i64 = rows[0].get(0);
+        assert_ne!(id, stale);
+        assert_eq!(db.db.root_ids().get(&root_table, &ADDRESS), Some(Some(id)));
+        assert_eq!(read_counter(&db, &tag).await, Some(3));
+        // and the next write diffs against the new rows
+        db.store(3, &tag, counter(4)).await;
+        assert_eq!(read_counter(&db, &tag).await, Some(4));
+        assert_eq!(count_rows(&db, &struct_tag_to_sql(&db.config, &tag)).await, 2);
+    }
+
+    #[tokio::test]
+    async fn reset_forgets_root_ids() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.store(1, &tag, counter(1)).await;
+
+        db.db.reset().await.unwrap();
+        db.db.initialize().await;
+        let root_table = root_table_name(&db.config, &tag);
+        assert_eq!(db.db.root_ids().get(&root_table, &ADDRESS), None);
+        // the resource is written afresh rather than diffed against rows
+        // that were dropped
+        db.publish(&counter_module()).await;
+        db.store(2, &tag, counter(2)).await;
+        assert_eq!(read_counter(&db, &tag).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn writes_know_their_version() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        let select_sql = format!("SELECT last_modified_version FROM {}", root_table_name(&db.config, &tag));
+
+        db.store(5, &tag, counter(1)).await;
+        let row = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap();
+        assert_eq!(row.get::<i64, _>(0), 5);
+        db.store(9, &tag, counter(2)).await;
+        let row = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap();
+        assert_eq!(row.get::<i64, _>(0), 9);
+    }
+
+    #[tokio::test]
+    async fn writes_know_their_block_timestamp() {
+        let db = TestDb::new(Config { with_timestamps: true, resource_timestamps: true, ..test_util::config() }).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        // nothing is recorded before the first version
+        db.db.load_block_timestamp().await.unwrap();
+
+        for &(version, usecs) in [(1, 100), (2, 250)].iter() {
+            db.db.set_block_timestamp(usecs);
+            db.store(version, &tag, counter(version)).await;
+            db.db.store_transaction(version).await.unwrap();
+        }
+        let select_sql = format!("SELECT last_modified_time FROM {}", root_table_name(&db.config, &tag));
+        let row = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap();
+        assert_eq!(row.get::<i64, _>(0), 250);
+
+        // a resumed run carries on from the last timestamp recorded
+        let resumed = DB::from_pool(db.pool.clone(), db.config.clone(), db.modules.clone());
+        resumed.load_block_timestamp().await.unwrap();
+        resumed.store_transaction(3).await.unwrap();
+        let select_sql = format!(
+            "SELECT version, timestamp_usecs FROM {} ORDER BY version",
+            db.config.transactions_table_name(),
+        );
+        let rows = sqlx::query(&select_sql)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.get::<i64, _>(0), row.get::<i64, _>(1)))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![(1, 100), (2, 250), (3, 250)]);
+    }
+
+    #[tokio::test]
+    async fn events_are_read_back_by_key() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        let (key, other) = (EventKey::new_from_address(&ADDRESS, 0), EventKey::new_from_address(&ADDRESS, 1));
+        let event = |key, sequence_number, value| {
+            ContractEvent::new(key, sequence_number, TypeTag::Struct(tag.clone()), bcs::to_bytes(&counter(value)).unwrap())
+        };
+        db.db.store_events(1, &[event(key, 1, 20), event(other, 0, 30)]).await.unwrap();
+        db.db.store_events(2, &[event(key, 0, 10)]).await.unwrap();
+
+        let values = db
+            .db
+            .events_for_key(&key)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| match event {
+                AnnotatedMoveValue::Struct(s) => s.value.into_iter().next().unwrap().1,
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![AnnotatedMoveValue::U64(10), AnnotatedMoveValue::U64(20)]);
+        assert!(db.db.events_for_key(&EventKey::new_from_address(&ADDRESS, 2)).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn events_are_not_read_without_with_events() {
+        let db = TestDb::new(test_util::config()).await;
+        let e = db.db.events_for_key(&EventKey::new_from_address(&ADDRESS, 0)).await.unwrap_err();
+        assert!(e.to_string().contains("--with-events"), "{}", e);
+    }
+
+    /// A module `name` with no structs, which uses the modules `dependencies`
+    /// at the same address.
+    fn module_depending_on(name: &str, dependencies: &[&str]) -> Vec<u8> {
+        let module = CompiledModule::deserialize(&test_util::module_bytes(name, &[])).unwrap();
+        let mut module = module.into_inner();
+        for dependency in dependencies {
+            module.identifiers.push(Identifier::new(*dependency).unwrap());
+            module.module_handles.push(ModuleHandle {
+                address: AddressIdentifierIndex(0),
+                name: IdentifierIndex((module.identifiers.len() - 1) as TableIndex),
+            });
+        }
+        let module: CompiledModule = module.freeze().unwrap();
+        let mut bytes = vec![];
+        module.serialize(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn published_modules_record_their_version_and_dependencies() {
+        let db = TestDb::new(test_util::config()).await;
+        let module_id = |name: &str| ModuleId::new(ADDRESS, Identifier::new(name).unwrap()).to_string();
+        let deps_sql = format!(
+            "SELECT depends_on_module_id FROM {} WHERE module_id = ? ORDER BY depends_on_module_id",
+            db.config.module_deps_table_name(),
+        );
+        let deps = || async {
+            sqlx::query(&deps_sql)
+                .bind(module_id("A"))
+                .fetch_all(&db.pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| row.get::<String, _>(0))
+                .collect::<Vec<_>>()
+        };
+
+        let bytes = module_depending_on("A", &["B", "C"]);
+        db.publish(&bytes).await;
+        let select_sql = format!(
+            "SELECT module_id, bytecode_version FROM {}",
+            db.config.module_versions_table_name(),
+        );
+        let row = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap();
+        assert_eq!(row.get::<String, _>(0), module_id("A"));
+        assert_eq!(row.get::<i64, _>(1), bytecode_version(&bytes) as i64);
+        assert_eq!(deps().await, vec![module_id("B"), module_id("C")]);
+
+        // an upgrade replaces the dependencies rather than adding to them
+        db.publish(&module_depending_on("A", &["C"])).await;
+        assert_eq!(deps().await, vec![module_id("C")]);
+    }
+
+    /// Records the version and kind of every write observed.
+    struct Kinds(Arc<std::sync::Mutex<Vec<(u64, Option<WriteKind>)>>>);
+
+    impl WriteObserver for Kinds {
+        fn on_write(&self, version: u64, _address: &AccountAddress, _path: &Path, _op: &WriteOp, kind: Option<WriteKind>) {
+            self.0.lock().unwrap().push((version, kind));
+        }
+    }
+
+    #[tokio::test]
+    async fn observers_are_told_what_each_write_did() {
+        let mut db = TestDb::new(test_util::config()).await;
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        db.db = DB::from_pool(db.pool.clone(), db.config.clone(), db.modules.clone())
+            .with_observer(Box::new(Kinds(seen.clone())));
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        db.store(5, &tag, counter(1)).await;
+        db.store(9, &tag, counter(2)).await;
+        // deletions are only skipped, so they did nothing
+        db.write(10, ADDRESS, Path::Resource(tag.clone()), WriteOp::Deletion).await;
+        db.write(11, ADDRESS, Path::Code(tag.module_id()), WriteOp::Deletion).await;
+
+        assert_eq!(*seen.lock().unwrap(), vec![
+            (0, Some(WriteKind::PublishModule)),
+            (5, Some(WriteKind::CreateResource)),
+            (9, Some(WriteKind::UpdateResource)),
+            (10, None),
+            (11, None),
+        ]);
+        assert_eq!(read_counter(&db, &tag).await, Some(2));
+    }
+
+    /// Records the version and value of every resource stored.
+    struct Stores(Arc<std::sync::Mutex<Vec<(u64, AnnotatedMoveValue)>>>);
+
+    impl WriteObserver for Stores {
+        fn on_write(&self, _version: u64, _address: &AccountAddress, _path: &Path, _op: &WriteOp, _kind: Option<WriteKind>) {}
+
+        fn on_store(&self, version: u64, _address: &AccountAddress, resource: &AnnotatedMoveStruct) {
+            let value = match &resource.value[0].1 {
+                AnnotatedMoveValue::U64(v) => AnnotatedMoveValue::U64(*v),
+                other => panic!("unexpected value {:?}", other),
+            };
+            self.0.lock().unwrap().push((version, value));
+        }
+    }
+
+    #[tokio::test]
+    async fn resources_without_struct_rows_are_rewritten_atomically() {
+        let mut db = TestDb::new(test_util::config()).await;
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        db.db = DB::from_pool(db.pool.clone(), db.config.clone(), db.modules.clone())
+            .with_observer(Box::new(Stores(seen.clone())));
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        db.store(1, &tag, counter(1)).await;
+        let table = struct_tag_to_sql(&db.config, &tag);
+        let delete_sql = format!("DELETE FROM {}", table);
+        sqlx::query(&delete_sql).execute(&db.pool).await.unwrap();
+
+        // a rewrite that fails keeps the root row
+        let trigger_sql = format!("CREATE TRIGGER fail BEFORE INSERT ON {} BEGIN SELECT RAISE(ABORT, 'failed'); END", table);
+        sqlx::query(&trigger_sql).execute(&db.pool).await.unwrap();
+        assert!(db.try_store(2, &tag, counter(2)).await.is_err());
+        let count_sql = format!("SELECT COUNT(*) FROM {}", root_table_name(&db.config, &tag));
+        let count: i64 = sqlx::query(&count_sql).fetch_one(&db.pool).await.unwrap().get(0);
+        assert_eq!(count, 1);
+
+        // and one that succeeds is observed like any other update
+        sqlx::query("DROP TRIGGER fail").execute(&db.pool).await.unwrap();
+        db.store(3, &tag, counter(3)).await;
+        let count: i64 = sqlx::query(&count_sql).fetch_one(&db.pool).await.unwrap().get(0);
+        assert_eq!(count, 1);
+        assert_eq!(read_counter(&db, &tag).await, Some(3));
+        assert_eq!(*seen.lock().unwrap(), vec![(1, AnnotatedMoveValue::U64(1)), (3, AnnotatedMoveValue::U64(3))]);
+    }
+
+    #[tokio::test]
+    async fn store_reports_schema_mismatch() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        // as left behind by an older version of `R`
+        let create_sql = format!("CREATE TABLE {} (__id INTEGER PRIMARY KEY, amount INTEGER NOT NULL)", struct_tag_to_sql(&db.config, &tag));
+        sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+
+        let error = db.try_store(1, &tag, counter(1)).await.unwrap_err().to_string();
+        assert!(error.contains("does not match the current schema"), "{}", error);
+        assert!(error.contains("unexpected: [\"amount INTEGER\"]"), "{}", error);
+        assert!(error.contains("missing: [\"value "), "{}", error);
+        // nothing was written
+        assert_eq!(read_counter(&db, &tag).await, None);
+    }
+
+    #[tokio::test]
+    async fn tables_of_failed_writes_are_created_again() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        // a `__root__` table without its version fails the first write after
+        // the struct table has been created
+        let root_table = root_table_name(&db.config, &tag);
+        let create_sql = format!("CREATE TABLE {} (address BLOB NOT NULL, id INTEGER NOT NULL)", root_table);
+        sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+        let error = db.try_store(1, &tag, counter(1)).await.unwrap_err().to_string();
+        assert!(error.contains("does not match the current schema"), "{}", error);
+
+        // the rollback dropped the struct table, so it is created again
+        let drop_sql = format!("DROP TABLE {}", root_table);
+        sqlx::query(&drop_sql).execute(&db.pool).await.unwrap();
+        db.store(2, &tag, counter(2)).await;
+        assert_eq!(read_counter(&db, &tag).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn bool_columns_created_without_their_check_are_reported() {
+        let module = test_util::module_bytes("M", &[TestStruct::resource("R", vec![("flag", SignatureToken::Bool)])]);
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let flag = |flag| MoveStruct::new(vec![MoveValue::Bool(flag)]);
+        for created_before_the_check in [false, true].iter().copied() {
+            let db = TestDb::new(test_util::config()).await;
+            db.publish(&module).await;
+            if created_before_the_check {
+                let create_sql = format!("CREATE TABLE {} (__id INTEGER PRIMARY KEY, flag BOOLEAN NOT NULL)", struct_tag_to_sql(&db.config, &tag));
+                sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+            }
+
+            let (logs, _guard) = test_util::Logs::capture();
+            db.store(1, &tag, flag(true)).await;
+            let contents = logs.contents();
+            let reported = contents.contains("the table was created without CHECK (\"flag\" IN (0, 1))");
+            assert_eq!(reported, created_before_the_check, "{}", contents);
+            // either way, it is written and read back
+            let expected = db.annotator().view_resource(&tag, &bcs::to_bytes(&flag(true)).unwrap()).await.unwrap();
+            assert_eq!(db.db.read_resource(&ADDRESS, &tag).await.unwrap(), Some(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_fails_instead_of_skipping() {
+        let db = TestDb::new(Config { strict: true, ..test_util::config() }).await;
+        // `M` is never published, so `R` doesn't resolve
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let error = db.try_store(1, &tag, counter(1)).await.unwrap_err().to_string();
+        assert!(error.contains("cannot apply write at version 1"), "{}", error);
+        assert!(error.contains("under --strict"), "{}", error);
+
+        let path = AccessPath::new(ADDRESS, vec![0xff]);
+        let error = db.db.execute_with_annotator(2, &path, &WriteOp::Deletion, &db.annotator()).await.unwrap_err().to_string();
+        assert!(error.contains("cannot apply write at version 2"), "{}", error);
+        assert!(error.contains("at path ff"), "{}", error);
+
+        // neither was recorded as skipped
+        let select_sql = format!("SELECT * FROM {}", db.config.skipped_writes_table_name());
+        assert!(sqlx::query(&select_sql).fetch_all(&db.pool).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_paths_are_skipped() {
+        let db = TestDb::new(test_util::config()).await;
+        let path = AccessPath::new(ADDRESS, vec![0xff]);
+        let op = WriteOp::Value(vec![1, 2, 3]);
+        db.db.execute_with_annotator(2, &path, &op, &db.annotator()).await.unwrap();
+
+        let select_sql = format!("SELECT version, address, path, reason FROM {}", db.config.skipped_writes_table_name());
+        let rows = sqlx::query(&select_sql).fetch_all(&db.pool).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<i64, _>(0), 2);
+        assert_eq!(rows[0].get::<Vec<u8>, _>(1), ADDRESS.to_vec());
+        assert_eq!(rows[0].get::<Vec<u8>, _>(2), vec![0xff]);
+        assert!(!rows[0].get::<String, _>(3).is_empty());
+        // along with the value, to inspect later
+        let select_sql = format!("SELECT path, value FROM {}", db.config.unknown_paths_table_name());
+        let row = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap();
+        assert_eq!((row.get::<Vec<u8>, _>(0), row.get::<Vec<u8>, _>(1)), (vec![0xff], vec![1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn unstorable_values_name_their_field() {
+        let (_dir, pool) = test_util::pool().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let config = test_util::config();
+        let struct_ = AnnotatedMoveStruct {
+            is_resource: true,
+            type_: test_util::struct_tag("M", "R", vec![]),
+            value: vec![(Identifier::new("signers").unwrap(), AnnotatedMoveValue::Vector(TypeTag::Signer, vec![]))],
+        };
+        let tables = CreatedTables::default();
+        let error = generate_sql(&config, &tables, &ADDRESS, &struct_, &[], 1, None, &mut conn).await.unwrap_err().to_string();
+        assert!(error.contains(&struct_.type_.to_string()), "{}", error);
+        assert!(error.contains("field signers"), "{}", error);
+
+        let error = vector_to_bytes(&[AnnotatedMoveValue::Address(ADDRESS)]).unwrap_err().to_string();
+        assert!(error.contains("not a primitive vector element"), "{}", error);
+    }
+
+    #[test]
+    fn signer_type_params_have_table_names() {
+        let tag = test_util::struct_tag("M", "R", vec![TypeTag::Signer, TypeTag::Vector(Box::new(TypeTag::Signer))]);
+        assert_eq!(struct_tag_from_name(&struct_tag_name(&tag)), Some(tag));
+    }
+
+    #[tokio::test]
+    async fn read_errors_are_not_absence() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        // a type that was never stored has no table
+        assert_eq!(read_counter(&db, &tag).await, None);
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), None);
+
+        let create_sql = format!("CREATE TABLE {} (address BLOB NOT NULL)", root_table_name(&db.config, &tag));
+        sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+        let error = db.db.read_resource(&ADDRESS, &tag).await.unwrap_err().to_string();
+        assert!(error.contains("no such column"), "{}", error);
+        let error = db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap_err().to_string();
+        assert!(error.contains("no such column"), "{}", error);
+        let error = db.try_store(1, &tag, counter(1)).await.unwrap_err().to_string();
+        assert!(error.contains("no such column"), "{}", error);
+    }
+
+    #[test]
+    fn json_structs_that_do_not_decode_are_errors() {
+        let struct_ = FatStructType {
+            address: ADDRESS,
+            module: Identifier::new("M").unwrap(),
+            name: Identifier::new("S").unwrap(),
+            is_resource: false,
+            ty_args: vec![],
+            fields: vec![
+                (Identifier::new("flag").unwrap(), FatType::Bool),
+                (Identifier::new("value").unwrap(), FatType::U64),
+            ],
+        };
+        assert_eq!(
+            json_to_struct(&struct_, r#"{"flag":true,"value":"7"}"#).unwrap(),
+            MoveValue::Struct(MoveStruct::new(vec![MoveValue::Bool(true), MoveValue::U64(7)])),
+        );
+        for json in &[r#"{"flag":true}"#, r#"{"flag":true,"value":7}"#, r#"{"flag":true,"value":"x"}"#] {
+            let error = json_to_struct(&struct_, json).unwrap_err().to_string();
+            assert!(error.contains("field value of S"), "{}", error);
+        }
+        assert!(json_to_struct(&struct_, "not json").is_err());
+    }
+
+    #[tokio::test]
+    async fn unlisted_types_create_no_tables() {
+        let config = Config {
+            only_structs: vec![test_util::struct_tag("M", "R", vec![])].into_iter().collect(),
+            ..test_util::config()
+        };
+        let db = TestDb::new(config).await;
+        let listed = test_util::struct_tag("M", "R", vec![]);
+        let unlisted = test_util::struct_tag("M", "S", vec![]);
+        db.publish(&test_util::module_bytes("M", &[
+            TestStruct::resource("R", vec![("value", SignatureToken::U64)]),
+            TestStruct::resource("S", vec![("value", SignatureToken::U64)]),
+        ])).await;
+        db.store(1, &listed, counter(1)).await;
+        db.store(1, &unlisted, counter(2)).await;
+
+        let tables = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| quote(&row.get::<String, _>(0)))
+            .collect::<Vec<_>>();
+        assert!(tables.contains(&struct_tag_to_sql(&db.config, &listed)));
+        assert!(!tables.contains(&struct_tag_to_sql(&db.config, &unlisted)));
+        assert!(!tables.contains(&root_table_name(&db.config, &unlisted)));
+        assert_eq!(read_counter(&db, &listed).await, Some(1));
+        assert_eq!(read_counter(&db, &unlisted).await, None);
+
+        // the unlisted resource is kept as its bytes
+        let select_sql = format!("SELECT value FROM {}", db.config.unindexed_resources_table_name());
+        let value = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap().get::<Vec<u8>, _>(0);
+        assert_eq!(value, bcs::to_bytes(&counter(2)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn table_prefixes_keep_chains_apart() {
+        let first = TestDb::new(Config { table_prefix: "first_".to_string(), ..test_util::config() }).await;
+        let second = Config { table_prefix: "second_".to_string(), strict: true, ..test_util::config() };
+        let second = sharing_pool(&first, second).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        first.publish(&counter_module()).await;
+        first.store(1, &tag, counter(1)).await;
+
+        // the second chain has not published the module, so can't store it
+        assert!(second.try_store(1, &tag, counter(2)).await.is_err());
+        second.publish(&counter_module()).await;
+        second.store(1, &tag, counter(2)).await;
+        second.store(2, &tag, counter(3)).await;
+
+        assert_eq!(read_counter(&first, &tag).await, Some(1));
+        assert_eq!(read_counter(&second, &tag).await, Some(3));
+        for db in &[&first, &second] {
+            assert_eq!(count_rows(db, &db.config.module_table_name()).await, 1);
+            assert_eq!(count_rows(db, &root_table_name(&db.config, &tag)).await, 1);
+        }
+        assert_ne!(struct_tag_to_sql(&first.config, &tag), struct_tag_to_sql(&second.config, &tag));
+    }
+
+    #[tokio::test]
+    async fn reset_only_drops_tables_it_generated() {
+        let short = TestDb::new(Config { table_prefix: "a".to_string(), ..test_util::config() }).await;
+        let long = sharing_pool(&short, Config { table_prefix: "ab".to_string(), ..test_util::config() }).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        for db in &[&short, &long] {
+            db.publish(&counter_module()).await;
+            db.store(1, &tag, counter(1)).await;
+        }
+        // tables of the user's that merely share the prefix
+        for table in &["a__notes", "ax__notes", "a_custom"] {
+            sqlx::query(&format!("CREATE TABLE {} (note STRING)", table)).execute(&short.pool).await.unwrap();
+        }
+        assert!(short.config.is_generated_table(&short.config.module_table_name()));
+        assert!(short.config.is_generated_table(&root_table_name(&short.config, &tag)));
+        assert!(short.config.is_generated_table(&struct_tag_to_sql(&short.config, &tag)));
+        assert!(!short.config.is_generated_table(&long.config.module_table_name()));
+
+        short.db.reset().await.unwrap();
+        assert!(!short.db.is_initialized().await);
+        assert_eq!(read_counter(&long, &tag).await, Some(1));
+        let tables = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .fetch_all(&short.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(0))
+            .collect::<HashSet<_>>();
+        for table in &["a__notes", "ax__notes", "a_custom"] {
+            assert!(tables.contains(*table), "{} was dropped", table);
+        }
+        assert!(tables.iter().all(|table| !short.config.is_generated_table(table)), "{:?}", tables);
+    }
+
+    #[tokio::test]
+    async fn queries_compare_in_numeric_order() {
+        for encoding in &[NumericEncoding::Blob, NumericEncoding::Text, NumericEncoding::Split] {
+            let db = TestDb::new(Config { numeric_encoding: *encoding, ..test_util::config() }).await;
+            let tag = test_util::struct_tag("M", "R", vec![]);
+            db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![
+                ("value", SignatureToken::U64),
+                ("big", SignatureToken::U128),
+            ])])).await;
+            // the largest values are those that wrap or set the top bit
+            let values = [(1, 1), (5, 5 << 64), (9, 9), (u64::MAX, u128::MAX)];
+            for (i, (value, big)) in values.iter().enumerate() {
+                let resource = MoveStruct::new(vec![MoveValue::U64(*value), MoveValue::U128(*big)]);
+                let op = WriteOp::Value(bcs::to_bytes(&resource).unwrap());
+                let address = AccountAddress::new([i as u8; AccountAddress::LENGTH]);
+                db.write(1, address, Path::Resource(tag.clone()), op).await;
+            }
+
+            let addresses = |found: Vec<(AccountAddress, AnnotatedMoveStruct)>| {
+                found.iter().map(|(address, _)| address.as_ref()[0]).collect::<Vec<_>>()
+            };
+            let found = db.db.query_resources(&tag, &[("value", FilterOp::Ge, MoveValue::U64(5))]).await.unwrap();
+            assert_eq!(addresses(found), vec![1, 2, 3], "{:?}", encoding);
+            let found = db.db.query_resources(&tag, &[("big", FilterOp::Ge, MoveValue::U128(9))]).await.unwrap();
+            assert_eq!(addresses(found), vec![1, 2, 3], "{:?}", encoding);
+            let filters = [("value", FilterOp::Ge, MoveValue::U64(5)), ("big", FilterOp::Lt, MoveValue::U128(u128::MAX))];
+            let found = db.db.query_resources(&tag, &filters).await.unwrap();
+            assert_eq!(addresses(found), vec![1, 2], "{:?}", encoding);
+
+            let error = db.db.query_resources(&tag, &[("value", FilterOp::Ge, MoveValue::U8(5))]).await.unwrap_err();
+            assert!(error.to_string().contains("is compared with"), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn numeric_encodings_round_trip_the_extremes() {
+        for encoding in &[NumericEncoding::Blob, NumericEncoding::Text, NumericEncoding::Split] {
+            let db = TestDb::new(Config { numeric_encoding: *encoding, ..test_util::config() }).await;
+            let tag = test_util::struct_tag("M", "R", vec![]);
+            db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![
+                ("value", SignatureToken::U64),
+                ("big", SignatureToken::U128),
+                ("values", test_util::vector_token(SignatureToken::U64)),
+                ("bigs", test_util::vector_token(SignatureToken::U128)),
+            ])])).await;
+            for (version, (value, big)) in [(u64::MAX, u128::MAX), (0, 0), (1 << 63, 1 << 127)].iter().enumerate() {
+                let resource = MoveStruct::new(vec![
+                    MoveValue::U64(*value),
+                    MoveValue::U128(*big),
+                    MoveValue::Vector(vec![MoveValue::U64(*value), MoveValue::U64(u64::MAX)]),
+                    MoveValue::Vector(vec![MoveValue::U128(*big), MoveValue::U128(u128::MAX)]),
+                ]);
+                let blob = bcs::to_bytes(&resource).unwrap();
+                db.store(version as u64 + 1, &tag, resource).await;
+                let expected = db.annotator().view_resource(&tag, &blob).await.unwrap();
+                let read = db.db.read_resource(&ADDRESS, &tag).await.unwrap().unwrap();
+                assert_eq!(read, expected, "{:?}", encoding);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn large_u64s_are_read_as_bitcast_or_rejected() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        // above i64::MAX, so stored as a negative INTEGER
+        db.store(1, &tag, counter(u64::MAX - 1)).await;
+        let select_sql = format!("SELECT value FROM {}", struct_tag_to_sql(&db.config, &tag));
+        let stored: i64 = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap().get(0);
+        assert_eq!(stored, -2);
+
+        assert_eq!(read_counter(&db, &tag).await, Some(u64::MAX - 1));
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(u64::MAX - 1));
+        let signed = Arc::new(Config { u64_bitcast: false, ..test_util::config() });
+        let signed = DB::from_pool(db.pool.clone(), signed, db.modules.clone());
+        let error = signed.read_resource(&ADDRESS, &tag).await.unwrap_err();
+        assert!(error.to_string().contains("holds -2, which is negative"), "{}", error);
+        assert!(signed.read_field_u64(&ADDRESS, &tag, "value").await.is_err());
+
+        // values that fit either way read the same
+        db.store(2, &tag, counter(5)).await;
+        assert_eq!(signed.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(5));
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(5));
+    }
+
+    #[tokio::test]
+    async fn resources_without_fields_are_keyed_like_any_struct() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "Marker", vec![]);
+        let holder = test_util::struct_tag("M", "Holder", vec![]);
+        db.publish(&test_util::module_bytes("M", &[
+            TestStruct::resource("Marker", vec![]),
+            TestStruct::resource("Holder", vec![("markers", test_util::vector_token(test_util::struct_token(0)))]),
+        ]))
+        .await;
+        db.store(1, &tag, MoveStruct::new(vec![])).await;
+        let table_name = struct_tag_to_sql(&db.config, &tag);
+        let pragma_sql = format!("PRAGMA table_info({})", table_name);
+        let columns = sqlx::query(&pragma_sql)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(1))
+            .collect::<Vec<_>>();
+        assert_eq!(columns, ["__id"]);
+
+        let annotator = db.annotator();
+        let expected = || annotator.view_resource(&tag, &[]);
+        assert_eq!(db.db.read_resource(&ADDRESS, &tag).await.unwrap(), Some(expected().await.unwrap()));
+        assert_eq!(db.db.query_resources(&tag, &[]).await.unwrap(), vec![(ADDRESS, expected().await.unwrap())]);
+
+        // dropping vector elements deletes their rows
+        let markers = |n| MoveStruct::new(vec![MoveValue::Vector(vec![MoveValue::Struct(MoveStruct::new(vec![])); n])]);
+        db.store(2, &holder, markers(2)).await;
+        assert_eq!(count_rows(&db, &table_name).await, 3);
+        db.store(3, &holder, markers(0)).await;
+        assert_eq!(count_rows(&db, &table_name).await, 1);
+        let expected = annotator.view_resource(&holder, &bcs::to_bytes(&markers(0)).unwrap()).await.unwrap();
+        assert_eq!(db.db.read_resource(&ADDRESS, &holder).await.unwrap(), Some(expected));
+    }
+
+    #[tokio::test]
+    async fn upgrading_renames_the_key_of_tables_without_fields() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "Marker", vec![]);
+        let table_name = struct_tag_to_sql(&db.config, &tag);
+        // as earlier releases created it
+        let create_sql = format!("CREATE TABLE {} (id INTEGER PRIMARY KEY)", table_name);
+        sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+
+        db.db.upgrade().await;
+        let pragma_sql = format!("PRAGMA table_info({})", table_name);
+        let columns = sqlx::query(&pragma_sql)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(1))
+            .collect::<Vec<_>>();
+        assert_eq!(columns, ["__id"]);
+    }
+
+    #[tokio::test]
+    async fn raw_bytes_are_kept_on_root_rows() {
+        let db = TestDb::new(Config { keep_raw: true, ..test_util::config() }).await;
+        db.publish(&counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let select_sql = format!("SELECT raw FROM {} WHERE address = ?", root_table_name(&db.config, &tag));
+        // an insert, then an update in place
+        for value in &[3, 5] {
+            db.store(*value, &tag, counter(*value)).await;
+            let raw: Vec<u8> = sqlx::query(&select_sql).bind(ADDRESS.as_ref()).fetch_one(&db.pool).await.unwrap().get(0);
+            assert_eq!(raw, bcs::to_bytes(&counter(*value)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn only_slow_queries_are_logged() {
+        for (threshold, logged) in &[(Duration::from_secs(0), true), (Duration::from_secs(3600), false)] {
+            let db = TestDb::new(Config { slow_query_threshold: Some(*threshold), ..test_util::config() }).await;
+            let (logs, _guard) = test_util::Logs::capture();
+            db.publish(&counter_module()).await;
+            assert_eq!(logs.contents().contains("slow query took"), *logged, "{:?}: {}", threshold, logs.contents());
+        }
+    }
+
+    #[tokio::test]
+    async fn skipped_writes_are_logged_with_their_version() {
+        let db = TestDb::new(test_util::config()).await;
+        let (logs, _guard) = test_util::Logs::capture();
+        // the module of the type was never published
+        db.store(7, &test_util::struct_tag("M", "R", vec![]), counter(1)).await;
+        let contents = logs.contents();
+        assert!(contents.contains("WARN"), "{}", contents);
+        assert!(contents.contains("skipping write") && contents.contains("version=7"), "{}", contents);
+    }
+
+    #[tokio::test]
+    async fn resources_of_removed_structs_are_skipped_with_a_warning() {
+        let db = TestDb::new(test_util::config()).await;
+        let r = test_util::struct_tag("M", "R", vec![]);
+        let s = test_util::struct_tag("M", "S", vec![]);
+        db.publish(&test_util::module_bytes("M", &[
+            TestStruct::resource("R", vec![("value", SignatureToken::U64)]),
+            TestStruct::resource("S", vec![("value", SignatureToken::U64)]),
+        ])).await;
+        // an upgrade that drops R
+        let upgraded = test_util::module_bytes("M", &[TestStruct::resource("S", vec![("value", SignatureToken::U64)])]);
+        db.write(2, ADDRESS, Path::Code(r.module_id()), WriteOp::Value(upgraded)).await;
+
+        let (logs, _guard) = test_util::Logs::capture();
+        db.store(3, &r, counter(1)).await;
+        db.store(3, &s, counter(2)).await;
+        let contents = logs.contents();
+        assert!(contents.contains("WARN") && contents.contains("struct def removed"), "{}", contents);
+        let select_sql = format!("SELECT version, reason FROM {}", db.config.skipped_writes_table_name());
+        let rows = sqlx::query(&select_sql).fetch_all(&db.pool).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<i64, _>(0), 3);
+        assert!(rows[0].get::<String, _>(1).starts_with("struct def removed: "), "{}", rows[0].get::<String, _>(1));
+        // and writing carries on with the types that remain
+        assert_eq!(read_counter(&db, &s).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn unresolved_fields_are_stored_as_null() {
+        for best_effort_resolution in [false, true].iter().copied() {
+            let db = TestDb::new(Config { best_effort_resolution, ..test_util::config() }).await;
+            db.publish(&test_util::module_bytes("G", &[TestStruct {
+                name: "Pair",
+                is_resource: true,
+                type_parameters: vec![Kind::All],
+                fields: vec![("value", SignatureToken::U64), ("item", SignatureToken::TypeParameter(0))],
+            }])).await;
+            // the item's type is in a module that was never published
+            let missing = TypeTag::Struct(test_util::struct_tag("Missing", "Item", vec![]));
+            let tag = test_util::struct_tag("G", "Pair", vec![missing]);
+            let value = MoveStruct::new(vec![MoveValue::U64(7), MoveValue::U64(9)]);
+            let op = WriteOp::Value(bcs::to_bytes(&value).unwrap());
+            db.write(1, ADDRESS, Path::Resource(tag.clone()), op).await;
+
+            let select_sql = format!("SELECT type_tag, value, unresolved FROM {}", db.config.partial_resources_table_name());
+            let rows = sqlx::query(&select_sql).fetch_all(&db.pool).await;
+            if !best_effort_resolution {
+                // the whole write is skipped, and nothing partial is kept
+                assert_eq!(count_rows(&db, &db.config.skipped_writes_table_name()).await, 1);
+                assert!(rows.is_err());
+                continue;
+            }
+            let rows = rows.unwrap();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].get::<String, _>(0), tag.to_string());
+            let stored: serde_json::Value = serde_json::from_str(&rows[0].get::<String, _>(1)).unwrap();
+            assert_eq!(stored, serde_json::json!({
+                "value": serde_json::to_value(AnnotatedMoveValue::U64(7)).unwrap(),
+                "item": null,
+            }));
+            let unresolved: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(&rows[0].get::<String, _>(2)).unwrap();
+            assert_eq!(unresolved.keys().collect::<Vec<_>>(), ["item"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_key_fields_are_stored_as_hex() {
+        let db = TestDb::new(Config { auth_keys_as_hex: true, ..test_util::config() }).await;
+        // any struct's field of that name, rather than only `DiemAccount`'s
+        db.publish(&test_util::module_bytes("M", &[
+            TestStruct::resource("Account", vec![
+                ("authentication_key", test_util::vector_token(SignatureToken::U8)),
+                ("sequence_number", SignatureToken::U64),
+            ]),
+            TestStruct::resource("Other", vec![
+                ("payload", test_util::vector_token(SignatureToken::U8)),
+                ("sequence_number", SignatureToken::U64),
+            ]),
+        ])).await;
+        let account = test_util::struct_tag("M", "Account", vec![]);
+        let other = test_util::struct_tag("M", "Other", vec![]);
+        let value = |key: Vec<u8>| MoveStruct::new(vec![
+            MoveValue::Vector(key.into_iter().map(MoveValue::U8).collect()),
+            MoveValue::U64(1),
+        ]);
+        let key = vec![0xab; AUTH_KEY_LENGTH];
+        db.store(1, &account, value(key.clone())).await;
+        db.store(1, &other, value(key.clone())).await;
+
+        // the key can be compared with text, while the same bytes under
+        // another name stay a blob
+        let column_type = |tag, column| {
+            format!("SELECT typeof({}) FROM {} WHERE __id = 1", column, struct_tag_to_sql(&db.config, tag))
+        };
+        let type_of_account: String =
+            sqlx::query(&column_type(&account, "authentication_key")).fetch_one(&db.pool).await.unwrap().get(0);
+        let type_of_other: String = sqlx::query(&column_type(&other, "payload")).fetch_one(&db.pool).await.unwrap().get(0);
+        assert_eq!((type_of_account.as_str(), type_of_other.as_str()), ("text", "blob"));
+        let select_sql = format!(
+            "SELECT COUNT(*) FROM {} WHERE authentication_key = '{}'",
+            struct_tag_to_sql(&db.config, &account),
+            hex::encode(&key),
+        );
+        let count: i64 = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap().get(0);
+        assert_eq!(count, 1);
+        let expected = db.annotator().view_resource(&account, &bcs::to_bytes(&value(key)).unwrap()).await.unwrap();
+        assert_eq!(db.db.read_resource(&ADDRESS, &account).await.unwrap(), Some(expected));
+
+        // an authentication key is 32 bytes, whether created or updated
+        let error = db.try_store(2, &account, value(vec![0xab; 31])).await.unwrap_err().to_string();
+        assert!(error.contains("31 bytes"), "{}", error);
+        let address = AccountAddress::new([1; AccountAddress::LENGTH]);
+        let op = WriteOp::Value(bcs::to_bytes(&value(vec![0xab; 33])).unwrap());
+        let error = db.try_write(2, address, Path::Resource(account.clone()), op).await.unwrap_err().to_string();
+        assert!(error.contains("33 bytes"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn flattened_validators_follow_the_latest_set() {
+        let db = TestDb::new(Config { flatten_validators: true, ..test_util::config() }).await;
+        let bytes_token = || test_util::vector_token(SignatureToken::U8);
+        db.publish(&test_util::module_bytes_at(CORE_CODE_ADDRESS, "DiemConfig", &[TestStruct {
+            name: "DiemConfig",
+            is_resource: true,
+            type_parameters: vec![Kind::All],
+            fields: vec![("payload", SignatureToken::TypeParameter(0))],
+        }])).await;
+        db.publish(&test_util::module_bytes_at(CORE_CODE_ADDRESS, "DiemSystem", &[
+            TestStruct::resource("DiemSystem", vec![("validators", test_util::vector_token(test_util::struct_token(1)))]),
+            TestStruct::plain("ValidatorInfo", vec![
+                ("addr", SignatureToken::Address),
+                ("consensus_voting_power", SignatureToken::U64),
+                ("config", test_util::struct_token(2)),
+                ("last_config_update_time", SignatureToken::U64),
+            ]),
+            TestStruct::plain("ValidatorConfig", vec![
+                ("consensus_pubkey", bytes_token()),
+                ("validator_network_addresses", bytes_token()),
+                ("fullnode_network_addresses", bytes_token()),
+            ]),
+        ])).await;
+        let system = StructTag {
+            address: CORE_CODE_ADDRESS,
+            module: Identifier::new("DiemSystem").unwrap(),
+            name: Identifier::new("DiemSystem").unwrap(),
+            type_params: vec![],
+        };
+        let tag = StructTag {
+            address: CORE_CODE_ADDRESS,
+            module: Identifier::new("DiemConfig").unwrap(),
+            name: Identifier::new("DiemConfig").unwrap(),
+            type_params: vec![TypeTag::Struct(system)],
+        };
+        let bytes = |b: u8| MoveValue::Vector(vec![MoveValue::U8(b)]);
+        let validator = |address: AccountAddress, power: u64| MoveValue::Struct(MoveStruct::new(vec![
+            MoveValue::Address(address),
+            MoveValue::U64(power),
+            MoveValue::Struct(MoveStruct::new(vec![bytes(1), bytes(2), bytes(3)])),
+            MoveValue::U64(0),
+        ]));
+        let set = |validators| MoveStruct::new(vec![
+            MoveValue::Struct(MoveStruct::new(vec![MoveValue::Vector(validators)])),
+        ]);
+        let select_sql = format!(
+            "SELECT version, address, consensus_voting_power FROM {} ORDER BY address",
+            db.config.validators_table_name(),
+        );
+        let rows = || async {
+            sqlx::query(&select_sql)
+                .fetch_all(&db.pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| (row.get::<i64, _>(0), row.get::<Vec<u8>, _>(1), row.get::<i64, _>(2)))
+                .collect::<Vec<_>>()
+        };
+        let (first, second) = (AccountAddress::new([1; AccountAddress::LENGTH]), AccountAddress::new([2; AccountAddress::LENGTH]));
+
+        db.store(1, &tag, set(vec![validator(first, 10), validator(second, 20)])).await;
+        assert_eq!(rows().await, vec![(1, first.to_vec(), 10), (1, second.to_vec(), 20)]);
+
+        // a new set replaces the old one, including validators that left it
+        db.store(2, &tag, set(vec![validator(second, 30)])).await;
+        assert_eq!(rows().await, vec![(2, second.to_vec(), 30)]);
+    }
+
+    #[tokio::test]
+    async fn auth_keys_stored_as_blobs_are_read_with_an_error() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("Account", vec![
+            ("authentication_key", test_util::vector_token(SignatureToken::U8)),
+        ])])).await;
+        let tag = test_util::struct_tag("M", "Account", vec![]);
+        let key = (0..AUTH_KEY_LENGTH).map(|_| MoveValue::U8(0xab)).collect();
+        db.store(1, &tag, MoveStruct::new(vec![MoveValue::Vector(key)])).await;
+
+        // the same database read as if it had been indexed with the flag
+        let config = Arc::new(Config { auth_keys_as_hex: true, ..test_util::config() });
+        let reading = DB::from_pool(db.pool.clone(), config, db.modules.clone());
+        let error = reading.read_resource(&ADDRESS, &tag).await.unwrap_err().to_string();
+        assert!(error.contains("column authentication_key"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn buffered_writes_flush_on_row_count() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        db.db.buffer_writes(None, Some(2));
+        let mut sync_state = db.db.sync_state().await;
+
+        // two writes to one path are one row
+        db.store(1, &tag, counter(1)).await;
+        db.store(2, &tag, counter(2)).await;
+        sync_state.next_version = 3;
+        db.db.save_sync_state(&sync_state).await.unwrap();
+        assert_eq!(read_counter(&db, &tag).await, None);
+        assert_eq!(db.db.sync_state().await.next_version, 0);
+
+        let other = AccountAddress::new([1; AccountAddress::LENGTH]);
+        let op = WriteOp::Value(bcs::to_bytes(&counter(3)).unwrap());
+        db.write(3, other, Path::Resource(tag.clone()), op).await;
+        sync_state.next_version = 4;
+        db.db.save_sync_state(&sync_state).await.unwrap();
+        assert_eq!(read_counter(&db, &tag).await, Some(2));
+        assert_eq!(db.db.read_field_u64(&other, &tag, "value").await.unwrap(), Some(3));
+        assert_eq!(db.db.sync_state().await.next_version, 4);
+        assert!(db.db.pending_writes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn buffered_writes_flush_on_interval() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&counter_module()).await;
+        let interval = Duration::from_millis(200);
+        db.db.buffer_writes(Some(interval), None);
+        let mut sync_state = db.db.sync_state().await;
+
+        db.store(1, &tag, counter(1)).await;
+        sync_state.next_version = 2;
+        db.db.save_sync_state(&sync_state).await.unwrap();
+        assert_eq!(read_counter(&db, &tag).await, None);
+        assert_eq!(db.db.pending_writes().len(), 1);
+
+        tokio::time::sleep(interval).await;
+        db.db.save_sync_state(&sync_state).await.unwrap();
+        assert_eq!(read_counter(&db, &tag).await, Some(1));
+        assert_eq!(db.db.sync_state().await.next_version, 2);
+    }
+
+    #[tokio::test]
+    async fn generic_instantiations_are_stored_apart() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::module_bytes("G", &[
+            TestStruct {
+                name: "Box",
+                is_resource: true,
+                type_parameters: vec![Kind::All],
+                fields: vec![("item", SignatureToken::TypeParameter(0))],
+            },
+            TestStruct::plain("Item", vec![("value", SignatureToken::U64)]),
+        ])).await;
+        let item = TypeTag::Struct(test_util::struct_tag("G", "Item", vec![]));
+        let of_u64 = test_util::struct_tag("G", "Box", vec![TypeTag::U64]);
+        let of_item = test_util::struct_tag("G", "Box", vec![item.clone()]);
+        let of_items = test_util::struct_tag("G", "Box", vec![TypeTag::Vector(Box::new(item))]);
+        let item_value = |value| MoveValue::Struct(MoveStruct::new(vec![MoveValue::U64(value)]));
+        db.store(1, &of_u64, counter(1)).await;
+        db.store(1, &of_item, MoveStruct::new(vec![item_value(2)])).await;
+        db.store(1, &of_items, MoveStruct::new(vec![MoveValue::Vector(vec![item_value(3), item_value(4)])])).await;
+
+        let tags = [&of_u64, &of_item, &of_items];
+        let tables = tags.iter().map(|tag| struct_tag_to_sql(&db.config, tag)).collect::<HashSet<_>>();
+        assert_eq!(tables.len(), tags.len());
+        for tag in &tags {
+            assert_eq!(count_rows(&db, &root_table_name(&db.config, tag)).await, 1);
+            assert_eq!(struct_tag_from_name(&struct_tag_name(tag)).as_ref(), Some(*tag));
+        }
+        assert_eq!(read_counter(&db, &of_u64).await, Some(1));
+        // the values of the items a `Box` holds
+        async fn read_items(db: &TestDb, tag: &StructTag) -> Vec<String> {
+            let resource = db.db.read_resource(&ADDRESS, tag).await.unwrap().unwrap();
+            let item_value = |item: &AnnotatedMoveValue| match item {
+                AnnotatedMoveValue::Struct(item) => format!("{:?}", item.value[0].1),
+                other => panic!("unexpected item {:?}", other),
+            };
+            match &resource.value[0].1 {
+                AnnotatedMoveValue::Vector(_, items) => items.iter().map(item_value).collect(),
+                item => vec![item_value(item)],
+            }
+        }
+        assert_eq!(read_items(&db, &of_item).await, vec!["U64(2)"]);
+        assert_eq!(read_items(&db, &of_items).await, vec!["U64(3)", "U64(4)"]);
+
+        // addresses are written in full, so the names of instantiations over
+        // types at addresses like 0x1 and 0x10 can't run into each other
+        let names = ["0x1", "0x10", "0x100"]
+            .iter()
+            .map(|address| {
+                let tag = StructTag {
+                    address: parser::parse_address(address).unwrap(),
+                    module: Identifier::new("A").unwrap(),
+                    name: Identifier::new("B").unwrap(),
+                    type_params: vec![],
+                };
+                struct_tag_name(&test_util::struct_tag("G", "Box", vec![TypeTag::Struct(tag)]))
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(names.len(), 3);
+        assert!(names.iter().all(|name| name.len() == names.iter().next().unwrap().len()), "{:?}", names);
+    }
+
+    #[tokio::test]
+    async fn scalar_options_are_nullable_columns() {
+        let db = TestDb::new(test_util::config()).await;
+        // `0x1::Option` and a resource holding an `Option<u64>`
+        let option_of_u64 = SignatureToken::StructInstantiation(StructHandleIndex(0), vec![SignatureToken::U64]);
+        db.publish(&test_util::module_bytes_at(CORE_CODE_ADDRESS, "Option", &[
+            TestStruct {
+                name: "Option",
+                is_resource: false,
+                type_parameters: vec![Kind::All],
+                fields: vec![("vec", test_util::vector_token(SignatureToken::TypeParameter(0)))],
+            },
+            TestStruct::resource("Holder", vec![("value", option_of_u64)]),
+        ])).await;
+        let tag = StructTag {
+            address: CORE_CODE_ADDRESS,
+            module: Identifier::new("Option").unwrap(),
+            name: Identifier::new("Holder").unwrap(),
+            type_params: vec![],
+        };
+        let none_address = AccountAddress::new([1; AccountAddress::LENGTH]);
+        let holder = |value: Vec<MoveValue>| {
+            let option = MoveValue::Struct(MoveStruct::new(vec![MoveValue::Vector(value)]));
+            WriteOp::Value(bcs::to_bytes(&MoveStruct::new(vec![option])).unwrap())
+        };
+        db.write(1, ADDRESS, Path::Resource(tag.clone()), holder(vec![MoveValue::U64(42)])).await;
+        db.write(1, none_address, Path::Resource(tag.clone()), holder(vec![])).await;
+
+        let select_sql = format!("SELECT value FROM {} ORDER BY __id", struct_tag_to_sql(&db.config, &tag));
+        let values = sqlx::query(&select_sql)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<Option<i64>, _>(0))
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![Some(42), None]);
+
+        // and both read back as the options they were
+        for (address, expected) in &[(ADDRESS, vec![42]), (none_address, vec![])] {
+            let resource = db.db.read_resource(address, &tag).await.unwrap().unwrap();
+            let option = match &resource.value[0].1 {
+                AnnotatedMoveValue::Struct(option) => option,
+                other => panic!("unexpected value {:?}", other),
+            };
+            let elements = match &option.value[0].1 {
+                AnnotatedMoveValue::Vector(_, elements) => elements
+                    .iter()
+                    .map(|element| match element {
+                        AnnotatedMoveValue::U64(v) => *v,
+                        other => panic!("unexpected element {:?}", other),
+                    })
+                    .collect::<Vec<_>>(),
+                other => panic!("unexpected option {:?}", other),
+            };
+            assert_eq!(&elements, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn tables_created_concurrently_are_checked() {
+        // as a `DB` of its own would, with no tables created yet
+        async fn ensure(db: &TestDb, name: &str, columns: &[&str]) -> Result<()> {
+            let columns = columns.iter().map(|column| column.to_string()).collect::<Vec<_>>();
+            let mut conn = db.pool.acquire().await?;
+            ensure_table(&db.config, &CreatedTables::default(), &name.to_string(), &columns, &mut conn).await
+        }
+        let db = TestDb::new(test_util::config()).await;
+        let value = ["__id INTEGER PRIMARY KEY", "value INTEGER NOT NULL"];
+        let amount = ["__id INTEGER PRIMARY KEY", "amount INTEGER NOT NULL"];
+
+        let (first, second) = tokio::join!(ensure(&db, "same", &value), ensure(&db, "same", &value));
+        first.unwrap();
+        second.unwrap();
+        // whichever comes second finds the other's columns
+        let (first, second) = tokio::join!(ensure(&db, "different", &value), ensure(&db, "different", &amount));
+        assert!(first.is_ok() != second.is_ok(), "{:?} {:?}", first, second);
+        let error = first.err().or(second.err()).unwrap().to_string();
+        assert!(error.contains("does not match the current schema"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn reordered_fields_keep_their_columns() {
+        let mut db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let owner = AccountAddress::new([7; AccountAddress::LENGTH]);
+        let value = ("value", SignatureToken::U64);
+        let owner_field = ("owner", SignatureToken::Address);
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![value.clone(), owner_field.clone()])])).await;
+        db.store(1, &tag, MoveStruct::new(vec![MoveValue::U64(1), MoveValue::Address(owner)])).await;
+        let pragma_sql = format!("PRAGMA table_info({})", struct_tag_to_sql(&db.config, &tag));
+        let columns = sqlx::query(&pragma_sql)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(1))
+            .collect::<Vec<_>>();
+
+        // an upgrade declaring the same fields the other way round, written
+        // by a `DB` opened afterwards, which checks the existing table anew
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![owner_field, value])])).await;
+        db.db = DB::from_pool(db.pool.clone(), db.config.clone(), db.modules.clone());
+        db.store(2, &tag, MoveStruct::new(vec![MoveValue::Address(owner), MoveValue::U64(2)])).await;
+
+        let reread = sqlx::query(&pragma_sql)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(1))
+            .collect::<Vec<_>>();
+        assert_eq!(reread, columns);
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(2));
+        let resource = db.db.read_resource(&ADDRESS, &tag).await.unwrap().unwrap();
+        let fields = resource.value.iter().map(|(name, value)| (name.as_str(), value)).collect::<Vec<_>>();
+        assert_eq!(fields, vec![("owner", &AnnotatedMoveValue::Address(owner)), ("value", &AnnotatedMoveValue::U64(2))]);
+    }
+
+    #[tokio::test]
+    async fn addresses_are_written_and_queried_as_full_blobs() {
+        // internal and trailing zero bytes, which a short form would drop
+        let mut bytes = [0; AccountAddress::LENGTH];
+        bytes[3] = 1;
+        bytes[8] = 2;
+        let address = AccountAddress::new(bytes);
+        let literal = format!("x'{}'", hex::encode(address));
+        for history in [false, true].iter().copied() {
+            let db = TestDb::new(Config { history, ..test_util::config() }).await;
+            let tag = test_util::struct_tag("A", "R", vec![]);
+            let inner_tag = test_util::struct_tag("A", "Inner", vec![]);
+            db.publish(&test_util::module_bytes("A", &[
+                TestStruct::resource("R", vec![
+                    ("owner", SignatureToken::Address),
+                    ("inner", test_util::struct_token(1)),
+                    ("owners", test_util::vector_token(SignatureToken::Address)),
+                ]),
+                TestStruct::plain("Inner", vec![("owner", SignatureToken::Address)]),
+            ]))
+            .await;
+            let value = MoveStruct::new(vec![
+                MoveValue::Address(address),
+                MoveValue::Struct(MoveStruct::new(vec![MoveValue::Address(address)])),
+                MoveValue::Vector(vec![MoveValue::Address(address)]),
+            ]);
+            let op = WriteOp::Value(bcs::to_bytes(&value).unwrap());
+            db.write(1, address, Path::Resource(tag.clone()), op).await;
+
+            // every kind of table holds the address as the same literal
+            let owners = vector_table_name(&db.config, &tag, &Identifier::new("owners").unwrap());
+            for (table, column) in [
+                (root_table_name(&db.config, &tag), "address"),
+                (struct_tag_to_sql(&db.config, &tag), "owner"),
+                (struct_tag_to_sql(&db.config, &inner_tag), "owner"),
+                (owners, "slot"),
+            ]
+            .iter()
+            {
+                let select_sql = format!("SELECT COUNT(*) FROM {} WHERE {} = {}", table, column, literal);
+                let count: i64 = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap().get(0);
+                assert_eq!(count, 1, "{}.{} with history {}", table, column, history);
+            }
+
+            // and each way of reading it back finds it
+            let annotator = db.annotator();
+            let raw = bcs::to_bytes(&value).unwrap();
+            let expected = annotator.view_resource(&tag, &raw).await.unwrap();
+            assert_eq!(db.db.read_resource(&address, &tag).await.unwrap(), Some(expected));
+            let expected = annotator.view_resource(&tag, &raw).await.unwrap();
+            assert_eq!(db.db.read_account(&address).await.unwrap(), vec![expected]);
+            let expected = annotator.view_resource(&tag, &raw).await.unwrap();
+            let found = db.db.query_resources(&tag, &[("owner", FilterOp::Eq, MoveValue::Address(address))]).await.unwrap();
+            assert_eq!(found, vec![(address, expected)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn addresses_of_the_wrong_width_are_rejected_on_read() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("A", "R", vec![]);
+        db.publish(&test_util::module_bytes("A", &[TestStruct::resource("R", vec![
+            ("owner", SignatureToken::Address),
+            ("owners", test_util::vector_token(SignatureToken::Address)),
+        ])]))
+        .await;
+        let value = MoveStruct::new(vec![
+            MoveValue::Address(test_util::ADDRESS),
+            MoveValue::Vector(vec![MoveValue::Address(test_util::ADDRESS)]),
+        ]);
+        db.write(1, test_util::ADDRESS, Path::Resource(tag.clone()), WriteOp::Value(bcs::to_bytes(&value).unwrap())).await;
+
+        let owners = vector_table_name(&db.config, &tag, &Identifier::new("owners").unwrap());
+        for (table, column) in [(owners, "slot"), (struct_tag_to_sql(&db.config, &tag), "owner")].iter() {
+            let update_sql = format!("UPDATE {} SET {} = x'0102'", table, column);
+            sqlx::query(&update_sql).execute(&db.pool).await.unwrap();
+            let error = db.db.read_resource(&test_util::ADDRESS, &tag).await.unwrap_err().to_string();
+            let expected = format!("{} column {}: stored address 0102 is 2 bytes wide", table, column);
+            assert!(error.starts_with(&expected), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn vectors_of_partial_elements_are_rejected_on_read() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("A", "R", vec![]);
+        db.publish(&test_util::module_bytes("A", &[TestStruct::resource("R", vec![
+            ("amounts", test_util::vector_token(SignatureToken::U64)),
+            ("nested", test_util::vector_token(test_util::vector_token(SignatureToken::U128))),
+        ])]))
+        .await;
+        let value = MoveStruct::new(vec![
+            MoveValue::Vector(vec![MoveValue::U64(1)]),
+            MoveValue::Vector(vec![MoveValue::Vector(vec![MoveValue::U128(1)])]),
+        ]);
+        db.write(1, test_util::ADDRESS, Path::Resource(tag.clone()), WriteOp::Value(bcs::to_bytes(&value).unwrap())).await;
+
+        let nested = vector_table_name(&db.config, &tag, &Identifier::new("nested").unwrap());
+        let cases = [(nested, "slot", 16), (struct_tag_to_sql(&db.config, &tag), "amounts", 8)];
+        for (table, column, width) in cases.iter() {
+            let update_sql = format!("UPDATE {} SET {} = x'010203'", table, column);
+            sqlx::query(&update_sql).execute(&db.pool).await.unwrap();
+            let error = db.db.read_resource(&test_util::ADDRESS, &tag).await.unwrap_err().to_string();
+            let expected = format!("{} column {}: vector of {} byte elements is stored in 3 bytes", table, column, width);
+            assert!(error.starts_with(&expected), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn fields_named_like_keywords_round_trip() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("K", "R", vec![]);
+        db.publish(&test_util::module_bytes("K", &[
+            TestStruct::resource("R", vec![
+                ("order", SignatureToken::U64),
+                ("index", test_util::struct_token(1)),
+                ("group", test_util::vector_token(test_util::struct_token(1))),
+            ]),
+            TestStruct::plain("Select", vec![("where", SignatureToken::Bool)]),
+        ]))
+        .await;
+        let value = |order, flags: Vec<bool>| {
+            let select = |flag| MoveValue::Struct(MoveStruct::new(vec![MoveValue::Bool(flag)]));
+            MoveStruct::new(vec![MoveValue::U64(order), select(true), MoveValue::Vector(flags.into_iter().map(select).collect())])
+        };
+        let annotator = db.annotator();
+
+        // written afresh, then diffed against what was written
+        for (version, order, flags) in vec![(1, 7, vec![true, false]), (2, 8, vec![false])] {
+            db.store(version, &tag, value(order, flags.clone())).await;
+            let raw = bcs::to_bytes(&value(order, flags)).unwrap();
+            let expected = annotator.view_resource(&tag, &raw).await.unwrap();
+            assert_eq!(db.db.read_resource(&ADDRESS, &tag).await.unwrap(), Some(expected));
+        }
+        let found = db.db.query_resources(&tag, &[("order", FilterOp::Eq, MoveValue::U64(8))]).await.unwrap();
+        assert_eq!(found.len(), 1);
+        let found = db.db.query_resources(&tag, &[("order", FilterOp::Eq, MoveValue::U64(7))]).await.unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn incremental_vacuum_frees_pages() {
+        let db = TestDb::new(test_util::config()).await;
+        assert!(!db.db.is_incremental_vacuum().await);
+        // the mode of an existing file only changes when it is rebuilt
+        sqlx::query("PRAGMA auto_vacuum = INCREMENTAL").execute(&db.pool).await.unwrap();
+        db.db.vacuum().await;
+        assert!(db.db.is_incremental_vacuum().await);
+
+        sqlx::query("CREATE TABLE scratch (data BLOB)").execute(&db.pool).await.unwrap();
+        for _ in 0..10 {
+            sqlx::query("INSERT INTO scratch VALUES (zeroblob(65536))").execute(&db.pool).await.unwrap();
+        }
+        sqlx::query("DROP TABLE scratch").execute(&db.pool).await.unwrap();
+        let pool = &db.pool;
+        let free_pages = || async move {
+            sqlx::query("PRAGMA freelist_count").fetch_one(pool).await.unwrap().get::<i64, _>(0)
+        };
+        let before = free_pages().await;
+        assert!(before > 10, "{} free pages", before);
+
+        db.db.incremental_vacuum(10).await;
+        assert_eq!(free_pages().await, before - 10);
+        db.db.incremental_vacuum(u32::MAX).await;
+        assert_eq!(free_pages().await, 0);
+    }
+
+    /// A module `N` with a resource `R { groups: vector<vector<Item>>, totals: vector<u64> }`
+    /// and `Item { value: u64, owners: vector<address> }`.
+    fn nested_module() -> Vec<u8> {
+        test_util::module_bytes("N", &[
+            TestStruct::resource("R", vec![
+                ("groups", test_util::vector_token(test_util::vector_token(test_util::struct_token(1)))),
+                ("totals", test_util::vector_token(SignatureToken::U64)),
+            ]),
+            TestStruct::plain("Item", vec![
+                ("value", SignatureToken::U64),
+                ("owners", test_util::vector_token(SignatureToken::Address)),
+            ]),
+        ])
+    }
+
+    fn nested(groups: Vec<Vec<(u64, usize)>>, totals: Vec<u64>) -> MoveStruct {
+        let item = |(value, owners): (u64, usize)| {
+            MoveValue::Struct(MoveStruct::new(vec![
+                MoveValue::U64(value),
+                MoveValue::Vector(vec![MoveValue::Address(ADDRESS); owners]),
+            ]))
+        };
+        MoveStruct::new(vec![
+            MoveValue::Vector(groups.into_iter().map(|group| MoveValue::Vector(group.into_iter().map(item).collect())).collect()),
+            MoveValue::Vector(totals.into_iter().map(MoveValue::U64).collect()),
+        ])
+    }
+
+    async fn count_rows(db: &TestDb, table_name: &str) -> i64 {
+        let select_sql = format!("SELECT COUNT(*) FROM {}", table_name);
+        sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap().get(0)
+    }
+
+    #[tokio::test]
+    async fn diffing_nested_vectors_leaves_no_rows_behind() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("N", "R", vec![]);
+        let item_tag = test_util::struct_tag("N", "Item", vec![]);
+        db.publish(&nested_module()).await;
+        db.store(1, &tag, nested(vec![vec![(1, 1), (2, 0)], vec![(3, 2)]], vec![1, 2])).await;
+        let groups = vector_table_name(&db.config, &tag, &Identifier::new("groups").unwrap());
+        let owners = vector_table_name(&db.config, &item_tag, &Identifier::new("owners").unwrap());
+        assert_eq!(count_rows(&db, &groups).await, 2);
+        assert_eq!(count_rows(&db, &elements_table_name(&groups)).await, 3);
+        assert_eq!(count_rows(&db, &struct_tag_to_sql(&db.config, &item_tag)).await, 3);
+        assert_eq!(count_rows(&db, &owners).await, 3);
+
+        let value = nested(vec![vec![(4, 1)]], vec![3]);
+        db.store(2, &tag, value.clone()).await;
+        let expected = db.annotator().view_resource(&tag, &bcs::to_bytes(&value).unwrap()).await.unwrap();
+        assert_eq!(db.db.read_resource(&ADDRESS, &tag).await.unwrap(), Some(expected));
+        assert_eq!(count_rows(&db, &groups).await, 1);
+        assert_eq!(count_rows(&db, &elements_table_name(&groups)).await, 1);
+        assert_eq!(count_rows(&db, &struct_tag_to_sql(&db.config, &item_tag)).await, 1);
+        assert_eq!(count_rows(&db, &owners).await, 1);
+    }
+
+    #[tokio::test]
+    async fn rolling_back_leaves_no_rows_behind() {
+        let db = TestDb::new(Config { history: true, ..test_util::config() }).await;
+        let tag = test_util::struct_tag("N", "R", vec![]);
+        let item_tag = test_util::struct_tag("N", "Item", vec![]);
+        db.publish(&nested_module()).await;
+        let groups = vector_table_name(&db.config, &tag, &Identifier::new("groups").unwrap());
+        let tables = [
+            root_table_name(&db.config, &tag),
+            struct_tag_to_sql(&db.config, &tag),
+            groups.clone(),
+            elements_table_name(&groups),
+            struct_tag_to_sql(&db.config, &item_tag),
+            vector_table_name(&db.config, &item_tag, &Identifier::new("owners").unwrap()),
+        ];
+        let (db_ref, tables) = (&db, &tables);
+        let counts = || async move {
+            let mut counts = vec![];
+            for table in tables {
+                counts.push(count_rows(db_ref, table).await);
+            }
+            counts
+        };
+
+        db.store(1, &tag, nested(vec![vec![(1, 1)]], vec![1])).await;
+        let kept = counts().await;
+        db.store(2, &tag, nested(vec![vec![(2, 2), (3, 0)], vec![(4, 1)]], vec![2])).await;
+        db.store(3, &tag, nested(vec![vec![(5, 3)]], vec![3])).await;
+
+        db.db.rollback(2).await.unwrap();
+        assert_eq!(counts().await, kept);
+        let value = nested(vec![vec![(1, 1)]], vec![1]);
+        let expected = db.annotator().view_resource(&tag, &bcs::to_bytes(&value).unwrap()).await.unwrap();
+        assert_eq!(db.db.read_resource(&ADDRESS, &tag).await.unwrap(), Some(expected));
+
+        // without history there is nothing earlier to go back to
+        let db = TestDb::new(test_util::config()).await;
+        assert!(db.db.rollback(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn struct_meta_follows_type_parameter_kinds() {
+        let db = TestDb::new(test_util::config()).await;
+        let generic = |name, kind| TestStruct {
+            name,
+            is_resource: false,
+            type_parameters: vec![kind],
+            fields: vec![("value", SignatureToken::TypeParameter(0))],
+        };
+        db.publish(&test_util::module_bytes("K", &[
+            TestStruct::resource("R", vec![("value", SignatureToken::U64)]),
+            TestStruct::plain("P", vec![("value", SignatureToken::U64)]),
+            generic("Any", Kind::All),
+            generic("OfResource", Kind::Resource),
+        ])).await;
+
+        let select_sql = format!("SELECT name, is_resource, abilities FROM {} ORDER BY name", db.config.struct_meta_table_name());
+        let rows = sqlx::query(&select_sql)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.get::<String, _>(0), row.get::<bool, _>(1), row.get::<String, _>(2)))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![
+            ("Any".to_string(), false, "copy,drop,store".to_string()),
+            ("OfResource".to_string(), false, "store".to_string()),
+            ("P".to_string(), false, "copy,drop,store".to_string()),
+            ("R".to_string(), true, "key,store".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn fields_are_read_through_sub_structs() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::module_bytes("F", &[
+            TestStruct::resource("R", vec![("value", SignatureToken::U64), ("inner", test_util::struct_token(1))]),
+            TestStruct::plain("Inner", vec![
+                ("amount", SignatureToken::U128),
+                ("frozen", SignatureToken::Bool),
+                ("owner", SignatureToken::Address),
+            ]),
+        ])).await;
+        let tag = test_util::struct_tag("F", "R", vec![]);
+        let owner = AccountAddress::new([7; AccountAddress::LENGTH]);
+        db.store(1, &tag, MoveStruct::new(vec![
+            MoveValue::U64(3),
+            MoveValue::Struct(MoveStruct::new(vec![MoveValue::U128(u128::MAX), MoveValue::Bool(true), MoveValue::Address(owner)])),
+        ])).await;
+
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(3));
+        assert_eq!(db.db.read_field_u128(&ADDRESS, &tag, "inner.amount").await.unwrap(), Some(u128::MAX));
+        assert_eq!(db.db.read_field_bool(&ADDRESS, &tag, "inner.frozen").await.unwrap(), Some(true));
+        assert_eq!(db.db.read_field_address(&ADDRESS, &tag, "inner.owner").await.unwrap(), Some(owner));
+        // nothing stored at another address
+        assert_eq!(db.db.read_field_u64(&owner, &tag, "value").await.unwrap(), None);
+
+        let error = db.db.read_field_u64(&ADDRESS, &tag, "inner.amount").await.unwrap_err().to_string();
+        assert!(error.contains("amount"), "{}", error);
+        let error = db.db.read_field_u64(&ADDRESS, &tag, "inner.missing").await.unwrap_err().to_string();
+        assert!(error.contains("has no field missing"), "{}", error);
+    }
+
+    /// The type of a field for `structs_round_trip` to declare.
+    #[derive(Clone, Debug)]
+    enum FieldType {
+        Bool,
+        U8,
+        U64,
+        U128,
+        Address,
+        Vector(Box<FieldType>),
+        /// A struct of its own with fields of these types.
+        Struct(Vec<FieldType>),
+    }
+
+    const FIELD_NAMES: [&str; 3] = ["a", "b", "c"];
+    const STRUCT_NAMES: [&str; 12] = ["R", "S1", "S2", "S3", "S4", "S5", "S6", "S7", "S8", "S9", "S10", "S11"];
+
+    fn field_types() -> impl Strategy<Value = Vec<FieldType>> {
+        let leaf = prop_oneof![
+            Just(FieldType::Bool),
+            Just(FieldType::U8),
+            Just(FieldType::U64),
+            Just(FieldType::U128),
+            Just(FieldType::Address),
+        ];
+        let field_type = leaf.prop_recursive(3, 12, 3, |inner| {
+            prop_oneof![
+                inner.clone().prop_map(|elem| FieldType::Vector(Box::new(elem))),
+                prop::collection::vec(inner, 1..=FIELD_NAMES.len()).prop_map(FieldType::Struct),
+            ]
+        });
+        prop::collection::vec(field_type, 1..=FIELD_NAMES.len())
+            .prop_filter("too many structs to name", |fields| struct_count(fields) < STRUCT_NAMES.len())
+    }
+
+    fn struct_count(fields: &[FieldType]) -> usize {
+        fields
+            .iter()
+            .map(|field| {
+                let mut field = field;
+                while let FieldType::Vector(elem) = field {
+                    field = elem;
+                }
+                match field {
+                    FieldType::Struct(fields) => 1 + struct_count(fields),
+                    _ => 0,
+                }
+            })
+            .sum()
+    }
+
+    fn value(ty: &FieldType) -> BoxedStrategy<MoveValue> {
+        match ty {
+            FieldType::Bool => any::<bool>().prop_map(MoveValue::Bool).boxed(),
+            FieldType::U8 => any::<u8>().prop_map(MoveValue::U8).boxed(),
+            FieldType::U64 => any::<u64>().prop_map(MoveValue::U64).boxed(),
+            FieldType::U128 => any::<u128>().prop_map(MoveValue::U128).boxed(),
+            FieldType::Address => any::<[u8; AccountAddress::LENGTH]>()
+                .prop_map(|bytes| MoveValue::Address(AccountAddress::new(bytes)))
+                .boxed(),
+            FieldType::Vector(elem) => prop::collection::vec(value(elem), 0..4).prop_map(MoveValue::Vector).boxed(),
+            FieldType::Struct(fields) => fields
+                .iter()
+                .map(value)
+                .collect::<Vec<_>>()
+                .prop_map(|values| MoveValue::Struct(MoveStruct::new(values)))
+                .boxed(),
+        }
+    }
+
+    /// Declare a struct with `fields`, after which every struct nested in
+    /// them is declared in turn.
+    fn declare(fields: &[FieldType], structs: &mut Vec<TestStruct>) {
+        let index = structs.len();
+        structs.push(TestStruct::plain(STRUCT_NAMES[index], vec![]));
+        let tokens = fields
+            .iter()
+            .zip(FIELD_NAMES.iter())
+            .map(|(field, name)| (*name, field_token(field, structs)))
+            .collect();
+        structs[index].fields = tokens;
+    }
+
+    fn field_token(field: &FieldType, structs: &mut Vec<TestStruct>) -> SignatureToken {
+        match field {
+            FieldType::Bool => SignatureToken::Bool,
+            FieldType::U8 => SignatureToken::U8,
+            FieldType::U64 => SignatureToken::U64,
+            FieldType::U128 => SignatureToken::U128,
+            FieldType::Address => SignatureToken::Address,
+            FieldType::Vector(elem) => test_util::vector_token(field_token(elem, structs)),
+            FieldType::Struct(fields) => {
+                let index = structs.len();
+                declare(fields, structs);
+                test_util::struct_token(index)
+            },
+        }
+    }
+
+    /// Write a resource `R` with `fields` and the given value with
+    /// `struct_to_sql`, then check `fetch_struct` reads back the same value,
+    /// which annotates to the same struct.
+    async fn round_trip(fields: &[FieldType], value: MoveValue) {
+        let mut structs = vec![];
+        declare(fields, &mut structs);
+        structs[0].is_resource = true;
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::module_bytes("P", &structs)).await;
+        let tag = test_util::struct_tag("P", "R", vec![]);
+        let annotator = db.annotator();
+        let annotated = annotator.view_resource(&tag, &bcs::to_bytes(&value).unwrap()).await.unwrap();
+
+        let mut conn = db.pool.acquire().await.unwrap();
+        let id = struct_to_sql(&db.config, &CreatedTables::default(), &annotated, &mut conn).await.unwrap();
+        let read = fetch_struct(&db.config, &tag, id, &db.resolver(), &mut conn).await.unwrap();
+        assert_eq!(read, Some(value));
+        let read = match read {
+            Some(MoveValue::Struct(read)) => read,
+            other => panic!("read back {:?}", other),
+        };
+        let struct_ = db.resolver().resolve_struct(&tag).await.unwrap();
+        assert_eq!(annotator.annotate_struct(&read, &struct_).await.unwrap(), annotated);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn structs_round_trip(
+            (fields, resource) in field_types().prop_flat_map(|fields| {
+                let resource = value(&FieldType::Struct(fields.clone()));
+                (Just(fields), resource)
+            })
+        ) {
+            tokio::runtime::Runtime::new().unwrap().block_on(round_trip(&fields, resource));
+        }
+    }
 }
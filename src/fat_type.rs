@@ -61,6 +61,18 @@ impl FatStructType {
         })
     }
 
+    /// The abilities this instantiation would have in Move versions that
+    /// have them. See `abilities`.
+    pub fn abilities(&self) -> &'static [&'static str] {
+        abilities(self.is_resource, self.ty_args.iter().any(FatType::is_resource_kind))
+    }
+
+    /// Whether values of this type can't be copied or dropped: it is either
+    /// declared a resource, or instantiated with one.
+    pub fn is_resource_kind(&self) -> bool {
+        self.is_resource || self.ty_args.iter().any(FatType::is_resource_kind)
+    }
+
     pub fn struct_tag(&self) -> PartialVMResult<StructTag> {
         let ty_args = self
             .ty_args
@@ -76,6 +88,23 @@ impl FatStructType {
     }
 }
 
+/// The bytecode this tool reads predates abilities and only marks structs as
+/// resources or not. Resources could be held in global storage and inside
+/// other resources but never copied or dropped, which is `key` and `store`.
+/// Other structs could be copied, dropped, and nested, which is `copy`,
+/// `drop`, and `store`, unless a type argument is a resource: that makes the
+/// struct as restricted as its argument, but it still can't be held in global
+/// storage on its own, which leaves only `store`.
+pub fn abilities(is_resource: bool, has_resource_args: bool) -> &'static [&'static str] {
+    if is_resource {
+        &["key", "store"]
+    } else if has_resource_args {
+        &["store"]
+    } else {
+        &["copy", "drop", "store"]
+    }
+}
+
 impl FatType {
     pub fn subst(&self, ty_args: &[FatType]) -> PartialVMResult<FatType> {
         use FatType::*;
@@ -107,6 +136,16 @@ impl FatType {
         Ok(res)
     }
 
+    /// Whether values of this type can't be copied or dropped. Type
+    /// parameters are only known once substituted, so count as neither.
+    pub fn is_resource_kind(&self) -> bool {
+        match self {
+            FatType::Vector(ty) => ty.is_resource_kind(),
+            FatType::Struct(struct_ty) => struct_ty.is_resource_kind(),
+            FatType::Bool | FatType::U8 | FatType::U64 | FatType::U128 | FatType::Address | FatType::TyParam(_) => false,
+        }
+    }
+
     pub fn type_tag(&self) -> PartialVMResult<TypeTag> {
         use FatType::*;
 
@@ -164,3 +203,37 @@ impl TryInto<MoveTypeLayout> for &FatType {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn struct_type(name: &str, is_resource: bool, ty_args: Vec<FatType>) -> FatStructType {
+        FatStructType {
+            address: AccountAddress::new([1; AccountAddress::LENGTH]),
+            module: Identifier::new("M").unwrap(),
+            name: Identifier::new(name).unwrap(),
+            is_resource,
+            ty_args,
+            fields: vec![],
+        }
+    }
+
+    #[test]
+    fn abilities_follow_the_instantiation() {
+        let resource = struct_type("R", true, vec![]);
+        let plain = struct_type("P", false, vec![]);
+        assert_eq!(resource.abilities(), &["key", "store"]);
+        assert_eq!(plain.abilities(), &["copy", "drop", "store"]);
+        assert_eq!(struct_type("G", false, vec![FatType::U64]).abilities(), &["copy", "drop", "store"]);
+        assert_eq!(struct_type("G", false, vec![FatType::Struct(Box::new(plain))]).abilities(), &["copy", "drop", "store"]);
+
+        // a resource anywhere among the type arguments restricts the struct
+        let of_resource = struct_type("G", false, vec![FatType::Struct(Box::new(resource.clone()))]);
+        assert_eq!(of_resource.abilities(), &["store"]);
+        let nested = FatType::Vector(Box::new(FatType::Struct(Box::new(of_resource))));
+        assert_eq!(struct_type("G", false, vec![nested]).abilities(), &["store"]);
+        let resource_of_resource = struct_type("R", true, vec![FatType::Struct(Box::new(resource))]);
+        assert_eq!(resource_of_resource.abilities(), &["key", "store"]);
+    }
+}
@@ -0,0 +1,1331 @@
+use anyhow::{anyhow, Result};
+use diem_json_rpc_client::views::EventView;
+use diem_types::{
+    account_address::AccountAddress,
+    account_state::AccountState,
+    access_path::{AccessPath, Path},
+    contract_event::ContractEvent,
+    transaction::{Transaction, TransactionOutput},
+    write_set::WriteOp,
+};
+use diem_vm::{
+    DiemVM, VMExecutor,
+};
+use move_core_types::language_storage::{ModuleId, StructTag, TypeTag, CORE_CODE_ADDRESS};
+use std::{
+    cmp,
+    collections::HashSet,
+    convert::TryFrom,
+    future::Future,
+    io::{self, BufRead, Write},
+    ops::Range,
+    path::{Path as FilePath, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    migrate::MigrateDatabase,
+    ConnectOptions, Connection,
+};
+use tokio::{
+    runtime::{self, Runtime},
+    task::{JoinError, JoinHandle},
+};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    annotator::MoveValueAnnotator,
+    backup::{self, Backup},
+    db::{Config, SyncState, DB, SCHEMA_VERSION},
+    resolver::{ModuleCache, Resolver},
+    state::{self, GenesisState, SqlState},
+    transactions::TransactionSource,
+    util,
+    ErrorPolicy,
+    Options,
+};
+
+/// How many transactions are fetched and executed together.
+const CHUNK_SIZE: u64 = 100;
+
+/// How many account states to ingest from a backup between saves of the
+/// backup position.
+const BACKUP_CHECKPOINT_INTERVAL: u64 = 1000;
+
+/// How many more times a failed fetch or block is attempted with
+/// `--on-error retry`.
+const MAX_RETRIES: u32 = 3;
+
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt)
+}
+
+/// What to do about a block that failed to execute.
+#[derive(Debug, PartialEq, Eq)]
+enum BlockFailure {
+    /// Stop indexing with the error.
+    Abort,
+    /// Execute the block again, as this attempt.
+    Retry(u32),
+    /// Stop indexing, as every retry failed too.
+    RetriesExhausted,
+    /// Execute the block's transactions one at a time, to find the failing
+    /// one.
+    Split,
+    /// Record the block's one transaction as skipped, and carry on after it.
+    Skip,
+}
+
+/// How `policy` handles the failure of `block`, which has already been
+/// retried `retries` times.
+fn on_block_failure(policy: ErrorPolicy, retries: u32, block: &Range<u64>) -> BlockFailure {
+    match policy {
+        ErrorPolicy::Abort => BlockFailure::Abort,
+        ErrorPolicy::Retry if retries < MAX_RETRIES => BlockFailure::Retry(retries + 1),
+        ErrorPolicy::Retry => BlockFailure::RetriesExhausted,
+        ErrorPolicy::Skip if block.end - block.start > 1 => BlockFailure::Split,
+        ErrorPolicy::Skip => BlockFailure::Skip,
+    }
+}
+
+/// Fetch transactions again after `error`, up to `MAX_RETRIES` times.
+async fn refetch(
+    source: &TransactionSource,
+    versions: Range<u64>,
+    with_events: bool,
+    mut error: anyhow::Error,
+) -> Result<(Vec<Transaction>, Vec<Option<Vec<EventView>>>)> {
+    for attempt in 1..=MAX_RETRIES {
+        warn!(
+            first_version = versions.start,
+            last_version = versions.end - 1,
+            "fetching failed ({}); retrying, attempt {} of {}",
+            error,
+            attempt,
+            MAX_RETRIES,
+        );
+        tokio::time::sleep(retry_delay(attempt)).await;
+        match source.get_transactions(versions.start, versions.end - versions.start, with_events).await {
+            Ok(txs) => return Ok(txs.into_iter().unzip()),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Execute transactions against `sql_state`, which reads the state the
+/// version before the first of them left behind, returning their outputs and
+/// the number of state queries made.
+async fn execute_block(
+    memory_state: bool,
+    prefetch: bool,
+    mut sql_state: SqlState,
+    txs: Vec<Transaction>,
+) -> Result<(Vec<TransactionOutput>, u64)> {
+    if memory_state {
+        match state::execute_block_in_memory(&sql_state, txs.clone(), state::MAX_MEMORY_PASSES).await? {
+            Some(outputs) => return Ok((outputs, sql_state.queries())),
+            None => warn!(
+                passes = state::MAX_MEMORY_PASSES,
+                "the read set of a block was still growing after the last pass; executing it against SQL instead",
+            ),
+        }
+    }
+    if prefetch {
+        let read_set = state::speculative_read_set(txs.clone()).await?;
+        sql_state.prefetch(&read_set).await?;
+    }
+    // VM is not async, but will call the `StateView` implementation which
+    // must make async calls so we use `spawn_blocking` to let tokio know.
+    tokio::task::spawn_blocking(move || {
+        let outputs = DiemVM::execute_block(txs, &sql_state)
+            .map_err(|status| anyhow!("execution failed with VM status {:?}", status))?;
+        Ok::<_, anyhow::Error>((outputs, sql_state.queries()))
+    }).await.map_err(blocking_error)?
+}
+
+/// The threads blocks execute on with `--vm-threads`, in a runtime of their
+/// own so that the VM never waits on, or holds up, SQLite and file reads on
+/// the main runtime's blocking pool.
+///
+/// With a pool, the next block of a chunk executes while the current one's
+/// writes are stored. That is only correct because:
+/// - it reads SQL as of the current block's last version overlaid with the
+///   current block's write sets and every buffered write, so each path the
+///   current block writes is read from memory, and no other path changes
+///   while the writes are stored;
+/// - it never follows a block that publishes modules, since resources read
+///   from SQL are decoded with modules from the shared cache, which the
+///   publication changes while it is stored;
+/// - its outputs are only used if it is the block that is due next, so a
+///   change of block size, a retry or a pause discards them, and a failure
+///   storing the current block stops indexing before they are used.
+struct VmPool(Option<Runtime>);
+
+impl VmPool {
+    fn new(threads: usize) -> Result<Self> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(threads)
+            .thread_name("vm")
+            .enable_all()
+            .build()?;
+        Ok(VmPool(Some(runtime)))
+    }
+
+    fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.0.as_ref().expect("the runtime is only taken on drop").spawn(future)
+    }
+}
+
+impl Drop for VmPool {
+    // dropping a runtime waits for its tasks, which async code must not
+    fn drop(&mut self) {
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Set the page size and auto-vacuum mode of a newly created database,
+/// before any table is. Neither can change while the file is in WAL mode,
+/// which the pool's connections switch it to, so they are set on a
+/// connection of their own and written out by vacuuming the empty file.
+async fn set_file_options(options: &Options) -> Result<()> {
+    if options.page_size.is_none() && options.auto_vacuum.is_none() {
+        return Ok(());
+    }
+    let mut conn = SqliteConnectOptions::from_str(&options.database_url())?
+        .journal_mode(SqliteJournalMode::Delete)
+        .connect()
+        .await?;
+    if let Some(page_size) = options.page_size {
+        sqlx::query(&format!("PRAGMA page_size = {}", page_size)).execute(&mut conn).await?;
+    }
+    if let Some(auto_vacuum) = options.auto_vacuum {
+        sqlx::query(&format!("PRAGMA auto_vacuum = {}", auto_vacuum.pragma_value())).execute(&mut conn).await?;
+    }
+    sqlx::query("VACUUM").execute(&mut conn).await?;
+    conn.close().await?;
+    Ok(())
+}
+
+/// The error of a blocking task that didn't finish, with the panic message
+/// when it panicked, e.g. on a bug in the `SqlState` read path, instead of
+/// an opaque "task panicked".
+fn blocking_error(e: JoinError) -> anyhow::Error {
+    if !e.is_panic() {
+        return e.into();
+    }
+    let payload = e.into_panic();
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "no message".to_string());
+    anyhow!("execution panicked: {}", message)
+}
+
+/// The bytes a transaction's write set occupies, approximated by the size of
+/// its paths and values.
+fn write_set_size(output: &TransactionOutput) -> u64 {
+    output
+        .write_set()
+        .iter()
+        .map(|(access_path, write_op)| {
+            let value_len = match write_op {
+                WriteOp::Value(v) => v.len(),
+                WriteOp::Deletion => 0,
+            };
+            (access_path.path.len() + value_len) as u64
+        })
+        .sum()
+}
+
+/// The time of the block a transaction belongs to, taken from the new value
+/// of `0x1::DiemTimestamp::CurrentTimeMicroseconds`. Only the block metadata
+/// transaction starting each block (and genesis) writes it.
+fn block_timestamp(output: &TransactionOutput) -> Option<u64> {
+    output.write_set().iter().find_map(|(access_path, write_op)| {
+        match (util::decode_access_path(access_path), write_op) {
+            (Ok((_, Path::Resource(tag))), WriteOp::Value(v))
+                if tag.address == CORE_CODE_ADDRESS
+                    && tag.module.as_str() == "DiemTimestamp"
+                    && tag.name.as_str() == "CurrentTimeMicroseconds" => {
+                // the struct's only field is a u64
+                bcs::from_bytes::<u64>(v).ok()
+            },
+            _ => None,
+        }
+    })
+}
+
+/// The modules a transaction, typically genesis, publishes.
+fn modules_published_by(output: &TransactionOutput) -> Vec<ModuleId> {
+    output
+        .write_set()
+        .iter()
+        .filter_map(|(access_path, _)| match util::decode_access_path(access_path) {
+            Ok((_, Path::Code(id))) => Some(id),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether a key of a backed up account state is one of the modules
+/// genesis publishes.
+fn is_genesis_module(genesis_modules: &HashSet<ModuleId>, key: &[u8]) -> Result<bool> {
+    Ok(match Path::try_from(key)? {
+        Path::Code(id) => genesis_modules.contains(&id),
+        Path::Resource(_) => false,
+    })
+}
+
+/// The modules declaring a struct and the structs in its type arguments.
+fn struct_modules(tag: &StructTag, module_ids: &mut Vec<ModuleId>) {
+    module_ids.push(tag.module_id());
+    for type_param in &tag.type_params {
+        let mut type_param = type_param;
+        while let TypeTag::Vector(elem) = type_param {
+            type_param = elem;
+        }
+        if let TypeTag::Struct(tag) = type_param {
+            struct_modules(tag, module_ids);
+        }
+    }
+}
+
+/// The address of the account state at `offset` of backup `name`: that of
+/// its account resource, or without one, of the modules it publishes.
+fn find_account_address(state: &AccountState, name: &str, offset: u64) -> Result<AccountAddress> {
+    let address = state
+        .get_account_address()
+        .map_err(|e| anyhow!("account state at offset {} of backup {}: {}", offset, name, e))?;
+    if let Some(address) = address {
+        return Ok(address);
+    }
+    for (key, _) in state.iter() {
+        let path = Path::try_from(key)
+            .map_err(|e| anyhow!("account state at offset {} of backup {} has a malformed key: {}", offset, name, e))?;
+        if let Path::Code(module_id) = path {
+            return Ok(*module_id.address());
+        }
+    }
+    Err(anyhow!(
+        "account state at offset {} of backup {} has neither an account resource nor modules",
+        offset,
+        name,
+    ))
+}
+
+/// Store the account states of a backup file as of `--backup-version`,
+/// starting from the offset the sync state records for the file, if any, and
+/// recording the offset reached every `checkpoint_interval` account states so
+/// that an interrupted ingestion resumes there.
+async fn ingest_backup_file(
+    db: &DB,
+    annotator: &MoveValueAnnotator,
+    options: &Options,
+    genesis_module_ids: &HashSet<ModuleId>,
+    file: &PathBuf,
+    checkpoint_interval: u64,
+    sync_state: &mut SyncState,
+) -> Result<()> {
+    let backup_version = options.backup_version.ok_or_else(|| anyhow!("--backup-file requires --backup-version"))?;
+    let name = file.display().to_string();
+    let offset = match &sync_state.backup_position {
+        Some((position_name, offset)) if *position_name == name => *offset,
+        _ => 0,
+    };
+    let mut backup = Backup::from_file(file)?.with_max_blob_size(options.backup_max_blob_bytes);
+    if offset > 0 {
+        info!("resuming {} at offset {}", name, offset);
+        backup.skip_to(offset)?;
+    }
+    sync_state.backup_position = Some((name.clone(), offset));
+    db.save_sync_state(sync_state).await?;
+
+    let mut count = 0u64;
+    loop {
+        let offset = backup.offset();
+        let account_state = match backup.next() {
+            Some(account_state) => account_state.map_err(|e| anyhow!("failed reading backup {}: {}", name, e))?,
+            None => break,
+        };
+        let address = find_account_address(&account_state, &name, offset)?;
+        let mut module_ids = vec![];
+        for (key, _) in account_state.iter() {
+            if let Ok(Path::Resource(tag)) = Path::try_from(key) {
+                struct_modules(&tag, &mut module_ids);
+            }
+        }
+        annotator.resolver().preload(&module_ids, options.module_batch_size).await?;
+        for (key, value) in account_state.iter() {
+            // genesis publishes these after the backup is ingested; modules
+            // published since still come from the backup
+            if options.genesis_modules_only && is_genesis_module(genesis_module_ids, key)? {
+                continue;
+            }
+            let access_path = AccessPath::new(address, key.clone());
+            let write_op = WriteOp::Value(value.clone());
+            db.execute_with_annotator(backup_version, &access_path, &write_op, annotator).await?;
+        }
+
+        count += 1;
+        if count % checkpoint_interval == 0 {
+            sync_state.backup_position = Some((name.clone(), backup.offset()));
+            db.save_sync_state(sync_state).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The events fetched alongside each transaction are a cheap check that
+/// local execution agrees with the chain.
+fn check_events(version: u64, executed: &[ContractEvent], fetched: Option<&[EventView]>) {
+    // transactions read from a file have nothing to compare with
+    if let Some(fetched) = fetched {
+        for mismatch in event_mismatches(version, executed, fetched) {
+            warn!(version, "{}", mismatch);
+        }
+    }
+}
+
+/// How the events a transaction emitted differ from those the endpoint
+/// reports. The endpoint gives each event's key and sequence number along
+/// with a view of its data, whose kind follows the event's type tag, so the
+/// executed events are converted to the same views to compare them whole.
+fn event_mismatches(version: u64, executed: &[ContractEvent], fetched: &[EventView]) -> Vec<String> {
+    if executed.len() != fetched.len() {
+        return vec![format!("emitted {} events but the endpoint reports {}", executed.len(), fetched.len())];
+    }
+    let mut mismatches = vec![];
+    for (i, (event, fetched)) in executed.iter().zip(fetched).enumerate() {
+        let view = match EventView::try_from((version, event.clone())) {
+            Ok(view) => view,
+            Err(e) => {
+                mismatches.push(format!("event {} of type {:?} has no endpoint view: {}", i, event.type_tag(), e));
+                continue;
+            },
+        };
+        if view.key != fetched.key {
+            mismatches.push(format!("event {} has key {:?} but the endpoint reports {:?}", i, view.key, fetched.key));
+        }
+        if view.sequence_number != fetched.sequence_number {
+            mismatches.push(format!(
+                "event {} has sequence number {} but the endpoint reports {}",
+                i,
+                view.sequence_number,
+                fetched.sequence_number,
+            ));
+        }
+        if view.transaction_version != fetched.transaction_version {
+            mismatches.push(format!("event {} is reported at version {}", i, fetched.transaction_version));
+        }
+        if view.data != fetched.data {
+            mismatches.push(format!(
+                "event {} of type {:?} has data {:?} but the endpoint reports {:?}",
+                i,
+                event.type_tag(),
+                view.data,
+                fetched.data,
+            ));
+        }
+    }
+    mismatches
+}
+
+#[cfg(feature = "parquet-out")]
+fn with_parquet_out(db: DB, dir: PathBuf) -> Result<DB> {
+    Ok(db.with_observer(Box::new(diem_sqlize::parquet_out::ParquetSink::from_dir(dir)?)))
+}
+
+#[cfg(not(feature = "parquet-out"))]
+fn with_parquet_out(_db: DB, _dir: PathBuf) -> Result<DB> {
+    Err(anyhow!("--parquet-out requires building with the `parquet-out` feature"))
+}
+
+/// Stop executing the block that started ahead of the current one, if any,
+/// as its outputs will never be used.
+fn abort_executing_ahead<T>(executing_ahead: &mut Option<(Range<u64>, JoinHandle<T>)>) {
+    if let Some((_, executing)) = executing_ahead.take() {
+        executing.abort();
+    }
+}
+
+/// The execution of `block` that started ahead of it, if any. The execution
+/// of any other block is aborted instead.
+fn take_executing_ahead<T>(executing_ahead: &mut Option<(Range<u64>, JoinHandle<T>)>, block: &Range<u64>) -> Option<JoinHandle<T>> {
+    match executing_ahead.take() {
+        Some((versions, executing)) if versions == *block => Some(executing),
+        Some((_, executing)) => {
+            executing.abort();
+            None
+        },
+        None => None,
+    }
+}
+
+/// The number of transactions to execute at a time after `block`, whose
+/// write sets took `write_set_bytes`: halved when that went over
+/// `--max-writeset-bytes`, and doubled up to a whole chunk when it was under
+/// half of it.
+fn resize_block(block_size: u64, block: &Range<u64>, write_set_bytes: u64, max_bytes: u64) -> u64 {
+    let resized = if write_set_bytes > max_bytes {
+        cmp::max(block_size / 2, 1)
+    } else if write_set_bytes < max_bytes / 2 {
+        cmp::min(block_size * 2, CHUNK_SIZE)
+    } else {
+        block_size
+    };
+    if resized != block_size {
+        info!(
+            "write sets of {} to {} took {} bytes; executing {} transactions at a time",
+            block.start,
+            block.end - 1,
+            write_set_bytes,
+            resized,
+        );
+    }
+    resized
+}
+
+/// Wait until indexing is resumed with SIGUSR1 or stopped with Ctrl-C, with
+/// everything before `next_version` written.
+async fn wait_while_paused(paused: &AtomicBool, stopping: &AtomicBool, next_version: u64) {
+    info!("paused before version {}; send SIGUSR1 to resume", next_version);
+    while paused.load(Ordering::SeqCst) && !stopping.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    if !stopping.load(Ordering::SeqCst) {
+        info!("resuming at version {}", next_version);
+    }
+}
+
+fn confirm_reset(database: &FilePath, table_prefix: &str, input: &mut impl BufRead, out: &mut impl Write) -> Result<bool> {
+    write!(
+        out,
+        "This deletes every table with prefix {:?} in {}. Type 'yes' to continue: ",
+        table_prefix,
+        database.display(),
+    )?;
+    out.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+/// Drop the tables of an earlier run when asked to with --reset, confirming
+/// first unless --yes was given.
+async fn reset_if_requested(
+    options: &Options,
+    config: &Config,
+    db: &DB,
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+) -> Result<()> {
+    if !options.reset {
+        return Ok(());
+    }
+    if !options.yes && !confirm_reset(&options.database, &config.table_prefix, input, out)? {
+        return Err(anyhow!("reset cancelled"));
+    }
+    db.reset().await
+}
+
+/// Forget the versions an endpoint at `latest_version` doesn't have, so that
+/// indexing continues from there. Only a database kept with history can.
+/// Modules published in those versions stay in `__module`, which keeps no
+/// history, until replaying publishes them again.
+async fn roll_back_behind_endpoint(db: &DB, config: &Config, sync_state: &mut SyncState, latest_version: u64) -> Result<()> {
+    if !config.history || latest_version == 0 {
+        return Err(anyhow!(
+            "the database is ahead of the endpoint; re-index with --reset, or use --history to roll back automatically"
+        ));
+    }
+    warn!(version = latest_version, "rolling the database back");
+    db.rollback(latest_version).await?;
+    db.load_block_timestamp().await?;
+    sync_state.next_version = latest_version;
+    db.save_sync_state(sync_state).await
+}
+
+/// Bootstrap from a backup or genesis, then replay the rest of the chain and
+/// keep tailing it until stopped with Ctrl-C. On Unix, SIGUSR1 pauses and
+/// resumes indexing between blocks.
+pub async fn run(options: Options, config: Arc<Config>) -> Result<()> {
+    let source = match &options.transactions_file {
+        Some(path) => TransactionSource::from_file(path)?,
+        None if !options.endpoint.is_empty() => TransactionSource::from_endpoints(&options.endpoint).await?,
+        None => unreachable!("--endpoint is required without --transactions-file"),
+    };
+
+    let database_url = options.database_url();
+    if !sqlx::Sqlite::database_exists(&database_url).await? {
+        sqlx::Sqlite::create_database(&database_url).await?;
+        set_file_options(&options).await?;
+    } else if options.page_size.is_some() || options.auto_vacuum.is_some() {
+        warn!(
+            database = %options.database.display(),
+            "the database already exists; --page-size and --auto-vacuum only apply when it is created",
+        );
+    }
+
+    let pool = SqlitePoolOptions::new()
+        .connect(&database_url).await?;
+    let modules = Arc::new(ModuleCache::new(options.module_cache_size));
+    let mut db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+    if let Some(dir) = options.parquet_out.clone() {
+        db = with_parquet_out(db, dir)?;
+    }
+    reset_if_requested(&options, &config, &db, &mut io::BufReader::new(io::stdin()), &mut io::stdout()).await?;
+    let resuming = db.is_initialized().await;
+    if resuming {
+        db.upgrade().await;
+        db.check_address_width().await?;
+        let schema_version = db.schema_version().await;
+        if schema_version > SCHEMA_VERSION {
+            return Err(anyhow!(
+                "the database was written under schema version {}, newer than this build's {}",
+                schema_version,
+                SCHEMA_VERSION,
+            ));
+        }
+        if schema_version < SCHEMA_VERSION {
+            warn!(
+                schema_version,
+                "resources were written under an older schema version; run `migrate-data --from-schema {}` to rewrite them under version {}",
+                schema_version,
+                SCHEMA_VERSION,
+            );
+        }
+    } else {
+        db.initialize().await;
+    }
+    let mut sync_state = db.sync_state().await;
+
+    // if state backup is provided, boostrap with that. a resumed run only
+    // uses it to finish an interrupted ingestion.
+    let backup = match (&options.backup_file, options.backup_version) {
+        (Some(backup_file), Some(backup_version)) => {
+            if !resuming || sync_state.backup_position.is_some() {
+                Some((backup_file, backup_version))
+            } else {
+                info!("database already includes the backup; ignoring it");
+                None
+            }
+        },
+        _ => None,
+    };
+    if let Some((backup_file, backup_version)) = backup {
+        if let Some(manifest) = &options.backup_manifest {
+            backup::verify_manifest(manifest, backup_file)?;
+        }
+
+        // build an initial resolver. we can do this from genesis since new
+        // modules don't get published.
+        let (genesis_tx, _) = source.get_transactions(0, 1, false).await?.remove(0);
+        let output = tokio::task::spawn_blocking(move || {
+            let state_view = GenesisState;
+            DiemVM::execute_block(vec![genesis_tx], &state_view)
+                .map(|mut outputs| outputs.remove(0))
+                .map_err(|status| anyhow!("executing genesis failed with VM status {:?}", status))
+        }).await.map_err(blocking_error)??;
+        let resolver = Resolver::from_pool_and_genesis_write_set(pool.clone(), config.clone(), output.write_set());
+        let annotator = MoveValueAnnotator::new(resolver);
+        let genesis_module_ids = modules_published_by(&output).into_iter().collect::<HashSet<_>>();
+
+        // continue from the recorded position of an interrupted ingestion
+        let first_file = match &sync_state.backup_position {
+            None => 0,
+            Some((name, _)) => backup_file
+                .iter()
+                .position(|file| file.display().to_string() == *name)
+                .ok_or_else(|| anyhow!("interrupted backup {} is not among the backup files", name))?,
+        };
+
+        // process state snaphost from backup
+        for file in &backup_file[first_file..] {
+            ingest_backup_file(
+                &db,
+                &annotator,
+                &options,
+                &genesis_module_ids,
+                file,
+                BACKUP_CHECKPOINT_INTERVAL,
+                &mut sync_state,
+            ).await?;
+        }
+
+        // every later resolver reads modules from the database, so any
+        // genesis module the backup didn't provide (all of them with
+        // --genesis-modules-only) is published now. genesis resources are
+        // never materialized here since the backup holds their post-genesis
+        // values.
+        for (access_path, write_op) in output.write_set() {
+            if let Ok((_, Path::Code(id))) = util::decode_access_path(access_path) {
+                if !db.is_published(&id).await {
+                    db.execute_with_annotator(0, access_path, write_op, &annotator).await?;
+                }
+            }
+        }
+        sync_state.backup_position = None;
+        sync_state.next_version = backup_version + 1;
+        sync_state.genesis_applied = true;
+        db.save_sync_state(&sync_state).await?;
+    }
+
+    let mut next_version = sync_state.next_version;
+    db.load_block_timestamp().await?;
+
+    // the modules genesis publishes, to warm the shared cache with
+    let mut genesis_modules = vec![];
+
+    if !sync_state.genesis_applied {
+        if next_version != 0 {
+            return Err(anyhow!("versions before {} are synced but genesis was never applied", next_version));
+        }
+
+        // Replay genesis (version 0)
+        let genesis_span = info_span!("genesis");
+        genesis_span.in_scope(|| info!("tx 0"));
+        let (tx, endpoint_events) = source.get_transactions(0, 1, options.with_events).await?.remove(0);
+        // VM is not async, but will call the `StateView` implementation which
+        // must make async calls so we use `spawn_blocking` to let tokio know.
+        // genesis can't be skipped, so a failure aborts whatever --on-error says
+        let output = tokio::task::spawn_blocking(move || {
+            let state_view = GenesisState;
+            DiemVM::execute_block(vec![tx], &state_view)
+                .map(|mut outputs| outputs.remove(0))
+                .map_err(|status| anyhow!("executing genesis failed with VM status {:?}", status))
+        }).await.map_err(blocking_error)??;
+        genesis_span.in_scope(|| info!("tx {}", output.status().status().unwrap()));
+        genesis_modules = modules_published_by(&output);
+        let resolver = Resolver::from_pool_and_genesis_write_set(pool.clone(), config.clone(), output.write_set());
+        let annotator = MoveValueAnnotator::new(resolver);
+
+        if let Some(usecs) = block_timestamp(&output) {
+            db.set_block_timestamp(usecs);
+        }
+        for (access_path, write_op) in output.write_set() {
+            db.execute_with_annotator(0, access_path, write_op, &annotator).await?;
+        }
+        if options.with_events {
+            check_events(0, output.events(), endpoint_events.as_deref());
+            db.store_events(0, output.events()).await?;
+        }
+        if config.with_timestamps {
+            db.store_transaction(0).await?;
+        }
+
+        next_version += 1;
+        sync_state.next_version = next_version;
+        sync_state.genesis_applied = true;
+        db.save_sync_state(&sync_state).await?;
+    }
+
+    let resolver = db.resolver();
+    resolver.preload(&genesis_modules, options.module_batch_size).await?;
+    if config.persist_layouts {
+        let loaded = resolver.load_layouts().await?;
+        info!("loaded {} stored type layouts", loaded);
+    }
+    let annotator = MoveValueAnnotator::new(resolver);
+
+    // from here on Ctrl-C stops at the next chunk boundary, so the database is
+    // always left at a whole version. a second Ctrl-C exits immediately.
+    let stopping = Arc::new(AtomicBool::new(false));
+    tokio::spawn({
+        let stopping = stopping.clone();
+        async move {
+            tokio::signal::ctrl_c().await.unwrap();
+            info!("stopping after the current chunk; press Ctrl-C again to exit now");
+            stopping.store(true, Ordering::SeqCst);
+            tokio::signal::ctrl_c().await.unwrap();
+            std::process::exit(130);
+        }
+    });
+
+    // SIGUSR1 pauses indexing at the next block boundary, e.g. for database
+    // maintenance, and a second SIGUSR1 resumes it
+    let paused = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    tokio::spawn({
+        let paused = paused.clone();
+        async move {
+            let mut toggles = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()).unwrap();
+            while toggles.recv().await.is_some() {
+                paused.fetch_xor(true, Ordering::SeqCst);
+            }
+        }
+    });
+
+    let incremental_vacuum_pages = match options.incremental_vacuum_pages {
+        Some(_) if !db.is_incremental_vacuum().await => {
+            warn!(
+                database = %options.database.display(),
+                "the database wasn't created with --auto-vacuum incremental; ignoring --incremental-vacuum-pages",
+            );
+            None
+        },
+        pages => pages,
+    };
+
+    if options.flush_interval_ms.is_some() || options.flush_max_rows.is_some() {
+        db.buffer_writes(options.flush_interval_ms.map(Duration::from_millis), options.flush_max_rows);
+    }
+
+    let vm_pool = options.vm_threads.map(|threads| VmPool::new(threads.get())).transpose()?;
+
+    // Replay the rest of the chain in chunks and continuing tailing
+    let with_events = options.with_events;
+    let mut block_size = CHUNK_SIZE;
+    'tail: while !stopping.load(Ordering::SeqCst) {
+        let latest_version = source.end_version().await?;
+        // an endpoint moving backwards was replaced or rewound, and has
+        // nothing to offer until it catches up with a chain we may not match
+        if latest_version < next_version && source.is_live() {
+            warn!(
+                endpoint_version = latest_version,
+                database_version = next_version,
+                "the endpoint is behind the database; it may have been replaced or rewound",
+            );
+            roll_back_behind_endpoint(&db, &config, &mut sync_state, latest_version).await?;
+            next_version = latest_version;
+            continue;
+        }
+        if latest_version < next_version || (latest_version == next_version && !source.is_live()) {
+            if !source.is_live() {
+                info!("reached the end of the transactions file");
+                break;
+            }
+            db.flush().await?;
+            info!("up to date; waiting for new blocks...");
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            continue;
+        }
+
+        // the next chunk is downloaded while the current one executes and is
+        // written. only the download can overlap: a chunk executes against
+        // the state the previous chunk's writes leave behind.
+        let chunk = |first_version: u64| first_version..cmp::min(first_version + CHUNK_SIZE, latest_version);
+        let fetch = |versions: Range<u64>| {
+            let source = source.clone();
+            let span = info_span!("fetch", first_version = versions.start, last_version = versions.end - 1);
+            tokio::spawn(async move {
+                let txs = source.get_transactions(versions.start, versions.end - versions.start, with_events).await?;
+                Ok::<_, anyhow::Error>(txs.into_iter().unzip::<_, _, Vec<_>, Vec<_>>())
+            }.instrument(span))
+        };
+        let mut chunk_starts = (next_version..latest_version).step_by(CHUNK_SIZE as usize);
+        let mut next_chunk = || chunk_starts.next().map(|first_version| (chunk(first_version), fetch(chunk(first_version))));
+        let mut fetching = next_chunk();
+        while let Some((versions, fetched)) = fetching.take() {
+            if stopping.load(Ordering::SeqCst) {
+                break 'tail;
+            }
+
+            let first_version = versions.start;
+            let last_version = versions.end - 1;
+            let chunk_span = info_span!("chunk", first_version, last_version);
+            chunk_span.in_scope(|| info!(
+                "syncing from {} to {} (module cache: {} hits, {} misses)",
+                first_version,
+                last_version,
+                modules.hits(),
+                modules.misses(),
+            ));
+            let (txs, endpoint_events) = match fetched.await? {
+                Ok(fetched) => fetched,
+                Err(e) if options.on_error == ErrorPolicy::Retry => {
+                    refetch(&source, versions.clone(), with_events, e).await?
+                },
+                Err(e) => return Err(e),
+            };
+            fetching = next_chunk();
+
+            // a chunk executes as one block unless earlier write sets went
+            // over --max-writeset-bytes, in which case it executes in smaller
+            // blocks so fewer outputs are held in memory at once
+            let mut endpoint_events = endpoint_events.into_iter();
+            let mut block_start = first_version;
+            let mut retries = 0;
+            let mut one_at_a_time = false;
+            // the next block, executing on the VM pool while this one is stored
+            let mut executing_ahead: Option<(Range<u64>, JoinHandle<Result<(Vec<TransactionOutput>, u64)>>)> = None;
+            while block_start <= last_version {
+                if paused.load(Ordering::SeqCst) {
+                    abort_executing_ahead(&mut executing_ahead);
+                    db.flush().await?;
+                    wait_while_paused(&paused, &stopping, block_start).await;
+                    if stopping.load(Ordering::SeqCst) {
+                        break 'tail;
+                    }
+                }
+                let size = if one_at_a_time { 1 } else { block_size };
+                let block = block_start..cmp::min(block_start + size, last_version + 1);
+                let block_txs = txs[(block.start - first_version) as usize..(block.end - first_version) as usize].to_vec();
+
+                let executed = match (take_executing_ahead(&mut executing_ahead, &block), &vm_pool) {
+                    (Some(executing), _) => executing.await.map_err(blocking_error).and_then(|executed| executed),
+                    (None, vm_pool) => {
+                        let sql_state = SqlState::from_pool(pool.clone(), config.clone(), modules.clone())
+                            .at_version(block.start - 1)
+                            .with_pending(db.pending_writes())
+                            .with_root_ids(db.root_ids());
+                        let executing = execute_block(options.memory_state, options.prefetch, sql_state, block_txs)
+                            .instrument(info_span!("execute", first_version = block.start, last_version = block.end - 1));
+                        match vm_pool {
+                            Some(vm_pool) => vm_pool.spawn(executing).await.map_err(blocking_error).and_then(|executed| executed),
+                            None => executing.await,
+                        }
+                    },
+                };
+                let (outputs, queries) = match executed {
+                    Ok(executed) => executed,
+                    Err(e) => match on_block_failure(options.on_error, retries, &block) {
+                        BlockFailure::Abort => {
+                            return Err(e.context(format!("executing {} to {}", block.start, block.end - 1)));
+                        },
+                        BlockFailure::Retry(attempt) => {
+                            retries = attempt;
+                            warn!(
+                                first_version = block.start,
+                                last_version = block.end - 1,
+                                "executing failed ({}); retrying, attempt {} of {}",
+                                e,
+                                retries,
+                                MAX_RETRIES,
+                            );
+                            tokio::time::sleep(retry_delay(retries)).await;
+                            continue;
+                        },
+                        BlockFailure::RetriesExhausted => {
+                            return Err(e.context(format!("executing {} to {} after {} retries", block.start, block.end - 1, retries)));
+                        },
+                        // find the failing transaction by executing the rest
+                        // of the chunk one transaction at a time
+                        BlockFailure::Split => {
+                            warn!(
+                                first_version = block.start,
+                                last_version = block.end - 1,
+                                "executing failed ({}); executing the transactions one at a time",
+                                e,
+                            );
+                            one_at_a_time = true;
+                            continue;
+                        },
+                        // the transactions after it go back to executing
+                        // together
+                        BlockFailure::Skip => {
+                            db.skip_transaction(block.start, &e.to_string()).await?;
+                            one_at_a_time = false;
+                            endpoint_events.next();
+                            next_version = block.end;
+                            sync_state.next_version = next_version;
+                            db.save_sync_state(&sync_state).await?;
+                            block_start = block.end;
+                            continue;
+                        },
+                    },
+                };
+                retries = 0;
+                let apply_span = info_span!("apply", first_version = block.start, last_version = block.end - 1);
+                apply_span.in_scope(|| info!("executed {} to {} with {} state queries", block.start, block.end - 1, queries));
+
+                // see `VmPool` for why the next block can execute before this
+                // one is stored
+                let next_block = block.end..cmp::min(block.end + size, last_version + 1);
+                if let Some(vm_pool) = &vm_pool {
+                    if !next_block.is_empty() && outputs.iter().all(|output| modules_published_by(output).is_empty()) {
+                        let mut pending = db.pending_writes();
+                        for output in &outputs {
+                            for (access_path, write_op) in output.write_set() {
+                                let value = match write_op {
+                                    WriteOp::Value(v) => Some(v.clone()),
+                                    WriteOp::Deletion => None,
+                                };
+                                pending.insert(access_path.clone(), value);
+                            }
+                        }
+                        let sql_state = SqlState::from_pool(pool.clone(), config.clone(), modules.clone())
+                            .at_version(next_block.start - 1)
+                            .with_pending(pending)
+                            .with_root_ids(db.root_ids());
+                        let next_txs = txs[(next_block.start - first_version) as usize..(next_block.end - first_version) as usize].to_vec();
+                        let span = info_span!("execute", first_version = next_block.start, last_version = next_block.end - 1);
+                        let executing = execute_block(options.memory_state, options.prefetch, sql_state, next_txs).instrument(span);
+                        executing_ahead = Some((next_block, vm_pool.spawn(executing)));
+                    }
+                }
+
+                // each output is dropped once it is written
+                let mut write_set_bytes = 0;
+                async {
+                    for ((version, output), fetched_events) in block.clone().zip(outputs).zip(endpoint_events.by_ref()) {
+                        write_set_bytes += write_set_size(&output);
+                        if let Some(usecs) = block_timestamp(&output) {
+                            db.set_block_timestamp(usecs);
+                        }
+                        for (access_path, write_op) in output.write_set() {
+                            db.execute_with_annotator(version, access_path, write_op, &annotator).await?;
+                        }
+                        if options.with_events {
+                            check_events(version, output.events(), fetched_events.as_deref());
+                            db.store_events(version, output.events()).await?;
+                        }
+                        if config.with_timestamps {
+                            db.store_transaction(version).await?;
+                        }
+                    }
+                    Ok::<_, anyhow::Error>(())
+                }.instrument(apply_span.clone()).await?;
+
+                next_version = block.end;
+                sync_state.next_version = next_version;
+                db.save_sync_state(&sync_state).await?;
+                block_start = block.end;
+
+                if let Some(max_bytes) = options.max_writeset_bytes {
+                    block_size = apply_span.in_scope(|| resize_block(block_size, &block, write_set_bytes, max_bytes));
+                }
+            }
+
+            // the pages this chunk's deletes freed are handed back to the
+            // filesystem a few at a time, instead of in one long VACUUM
+            if let Some(pages) = incremental_vacuum_pages {
+                db.incremental_vacuum(pages.get()).await;
+            }
+        }
+    }
+    db.flush().await?;
+    info!("stopped before version {}", next_version);
+
+    if options.vacuum_on_exit {
+        let before = std::fs::metadata(&options.database)?.len();
+        db.vacuum().await;
+        let after = std::fs::metadata(&options.database)?.len();
+        info!("vacuumed {} from {} to {} bytes", options.database.display(), before, after);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use diem_crypto::HashValue;
+    use diem_types::{account_state_blob::AccountStateBlob, event::EventKey};
+    use libflate::gzip::Encoder;
+    use move_core_types::identifier::Identifier;
+    use sqlx::Row;
+    use std::{collections::BTreeMap, fs::File};
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, ADDRESS};
+
+    /// An account state as a backup frames it, publishing the counter module
+    /// and holding a counter of `value`.
+    fn account_frame(value: u64) -> Vec<u8> {
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let mut state = BTreeMap::new();
+        state.insert(bcs::to_bytes(&Path::Code(tag.module_id())).unwrap(), test_util::counter_module());
+        state.insert(bcs::to_bytes(&Path::Resource(tag)).unwrap(), bcs::to_bytes(&test_util::counter(value)).unwrap());
+        let blob = AccountStateBlob::from(bcs::to_bytes(&state).unwrap());
+        let bytes = bcs::to_bytes(&(HashValue::zero(), blob)).unwrap();
+        let mut framed = (bytes.len() as u32).to_be_bytes().to_vec();
+        framed.extend(bytes);
+        framed
+    }
+
+    fn write_backup(path: &PathBuf, bytes: &[u8]) {
+        let mut encoder = Encoder::new(File::create(path).unwrap()).unwrap();
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().into_result().unwrap();
+    }
+
+    /// Options for indexing from `args`, which are given after the
+    /// transactions file every run needs a source of transactions in.
+    fn options(args: &[&str]) -> Options {
+        let mut all_args = vec!["diem-sqlize", "--transactions-file", "transactions"];
+        all_args.extend(args);
+        Options::from_iter(all_args)
+    }
+
+    #[tokio::test]
+    async fn file_options_apply_to_new_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chain.db").display().to_string();
+        let options = options(&["--database", &path, "--page-size", "8192", "--auto-vacuum", "incremental"]);
+        sqlx::Sqlite::create_database(&options.database_url()).await.unwrap();
+        set_file_options(&options).await.unwrap();
+
+        // and are kept once the pool switches the file to WAL mode
+        let pool = SqlitePoolOptions::new().connect(&options.database_url()).await.unwrap();
+        let pragma = |name: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let row = sqlx::query(&format!("PRAGMA {}", name)).fetch_one(&pool).await.unwrap();
+                row.get::<i64, _>(0)
+            }
+        };
+        assert_eq!(pragma("page_size").await, 8192);
+        // 2 is incremental
+        assert_eq!(pragma("auto_vacuum").await, 2);
+    }
+
+    #[tokio::test]
+    async fn interrupted_backup_ingestion_resumes_at_its_offset() {
+        let db = TestDb::new(test_util::config()).await;
+        let annotator = db.annotator();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("backup.gz");
+        let name = file.display().to_string();
+        let options = options(&["--backup-file", &name, "--backup-version", "5"]);
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let frames = (1..=5).map(account_frame).collect::<Vec<_>>();
+
+        // the first run stops at a read error in the fourth account state,
+        // with the position past the second recorded
+        let mut interrupted = frames[..3].concat();
+        interrupted.extend_from_slice(&frames[3][..2]);
+        write_backup(&file, &interrupted);
+        let mut sync_state = db.db.sync_state().await;
+        let result = ingest_backup_file(&db.db, &annotator, &options, &HashSet::new(), &file, 2, &mut sync_state).await;
+        assert!(result.is_err());
+        let position = db.db.sync_state().await.backup_position;
+        assert_eq!(position, Some((name.clone(), 2 * frames[0].len() as u64)));
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(3));
+
+        // the next run skips to the recorded position, so the account states
+        // before it are never read again; here they no longer deserialize
+        let mut corrupt = frames[0].clone();
+        for byte in &mut corrupt[4..] {
+            *byte = 0xff;
+        }
+        write_backup(&file, &[corrupt.clone(), corrupt, frames[2..].concat()].concat());
+        let mut sync_state = db.db.sync_state().await;
+        ingest_backup_file(&db.db, &annotator, &options, &HashSet::new(), &file, 2, &mut sync_state).await.unwrap();
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(5));
+        let position = db.db.sync_state().await.backup_position;
+        assert_eq!(position, Some((name, 4 * frames[0].len() as u64)));
+    }
+
+    #[tokio::test]
+    async fn databases_ahead_of_the_endpoint_roll_back_with_history() {
+        let db = TestDb::new(Config { history: true, ..test_util::config() }).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::counter_module()).await;
+        db.store(1, &tag, test_util::counter(1)).await;
+        db.store(3, &tag, test_util::counter(3)).await;
+        let mut sync_state = db.db.sync_state().await;
+        sync_state.next_version = 4;
+        db.db.save_sync_state(&sync_state).await.unwrap();
+
+        roll_back_behind_endpoint(&db.db, &db.config, &mut sync_state, 2).await.unwrap();
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(1));
+        assert_eq!(sync_state.next_version, 2);
+        assert_eq!(db.db.sync_state().await.next_version, 2);
+
+        // an endpoint with nothing at all has no version to go back to
+        assert!(roll_back_behind_endpoint(&db.db, &db.config, &mut sync_state, 0).await.is_err());
+        let db = TestDb::new(test_util::config()).await;
+        let mut sync_state = db.db.sync_state().await;
+        let error = roll_back_behind_endpoint(&db.db, &db.config, &mut sync_state, 2).await.unwrap_err().to_string();
+        assert!(error.contains("--history"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn pauses_last_until_resumed_or_stopped() {
+        let wait = |paused: bool, resume: Option<bool>| async move {
+            let (paused, stopping) = (Arc::new(AtomicBool::new(paused)), Arc::new(AtomicBool::new(false)));
+            if let Some(stop) = resume {
+                let flag = if stop { stopping.clone() } else { paused.clone() };
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    flag.store(stop, Ordering::SeqCst);
+                });
+            }
+            let (logs, _guard) = test_util::Logs::capture();
+            tokio::time::timeout(Duration::from_secs(5), wait_while_paused(&paused, &stopping, 7)).await.unwrap();
+            logs.contents()
+        };
+
+        let logs = wait(false, None).await;
+        assert!(logs.contains("resuming at version 7"), "{}", logs);
+        let logs = wait(true, Some(false)).await;
+        assert!(logs.contains("paused before version 7") && logs.contains("resuming at version 7"), "{}", logs);
+        // stopping while paused doesn't resume
+        let logs = wait(true, Some(true)).await;
+        assert!(logs.contains("paused before version 7") && !logs.contains("resuming"), "{}", logs);
+    }
+
+    #[test]
+    fn blocks_shrink_over_the_write_set_limit_and_grow_well_under_it() {
+        let (logs, _guard) = test_util::Logs::capture();
+        assert_eq!(resize_block(50, &(10..60), 1500, 1000), 25);
+        assert!(logs.contents().contains("write sets of 10 to 59 took 1500 bytes; executing 25 transactions at a time"));
+        assert_eq!(resize_block(1, &(10..11), 1500, 1000), 1);
+        assert_eq!(resize_block(50, &(10..60), 400, 1000), 100);
+        assert_eq!(resize_block(CHUNK_SIZE, &(10..110), 400, 1000), CHUNK_SIZE);
+        // between half the limit and the limit, the size is kept
+        assert_eq!(resize_block(50, &(10..60), 700, 1000), 50);
+        assert_eq!(logs.contents().lines().count(), 2, "{}", logs.contents());
+    }
+
+    #[tokio::test]
+    async fn blocks_executing_ahead_are_aborted_unless_due() {
+        // a task that holds its sender until it is aborted
+        let spawn = || {
+            let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+            let handle = tokio::spawn(async move {
+                let _sender = sender;
+                tokio::time::sleep(Duration::from_secs(3600)).await
+            });
+            (handle, receiver)
+        };
+
+        let (handle, receiver) = spawn();
+        let mut executing_ahead = Some((10..20, handle));
+        assert!(take_executing_ahead(&mut executing_ahead, &(10..20)).is_some());
+        assert!(executing_ahead.is_none());
+        drop(receiver);
+
+        // a block of another size won't be used, and neither will one
+        // executing ahead of a pause
+        let (handle, receiver) = spawn();
+        let mut executing_ahead = Some((10..20, handle));
+        assert!(take_executing_ahead(&mut executing_ahead, &(10..11)).is_none());
+        assert!(receiver.await.is_err());
+        let (handle, receiver) = spawn();
+        let mut executing_ahead = Some((10..20, handle));
+        abort_executing_ahead(&mut executing_ahead);
+        assert!(executing_ahead.is_none());
+        assert!(receiver.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn panics_of_blocking_tasks_keep_their_message() {
+        let error = tokio::spawn(async { panic!("boom") }).await.unwrap_err();
+        assert_eq!(blocking_error(error).to_string(), "execution panicked: boom");
+        let version = 7;
+        let error = tokio::task::spawn_blocking(move || panic!("failed at {}", version)).await.unwrap_err();
+        assert_eq!(blocking_error(error).to_string(), "execution panicked: failed at 7");
+        let error = tokio::spawn(async { std::panic::resume_unwind(Box::new(7)) }).await.unwrap_err();
+        assert_eq!(blocking_error(error).to_string(), "execution panicked: no message");
+
+        let handle = tokio::spawn(tokio::time::sleep(Duration::from_secs(60)));
+        handle.abort();
+        let error = blocking_error(handle.await.unwrap_err()).to_string();
+        assert!(!error.starts_with("execution panicked"), "{}", error);
+    }
+
+    #[test]
+    fn failed_blocks_are_handled_by_policy() {
+        let block = 10..15;
+        let single = 12..13;
+        assert_eq!(on_block_failure(ErrorPolicy::Abort, 0, &block), BlockFailure::Abort);
+        assert_eq!(on_block_failure(ErrorPolicy::Abort, 0, &single), BlockFailure::Abort);
+        assert_eq!(on_block_failure(ErrorPolicy::Retry, 0, &block), BlockFailure::Retry(1));
+        assert_eq!(on_block_failure(ErrorPolicy::Retry, MAX_RETRIES - 1, &single), BlockFailure::Retry(MAX_RETRIES));
+        assert_eq!(on_block_failure(ErrorPolicy::Retry, MAX_RETRIES, &block), BlockFailure::RetriesExhausted);
+        assert_eq!(on_block_failure(ErrorPolicy::Skip, 0, &block), BlockFailure::Split);
+        assert_eq!(on_block_failure(ErrorPolicy::Skip, 0, &single), BlockFailure::Skip);
+    }
+
+    #[test]
+    fn account_states_without_an_address_are_errors() {
+        let empty = BTreeMap::<Vec<u8>, Vec<u8>>::new();
+        let state = AccountState::try_from(&AccountStateBlob::from(bcs::to_bytes(&empty).unwrap())).unwrap();
+        let error = find_account_address(&state, "backup.gz", 12).unwrap_err().to_string();
+        assert!(error.contains("offset 12 of backup backup.gz"), "{}", error);
+    }
+
+    #[test]
+    fn events_are_compared_whole() {
+        let event = |sequence_number, data: Vec<u8>| {
+            ContractEvent::new(
+                EventKey::new_from_address(&CORE_CODE_ADDRESS, 0),
+                sequence_number,
+                TypeTag::Vector(Box::new(TypeTag::U8)),
+                data,
+            )
+        };
+        let executed = vec![event(0, vec![1]), event(1, vec![2])];
+        let fetched = executed.iter().map(|e| EventView::try_from((7, e.clone())).unwrap()).collect::<Vec<_>>();
+        assert!(event_mismatches(7, &executed, &fetched).is_empty());
+
+        assert_eq!(event_mismatches(7, &executed[..1], &fetched).len(), 1);
+        let mismatches = event_mismatches(7, &[event(0, vec![1]), event(2, vec![2])], &fetched);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("sequence number 2"), "{:?}", mismatches);
+        let mismatches = event_mismatches(7, &[event(0, vec![1]), event(1, vec![3])], &fetched);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].starts_with("event 1 of type"), "{:?}", mismatches);
+        let other_key = ContractEvent::new(
+            EventKey::new_from_address(&CORE_CODE_ADDRESS, 1),
+            1,
+            TypeTag::Vector(Box::new(TypeTag::U8)),
+            vec![2],
+        );
+        let mismatches = event_mismatches(7, &[event(0, vec![1]), other_key], &fetched);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("has key"), "{:?}", mismatches);
+    }
+
+    #[test]
+    fn only_genesis_modules_are_skipped() {
+        let genesis_module = ModuleId::new(CORE_CODE_ADDRESS, Identifier::new("DiemAccount").unwrap());
+        let later_module = ModuleId::new(CORE_CODE_ADDRESS, Identifier::new("Later").unwrap());
+        let genesis_modules = vec![genesis_module.clone()].into_iter().collect::<HashSet<_>>();
+        let key = |path: &Path| bcs::to_bytes(path).unwrap();
+
+        assert!(is_genesis_module(&genesis_modules, &key(&Path::Code(genesis_module))).unwrap());
+        assert!(!is_genesis_module(&genesis_modules, &key(&Path::Code(later_module))).unwrap());
+        let tag = StructTag {
+            address: CORE_CODE_ADDRESS,
+            module: Identifier::new("DiemAccount").unwrap(),
+            name: Identifier::new("DiemAccount").unwrap(),
+            type_params: vec![],
+        };
+        assert!(!is_genesis_module(&genesis_modules, &key(&Path::Resource(tag))).unwrap());
+        assert!(is_genesis_module(&genesis_modules, &[0xff]).is_err());
+    }
+
+    #[tokio::test]
+    async fn reset_asks_unless_told_yes() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::counter_module()).await;
+        db.store(1, &tag, test_util::counter(1)).await;
+
+        // nothing is asked or dropped without --reset
+        let mut out = vec![];
+        reset_if_requested(&options(&[]), &db.config, &db.db, &mut &b"yes\n"[..], &mut out).await.unwrap();
+        assert!(out.is_empty());
+        // anything but "yes" cancels
+        let reset = options(&["--reset"]);
+        let error = reset_if_requested(&reset, &db.config, &db.db, &mut &b"y\n"[..], &mut out).await.unwrap_err();
+        assert_eq!(error.to_string(), "reset cancelled");
+        assert!(String::from_utf8(out).unwrap().contains("Type 'yes' to continue"));
+        assert_eq!(db.db.read_field_u64(&ADDRESS, &tag, "value").await.unwrap(), Some(1));
+        reset_if_requested(&reset, &db.config, &db.db, &mut &b"yes\n"[..], &mut vec![]).await.unwrap();
+        assert!(!db.db.is_initialized().await);
+
+        // --yes drops them without asking
+        db.db.initialize().await;
+        let mut out = vec![];
+        let yes = options(&["--reset", "--yes"]);
+        reset_if_requested(&yes, &db.config, &db.db, &mut &b""[..], &mut out).await.unwrap();
+        assert!(out.is_empty());
+        assert!(!db.db.is_initialized().await);
+    }
+}
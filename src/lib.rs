@@ -0,0 +1,113 @@
+//! Replays the Diem blockchain into SQLite, storing every resource as rows of
+//! tables generated from its Move type.
+//!
+//! Besides the `diem-sqlize` binary, the crate exposes the type resolution it
+//! is built on, so that other tools can decode resource blobs against the
+//! modules stored in a database:
+//!
+//! ```
+//! use diem_sqlize::{
+//!     annotator::MoveValueAnnotator,
+//!     db::{Config, DB},
+//!     resolver::{ModuleCache, Resolver, DEFAULT_MODULE_CACHE_SIZE},
+//! };
+//! use diem_types::{
+//!     access_path::{AccessPath, Path},
+//!     write_set::WriteOp,
+//! };
+//! use move_core_types::{
+//!     identifier::Identifier,
+//!     language_storage::{ModuleId, StructTag, CORE_CODE_ADDRESS},
+//!     value::{MoveStruct, MoveValue},
+//! };
+//! use sqlx::sqlite::SqlitePoolOptions;
+//! use std::sync::Arc;
+//! # use vm::file_format::{
+//! #     empty_module, CompiledModule, FieldDefinition, IdentifierIndex, ModuleHandleIndex, SignatureToken,
+//! #     StructDefinition, StructFieldInformation, StructHandle, StructHandleIndex, TypeSignature,
+//! # };
+//! #
+//! # /// `module M { resource struct R { value: u64 } }` at 0x1.
+//! # fn module_bytes() -> Vec<u8> {
+//! #     let mut module = empty_module();
+//! #     module.identifiers[0] = Identifier::new("M").unwrap();
+//! #     module.identifiers.push(Identifier::new("R").unwrap());
+//! #     module.identifiers.push(Identifier::new("value").unwrap());
+//! #     module.address_identifiers[0] = CORE_CODE_ADDRESS;
+//! #     module.struct_handles.push(StructHandle {
+//! #         module: ModuleHandleIndex(0),
+//! #         name: IdentifierIndex(1),
+//! #         is_nominal_resource: true,
+//! #         type_parameters: vec![],
+//! #     });
+//! #     module.struct_defs.push(StructDefinition {
+//! #         struct_handle: StructHandleIndex(0),
+//! #         field_information: StructFieldInformation::Declared(vec![FieldDefinition {
+//! #             name: IdentifierIndex(2),
+//! #             signature: TypeSignature(SignatureToken::U64),
+//! #         }]),
+//! #     });
+//! #     let module: CompiledModule = module.freeze().unwrap();
+//! #     let mut bytes = vec![];
+//! #     module.serialize(&mut bytes).unwrap();
+//! #     bytes
+//! # }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! // one connection, since each would open a database of its own in memory
+//! let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?;
+//! // the table prefix must match the one the database was indexed with
+//! let config = Arc::new(Config::default());
+//! let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+//! let annotator = MoveValueAnnotator::new(Resolver::from_shared_cache(pool.clone(), config.clone(), modules.clone()));
+//!
+//! // seed the database with `0x1::M`, as indexing its publication would
+//! let db = DB::from_pool(pool.clone(), config.clone(), modules);
+//! db.initialize().await;
+//! let id = ModuleId::new(CORE_CODE_ADDRESS, Identifier::new("M")?);
+//! let access_path = AccessPath::new(CORE_CODE_ADDRESS, bcs::to_bytes(&Path::Code(id))?);
+//! db.execute_with_annotator(0, &access_path, &WriteOp::Value(module_bytes()), &annotator).await?;
+//!
+//! // decode a blob of `0x1::M::R` against the stored module
+//! let tag = StructTag {
+//!     address: CORE_CODE_ADDRESS,
+//!     module: Identifier::new("M")?,
+//!     name: Identifier::new("R")?,
+//!     type_params: vec![],
+//! };
+//! let blob = bcs::to_bytes(&MoveStruct::new(vec![MoveValue::U64(7)]))?;
+//! println!("{}", annotator.view_resource(&tag, &blob).await?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `Resolver::resolve_struct` gives the fully instantiated layout of a type
+//! without decoding a value.
+
+pub mod annotator;
+pub mod db;
+pub mod fat_type;
+#[cfg(feature = "parquet-out")]
+pub mod parquet_out;
+pub mod resolver;
+
+// used by the binary, but not meant for other crates
+#[doc(hidden)]
+pub mod backup;
+#[doc(hidden)]
+pub mod migrate;
+#[doc(hidden)]
+pub mod parser;
+#[cfg(feature = "replay")]
+#[doc(hidden)]
+pub mod state;
+#[doc(hidden)]
+pub mod transactions;
+#[doc(hidden)]
+pub mod util;
+
+// fixtures for the tests and the replay benchmark
+#[cfg(any(test, feature = "bench"))]
+#[doc(hidden)]
+pub mod test_util;
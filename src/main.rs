@@ -1,188 +1,424 @@
 use anyhow::{anyhow, Result};
-use diem_json_rpc_client::async_client::{Client, Retry};
-use diem_types::{
-    account_address::AccountAddress,
-    account_state::AccountState,
-    access_path::{AccessPath, Path},
-    transaction::Transaction,
-    write_set::WriteOp,
-};
-use diem_vm::{
-    DiemVM, VMExecutor,
-};
-use itertools::Itertools;
+use move_core_types::language_storage::StructTag;
+#[cfg(feature = "replay")]
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::{
-    convert::TryFrom,
+    net::SocketAddr,
     path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
-use sqlx::{
-    sqlite::SqlitePoolOptions,
-    migrate::MigrateDatabase,
-};
-use structopt::StructOpt;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use structopt::{clap::AppSettings, StructOpt};
+use tracing_subscriber::fmt::format::FmtSpan;
 use url::Url;
 
 use crate::{
-    annotator::MoveValueAnnotator,
-    backup::Backup,
-    db::DB,
-    resolver::Resolver,
-    state::{GenesisState, SqlState},
+    commands::Command,
+    db::{Config, NumericEncoding},
 };
 
-mod annotator;
-mod backup;
-mod db;
-mod fat_type;
-mod resolver;
-mod state;
-mod util;
+// the library's modules, imported so they are reachable as `crate::*` just as
+// the binary's own are
+use diem_sqlize::{annotator, db, fat_type, migrate, parser, resolver};
+// only indexing and the commands that execute transactions use these
+#[cfg(feature = "replay")]
+use diem_sqlize::{backup, state, transactions, util};
+
+mod commands;
+#[cfg(feature = "replay")]
+mod index;
+// the library only builds test_util for its own tests
+#[cfg(test)]
+#[path = "test_util.rs"]
+mod test_util;
+#[cfg(feature = "serve")]
+mod serve;
+
+/// Indexing executes every transaction, so a build without the VM only offers
+/// the commands that query an existing database.
+#[cfg(not(feature = "replay"))]
+mod index {
+    use anyhow::{anyhow, Result};
+    use std::sync::Arc;
+
+    use crate::{db::Config, Options};
+
+    pub async fn run(_options: Options, _config: Arc<Config>) -> Result<()> {
+        Err(anyhow!("indexing requires building with the `replay` feature"))
+    }
+}
 
 #[derive(Debug, StructOpt)]
-#[structopt(rename_all = "kebab-case")]
+#[structopt(rename_all = "kebab-case", setting = AppSettings::SubcommandsNegateReqs)]
 struct Options {
-    #[structopt(long, parse(try_from_str = Url::parse))]
-    pub endpoint: Url,
+    /// JSON-RPC endpoint to fetch transactions from. May be repeated, in
+    /// which case indexing fails over to the next endpoint when a request
+    /// fails, and commands use the first
+    #[cfg_attr(feature = "replay", structopt(long, required_unless("transactions-file"), parse(try_from_str = Url::parse)))]
+    #[cfg_attr(not(feature = "replay"), structopt(long, parse(try_from_str = Url::parse)))]
+    pub endpoint: Vec<Url>,
+    /// Replay the transactions recorded in this file instead of fetching them
+    /// from the endpoint, stopping at the end of the file
+    #[cfg(feature = "replay")]
+    #[structopt(long, parse(from_os_str))]
+    pub transactions_file: Option<PathBuf>,
+    #[cfg(feature = "replay")]
     #[structopt(long, parse(from_os_str), requires("backup-version"))]
     pub backup_file: Option<Vec<PathBuf>>,
+    #[cfg(feature = "replay")]
     #[structopt(long, requires("backup-file"))]
     pub backup_version: Option<u64>,
+    /// Check each backup file against its SHA-256 digest in this manifest,
+    /// in the format written by `sha256sum`, before ingesting any of them
+    #[cfg(feature = "replay")]
+    #[structopt(long, parse(from_os_str), requires("backup-file"))]
+    pub backup_manifest: Option<PathBuf>,
+    /// Treat a backup as corrupt if it declares an account state blob larger
+    /// than this many bytes
+    #[cfg(feature = "replay")]
+    #[structopt(long, default_value = "67108864", requires("backup-file"))]
+    pub backup_max_blob_bytes: usize,
+    /// When bootstrapping from a backup, take the modules genesis publishes
+    /// from its write set rather than the backup. Modules published since
+    /// still come from the backup
+    #[cfg(feature = "replay")]
+    #[structopt(long, requires("backup-file"))]
+    pub genesis_modules_only: bool,
+    /// The SQLite database to index into, and that commands read
+    #[structopt(long, default_value = "chain.db", parse(from_os_str))]
+    pub database: PathBuf,
+    /// Prefix for every generated table name, allowing several chains to be
+    /// indexed into the same database
+    #[structopt(long, default_value = "", parse(try_from_str = parse_table_prefix))]
+    pub table_prefix: String,
+    /// Store each resource's original bcs bytes alongside its decoded columns
+    #[structopt(long)]
+    pub keep_raw: bool,
+    /// Log any SQL statement taking at least this many milliseconds
+    #[structopt(long)]
+    pub slow_query_ms: Option<u64>,
+    /// Add an `event_key` column, derived from the guid, to event handles
+    #[structopt(long)]
+    pub expand_event_handles: bool,
+    /// Maximum number of deserialized modules to keep cached
+    #[cfg(feature = "replay")]
+    #[structopt(long, default_value = "1000")]
+    pub module_cache_size: usize,
+    /// How many modules to read per query when loading several into the
+    /// cache at once, e.g. those a backup account's resources need
+    #[cfg(feature = "replay")]
+    #[structopt(long, default_value = "400")]
+    pub module_batch_size: usize,
+    /// Keep the layout of every resolved type in the database, keyed by the
+    /// hash of the modules it came from, and load them on startup instead of
+    /// deserializing those modules again
+    #[structopt(long)]
+    pub persist_layouts: bool,
+    /// Store the events emitted by each transaction, checking them against
+    /// the events reported by the endpoint
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub with_events: bool,
+    /// Only store resources of this type in tables; may be repeated. Without
+    /// type parameters, every instantiation of a generic type is stored.
+    /// Other resources are kept as bytes in `__unindexed_resources`, so that
+    /// replay can still read them
+    #[structopt(long, parse(try_from_str = parser::parse_struct_tag))]
+    pub only_struct: Vec<StructTag>,
+    /// How to store u64 and u128 fields: `blob` is compact, `text` and `split`
+    /// (u128 as `_hi` and `_lo` integer columns) compare in numeric order
+    #[structopt(long, default_value = "blob", possible_values = NumericEncoding::VARIANTS)]
+    pub numeric_encoding: NumericEncoding,
+    /// Read u64 INTEGER columns as signed, rejecting negative values rather
+    /// than reading them as the u64 with the same bits, e.g. for tables whose
+    /// u64s were written by another tool as plain numbers
+    #[structopt(long)]
+    pub no_u64_bitcast: bool,
+    /// Keep every version of each resource rather than only the latest
+    #[structopt(long)]
+    pub history: bool,
+    /// Fetch modules missing from the database from the endpoint, e.g. when
+    /// indexing starts from a backup without every module
+    #[structopt(long)]
+    pub resolve_from_endpoint: bool,
+    /// Skip resources whose values nest structs and vectors deeper than this
+    #[structopt(long, default_value = "256")]
+    pub max_value_depth: usize,
+    /// Reclaim unused space in the database after stopping with Ctrl-C
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub vacuum_on_exit: bool,
+    /// Execute blocks against state loaded into memory ahead of time instead
+    /// of reading SQL from inside the VM
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub memory_state: bool,
+    /// Before executing each block, load the reads it is known to make in
+    /// one batched query per resource type
+    #[cfg(feature = "replay")]
+    #[structopt(long, conflicts_with("memory-state"))]
+    pub prefetch: bool,
+    /// Execute blocks on a pool of this many threads of their own, starting
+    /// each block of a chunk once the previous one has executed rather than
+    /// once its writes are stored. Blocks still execute one at a time, since
+    /// each reads what the one before it wrote
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub vm_threads: Option<NonZeroUsize>,
+    /// After transactions whose write sets take more than this many bytes,
+    /// execute fewer transactions at a time to bound memory use
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub max_writeset_bytes: Option<u64>,
+    /// Hold writes back and apply them at most this often, keeping only the
+    /// latest write to each resource (unless --history is given). The sync
+    /// state is saved with them, so a restart replays what was held back
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub flush_interval_ms: Option<u64>,
+    /// Apply held back writes once this many resources and modules have
+    /// pending writes
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub flush_max_rows: Option<usize>,
+    /// Also keep the current validator set in a flat `__validators` table
+    #[structopt(long)]
+    pub flatten_validators: bool,
+    /// Store sub-structs with only scalar fields as a JSON column of their
+    /// parent instead of in their own table, saving joins
+    #[structopt(long)]
+    pub nested_as_json: bool,
+    /// Keep the fields that can be decoded of resources whose types only
+    /// partly resolve in `__partial_resources`, instead of skipping them
+    #[structopt(long)]
+    pub best_effort_resolution: bool,
+    /// Record the block timestamp in effect at every version in a
+    /// `__transactions` table. Timestamps come from each block's update of
+    /// `0x1::DiemTimestamp::CurrentTimeMicroseconds`
+    #[structopt(long)]
+    pub with_timestamps: bool,
+    /// Also store the block timestamp of each resource's last modification
+    /// as `last_modified_time`
+    #[structopt(long)]
+    pub resource_timestamps: bool,
+    /// Store `vector<u8>` fields named `authentication_key`, in any struct,
+    /// as hex text, so they can be compared with strings in SQL
+    #[structopt(long)]
+    pub auth_keys_as_hex: bool,
+    /// What to do when fetching or executing transactions fails: `abort`
+    /// stops, `retry` tries again a few times before stopping, and `skip`
+    /// records each failing transaction in `__skipped_transactions` and
+    /// carries on without its writes
+    #[structopt(long, default_value = "abort", possible_values = ErrorPolicy::VARIANTS)]
+    pub on_error: ErrorPolicy,
+    /// Also append every stored resource to a Parquet dataset in this
+    /// directory, with a subdirectory per type and a file per saved chunk.
+    /// Requires the `parquet-out` feature
+    #[cfg(feature = "replay")]
+    #[structopt(long, parse(from_os_str))]
+    pub parquet_out: Option<PathBuf>,
+    /// Stop with an error at any write that would otherwise be skipped or
+    /// only partly stored, e.g. one whose type doesn't resolve, so that a
+    /// run that finishes has stored everything exactly
+    #[structopt(long, conflicts_with("best-effort-resolution"))]
+    pub strict: bool,
+    /// The SQLite page size in bytes, a power of two from 512 to 65536. Only
+    /// applies when the database is created; it is fixed from then on
+    #[cfg(feature = "replay")]
+    #[structopt(long, parse(try_from_str = parse_page_size))]
+    pub page_size: Option<u32>,
+    /// The SQLite auto-vacuum mode: `none`, `full`, or `incremental`. Only
+    /// applies when the database is created; it is fixed from then on
+    #[cfg(feature = "replay")]
+    #[structopt(long, possible_values = AutoVacuum::VARIANTS)]
+    pub auto_vacuum: Option<AutoVacuum>,
+    /// After each chunk of transactions, free at most this many unused pages
+    /// from the end of the database, reclaiming space gradually rather than with
+    /// one long VACUUM. Requires a database created with
+    /// `--auto-vacuum incremental`
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub incremental_vacuum_pages: Option<NonZeroU32>,
+    /// While indexing, also answer JSON queries over HTTP on this address:
+    /// `/resource/{address}/{struct tag}`, `/account/{address}`, and
+    /// `/version`. Requires the `serve` feature
+    #[structopt(long)]
+    pub serve_addr: Option<SocketAddr>,
+    /// Print how long each span of work took to stderr as it ends: `info`
+    /// for the fetch, execute, and apply phases of each block, and `debug`
+    /// also for each resource write and module loaded
+    #[structopt(long)]
+    pub trace_spans: Option<tracing::Level>,
+    /// Drop all previously indexed data (for this table prefix) and start over
+    #[cfg(feature = "replay")]
+    #[structopt(long)]
+    pub reset: bool,
+    /// Don't ask for confirmation before --reset
+    #[cfg(feature = "replay")]
+    #[structopt(long, requires("reset"))]
+    pub yes: bool,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
 }
 
-fn find_account_address(state: &AccountState) -> AccountAddress {
-    state
-        .get_account_address()
-        .unwrap()
-        .unwrap_or_else(|| {
-            state
-                .iter()
-                .find_map(|(k, _)| {
-                    match Path::try_from(k).unwrap() {
-                        Path::Code(module_id) => Some(module_id.address().clone()),
-                        Path::Resource(_) => None,
-                    }
-                })
-                .unwrap()
-        })
+impl Options {
+    /// The database as sqlx addresses it.
+    pub fn database_url(&self) -> String {
+        format!("sqlite:{}", self.database.display())
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let options = Options::from_args();
+/// How indexing handles a chunk of transactions that fails to be fetched or
+/// executed. Transactions that can't be fetched can't be skipped, so fetch
+/// errors abort under `Skip`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    Abort,
+    Retry,
+    Skip,
+}
+
+impl ErrorPolicy {
+    pub const VARIANTS: &'static [&'static str] = &["abort", "retry", "skip"];
+}
 
-    let client = Client::from_url(options.endpoint.clone(), Retry::default()).unwrap();
+impl FromStr for ErrorPolicy {
+    type Err = anyhow::Error;
 
-    if sqlx::Sqlite::database_exists("sqlite:chain.db").await? {
-        return Err(anyhow!("database already exists"));
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(ErrorPolicy::Abort),
+            "retry" => Ok(ErrorPolicy::Retry),
+            "skip" => Ok(ErrorPolicy::Skip),
+            _ => Err(anyhow!("unknown error policy {}", s)),
+        }
     }
+}
+
+/// How SQLite gives the space of deleted rows back to the file system.
+#[cfg(feature = "replay")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoVacuum {
+    None,
+    Full,
+    Incremental,
+}
+
+#[cfg(feature = "replay")]
+impl AutoVacuum {
+    pub const VARIANTS: &'static [&'static str] = &["none", "full", "incremental"];
 
-    sqlx::Sqlite::create_database("sqlite:chain.db").await?;
-
-    let pool = SqlitePoolOptions::new()
-        .connect("sqlite:chain.db").await?;
-    let db = DB::from_pool(pool.clone());
-    db.initialize().await;
-
-    // if state backup is provided, boostrap with that
-    let mut next_version = if let (Some(backup_file), Some(backup_version)) = (options.backup_file, options.backup_version) {
-        // build an initial resolver. we can do this from genesis since new
-        // modules don't get published.
-        let txns = client.get_transactions(0, 1, false).await?;
-        let bytes = hex::decode(&txns[0].bytes)?;
-        let genesis_tx: Transaction = bcs::from_bytes(&bytes)?;
-        let output = tokio::task::spawn_blocking(move || {
-            let state_view = GenesisState;
-            let mut outputs = DiemVM::execute_block(vec![genesis_tx], &state_view).unwrap();
-            outputs.remove(0)
-        }).await?;
-        let resolver = Resolver::from_pool_and_genesis_write_set(pool.clone(), output.write_set());
-        let annotator = MoveValueAnnotator::new(resolver);
-
-        // process state snaphost from backup
-        for file in backup_file {
-            let backup = Backup::from_file(&file)?;
-            for account_state in backup {
-                let address = find_account_address(&account_state);
-                for (key, value) in account_state.iter() {
-                    let access_path = AccessPath::new(address.clone(), key.clone());
-                    let write_op = WriteOp::Value(value.clone());
-                    db.execute_with_annotator(&access_path, &write_op, &annotator).await;
-                }
-            }
+    pub fn pragma_value(self) -> &'static str {
+        match self {
+            AutoVacuum::None => "NONE",
+            AutoVacuum::Full => "FULL",
+            AutoVacuum::Incremental => "INCREMENTAL",
         }
-        backup_version + 1
-    } else {
-        0
-    };
-
-    if next_version == 0 {
-        // Replay genesis (version 0)
-        println!("tx 0");
-        let txs = client.get_transactions(0, 1, false).await?;
-        let bytes = hex::decode(&txs[0].bytes).unwrap();
-        let tx: Transaction = bcs::from_bytes(&bytes).unwrap();
-        // VM is not async, but will call the `StateView` implementation which
-        // must make async calls so we use `spawn_blocking` to let tokio know.
-        let output = tokio::task::spawn_blocking(move || {
-            let state_view = GenesisState;
-            let mut outputs = DiemVM::execute_block(vec![tx], &state_view).unwrap();
-            outputs.remove(0)
-        }).await?;
-        println!("tx {}", output.status().status().unwrap());
-        let resolver = Resolver::from_pool_and_genesis_write_set(pool.clone(), output.write_set());
-        let annotator = MoveValueAnnotator::new(resolver);
-
-        for (access_path, write_op) in output.write_set() {
-            db.execute_with_annotator(access_path, write_op, &annotator).await;
+    }
+}
+
+#[cfg(feature = "replay")]
+impl FromStr for AutoVacuum {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(AutoVacuum::None),
+            "full" => Ok(AutoVacuum::Full),
+            "incremental" => Ok(AutoVacuum::Incremental),
+            _ => Err(anyhow!("unknown auto-vacuum mode {}", s)),
         }
+    }
+}
 
-        next_version += 1;
+#[cfg(feature = "replay")]
+fn parse_page_size(s: &str) -> Result<u32> {
+    let size = s.parse::<u32>()?;
+    if size.is_power_of_two() && (512..=65536).contains(&size) {
+        Ok(size)
+    } else {
+        Err(anyhow!("page size must be a power of two from 512 to 65536"))
     }
+}
 
-    let resolver = Resolver::from_pool(pool.clone());
-    let annotator = MoveValueAnnotator::new(resolver);
-
-    // Replay the rest of the chain in chunks and continuing tailing
-    loop {
-        let metadata = client.get_metadata().await?;
-        let latest_version = metadata.version;
-        if latest_version < next_version {
-            println!("up to date; waiting for new blocks...");
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            continue;
-        }
+fn parse_table_prefix(prefix: &str) -> Result<String> {
+    if prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(prefix.to_string())
+    } else {
+        Err(anyhow!("table prefix may only contain ASCII letters, digits, and underscores"))
+    }
+}
 
-        for versions in &(next_version..latest_version).chunks(100) {
-            let versions = versions.collect::<Vec<_>>();
-            let first_version = versions[0];
-            let last_version = versions.last().unwrap();
-            
-            println!("syncing from {} to {}", first_version, last_version);
-            let txs = client.get_transactions(first_version, versions.len() as u64, false)
-                .await?
-                .iter()
-                .map(|t| {
-                    let bytes = hex::decode(&t.bytes).unwrap();
-                    bcs::from_bytes::<Transaction>(&bytes).unwrap()
-                })
-                .collect::<Vec<_>>();
-
-            // VM is not async, but will call the `StateView` implementation which
-            // must make async calls so we use `spawn_blocking` to let tokio know.
-            let pool = pool.clone();
-            let pool2 = pool.clone();
-            let outputs = tokio::task::spawn_blocking(move || {
-                let state_view = SqlState::from_pool(pool2);
-                DiemVM::execute_block(txs, &state_view).unwrap()
-            }).await?;
-
-            for output in outputs {
-                for (access_path, write_op) in output.write_set() {
-                    db.execute_with_annotator(access_path, write_op, &annotator).await;
-                }
-            }
-
-            next_version = last_version + 1;
-        }
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut options = Options::from_args();
+
+    if options.strict && options.on_error == ErrorPolicy::Skip {
+        return Err(anyhow!("--strict cannot be combined with --on-error skip"));
+    }
+
+    // pauses and slow queries are always logged; --trace-spans adds how long
+    // each span of work took
+    tracing_subscriber::fmt()
+        .with_max_level(options.trace_spans.unwrap_or(tracing::Level::INFO))
+        .with_span_events(if options.trace_spans.is_some() { FmtSpan::CLOSE } else { FmtSpan::NONE })
+        .with_writer(std::io::stderr)
+        .init();
+
+    let config = Arc::new(Config {
+        table_prefix: options.table_prefix.clone(),
+        keep_raw: options.keep_raw,
+        slow_query_threshold: options.slow_query_ms.map(Duration::from_millis),
+        expand_event_handles: options.expand_event_handles,
+        only_structs: options.only_struct.iter().cloned().collect(),
+        numeric_encoding: options.numeric_encoding,
+        u64_bitcast: !options.no_u64_bitcast,
+        history: options.history,
+        module_endpoint: if options.resolve_from_endpoint {
+            options.endpoint.first().cloned()
+        } else {
+            None
+        },
+        max_value_depth: options.max_value_depth,
+        log_sql: false,
+        flatten_validators: options.flatten_validators,
+        nested_as_json: options.nested_as_json,
+        best_effort_resolution: options.best_effort_resolution,
+        with_timestamps: options.with_timestamps,
+        resource_timestamps: options.resource_timestamps,
+        auth_keys_as_hex: options.auth_keys_as_hex,
+        strict: options.strict,
+        persist_layouts: options.persist_layouts,
+    });
+
+    if let Some(command) = options.command.take() {
+        let pool = SqlitePoolOptions::new()
+            .connect(&options.database_url()).await?;
+        return command.run(pool, config, &options.database, options.endpoint.first().cloned()).await;
+    }
+
+    match options.serve_addr {
+        Some(addr) => {
+            // the database may only be created once indexing starts
+            let pool = SqlitePoolOptions::new().connect_lazy(&options.database_url())?;
+            index_and_serve(options, config, addr, pool).await
+        },
+        None => index::run(options, config).await,
+    }
+}
+
+/// Index and answer queries together, until indexing stops.
+#[cfg(feature = "serve")]
+async fn index_and_serve(options: Options, config: Arc<Config>, addr: SocketAddr, pool: SqlitePool) -> Result<()> {
+    tokio::select! {
+        result = index::run(options, config.clone()) => result,
+        result = serve::run(addr, pool, config) => result,
     }
 }
+
+#[cfg(not(feature = "serve"))]
+async fn index_and_serve(_options: Options, _config: Arc<Config>, _addr: SocketAddr, _pool: SqlitePool) -> Result<()> {
+    Err(anyhow!("--serve-addr requires building with the `serve` feature"))
+}
@@ -0,0 +1,183 @@
+use anyhow::{anyhow, Result};
+use move_core_types::value::MoveValue;
+use sqlx::{sqlite::SqlitePool, Row};
+use std::{io::Write, sync::Arc};
+
+use crate::{
+    annotator::MoveValueAnnotator,
+    db::{self, Config, DB, SCHEMA_VERSION},
+    resolver::{ModuleCache, Resolver, DEFAULT_MODULE_CACHE_SIZE},
+};
+
+/// Rewrite every stored resource written under schema version `from` with
+/// the current layout, then stamp the database with `SCHEMA_VERSION`.
+///
+/// Resources are read back with the rules of `from` and written to tables
+/// under a temporary prefix, each in its own transaction. Only once all of
+/// them are written are the old tables replaced, so an interrupted migration
+/// leaves the database as it was and can simply be run again. Progress is
+/// written to `out`.
+pub async fn run(pool: SqlitePool, config: Arc<Config>, from: u64, out: &mut impl Write) -> Result<()> {
+    let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+    let db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+    if !db.is_initialized().await {
+        return Err(anyhow!("there is no indexed data with table prefix {:?} to migrate", config.table_prefix));
+    }
+    db.upgrade().await;
+    let current = db.schema_version().await;
+    if current != from {
+        return Err(anyhow!("the database is at schema version {}, not {}", current, from));
+    }
+    if from == SCHEMA_VERSION {
+        writeln!(out, "already at schema version {}", SCHEMA_VERSION)?;
+        return Ok(());
+    }
+
+    let old_config = Arc::new(db::schema_config(from, &config)?);
+    let new_config = Arc::new(Config {
+        table_prefix: format!("{}__migrating__", config.table_prefix),
+        ..(*config).clone()
+    });
+    let resolver = Resolver::from_shared_cache(pool.clone(), old_config.clone(), modules.clone());
+    let annotator = MoveValueAnnotator::new(Resolver::from_shared_cache(pool.clone(), old_config.clone(), modules));
+    let created_tables = db::CreatedTables::default();
+    let mut conn = pool.acquire().await?;
+
+    // start over from anything an interrupted run left behind
+    for table in table_names(&pool).await? {
+        if table.starts_with(new_config.table_prefix.as_str()) {
+            sqlx::query(&format!("DROP TABLE {}", db::quote(&table))).execute(&mut conn).await?;
+        }
+    }
+
+    let root_prefix = format!("{}__root__", config.table_prefix);
+    let mut root_tables = table_names(&pool)
+        .await?
+        .into_iter()
+        .filter(|name| name.starts_with(&root_prefix))
+        .collect::<Vec<_>>();
+    root_tables.sort();
+    for root_table in root_tables {
+        let tag = db::struct_tag_from_name(&root_table[root_prefix.len()..])
+            .ok_or_else(|| anyhow!("cannot tell the type stored in {}", root_table))?;
+        let struct_ = resolver.resolve_struct(&tag).await?;
+        let rows = sqlx::query(&format!("SELECT * FROM {} ORDER BY rowid", db::quote(&root_table)))
+            .fetch_all(&mut conn)
+            .await?;
+        for row in &rows {
+            let address = db::address_from_bytes(&row.get::<Vec<u8>, _>("address"))?;
+            let id: i64 = row.get("id");
+            let version = row.get::<i64, _>("last_modified_version") as u64;
+            // only present when indexed with --keep-raw or --resource-timestamps,
+            // in which case they are written again
+            let raw = row.try_get::<Vec<u8>, _>("raw").unwrap_or_default();
+            let time = row.try_get::<Option<i64>, _>("last_modified_time").ok().flatten();
+
+            let value = match db::fetch_struct(&old_config, &tag, id, &resolver, &mut conn).await? {
+                Some(MoveValue::Struct(value)) => value,
+                _ => return Err(anyhow!("{} at {} is missing its struct row", tag, address)),
+            };
+            let value = annotator.annotate_struct(&value, &struct_).await?;
+            sqlx::query("BEGIN").execute(&mut conn).await?;
+            let time = time.map(|usecs| usecs as u64);
+            db::generate_sql(&new_config, &created_tables, &address, &value, &raw, version, time, &mut conn).await?;
+            sqlx::query("COMMIT").execute(&mut conn).await?;
+        }
+        writeln!(out, "migrated {} rows of {}", rows.len(), tag)?;
+    }
+
+    // swap the rewritten tables in for the old ones all at once
+    let tables = table_names(&pool).await?;
+    sqlx::query("BEGIN").execute(&mut conn).await?;
+    for table in &tables {
+        if is_resource_table(&config, table) {
+            sqlx::query(&format!("DROP TABLE {}", db::quote(table))).execute(&mut conn).await?;
+        }
+    }
+    for table in &tables {
+        if let Some(rest) = table.strip_prefix(new_config.table_prefix.as_str()) {
+            let renamed = format!("{}{}", config.table_prefix, rest);
+            let rename_sql = format!("ALTER TABLE {} RENAME TO {}", db::quote(table), db::quote(&renamed));
+            sqlx::query(&rename_sql).execute(&mut conn).await?;
+        }
+    }
+    let update_sql = format!("UPDATE {} SET schema_version = ? WHERE id = 0", config.sync_state_table_name());
+    sqlx::query(&update_sql).bind(SCHEMA_VERSION as i64).execute(&mut conn).await?;
+    sqlx::query("COMMIT").execute(&mut conn).await?;
+
+    writeln!(out, "migrated from schema version {} to {}", from, SCHEMA_VERSION)?;
+    Ok(())
+}
+
+/// Every table, named as `sqlite_master` lists them, without quotes.
+async fn table_names(pool: &SqlitePool) -> Result<Vec<String>> {
+    Ok(sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'")
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.get::<String, _>(0))
+        .collect())
+}
+
+/// Whether a table holds resources, as a `__root__` table or one of the
+/// struct and vector tables below them, rather than bookkeeping.
+fn is_resource_table(config: &Config, name: &str) -> bool {
+    match name.strip_prefix(config.table_prefix.as_str()) {
+        Some(rest) => rest.starts_with("__root__") || (rest.starts_with('x') && config.is_generated_table(name)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use move_core_types::value::MoveStruct;
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::{
+        annotator::AnnotatedMoveValue,
+        db::NumericEncoding,
+        test_util::{self, TestDb, TestStruct, ADDRESS},
+    };
+
+    #[tokio::test]
+    async fn schema_0_numbers_are_rewritten_under_the_encoding() {
+        let db = TestDb::new(test_util::config()).await;
+        let module = test_util::module_bytes("M", &[TestStruct::resource("R", vec![("total", SignatureToken::U128)])]);
+        db.publish(&module).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let total = u128::MAX - 1;
+        db.store(1, &tag, MoveStruct::new(vec![MoveValue::U128(total)])).await;
+        // as written before the schema version was recorded
+        let update_sql = format!("UPDATE {} SET schema_version = 0 WHERE id = 0", db.config.sync_state_table_name());
+        sqlx::query(&update_sql).execute(&db.pool).await.unwrap();
+
+        let config = Arc::new(Config { numeric_encoding: NumericEncoding::Text, ..test_util::config() });
+        let mut out = vec![];
+        run(db.pool.clone(), config.clone(), 0, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!(
+            "migrated 1 rows of {}\nmigrated from schema version 0 to {}\n",
+            tag,
+            SCHEMA_VERSION,
+        ));
+
+        let pragma_sql = format!("PRAGMA table_info({})", db::struct_tag_to_sql(&config, &tag));
+        let columns = sqlx::query(&pragma_sql)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.get::<String, _>(1), row.get::<String, _>(2)))
+            .collect::<Vec<_>>();
+        assert!(columns.contains(&("total".to_string(), "TEXT".to_string())), "{:?}", columns);
+        let migrated = DB::from_pool(db.pool.clone(), config.clone(), db.modules.clone());
+        assert_eq!(migrated.schema_version().await, SCHEMA_VERSION);
+        let resource = migrated.read_resource(&ADDRESS, &tag).await.unwrap().unwrap();
+        assert!(matches!(resource.value[0].1, AnnotatedMoveValue::U128(v) if v == total), "{:?}", resource);
+
+        // a second run has nothing to do
+        let mut out = vec![];
+        run(db.pool.clone(), config, SCHEMA_VERSION, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("already at schema version {}\n", SCHEMA_VERSION));
+    }
+}
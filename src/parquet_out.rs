@@ -0,0 +1,312 @@
+use anyhow::{anyhow, Result};
+use arrow::{
+    array::{ArrayRef, BinaryArray, BooleanArray, StringArray, UInt64Array, UInt8Array},
+    datatypes::{DataType, Field, Schema},
+    record_batch::RecordBatch,
+};
+use diem_types::{access_path::Path, write_set::WriteOp};
+use move_core_types::{account_address::AccountAddress, language_storage::StructTag};
+use parquet::arrow::ArrowWriter;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    annotator::{AnnotatedMoveStruct, AnnotatedMoveValue},
+    db::{self, WriteKind, WriteObserver},
+};
+
+/// One flattened field of a stored resource.
+enum Cell {
+    U8(u8),
+    U64(u64),
+    Bool(bool),
+    /// u128s as decimal, addresses as hex, and vectors and sub-structs as
+    /// JSON.
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Cell {
+    fn data_type(&self) -> DataType {
+        match self {
+            Cell::U8(_) => DataType::UInt8,
+            Cell::U64(_) => DataType::UInt64,
+            Cell::Bool(_) => DataType::Boolean,
+            Cell::Text(_) => DataType::Utf8,
+            Cell::Bytes(_) => DataType::Binary,
+        }
+    }
+}
+
+struct Row {
+    version: u64,
+    address: AccountAddress,
+    cells: Vec<(String, Cell)>,
+}
+
+impl Row {
+    /// The name and column type of each cell, which is the same for every row
+    /// of a type until a module upgrade changes its fields.
+    fn layout(&self) -> Vec<(&str, DataType)> {
+        self.cells.iter().map(|(name, cell)| (name.as_str(), cell.data_type())).collect()
+    }
+}
+
+/// Writes every stored resource to an append-only Parquet dataset with a
+/// directory per struct tag. Rows are buffered until the sync state is saved,
+/// then each type's rows are written to a file named after the versions it
+/// covers. Rows on either side of a change to a type's fields go to separate
+/// files, which are also numbered by their run. A run that crashed before its
+/// sync state was saved writes the same files again when resumed, replacing
+/// them.
+pub struct ParquetSink {
+    dir: PathBuf,
+    rows: Mutex<HashMap<StructTag, Vec<Row>>>,
+}
+
+impl ParquetSink {
+    pub fn from_dir(dir: PathBuf) -> Result<ParquetSink> {
+        fs::create_dir_all(&dir)?;
+        Ok(ParquetSink {
+            dir,
+            rows: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Write rows of a single layout, the `run`th of the flush if it split
+    /// the type's rows into several.
+    fn write_file(&self, tag: &StructTag, rows: Vec<Row>, run: Option<usize>) -> Result<()> {
+        let mut fields = vec![
+            Field::new("version", DataType::UInt64, false),
+            Field::new("address", DataType::Utf8, false),
+        ];
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(rows.iter().map(|row| row.version).collect::<Vec<_>>())),
+            Arc::new(StringArray::from(rows.iter().map(|row| hex::encode(row.address)).collect::<Vec<_>>())),
+        ];
+        // the rows all have the first row's layout, so its cells decide each
+        // column's type
+        for (i, (name, cell)) in rows[0].cells.iter().enumerate() {
+            let cells = rows.iter().map(|row| &row.cells[i].1);
+            let column: ArrayRef = match cell {
+                Cell::U8(_) => Arc::new(UInt8Array::from(cells.map(|cell| match cell {
+                    Cell::U8(v) => *v,
+                    _ => unreachable!(),
+                }).collect::<Vec<_>>())),
+                Cell::U64(_) => Arc::new(UInt64Array::from(cells.map(|cell| match cell {
+                    Cell::U64(v) => *v,
+                    _ => unreachable!(),
+                }).collect::<Vec<_>>())),
+                Cell::Bool(_) => Arc::new(BooleanArray::from(cells.map(|cell| match cell {
+                    Cell::Bool(v) => *v,
+                    _ => unreachable!(),
+                }).collect::<Vec<_>>())),
+                Cell::Text(_) => Arc::new(StringArray::from(cells.map(|cell| match cell {
+                    Cell::Text(v) => v.as_str(),
+                    _ => unreachable!(),
+                }).collect::<Vec<_>>())),
+                Cell::Bytes(_) => Arc::new(BinaryArray::from(cells.map(|cell| match cell {
+                    Cell::Bytes(v) => v.as_slice(),
+                    _ => unreachable!(),
+                }).collect::<Vec<_>>())),
+            };
+            fields.push(Field::new(name, cell.data_type(), false));
+            columns.push(column);
+        }
+
+        let schema = Arc::new(Schema::new(fields));
+        let batch = RecordBatch::try_new(schema.clone(), columns)?;
+        let dir = self.dir.join(db::struct_tag_name(tag));
+        fs::create_dir_all(&dir)?;
+        let versions = format!("{}-{}", rows[0].version, rows[rows.len() - 1].version);
+        let name = match run {
+            Some(run) => format!("{}_{}.parquet", versions, run),
+            None => format!("{}.parquet", versions),
+        };
+        // written aside and renamed into place, so that a file is never seen
+        // half written and one from an earlier attempt is replaced whole
+        let temp = dir.join(format!("{}.tmp", name));
+        let mut writer = ArrowWriter::try_new(File::create(&temp)?, schema, None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        fs::rename(&temp, dir.join(name))?;
+        Ok(())
+    }
+}
+
+impl WriteObserver for ParquetSink {
+    fn on_write(&self, _version: u64, _address: &AccountAddress, _path: &Path, _op: &WriteOp, _kind: Option<WriteKind>) {}
+
+    fn on_store(&self, version: u64, address: &AccountAddress, resource: &AnnotatedMoveStruct) {
+        let cells = resource.value
+            .iter()
+            .map(|(name, value)| (name.to_string(), to_cell(value)))
+            .collect();
+        self.rows
+            .lock()
+            .unwrap()
+            .entry(resource.type_.clone())
+            .or_default()
+            .push(Row { version, address: *address, cells });
+    }
+
+    fn flush(&self) -> Result<()> {
+        let rows = std::mem::take(&mut *self.rows.lock().unwrap());
+        for (tag, rows) in rows {
+            let runs = split_by_layout(rows);
+            let numbered = runs.len() > 1;
+            for (i, rows) in runs.into_iter().enumerate() {
+                self.write_file(&tag, rows, Some(i).filter(|_| numbered))
+                    .map_err(|e| anyhow!("failed writing parquet rows of {}: {}", tag, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split the rows of one type, in order, wherever the layout changes, so
+/// that each file has a single schema.
+fn split_by_layout(rows: Vec<Row>) -> Vec<Vec<Row>> {
+    let mut runs: Vec<Vec<Row>> = vec![];
+    for row in rows {
+        match runs.last_mut() {
+            Some(run) if run[0].layout() == row.layout() => run.push(row),
+            _ => runs.push(vec![row]),
+        }
+    }
+    runs
+}
+
+fn to_cell(value: &AnnotatedMoveValue) -> Cell {
+    match value {
+        AnnotatedMoveValue::U8(v) => Cell::U8(*v),
+        AnnotatedMoveValue::U64(v) => Cell::U64(*v),
+        AnnotatedMoveValue::U128(v) => Cell::Text(v.to_string()),
+        AnnotatedMoveValue::Bool(v) => Cell::Bool(*v),
+        AnnotatedMoveValue::Address(v) => Cell::Text(hex::encode(v)),
+        AnnotatedMoveValue::Bytes(v) => Cell::Bytes(v.clone()),
+        AnnotatedMoveValue::Vector(..) | AnnotatedMoveValue::Struct(_) => {
+            Cell::Text(serde_json::to_string(value).unwrap())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::Array;
+    use move_core_types::identifier::Identifier;
+    use parquet::{
+        arrow::{ArrowReader, ParquetFileArrowReader},
+        file::reader::SerializedFileReader,
+    };
+
+    use super::*;
+    use crate::test_util::{self, ADDRESS};
+
+    fn resource(fields: Vec<(&str, AnnotatedMoveValue)>) -> AnnotatedMoveStruct {
+        AnnotatedMoveStruct {
+            is_resource: true,
+            type_: test_util::struct_tag("M", "R", vec![]),
+            value: fields.into_iter().map(|(name, value)| (Identifier::new(name).unwrap(), value)).collect(),
+        }
+    }
+
+    /// The only batch in a Parquet file, read with the arrow reader.
+    fn read(path: PathBuf) -> RecordBatch {
+        let reader = SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+        let mut batches = ParquetFileArrowReader::new(Arc::new(reader)).get_record_reader(1024).unwrap();
+        let batch = batches.next().unwrap().unwrap();
+        assert!(batches.next().is_none());
+        batch
+    }
+
+    #[test]
+    fn stored_resources_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ParquetSink::from_dir(dir.path().to_path_buf()).unwrap();
+        let other = AccountAddress::new([1; AccountAddress::LENGTH]);
+        sink.on_store(1, &ADDRESS, &resource(vec![
+            ("value", AnnotatedMoveValue::U64(7)),
+            ("flag", AnnotatedMoveValue::Bool(true)),
+        ]));
+        sink.on_store(2, &other, &resource(vec![
+            ("value", AnnotatedMoveValue::U64(9)),
+            ("flag", AnnotatedMoveValue::Bool(false)),
+        ]));
+        // an upgrade adds a field
+        sink.on_store(3, &ADDRESS, &resource(vec![
+            ("value", AnnotatedMoveValue::U64(11)),
+            ("flag", AnnotatedMoveValue::Bool(true)),
+            ("owner", AnnotatedMoveValue::Address(other)),
+        ]));
+        sink.flush().unwrap();
+
+        let type_dir = dir.path().join(db::struct_tag_name(&test_util::struct_tag("M", "R", vec![])));
+        let batch = read(type_dir.join("1-2_0.parquet"));
+        assert_eq!(batch.num_rows(), 2);
+        let schema = batch.schema();
+        let names = schema.fields().iter().map(|field| field.name().as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["version", "address", "value", "flag"]);
+        let versions = batch.column(0).as_any().downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!((versions.value(0), versions.value(1)), (1, 2));
+        let addresses = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!((addresses.value(0), addresses.value(1)), (hex::encode(ADDRESS).as_str(), hex::encode(other).as_str()));
+        let values = batch.column(2).as_any().downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!((values.value(0), values.value(1)), (7, 9));
+        let flags = batch.column(3).as_any().downcast_ref::<BooleanArray>().unwrap();
+        assert_eq!((flags.value(0), flags.value(1)), (true, false));
+
+        let batch = read(type_dir.join("3-3_1.parquet"));
+        assert_eq!(batch.num_rows(), 1);
+        let owners = batch.column(4).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(owners.value(0), hex::encode(other));
+
+        // the rows were written once
+        sink.flush().unwrap();
+        assert_eq!(fs::read_dir(&type_dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn flushing_the_same_versions_again_replaces_their_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let type_dir = dir.path().join(db::struct_tag_name(&test_util::struct_tag("M", "R", vec![])));
+        // as a run resumed after crashing before its sync state was saved
+        for value in &[7, 9] {
+            let sink = ParquetSink::from_dir(dir.path().to_path_buf()).unwrap();
+            sink.on_store(1, &ADDRESS, &resource(vec![("value", AnnotatedMoveValue::U64(*value))]));
+            sink.flush().unwrap();
+        }
+
+        let names = fs::read_dir(&type_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["1-1.parquet"]);
+        let batch = read(type_dir.join("1-1.parquet"));
+        let values = batch.column(2).as_any().downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!(values.value(0), 9);
+    }
+
+    #[test]
+    fn layouts_of_one_version_get_a_file_each() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ParquetSink::from_dir(dir.path().to_path_buf()).unwrap();
+        let other = AccountAddress::new([1; AccountAddress::LENGTH]);
+        // an upgrade within version 5, between writes to two accounts
+        sink.on_store(5, &ADDRESS, &resource(vec![("value", AnnotatedMoveValue::U64(7))]));
+        sink.on_store(5, &other, &resource(vec![
+            ("value", AnnotatedMoveValue::U64(9)),
+            ("flag", AnnotatedMoveValue::Bool(true)),
+        ]));
+        sink.flush().unwrap();
+
+        let type_dir = dir.path().join(db::struct_tag_name(&test_util::struct_tag("M", "R", vec![])));
+        assert_eq!(read(type_dir.join("5-5_0.parquet")).num_columns(), 3);
+        assert_eq!(read(type_dir.join("5-5_1.parquet")).num_columns(), 4);
+        assert_eq!(fs::read_dir(&type_dir).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Result};
+use move_core_types::{
+    account_address::AccountAddress,
+    identifier::Identifier,
+    language_storage::{StructTag, TypeTag},
+};
+
+/// Parse a struct tag in its canonical form, e.g.
+/// `0x1::Diem::Diem<0x1::XUS::XUS>`.
+pub fn parse_struct_tag(s: &str) -> Result<StructTag> {
+    match parse_type_tag(s)? {
+        TypeTag::Struct(tag) => Ok(tag),
+        other => Err(anyhow!("{} is not a struct type", other)),
+    }
+}
+
+/// Parse an account address in hex, with or without a leading `0x`.
+pub fn parse_address(s: &str) -> Result<AccountAddress> {
+    let literal = if s.starts_with("0x") { s.to_string() } else { format!("0x{}", s) };
+    AccountAddress::from_hex_literal(&literal).map_err(|e| anyhow!("invalid address {}: {}", s, e))
+}
+
+/// Parse a type tag in its canonical form, e.g. `vector<u8>` or
+/// `0x1::Option::Option<u64>`.
+pub fn parse_type_tag(s: &str) -> Result<TypeTag> {
+    let mut parser = TypeTagParser { rest: s };
+    let type_tag = parser.parse_type_tag()?;
+    parser.skip_whitespace();
+    if !parser.rest.is_empty() {
+        return Err(anyhow!("unexpected {:?} after type {}", parser.rest, type_tag));
+    }
+    Ok(type_tag)
+}
+
+struct TypeTagParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> TypeTagParser<'a> {
+    fn parse_type_tag(&mut self) -> Result<TypeTag> {
+        let word = self.next_word();
+        Ok(match word {
+            "bool" => TypeTag::Bool,
+            "u8" => TypeTag::U8,
+            "u64" => TypeTag::U64,
+            "u128" => TypeTag::U128,
+            "address" => TypeTag::Address,
+            "signer" => TypeTag::Signer,
+            "vector" => {
+                self.expect("<")?;
+                let element = self.parse_type_tag()?;
+                self.expect(">")?;
+                TypeTag::Vector(Box::new(element))
+            },
+            "" => return Err(self.expected("a type")),
+            w if w.starts_with("0x") => TypeTag::Struct(self.parse_struct_tag(w)?),
+            w => return Err(anyhow!("unknown type {}", w)),
+        })
+    }
+
+    fn parse_struct_tag(&mut self, address: &str) -> Result<StructTag> {
+        let address = AccountAddress::from_hex_literal(address)
+            .map_err(|e| anyhow!("invalid address {}: {}", address, e))?;
+        self.expect("::")?;
+        let module = self.parse_identifier()?;
+        self.expect("::")?;
+        let name = self.parse_identifier()?;
+
+        let mut type_params = vec![];
+        if self.consume("<") {
+            loop {
+                type_params.push(self.parse_type_tag()?);
+                if self.consume(",") {
+                    continue;
+                }
+                self.expect(">")?;
+                break;
+            }
+        }
+
+        Ok(StructTag {
+            address,
+            module,
+            name,
+            type_params,
+        })
+    }
+
+    fn parse_identifier(&mut self) -> Result<Identifier> {
+        let word = self.next_word();
+        if word.is_empty() {
+            return Err(self.expected("an identifier"));
+        }
+        Identifier::new(word).map_err(|e| anyhow!("invalid identifier {}: {}", word, e))
+    }
+
+    fn next_word(&mut self) -> &'a str {
+        self.skip_whitespace();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or_else(|| self.rest.len());
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        word
+    }
+
+    fn consume(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest.starts_with(token) {
+            self.rest = &self.rest[token.len()..];
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if self.consume(token) {
+            Ok(())
+        } else {
+            Err(self.expected(&format!("{:?}", token)))
+        }
+    }
+
+    fn expected(&self, what: &str) -> anyhow::Error {
+        if self.rest.is_empty() {
+            anyhow!("expected {} but reached the end", what)
+        } else {
+            anyhow!("expected {} at {:?}", what, self.rest)
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use move_core_types::language_storage::CORE_CODE_ADDRESS;
+
+    use super::*;
+
+    fn tag(module: &str, name: &str, type_params: Vec<TypeTag>) -> StructTag {
+        StructTag {
+            address: CORE_CODE_ADDRESS,
+            module: Identifier::new(module).unwrap(),
+            name: Identifier::new(name).unwrap(),
+            type_params,
+        }
+    }
+
+    #[test]
+    fn struct_tags_parse() {
+        assert_eq!(parse_struct_tag("0x1::XUS::XUS").unwrap(), tag("XUS", "XUS", vec![]));
+        let xus = TypeTag::Struct(tag("XUS", "XUS", vec![]));
+        assert_eq!(
+            parse_struct_tag("0x1::Diem::Diem<0x1::XUS::XUS>").unwrap(),
+            tag("Diem", "Diem", vec![xus.clone()]),
+        );
+        // nested generics, with whitespace anywhere between tokens
+        let nested = parse_struct_tag(" 0x1::Event::EventHandle < vector<0x1::Diem::Diem<0x1::XUS::XUS>> , u64 > ").unwrap();
+        assert_eq!(nested, tag("Event", "EventHandle", vec![
+            TypeTag::Vector(Box::new(TypeTag::Struct(tag("Diem", "Diem", vec![xus])))),
+            TypeTag::U64,
+        ]));
+        // the canonical form a tag displays as parses back to it
+        assert_eq!(parse_struct_tag(&nested.to_string()).unwrap(), nested);
+    }
+
+    #[test]
+    fn malformed_struct_tags_are_errors() {
+        let error = |s| parse_struct_tag(s).unwrap_err().to_string();
+        assert!(error("u64").contains("is not a struct type"), "{}", error("u64"));
+        assert!(error("0x1::XUS").contains("expected \"::\" but reached the end"), "{}", error("0x1::XUS"));
+        assert!(error("0x1::Diem::Diem<").contains("expected a type but reached the end"), "{}", error("0x1::Diem::Diem<"));
+        assert!(error("0x1::Diem::Diem<u64").contains("expected \">\""), "{}", error("0x1::Diem::Diem<u64"));
+        assert!(error("0x1::Diem::Diem<u64>>").contains("unexpected \">\""), "{}", error("0x1::Diem::Diem<u64>>"));
+        assert!(error("0x1::Diem::Diem<u32>").contains("unknown type u32"), "{}", error("0x1::Diem::Diem<u32>"));
+        assert!(error("0xzz::Diem::Diem").contains("invalid address"), "{}", error("0xzz::Diem::Diem"));
+        assert!(error("0x1::1Diem::Diem").contains("invalid identifier 1Diem"), "{}", error("0x1::1Diem::Diem"));
+        assert!(error("").contains("expected a type"), "{}", error(""));
+    }
+
+    #[test]
+    fn addresses_parse_with_or_without_prefix() {
+        assert_eq!(parse_address("0x1").unwrap(), CORE_CODE_ADDRESS);
+        assert_eq!(parse_address("1").unwrap(), CORE_CODE_ADDRESS);
+        assert!(parse_address("0x1g").is_err());
+    }
+}
@@ -1,94 +1,265 @@
 use anyhow::{anyhow, Result};
+use diem_json_rpc_client::async_client::{Client, Retry};
 use diem_types::{
-    access_path::Path,
+    access_path::{AccessPath, Path},
     account_address::AccountAddress,
+    account_state::AccountState,
+    account_state_blob::AccountStateBlob,
     write_set::{WriteOp, WriteSet},
 };
 use move_core_types::{
-    identifier::IdentStr,
+    identifier::{IdentStr, Identifier},
     language_storage::{ModuleId, StructTag, TypeTag},
 };
+use lru::LruCache;
+use sha2::{Digest, Sha256};
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
+    convert::TryFrom,
+    fmt,
     future::Future,
     pin::Pin,
-    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 use sqlx::{
     Row,
     sqlite::SqlitePool,
 };
+use url::Url;
 use vm::{
     access::ModuleAccess,
     file_format::{CompiledModule, SignatureToken, StructDefinitionIndex, StructFieldInformation, StructHandleIndex},
 };
 
 use crate::{
+    db::{self, Config, CreatedTables, StoredLayout},
     fat_type::{FatStructType, FatType},
 };
 
+/// The number of modules a resolver keeps cached unless told otherwise.
+pub const DEFAULT_MODULE_CACHE_SIZE: usize = 1000;
+
+/// A size bounded cache of deserialized modules. This is meant to be shared
+/// by every resolver in the process.
+pub struct ModuleCache {
+    modules: Mutex<LruCache<ModuleId, Arc<CompiledModule>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// The hash of each module's bytes as stored, for checking layouts kept
+    /// by `--persist-layouts` against.
+    hashes: Mutex<HashMap<ModuleId, Vec<u8>>>,
+    /// Layouts kept by `--persist-layouts`, keyed by struct tag.
+    layouts: Mutex<HashMap<String, StoredLayout>>,
+}
+
+impl ModuleCache {
+    pub fn new(capacity: usize) -> Self {
+        ModuleCache {
+            modules: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            hashes: Mutex::new(HashMap::new()),
+            layouts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn get(&self, module_id: &ModuleId) -> Option<Arc<CompiledModule>> {
+        let module = self.modules.lock().unwrap().get(module_id).cloned();
+        if module.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        module
+    }
+
+    fn contains(&self, module_id: &ModuleId) -> bool {
+        self.modules.lock().unwrap().contains(module_id)
+    }
+
+    fn insert(&self, module_id: ModuleId, module: Arc<CompiledModule>) {
+        self.modules.lock().unwrap().put(module_id, module);
+    }
+
+    /// Forget a module, e.g. because it was upgraded. Stored layouts built
+    /// from it then no longer match its hash and are resolved again.
+    pub fn invalidate(&self, module_id: &ModuleId) {
+        self.modules.lock().unwrap().pop(module_id);
+        self.hashes.lock().unwrap().remove(module_id);
+    }
+}
+
 pub struct Resolver {
     pool: SqlitePool,
-    cache: RefCell<HashMap<ModuleId, Rc<CompiledModule>>>,
+    config: Arc<Config>,
+    cache: Arc<ModuleCache>,
+    /// The tables created through this resolver, i.e. `__layouts`.
+    tables: Arc<CreatedTables>,
+    /// Where modules missing from the database are read from, in place of a
+    /// client of `Config::module_endpoint`.
+    module_source: Option<Arc<dyn ModuleSource>>,
 }
 
 impl Resolver {
-    pub fn from_pool(pool: SqlitePool) -> Self {
-        let cache = RefCell::new(HashMap::new());
+    /// Create a resolver with its own cache. Long lived code should share a
+    /// cache via `from_shared_cache` instead.
+    pub fn from_pool(pool: SqlitePool, config: Arc<Config>) -> Self {
+        let cache = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+        Resolver::from_shared_cache(pool, config, cache)
+    }
+
+    pub fn from_shared_cache(pool: SqlitePool, config: Arc<Config>, cache: Arc<ModuleCache>) -> Self {
         Resolver {
             pool,
+            config,
             cache,
+            tables: Arc::new(CreatedTables::default()),
+            module_source: None,
         }
     }
 
+    /// Share the tables created by the `DB` writing to the same database,
+    /// rather than checking them anew. `DB::resolver` does this.
+    pub fn with_tables(mut self, tables: Arc<CreatedTables>) -> Resolver {
+        self.tables = tables;
+        self
+    }
+
+    /// Read the modules missing from the database from `module_source`
+    /// rather than the endpoint itself. This still only happens with
+    /// `--resolve-from-endpoint`.
+    pub fn with_module_source(mut self, module_source: Arc<dyn ModuleSource>) -> Resolver {
+        self.module_source = Some(module_source);
+        self
+    }
+
     /// Pre-fill the internal cache with the modules published in the write
     /// set. This should only be needed when resolving the genesis
     /// transaction.
-    pub fn from_pool_and_genesis_write_set(pool: SqlitePool, write_set: &WriteSet) -> Self {
-        let mut map = HashMap::new();
+    pub fn from_pool_and_genesis_write_set(pool: SqlitePool, config: Arc<Config>, write_set: &WriteSet) -> Self {
+        let mut modules = vec![];
         for (access_path, write_op) in write_set {
             let path: Path = bcs::from_bytes(&access_path.path).unwrap();
             match (&path, write_op) {
                 (Path::Code(module_id), WriteOp::Value(value)) => {
                     let module = CompiledModule::deserialize(value).unwrap();
-                    map.insert(module_id.clone(), Rc::new(module));
+                    modules.push((module_id.clone(), Arc::new(module)));
                 },
                 _ => {},
             }
         }
 
-        let cache = RefCell::new(map);
-        Resolver {
-            pool,
-            cache,
+        // these modules may not be in the database yet, so none of them can
+        // be evicted
+        let cache = ModuleCache::new(modules.len() + DEFAULT_MODULE_CACHE_SIZE);
+        for (module_id, module) in modules {
+            cache.insert(module_id, module);
         }
+        Resolver::from_shared_cache(pool, config, Arc::new(cache))
     }
 
-    pub async fn get_module(&self, address: &AccountAddress, name: &IdentStr) -> Result<Rc<CompiledModule>> {
-        let mut db = self.pool.acquire().await?;
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub async fn get_module(&self, address: &AccountAddress, name: &IdentStr) -> Result<Arc<CompiledModule>> {
         let module_id = ModuleId::new(address.clone(), name.to_owned());
-        let cached = self.cache.borrow().get(&module_id).cloned();
-        if let Some(module) = cached {
-            Ok(module)
-        } else {
-            let result = sqlx::query("SELECT data FROM __module WHERE address = ? AND name = ?")
-                .bind(address.as_ref())
-                .bind(name.as_str())
-                .fetch_optional(&mut db)
-                .await?;
-            match result {
-                None => Err(anyhow!("module {}::{} not found", address.short_str(), name)),
-                Some(row) => {
-                    let data: Vec<u8> = row.get(0);
-                    let module = CompiledModule::deserialize(&data)
-                        .map_err(|e| anyhow!("module {}::{} failed deserialization: {}", address.short_str(), name, e))?;
-                    let module = Rc::new(module);
-                    self.cache.borrow_mut().insert(module_id, module.clone());
-                    Ok(module)
-                },
+        if let Some(module) = self.cache.get(&module_id) {
+            return Ok(module);
+        }
+        self.load_module(module_id).await
+    }
+
+    /// Read a module missing from the cache from the database, or the
+    /// endpoint with `--resolve-from-endpoint`, and cache it.
+    #[tracing::instrument(level = "debug", skip(self, module_id), fields(module = %module_id))]
+    async fn load_module(&self, module_id: ModuleId) -> Result<Arc<CompiledModule>> {
+        let (address, name) = (module_id.address(), module_id.name());
+        let mut db = self.pool.acquire().await?;
+        let select_sql = format!(
+            "SELECT data FROM {} WHERE address = ? AND name = ?",
+            self.config.module_table_name(),
+        );
+        let result = sqlx::query(&select_sql)
+            .bind(address.as_ref())
+            .bind(name.as_str())
+            .fetch_optional(&mut db)
+            .await?;
+        let data: Vec<u8> = match (result, &self.config.module_endpoint) {
+            (Some(row), _) => {
+                let data: Vec<u8> = row.get(0);
+                self.cache.hashes.lock().unwrap().insert(module_id.clone(), Sha256::digest(&data).to_vec());
+                data
+            },
+            (None, Some(endpoint)) => {
+                let data = self
+                    .fetch_module_from_endpoint(endpoint, &module_id)
+                    .await?
+                    .ok_or_else(|| anyhow!("module {}::{} not found locally or at the endpoint", address.short_str(), name))?;
+                tracing::warn!(module = %module_id, "module not in the database; fetched it from the endpoint");
+                data
+            },
+            (None, None) => return Err(anyhow!("module {}::{} not found", address.short_str(), name)),
+        };
+        let module = CompiledModule::deserialize(&data)
+            .map_err(|e| anyhow!("module {}::{} failed deserialization: {}", address.short_str(), name, e))?;
+        let module = Arc::new(module);
+        self.cache.insert(module_id, module.clone());
+        Ok(module)
+    }
+
+    /// Read a module from the `module_source`, or a client of `endpoint`.
+    async fn fetch_module_from_endpoint(&self, endpoint: &Url, module_id: &ModuleId) -> Result<Option<Vec<u8>>> {
+        match &self.module_source {
+            Some(source) => source.fetch_module(module_id).await,
+            None => Client::from_url(endpoint.clone(), Retry::default())?.fetch_module(module_id).await,
+        }
+    }
+
+    /// Load many modules into the cache with one query per `batch_size` of
+    /// them, rather than the query each that `get_module` makes. Modules
+    /// already cached, or not stored, are left alone. Returns how many were
+    /// loaded.
+    pub async fn preload(&self, module_ids: &[ModuleId], batch_size: usize) -> Result<usize> {
+        let wanted = module_ids
+            .iter()
+            .filter(|module_id| !self.cache.contains(module_id))
+            .collect::<Vec<_>>();
+        let mut db = self.pool.acquire().await?;
+        let mut loaded = 0;
+        for chunk in wanted.chunks(batch_size.max(1)) {
+            let select_sql = format!(
+                "SELECT address, name, data FROM {} WHERE (address, name) IN (VALUES {})",
+                self.config.module_table_name(),
+                vec!["(?, ?)"; chunk.len()].join(", "),
+            );
+            let mut query = sqlx::query(&select_sql);
+            for module_id in chunk {
+                query = query.bind(module_id.address().as_ref()).bind(module_id.name().as_str());
+            }
+            for row in query.fetch_all(&mut db).await? {
+                let address = db::address_from_bytes(&row.get::<Vec<u8>, _>(0))?;
+                let module_id = ModuleId::new(address, Identifier::new(row.get::<String, _>(1))?);
+                let data: Vec<u8> = row.get(2);
+                let module = CompiledModule::deserialize(&data)
+                    .map_err(|e| anyhow!("module {} failed deserialization: {}", module_id, e))?;
+                self.cache.hashes.lock().unwrap().insert(module_id.clone(), Sha256::digest(&data).to_vec());
+                self.cache.insert(module_id, Arc::new(module));
+                loaded += 1;
             }
         }
+        Ok(loaded)
     }
 
     pub fn resolve_type<'a>(&'a self, type_tag: &'a TypeTag) -> Pin<Box<dyn Future<Output=Result<FatType>> + 'a>> {
@@ -108,6 +279,12 @@ impl Resolver {
 
     pub fn resolve_struct<'a>(&'a self, struct_tag: &'a StructTag) -> Pin<Box<dyn Future<Output=Result<FatStructType>> + 'a>> {
         Box::pin(async move {
+            let key = struct_tag.to_string();
+            if self.config.persist_layouts {
+                if let Some(layout) = self.stored_layout(&key).await {
+                    return Ok(layout);
+                }
+            }
             let module = self.get_module(&struct_tag.address, &struct_tag.module).await?;
             let struct_def = find_struct_def_in_module(&module, &struct_tag.name)?;
             let mut ty_args = vec![];
@@ -115,10 +292,130 @@ impl Resolver {
                 ty_args.push(self.resolve_type(ty).await?);
             }
             let ty_body = self.resolve_struct_definition(&module, struct_def).await?;
-            ty_body.subst(&ty_args).map_err(|e| anyhow!("struct {:?} cannot be resolved {:?}", struct_tag, e))
+            let layout = ty_body
+                .subst(&ty_args)
+                .map_err(|e| anyhow!("struct {:?} cannot be resolved {:?}", struct_tag, e))?;
+            if self.config.persist_layouts {
+                self.store_layout(key, &layout).await?;
+            }
+            Ok(layout)
         })
     }
 
+    /// Load the layouts kept by earlier runs with `--persist-layouts` into
+    /// the shared cache, returning how many there are. Each is checked
+    /// against the modules it came from before it is first used.
+    pub async fn load_layouts(&self) -> Result<usize> {
+        let layouts = db::load_layouts(&self.config, &self.pool).await?;
+        let count = layouts.len();
+        self.cache.layouts.lock().unwrap().extend(layouts);
+        Ok(count)
+    }
+
+    /// A stored layout, if the modules it came from are unchanged since.
+    async fn stored_layout(&self, key: &str) -> Option<FatStructType> {
+        let (modules, hash) = {
+            let layouts = self.cache.layouts.lock().unwrap();
+            let stored = layouts.get(key)?;
+            (stored.modules.clone(), stored.hash.clone())
+        };
+        let current = self.modules_hash(&modules).await.ok();
+        let mut layouts = self.cache.layouts.lock().unwrap();
+        if current.as_ref() == Some(&hash) {
+            return layouts.get(key).map(|stored| stored.layout.clone());
+        }
+        layouts.remove(key);
+        None
+    }
+
+    async fn store_layout(&self, key: String, layout: &FatStructType) -> Result<()> {
+        let mut modules = BTreeSet::new();
+        collect_modules(layout, &mut modules);
+        let modules = modules.into_iter().collect::<Vec<_>>();
+        // modules that aren't stored, e.g. during genesis or when fetched
+        // from the endpoint, have nothing to check the layout against later
+        let hash = match self.modules_hash(&modules).await {
+            Ok(hash) => hash,
+            Err(_) => return Ok(()),
+        };
+        let stored = StoredLayout {
+            modules,
+            hash,
+            layout: layout.clone(),
+        };
+        db::store_layout(&self.config, &self.tables, &self.pool, &key, &stored).await?;
+        self.cache.layouts.lock().unwrap().insert(key, stored);
+        Ok(())
+    }
+
+    /// A hash over the stored bytes of each of the modules, in order.
+    async fn modules_hash(&self, modules: &[ModuleId]) -> Result<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        for module_id in modules {
+            hasher.update(self.module_hash(module_id).await?);
+        }
+        Ok(hasher.finalize().to_vec())
+    }
+
+    async fn module_hash(&self, module_id: &ModuleId) -> Result<Vec<u8>> {
+        if let Some(hash) = self.cache.hashes.lock().unwrap().get(module_id) {
+            return Ok(hash.clone());
+        }
+        let select_sql = format!(
+            "SELECT data FROM {} WHERE address = ? AND name = ?",
+            self.config.module_table_name(),
+        );
+        let row = sqlx::query(&select_sql)
+            .bind(module_id.address().as_ref())
+            .bind(module_id.name().as_str())
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow!("module {} not stored", module_id))?;
+        let hash = Sha256::digest(&row.get::<Vec<u8>, _>(0)).to_vec();
+        self.cache.hashes.lock().unwrap().insert(module_id.clone(), hash.clone());
+        Ok(hash)
+    }
+
+    /// Resolve each field of a struct on its own, so that the fields whose
+    /// types are available can be used even when the others' aren't.
+    pub async fn resolve_struct_fields(&self, struct_tag: &StructTag) -> Result<Vec<(Identifier, Result<FatType>)>> {
+        let module = self.get_module(&struct_tag.address, &struct_tag.module).await?;
+        let idx = find_struct_def_in_module(&module, &struct_tag.name)?;
+        let mut ty_args = vec![];
+        for ty in &struct_tag.type_params {
+            ty_args.push(self.resolve_type(ty).await);
+        }
+        // an unresolved type argument only fails the fields that use it, and
+        // is never substituted into the others
+        let substitutes = ty_args
+            .iter()
+            .map(|ty| ty.as_ref().map(FatType::clone).unwrap_or(FatType::Bool))
+            .collect::<Vec<_>>();
+        let defs = match &module.struct_def_at(idx).field_information {
+            StructFieldInformation::Native => return Err(anyhow!("unexpected native struct")),
+            StructFieldInformation::Declared(defs) => defs,
+        };
+        let mut fields = vec![];
+        for field_def in defs {
+            let name = module.identifier_at(field_def.name).to_owned();
+            let type_ = match self.resolve_signature(&module, &field_def.signature.0).await {
+                Ok(ty) => {
+                    let unresolved_arg = ty_args
+                        .iter()
+                        .enumerate()
+                        .find_map(|(i, arg)| arg.as_ref().err().filter(|_| mentions_ty_param(&ty, i)));
+                    match unresolved_arg {
+                        Some(e) => Err(anyhow!("type argument: {}", e)),
+                        None => ty.subst(&substitutes).map_err(|e| anyhow!("substitution failure: {:?}", e)),
+                    }
+                },
+                Err(e) => Err(e),
+            };
+            fields.push((name, type_));
+        }
+        Ok(fields)
+    }
+
     fn resolve_signature<'a>(
         &'a self,
         module: &'a CompiledModule,
@@ -164,12 +461,14 @@ impl Resolver {
             self.get_module(
                 module.address_identifier_at(module_handle.address),
                 module.identifier_at(module_handle.name),
-            ).await?
+            ).await
+            .map_err(|e| anyhow!("{} (referenced from {})", e, module.self_id()))?
         };
         let target_idx = find_struct_def_in_module(
             &target_module,
             module.identifier_at(struct_handle.name),
-        )?;
+        )
+        .map_err(|e| anyhow!("{} (referenced from {})", e, module.self_id()))?;
         self.resolve_struct_definition(&target_module, target_idx).await
     }
 
@@ -205,6 +504,75 @@ impl Resolver {
     }
 }
 
+/// Where `--resolve-from-endpoint` reads modules from: the endpoint's latest
+/// state. Modules found this way are only cached, never stored, since they
+/// may be newer than the version the database is at. This is only a trait so
+/// that resolving from the endpoint can be tested without a node.
+pub trait ModuleSource: Send + Sync {
+    fn fetch_module<'a>(&'a self, module_id: &'a ModuleId) -> Pin<Box<dyn Future<Output=Result<Option<Vec<u8>>>> + 'a>>;
+}
+
+impl ModuleSource for Client<Retry> {
+    fn fetch_module<'a>(&'a self, module_id: &'a ModuleId) -> Pin<Box<dyn Future<Output=Result<Option<Vec<u8>>>> + 'a>> {
+        Box::pin(async move {
+            let view = self.get_account_state_with_proof(module_id.address(), None, None).await?;
+            let blob = match view.blob {
+                Some(blob) => blob,
+                None => return Ok(None),
+            };
+            let state = AccountState::try_from(&AccountStateBlob::from(blob.into_bytes()?))?;
+            let key = AccessPath::code_access_path(module_id).path;
+            Ok(state.get(&key).cloned())
+        })
+    }
+}
+
+/// The modules defining every struct in a layout, which it has to be
+/// resolved again from if any of them change.
+fn collect_modules(struct_: &FatStructType, modules: &mut BTreeSet<ModuleId>) {
+    modules.insert(ModuleId::new(struct_.address, struct_.module.clone()));
+    for ty in struct_.ty_args.iter().chain(struct_.fields.iter().map(|(_, ty)| ty)) {
+        let mut ty = ty;
+        while let FatType::Vector(elem) = ty {
+            ty = elem;
+        }
+        if let FatType::Struct(s) = ty {
+            collect_modules(s, modules);
+        }
+    }
+}
+
+fn mentions_ty_param(ty: &FatType, idx: usize) -> bool {
+    match ty {
+        FatType::TyParam(i) => *i == idx,
+        FatType::Vector(ty) => mentions_ty_param(ty, idx),
+        FatType::Struct(s) => s
+            .ty_args
+            .iter()
+            .chain(s.fields.iter().map(|(_, ty)| ty))
+            .any(|ty| mentions_ty_param(ty, idx)),
+        FatType::Bool | FatType::U8 | FatType::U64 | FatType::U128 | FatType::Address => false,
+    }
+}
+
+/// The error for a struct its module doesn't declare, e.g. one removed by
+/// an upgrade while resources of it are still stored. Resolving a struct tag
+/// fails with this error itself, so callers can tell it apart with
+/// `anyhow::Error::is`.
+#[derive(Debug)]
+pub struct MissingStructDef {
+    pub module: ModuleId,
+    pub name: Identifier,
+}
+
+impl fmt::Display for MissingStructDef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "struct {} not found in {}", self.name, self.module)
+    }
+}
+
+impl std::error::Error for MissingStructDef {}
+
 fn find_struct_def_in_module(module: &CompiledModule, name: &IdentStr) -> Result<StructDefinitionIndex> {
     for (i, defs) in module.struct_defs().iter().enumerate() {
         let st_handle = module.struct_handle_at(defs.struct_handle);
@@ -212,5 +580,149 @@ fn find_struct_def_in_module(module: &CompiledModule, name: &IdentStr) -> Result
             return Ok(StructDefinitionIndex::new(i as u16));
         }
     }
-    Err(anyhow!("struct {} not found in {}", name, module.self_id()))
+    Err(MissingStructDef {
+        module: module.self_id(),
+        name: name.to_owned(),
+    }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct, ADDRESS};
+
+    #[tokio::test]
+    async fn module_cache_evicts_least_recently_used() {
+        let db = TestDb::new(test_util::config()).await;
+        let names = ["A", "B", "C"].iter().map(|name| Identifier::new(*name).unwrap()).collect::<Vec<_>>();
+        for name in &names {
+            let structs = [TestStruct::resource("R", vec![("value", SignatureToken::U64)])];
+            db.publish(&test_util::module_bytes(name.as_str(), &structs)).await;
+        }
+        let cache = Arc::new(ModuleCache::new(2));
+        let resolver = Resolver::from_shared_cache(db.pool.clone(), db.config.clone(), cache.clone());
+        let cached = |name: &Identifier| cache.contains(&ModuleId::new(ADDRESS, name.clone()));
+
+        // each is read from the database, and the third evicts the first
+        for name in &names {
+            resolver.get_module(&ADDRESS, name).await.unwrap();
+        }
+        assert_eq!((cache.hits(), cache.misses()), (0, 3));
+        assert!(!cached(&names[0]) && cached(&names[1]) && cached(&names[2]));
+
+        // using the second makes the third the least recently used
+        resolver.get_module(&ADDRESS, &names[1]).await.unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        resolver.get_module(&ADDRESS, &names[0]).await.unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 4));
+        assert!(cached(&names[0]) && cached(&names[1]) && !cached(&names[2]));
+
+        // an invalidated module is read again
+        cache.invalidate(&ModuleId::new(ADDRESS, names[1].clone()));
+        resolver.get_module(&ADDRESS, &names[1]).await.unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 5));
+    }
+
+    #[tokio::test]
+    async fn preloading_fills_the_cache_with_stored_modules() {
+        let db = TestDb::new(test_util::config()).await;
+        let ids = ["A", "B", "C", "D"]
+            .iter()
+            .map(|name| ModuleId::new(ADDRESS, Identifier::new(*name).unwrap()))
+            .collect::<Vec<_>>();
+        for id in &ids[..3] {
+            let structs = [TestStruct::resource("R", vec![("value", SignatureToken::U64)])];
+            db.publish(&test_util::module_bytes(id.name().as_str(), &structs)).await;
+        }
+        let cache = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+        let resolver = Resolver::from_shared_cache(db.pool.clone(), db.config.clone(), cache.clone());
+
+        // one query reads them all. D was never stored, and is left for
+        // get_module to fail on
+        assert_eq!(resolver.preload(&ids, ids.len()).await.unwrap(), 3);
+        for id in &ids[..3] {
+            resolver.get_module(&ADDRESS, id.name()).await.unwrap();
+        }
+        assert_eq!((cache.hits(), cache.misses()), (3, 0));
+        assert!(resolver.get_module(&ADDRESS, ids[3].name()).await.is_err());
+
+        // modules already cached aren't read again, however they're batched
+        assert_eq!(resolver.preload(&ids, 1).await.unwrap(), 0);
+    }
+
+    /// Serves modules from memory in place of an endpoint.
+    struct Modules(HashMap<ModuleId, Vec<u8>>);
+
+    impl ModuleSource for Modules {
+        fn fetch_module<'a>(&'a self, module_id: &'a ModuleId) -> Pin<Box<dyn Future<Output=Result<Option<Vec<u8>>>> + 'a>> {
+            Box::pin(async move { Ok(self.0.get(module_id).cloned()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_modules_are_read_from_the_endpoint() {
+        let endpoint = Url::parse("http://127.0.0.1:1").unwrap();
+        let db = TestDb::new(Config { module_endpoint: Some(endpoint), ..test_util::config() }).await;
+        let module_id = ModuleId::new(ADDRESS, Identifier::new("M").unwrap());
+        let source = Modules(vec![(module_id.clone(), test_util::counter_module())].into_iter().collect());
+        let resolver = db.resolver().with_module_source(Arc::new(source));
+
+        // the module is cached, but not stored
+        resolver.get_module(&ADDRESS, module_id.name()).await.unwrap();
+        resolver.get_module(&ADDRESS, module_id.name()).await.unwrap();
+        assert_eq!((db.modules.hits(), db.modules.misses()), (1, 1));
+        let select_sql = format!("SELECT COUNT(*) FROM {}", db.config.module_table_name());
+        let count: i64 = sqlx::query(&select_sql).fetch_one(&db.pool).await.unwrap().get(0);
+        assert_eq!(count, 0);
+
+        let missing = Identifier::new("N").unwrap();
+        let error = resolver.get_module(&ADDRESS, &missing).await.unwrap_err().to_string();
+        assert_eq!(error, format!("module {}::N not found locally or at the endpoint", ADDRESS.short_str()));
+    }
+
+    fn field_names(layout: &FatStructType) -> Vec<&str> {
+        layout.fields.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn persisted_layouts_are_reused_by_later_runs() {
+        let db = TestDb::new(Config { persist_layouts: true, ..test_util::config() }).await;
+        db.publish(&test_util::counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.resolver().resolve_struct(&tag).await.unwrap();
+
+        // a new cache, as a later run starts with, resolves the type without
+        // reading its module
+        let cache = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+        let resolver = Resolver::from_shared_cache(db.pool.clone(), db.config.clone(), cache.clone());
+        assert_eq!(resolver.load_layouts().await.unwrap(), 1);
+        let layout = resolver.resolve_struct(&tag).await.unwrap();
+        assert_eq!(field_names(&layout), vec!["value"]);
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn persisted_layouts_are_discarded_once_their_module_changes() {
+        let db = TestDb::new(Config { persist_layouts: true, ..test_util::config() }).await;
+        db.publish(&test_util::counter_module()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        let resolver = db.resolver();
+        resolver.resolve_struct(&tag).await.unwrap();
+        let upgraded = [TestStruct::resource("R", vec![("value", SignatureToken::U64), ("extra", SignatureToken::Bool)])];
+        db.publish(&test_util::module_bytes("M", &upgraded)).await;
+
+        // a later run finds the stored layout no longer matches the module
+        let cache = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+        let later = Resolver::from_shared_cache(db.pool.clone(), db.config.clone(), cache.clone());
+        assert_eq!(later.load_layouts().await.unwrap(), 1);
+        let layout = later.resolve_struct(&tag).await.unwrap();
+        assert_eq!(field_names(&layout), vec!["value", "extra"]);
+        assert_eq!(cache.misses(), 1);
+
+        // and so does this run, since publishing invalidated the module
+        let layout = resolver.resolve_struct(&tag).await.unwrap();
+        assert_eq!(field_names(&layout), vec!["value", "extra"]);
+    }
 }
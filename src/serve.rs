@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Result};
+use sqlx::sqlite::SqlitePool;
+use std::{fmt, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    db::{Config, DB},
+    parser,
+    resolver::{ModuleCache, DEFAULT_MODULE_CACHE_SIZE},
+};
+
+/// The most bytes of request line and headers read from a client.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answer read requests on `addr` until dropped:
+///
+/// - `GET /resource/{address}/{struct tag}`: one annotated resource
+/// - `GET /account/{address}`: every resource of an account, keyed by struct tag
+/// - `GET /version`: the latest version the database is synced to
+///
+/// Requests are answered one at a time, in the same task as indexing, since
+/// reading back values isn't `Send`. Each connection serves one request.
+pub async fn run(addr: SocketAddr, pool: SqlitePool, config: Arc<Config>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("serving queries on http://{}", listener.local_addr()?);
+    let db = DB::from_pool(pool, config, Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE)));
+    serve(listener, &db).await
+}
+
+async fn serve(listener: TcpListener, db: &DB) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        if let Err(e) = serve_connection(db, stream).await {
+            tracing::warn!("failed to answer a query: {}", e);
+        }
+    }
+}
+
+/// The database can't answer yet, e.g. because indexing hasn't created it.
+#[derive(Debug)]
+struct Unavailable(anyhow::Error);
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the database is not initialized yet: {}", self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+async fn serve_connection(db: &DB, mut stream: TcpStream) -> Result<()> {
+    let path = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(path) => path,
+        Err(_) => return Err(anyhow!("timed out reading the request")),
+    };
+    let (status, body) = match path {
+        Ok(path) => match respond(db, &path).await {
+            Ok(Some(body)) => ("200 OK", body),
+            Ok(None) => ("404 Not Found", error_body("not found")),
+            Err(e) if e.is::<Unavailable>() => ("503 Service Unavailable", error_body(&e.to_string())),
+            Err(e) => ("400 Bad Request", error_body(&e.to_string())),
+        },
+        Err(e) => ("400 Bad Request", error_body(&e.to_string())),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The decoded path of a GET request, ignoring headers and any query string.
+async fn read_request(stream: &mut TcpStream) -> Result<String> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return Err(anyhow!("request too long"));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed mid-request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(target)) => percent_decode(target.split('?').next().unwrap_or_default()),
+        _ => Err(anyhow!("only GET requests are supported")),
+    }
+}
+
+/// The JSON body for a path, or None if nothing is there.
+async fn respond(db: &DB, path: &str) -> Result<Option<String>> {
+    let segments = path.trim_matches('/').splitn(3, '/').collect::<Vec<_>>();
+    let body = match segments.as_slice() {
+        ["version"] => {
+            let next_version = db.try_sync_state().await.map_err(Unavailable)?.next_version;
+            serde_json::json!({ "version": next_version.checked_sub(1) })
+        },
+        ["account", address] => {
+            let address = parser::parse_address(address)?;
+            let mut object = serde_json::Map::new();
+            for resource in db.read_account(&address).await? {
+                object.insert(resource.type_.to_string(), serde_json::to_value(&resource)?);
+            }
+            serde_json::Value::Object(object)
+        },
+        ["resource", address, tag] => {
+            let address = parser::parse_address(address)?;
+            let tag = parser::parse_struct_tag(tag)?;
+            match db.read_resource(&address, &tag).await? {
+                Some(resource) => serde_json::to_value(&resource)?,
+                None => return Ok(None),
+            }
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(body.to_string()))
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+/// Undo the `%XX` escapes clients use for the `<`, `>`, `,` and spaces of
+/// struct tags.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' && tail.len() >= 2 {
+            let hex = std::str::from_utf8(&tail[..2])?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| anyhow!("bad escape %{}", hex))?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::{
+        db::SyncState,
+        test_util::{self, TestDb, TestStruct, ADDRESS},
+    };
+
+    /// The status line and body `serve` answers a GET of `path` with.
+    async fn get(db: &DB, path: &str) -> (String, serde_json::Value) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let request = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        // the database isn't `Send`, so the server runs in this task too
+        let response = tokio::select! {
+            result = serve(listener, db) => panic!("serving stopped: {:?}", result),
+            response = request => response,
+        };
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn version_is_unavailable_until_initialized() {
+        let (_dir, pool) = test_util::pool().await;
+        let config = Arc::new(test_util::config());
+        let db = DB::from_pool(pool, config, Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE)));
+        let (status, body) = get(&db, "/version").await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert!(body["error"].as_str().unwrap().starts_with("the database is not initialized yet"), "{}", body);
+
+        db.initialize().await;
+        assert_eq!(get(&db, "/version").await, ("HTTP/1.1 200 OK".to_string(), serde_json::json!({ "version": null })));
+        let state = SyncState { next_version: 5, backup_position: None, genesis_applied: true };
+        db.save_sync_state(&state).await.unwrap();
+        assert_eq!(get(&db, "/version").await, ("HTTP/1.1 200 OK".to_string(), serde_json::json!({ "version": 4 })));
+    }
+
+    #[tokio::test]
+    async fn resources_and_accounts_are_answered_as_json() {
+        let db = TestDb::new(test_util::config()).await;
+        db.publish(&test_util::module_bytes("M", &[
+            TestStruct::resource("R", vec![("value", SignatureToken::U64)]),
+            TestStruct::resource("S", vec![("value", SignatureToken::U64)]),
+        ])).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.store(1, &tag, test_util::counter(7)).await;
+        let resource = serde_json::to_value(db.db.read_resource(&ADDRESS, &tag).await.unwrap().unwrap()).unwrap();
+        let ok = "HTTP/1.1 200 OK".to_string();
+
+        let path = format!("/resource/{}/0x{}::M::R", hex::encode(ADDRESS), hex::encode(ADDRESS));
+        assert_eq!(get(&db.db, &path).await, (ok.clone(), resource.clone()));
+        let path = format!("/account/0x{}", hex::encode(ADDRESS));
+        let mut account = serde_json::Map::new();
+        account.insert(tag.to_string(), resource);
+        assert_eq!(get(&db.db, &path).await, (ok, serde_json::Value::Object(account)));
+
+        // a type that resolves but isn't stored, and a path that means nothing
+        let path = format!("/resource/{}/0x{}::M::S", hex::encode(ADDRESS), hex::encode(ADDRESS));
+        let (status, _) = get(&db.db, &path).await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = get(&db.db, "/nothing/here").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, body) = get(&db.db, "/account/not-an-address").await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert!(body["error"].as_str().unwrap().starts_with("invalid address not-an-address"), "{}", body);
+    }
+}
@@ -2,16 +2,36 @@ use anyhow::{anyhow, Result};
 use diem_state_view::StateView;
 use diem_types::{
     access_path::{AccessPath, Path},
+    transaction::{Transaction, TransactionOutput},
+};
+use diem_vm::{DiemVM, VMExecutor};
+use move_core_types::{
+    account_address::AccountAddress,
+    language_storage::StructTag,
+};
+use sqlx::{
+    Row,
+    pool::PoolConnection,
+    sqlite::{Sqlite, SqlitePool, SqliteRow},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
-use sqlx::{Row, sqlite::SqlitePool};
 use tokio::runtime;
 
 use crate::{
     db,
-    resolver::Resolver,
+    resolver::{ModuleCache, Resolver},
     util,
 };
 
+/// Addresses bound in one batched query, safely under SQLite's default limit
+/// of 999 parameters.
+const MAX_BATCH_SIZE: usize = 500;
 
 /// State for the genesis transaction is empty
 pub struct GenesisState;
@@ -32,76 +52,662 @@ impl StateView for GenesisState {
 
 /// State for normal transactions reads from SQL. Structs are stored in
 /// tables, and a special table `__root__$struct` maps addresses to top level
-/// structs. Modules are stored in `__module`.
+/// structs. Modules are stored in `__module`. All of these names carry the
+/// configured table prefix. When raw bytes are kept, resources are read
+/// straight from the `__root__` row instead of being reconstructed. With
+/// history kept, reads see the latest version at or before `version`.
 pub struct SqlState {
     pool: SqlitePool,
+    config: Arc<db::Config>,
+    modules: Arc<ModuleCache>,
+    version: u64,
+    prefetched: HashMap<AccessPath, Option<Vec<u8>>>,
+    pending: HashMap<AccessPath, Option<Vec<u8>>>,
+    root_ids: Arc<db::RootIdCache>,
+    queries: AtomicU64,
 }
 
 impl SqlState {
-    pub fn from_pool(pool: SqlitePool) -> SqlState {
+    pub fn from_pool(pool: SqlitePool, config: Arc<db::Config>, modules: Arc<ModuleCache>) -> SqlState {
         SqlState {
             pool,
+            config,
+            modules,
+            version: u64::MAX,
+            prefetched: HashMap::new(),
+            pending: HashMap::new(),
+            root_ids: Arc::new(db::RootIdCache::new(db::DEFAULT_ROOT_ID_CACHE_SIZE)),
+            queries: AtomicU64::new(0),
+        }
+    }
+
+    /// A snapshot of the state right after `version`, for executing
+    /// transactions against the past. Resources are read as they were then,
+    /// which needs history, but modules are always the latest published.
+    pub fn as_of(pool: SqlitePool, config: Arc<db::Config>, modules: Arc<ModuleCache>, version: u64) -> Result<SqlState> {
+        if !config.history {
+            return Err(anyhow!("reading the state as of a past version requires indexing with --history"));
+        }
+        Ok(SqlState::from_pool(pool, config, modules).at_version(version))
+    }
+
+    /// Read the state as of `version` rather than the latest. This only
+    /// makes a difference when history is kept.
+    pub fn at_version(mut self, version: u64) -> SqlState {
+        self.version = version;
+        self
+    }
+
+    /// Read these values, from `DB::pending_writes`, in place of what is in
+    /// SQL.
+    pub fn with_pending(mut self, pending: HashMap<AccessPath, Option<Vec<u8>>>) -> SqlState {
+        self.pending = pending;
+        self
+    }
+
+    /// Share the root ids cached by the `DB` writing these tables, from
+    /// `DB::root_ids`, rather than caching them anew.
+    pub fn with_root_ids(mut self, root_ids: Arc<db::RootIdCache>) -> SqlState {
+        self.root_ids = root_ids;
+        self
+    }
+
+    /// The number of SQL reads this state has issued, not counting the
+    /// reconstruction of structs.
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    /// Load values ahead of execution so that `get` can serve them from
+    /// memory. See `speculative_read_set` for one way to choose them.
+    pub async fn prefetch(&mut self, access_paths: &[AccessPath]) -> Result<()> {
+        let values = self.fetch_many(access_paths).await?;
+        self.prefetched.extend(access_paths.iter().cloned().zip(values));
+        Ok(())
+    }
+
+    /// Read a value from SQL. This is what `get` runs on its own runtime.
+    pub async fn fetch(&self, access_path: &AccessPath) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(access_path) {
+            return Ok(value.clone());
+        }
+        let (address, path) = match util::decode_access_path(access_path) {
+            Ok(decoded) => decoded,
+            Err(e) if self.config.strict => return Err(anyhow!("cannot read from {}: {}", access_path.address, e)),
+            Err(e) => {
+                tracing::warn!(address = %access_path.address, "not reading from the address: {}", e);
+                return Ok(None);
+            },
+        };
+        let mut db = self.pool.acquire().await?;
+        if let Path::Resource(struct_tag) = &path {
+            let root_table = db::root_table_name(&self.config, struct_tag);
+            match self.cached_root_id(&root_table, &address) {
+                Some(None) => return Ok(None),
+                Some(Some(id)) => return self.resource_from_id(struct_tag, id, &mut db).await,
+                None => {},
+            }
+        }
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        match path {
+            Path::Code(module_id) => {
+                let select_sql = format!(
+                    "SELECT data FROM {} WHERE address = ? AND name = ?",
+                    self.config.module_table_name(),
+                );
+                let result = sqlx::query(&select_sql)
+                    .bind(module_id.address().as_ref())
+                    .bind(module_id.name().as_str())
+                    .fetch_optional(&mut db)
+                    .await?;
+                match result {
+                    None => Ok(None),
+                    Some(row) => Ok(row.get(0)),
+                }
+            },
+            Path::Resource(struct_tag) if !self.config.is_indexed(&struct_tag) => {
+                let select_sql = format!(
+                    "SELECT value FROM {} WHERE address = ? AND struct_tag = ?{} ORDER BY last_modified_version DESC LIMIT 1",
+                    self.config.unindexed_resources_table_name(),
+                    if self.config.history { " AND last_modified_version <= ?" } else { "" },
+                );
+                let mut query = sqlx::query(&select_sql).bind(address.as_ref()).bind(struct_tag.to_string());
+                if self.config.history {
+                    query = query.bind(self.version_bound());
+                }
+                // nothing unindexed may have been written yet
+                let result = db::absent_if_missing_table(query.fetch_optional(&mut db).await)?;
+                Ok(result.and_then(|row| row.get(0)))
+            },
+            Path::Resource(struct_tag) => {
+                let select_sql = if self.config.history {
+                    format!(
+                        "SELECT {} FROM {} WHERE address = ? AND last_modified_version <= ? ORDER BY last_modified_version DESC LIMIT 1",
+                        self.root_columns(),
+                        db::root_table_name(&self.config, &struct_tag),
+                    )
+                } else {
+                    format!(
+                        "SELECT {} FROM {} WHERE address = ?",
+                        self.root_columns(),
+                        db::root_table_name(&self.config, &struct_tag),
+                    )
+                };
+                let mut query = sqlx::query(&select_sql).bind(address.as_ref());
+                if self.config.history {
+                    query = query.bind(self.version_bound());
+                }
+                // a type that was never stored has no table
+                let result = db::absent_if_missing_table(query.fetch_optional(&mut db).await)?;
+                if !self.config.history {
+                    let id = result.as_ref().map(|row| row.get::<i64, _>(0));
+                    self.root_ids.insert(&db::root_table_name(&self.config, &struct_tag), address, id);
+                }
+                match result {
+                    None => Ok(None),
+                    Some(row) => self.resource_from_row(&struct_tag, &row, 0, &mut db).await,
+                }
+            },
+        }
+    }
+
+    /// Read many values, issuing one query per `__root__` table rather than
+    /// one per access path. Values are returned in the order of
+    /// `access_paths`.
+    pub async fn fetch_many(&self, access_paths: &[AccessPath]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut values = vec![None; access_paths.len()];
+        let mut resources: HashMap<StructTag, Vec<(usize, AccountAddress)>> = HashMap::new();
+        for (i, access_path) in access_paths.iter().enumerate() {
+            if let Some(value) = self.pending.get(access_path) {
+                values[i] = value.clone();
+                continue;
+            }
+            let (address, path) = match util::decode_access_path(access_path) {
+                Ok(decoded) => decoded,
+                Err(e) if self.config.strict => return Err(anyhow!("cannot read from {}: {}", access_path.address, e)),
+                Err(e) => {
+                    tracing::warn!(address = %access_path.address, "not reading from the address: {}", e);
+                    continue;
+                },
+            };
+            match path {
+                // modules are few and rarely missed, so aren't worth batching,
+                // and unindexed resources have no root table to batch over
+                Path::Code(_) => values[i] = self.fetch(access_path).await?,
+                Path::Resource(struct_tag) if !self.config.is_indexed(&struct_tag) => {
+                    values[i] = self.fetch(access_path).await?;
+                },
+                Path::Resource(struct_tag) => resources.entry(struct_tag).or_default().push((i, address)),
+            }
+        }
+
+        let mut db = self.pool.acquire().await?;
+        for (struct_tag, wanted) in resources {
+            let root_table = db::root_table_name(&self.config, &struct_tag);
+            let mut uncached = vec![];
+            for (i, address) in wanted {
+                match self.cached_root_id(&root_table, &address) {
+                    Some(None) => {},
+                    Some(Some(id)) => values[i] = self.resource_from_id(&struct_tag, id, &mut db).await?,
+                    None => uncached.push((i, address)),
+                }
+            }
+            for chunk in uncached.chunks(MAX_BATCH_SIZE) {
+                // with history, only each address's latest row that is
+                // visible at this version
+                let latest_only = if self.config.history {
+                    format!(
+                        " AND last_modified_version = (SELECT MAX(last_modified_version) FROM {} AS h WHERE h.address = r.address AND h.last_modified_version <= ?)",
+                        root_table,
+                    )
+                } else {
+                    "".to_string()
+                };
+                let select_sql = format!(
+                    "SELECT address, {} FROM {} AS r WHERE address IN ({}){}",
+                    self.root_columns(),
+                    root_table,
+                    vec!["?"; chunk.len()].join(", "),
+                    latest_only,
+                );
+                let mut query = sqlx::query(&select_sql);
+                for (_, address) in chunk {
+                    query = query.bind(address.as_ref());
+                }
+                if self.config.history {
+                    query = query.bind(self.version_bound());
+                }
+                self.queries.fetch_add(1, Ordering::Relaxed);
+                let rows = match query.fetch_all(&mut db).await {
+                    Ok(rows) => rows,
+                    Err(e) if db::is_missing_table(&e) => vec![],
+                    Err(e) => return Err(e.into()),
+                };
+                let mut ids = HashMap::new();
+                for row in rows {
+                    let address: Vec<u8> = row.get(0);
+                    ids.insert(address.clone(), row.get::<i64, _>(1));
+                    let value = self.resource_from_row(&struct_tag, &row, 1, &mut db).await?;
+                    for (i, wanted_address) in chunk {
+                        if wanted_address.as_ref() == address.as_slice() {
+                            values[*i] = value.clone();
+                        }
+                    }
+                }
+                if !self.config.history {
+                    for (_, address) in chunk {
+                        self.root_ids.insert(&root_table, *address, ids.get(address.as_ref()).copied());
+                    }
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// The `__root__` id of a resource as cached in `root_ids`.
+    /// With history, reads are bounded by version while the cache only
+    /// knows the latest row, and with raw bytes kept the id alone can't
+    /// serve a read, so neither uses it.
+    fn cached_root_id(&self, root_table: &str, address: &AccountAddress) -> Option<Option<i64>> {
+        if self.config.history {
+            return None;
+        }
+        match self.root_ids.get(root_table, address) {
+            Some(Some(_)) if self.config.keep_raw => None,
+            cached => cached,
+        }
+    }
+
+    /// `version` as bound in SQL, where integers are signed.
+    fn version_bound(&self) -> i64 {
+        self.version.min(i64::MAX as u64) as i64
+    }
+
+    /// With raw bytes kept there is no need to reconstruct the struct.
+    fn root_columns(&self) -> &'static str {
+        if self.config.keep_raw { "id, raw" } else { "id" }
+    }
+
+    /// The bytes of a resource from the `root_columns` of its `__root__` row,
+    /// starting at `column`, if all of its rows are there.
+    async fn resource_from_row(
+        &self,
+        struct_tag: &StructTag,
+        row: &SqliteRow,
+        column: usize,
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.config.keep_raw {
+            if let Some(raw) = row.get::<Option<Vec<u8>>, _>(column + 1) {
+                return Ok(Some(raw));
+            }
+        }
+        self.resource_from_id(struct_tag, row.get(column), db).await
+    }
+
+    /// The bytes of a resource reconstructed from its struct row. A root row
+    /// whose struct rows are missing, as a crash between writing the two
+    /// once could leave, reads as absent, just as `DB::store` overwrites it.
+    async fn resource_from_id(&self, struct_tag: &StructTag, id: i64, db: &mut PoolConnection<Sqlite>) -> Result<Option<Vec<u8>>> {
+        let resolver = Resolver::from_shared_cache(self.pool.clone(), self.config.clone(), self.modules.clone());
+        // serializing the Option itself would prefix the resource with a tag
+        match db::fetch_struct(&self.config, struct_tag, id, &resolver, db).await? {
+            Some(struct_) => {
+                let bytes = bcs::to_bytes(&struct_).map_err(|e| anyhow!("failed to serialize {}: {}", struct_tag, e))?;
+                Ok(Some(bytes))
+            },
+            None => Ok(None),
         }
     }
 }
 
 impl StateView for SqlState {
     fn get(&self, access_path: &AccessPath) -> Result<Option<Vec<u8>>> {
-        let (address, path) = util::decode_access_path(access_path);
+        if let Some(value) = self.prefetched.get(access_path) {
+            return Ok(value.clone());
+        }
         let rt = runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap();
-        rt.block_on(async {
-            let mut db = self.pool.acquire().await?;
-            match path {
-                Path::Code(module_id) => {
-                    //println!("module get({})", module_id);
-                    let select_sql = "SELECT data FROM __module WHERE address = ? AND name = ?";
-                    let result = sqlx::query(select_sql)
-                        .bind(module_id.address().as_ref())
-                        .bind(module_id.name().as_str())
-                        .fetch_optional(&mut db)
-                        .await
-                        .unwrap();
-                    match result {
-                        None => Ok(None),
-                        Some(row) => Ok(row.get(0)),
-                    }
-                },
-                Path::Resource(struct_tag) => {
-                    //println!("resource get({}::{})", address, struct_tag);
-                    let sql_tag = db::struct_tag_to_sql(&struct_tag);
-                    let select_sql = format!(
-                        "SELECT id FROM __root__{} WHERE address = ?",
-                        sql_tag,
-                    );
-                    //println!("QUERY: {}\nPARAM: {}", select_sql, hex::encode(address));
-                    let result = sqlx::query(&select_sql)
-                        .bind(address.as_ref())
-                        .fetch_optional(&mut db)
-                        .await
-                        .unwrap_or(None);
-                    match result {
-                        None => Ok(None),
-                        Some(row) => {
-                            let resolver = Resolver::from_pool(self.pool.clone());
-                            let struct_ = db::fetch_struct(&struct_tag, row.get(0), &resolver, &mut db).await.unwrap();
-                            let bytes = bcs::to_bytes(&struct_).unwrap();
-                            Ok(Some(bytes))
-                        },
-                    }
-                },
-            }
-        })
+        rt.block_on(self.fetch(access_path))
+    }
+
+    /// Reads whatever wasn't prefetched with one query per resource type,
+    /// via `fetch_many`.
+    fn multi_get(&self, access_paths: &[AccessPath]) -> Result<Vec<Option<Vec<u8>>>> {
+        let missing = access_paths
+            .iter()
+            .filter(|access_path| !self.prefetched.contains_key(access_path))
+            .cloned()
+            .collect::<Vec<_>>();
+        let fetched = if missing.is_empty() {
+            HashMap::new()
+        } else {
+            let rt = runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let values = rt.block_on(self.fetch_many(&missing))?;
+            missing.into_iter().zip(values).collect::<HashMap<_, _>>()
+        };
+        Ok(access_paths
+            .iter()
+            .map(|access_path| match self.prefetched.get(access_path) {
+                Some(value) => value.clone(),
+                None => fetched[access_path].clone(),
+            })
+            .collect())
+    }
+
+    fn is_genesis(&self) -> bool {
+        false
+    }
+}
+
+/// State served purely from memory, so the VM never waits on SQL. Reads of
+/// paths that haven't been loaded return nothing and are recorded as missing.
+#[derive(Default)]
+pub struct MemoryState {
+    values: HashMap<AccessPath, Option<Vec<u8>>>,
+    missing: Mutex<HashSet<AccessPath>>,
+}
+
+impl StateView for MemoryState {
+    fn get(&self, access_path: &AccessPath) -> Result<Option<Vec<u8>>> {
+        match self.values.get(access_path) {
+            Some(value) => Ok(value.clone()),
+            None => {
+                self.missing.lock().unwrap().insert(access_path.clone());
+                Ok(None)
+            },
+        }
     }
 
-    fn multi_get(&self, _access_paths: &[AccessPath]) -> Result<Vec<Option<Vec<u8>>>> {
-        //println!("get({:?})", access_paths);
-        Err(anyhow!("not implemented"))
+    fn multi_get(&self, access_paths: &[AccessPath]) -> Result<Vec<Option<Vec<u8>>>> {
+        access_paths.iter().map(|access_path| self.get(access_path)).collect()
     }
 
     fn is_genesis(&self) -> bool {
         false
     }
 }
+
+/// How many times `execute_block_in_memory` runs a block before giving up on
+/// loading its read set. Each pass only finds the reads that depend on what
+/// the previous pass loaded, so a block needing more is rare but possible.
+pub const MAX_MEMORY_PASSES: usize = 10;
+
+/// Execute a block against a `MemoryState`, loading its read set from SQL
+/// asynchronously. The read set isn't known up front, so the block is run
+/// repeatedly: each pass loads whatever the previous pass found missing, and
+/// the first pass with no misses is the result. Every read in that pass was
+/// served from SQL state, so it matches executing against `SqlState`.
+///
+/// Returns None if there are still misses after `max_passes`, in which case
+/// the block should be executed against `sql_state` instead.
+pub async fn execute_block_in_memory(
+    sql_state: &SqlState,
+    txs: Vec<Transaction>,
+    max_passes: usize,
+) -> Result<Option<Vec<TransactionOutput>>> {
+    let mut state = MemoryState::default();
+    for _ in 0..max_passes {
+        let block = txs.clone();
+        let (result, returned) = tokio::task::spawn_blocking(move || {
+            let result = DiemVM::execute_block(block, &state);
+            (result, state)
+        }).await?;
+        state = returned;
+
+        let missing = std::mem::take(&mut *state.missing.lock().unwrap());
+        if missing.is_empty() {
+            return result.map(Some).map_err(|status| anyhow!("block execution failed: {:?}", status));
+        }
+        let missing = missing.into_iter().collect::<Vec<_>>();
+        let values = sql_state.fetch_many(&missing).await?;
+        state.values.extend(missing.into_iter().zip(values));
+    }
+    Ok(None)
+}
+
+/// The reads a block makes before it depends on any state. A single pass
+/// against empty memory finds these without touching SQL; they are typically
+/// the senders' accounts and the on-chain configs read by the prologue, so
+/// prefetching them serves most of a block's reads from memory. Reads that
+/// depend on what was read aren't found and still go to SQL.
+pub async fn speculative_read_set(txs: Vec<Transaction>) -> Result<Vec<AccessPath>> {
+    let state = tokio::task::spawn_blocking(move || {
+        let state = MemoryState::default();
+        let _ = DiemVM::execute_block(txs, &state);
+        state
+    }).await?;
+    let missing = state.missing.into_inner().unwrap();
+    Ok(missing.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use diem_crypto::HashValue;
+    use diem_types::{access_path::Path, block_metadata::BlockMetadata, write_set::WriteOp};
+    use move_core_types::value::{MoveStruct, MoveValue};
+    use vm::file_format::SignatureToken;
+
+    use super::*;
+    use crate::test_util::{self, TestDb, TestStruct, ADDRESS};
+
+    fn sql_state(db: &TestDb) -> SqlState {
+        SqlState::from_pool(db.pool.clone(), db.config.clone(), db.modules.clone())
+    }
+
+    #[tokio::test]
+    async fn read_errors_are_not_absence() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![("value", SignatureToken::U64)])])).await;
+        let access_path = test_util::access_path(ADDRESS, &Path::Resource(tag.clone()));
+        // a type that was never stored has no table
+        assert_eq!(sql_state(&db).fetch(&access_path).await.unwrap(), None);
+        assert_eq!(sql_state(&db).fetch_many(&[access_path.clone()]).await.unwrap(), vec![None]);
+
+        let create_sql = format!("CREATE TABLE {} (address BLOB NOT NULL)", db::root_table_name(&db.config, &tag));
+        sqlx::query(&create_sql).execute(&db.pool).await.unwrap();
+        assert!(sql_state(&db).fetch(&access_path).await.is_err());
+        assert!(sql_state(&db).fetch_many(&[access_path]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn root_row_without_struct_table_is_absent() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![("value", SignatureToken::U64)])])).await;
+        db.store(1, &tag, MoveStruct::new(vec![MoveValue::U64(1)])).await;
+        let access_path = test_util::access_path(ADDRESS, &Path::Resource(tag.clone()));
+        assert!(sql_state(&db).fetch(&access_path).await.unwrap().is_some());
+
+        // as a crash between writing the two could once leave behind
+        let drop_sql = format!("DROP TABLE {}", db::struct_tag_to_sql(&db.config, &tag));
+        sqlx::query(&drop_sql).execute(&db.pool).await.unwrap();
+        assert_eq!(sql_state(&db).fetch(&access_path).await.unwrap(), None);
+        assert_eq!(sql_state(&db).fetch_many(&[access_path.clone()]).await.unwrap(), vec![None]);
+        // the same through the root id `store` cached
+        let cached = sql_state(&db).with_root_ids(db.db.root_ids());
+        assert_eq!(cached.fetch(&access_path).await.unwrap(), None);
+        assert_eq!(cached.fetch_many(&[access_path]).await.unwrap(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn unindexed_resources_are_served() {
+        for history in &[false, true] {
+            let tag = test_util::struct_tag("M", "R", vec![]);
+            let config = db::Config {
+                history: *history,
+                only_structs: vec![test_util::struct_tag("M", "Other", vec![])].into_iter().collect(),
+                ..test_util::config()
+            };
+            let db = TestDb::new(config).await;
+            db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![("value", SignatureToken::U64)])])).await;
+            let access_path = test_util::access_path(ADDRESS, &Path::Resource(tag.clone()));
+            assert_eq!(sql_state(&db).fetch(&access_path).await.unwrap(), None);
+
+            db.store(1, &tag, MoveStruct::new(vec![MoveValue::U64(1)])).await;
+            db.store(2, &tag, MoveStruct::new(vec![MoveValue::U64(2)])).await;
+            let latest = bcs::to_bytes(&MoveStruct::new(vec![MoveValue::U64(2)])).unwrap();
+            assert_eq!(sql_state(&db).fetch(&access_path).await.unwrap(), Some(latest.clone()));
+            assert_eq!(sql_state(&db).fetch_many(&[access_path.clone()]).await.unwrap(), vec![Some(latest)]);
+            if *history {
+                let first = bcs::to_bytes(&MoveStruct::new(vec![MoveValue::U64(1)])).unwrap();
+                assert_eq!(sql_state(&db).at_version(1).fetch(&access_path).await.unwrap(), Some(first));
+            }
+
+            db.write(3, ADDRESS, Path::Resource(tag.clone()), WriteOp::Deletion).await;
+            assert_eq!(sql_state(&db).fetch(&access_path).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn past_versions_are_read_with_history() {
+        let db = TestDb::new(db::Config { history: true, ..test_util::config() }).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::counter_module()).await;
+        db.store(1, &tag, test_util::counter(1)).await;
+        db.store(3, &tag, test_util::counter(3)).await;
+        let access_path = test_util::access_path(ADDRESS, &Path::Resource(tag));
+        let as_of = |version| SqlState::as_of(db.pool.clone(), db.config.clone(), db.modules.clone(), version).unwrap();
+
+        assert_eq!(as_of(0).fetch(&access_path).await.unwrap(), None);
+        for &(version, value) in [(1, 1), (2, 1), (3, 3)].iter() {
+            let expected = bcs::to_bytes(&test_util::counter(value)).unwrap();
+            assert_eq!(as_of(version).fetch(&access_path).await.unwrap(), Some(expected));
+        }
+
+        let db = TestDb::new(test_util::config()).await;
+        let error = SqlState::as_of(db.pool.clone(), db.config.clone(), db.modules.clone(), 1).err().unwrap().to_string();
+        assert!(error.contains("--history"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn multi_get_matches_get_per_key() {
+        for history in &[false, true] {
+            let db = TestDb::new(db::Config { history: *history, ..test_util::config() }).await;
+            let tag = test_util::struct_tag("M", "R", vec![]);
+            db.publish(&test_util::counter_module()).await;
+            let addresses = (1..=3).map(|i| AccountAddress::new([i; AccountAddress::LENGTH])).collect::<Vec<_>>();
+            for (version, address) in addresses[..2].iter().enumerate() {
+                let op = WriteOp::Value(bcs::to_bytes(&test_util::counter(version as u64)).unwrap());
+                db.write(version as u64 + 1, *address, Path::Resource(tag.clone()), op).await;
+            }
+            let path = |address: AccountAddress| test_util::access_path(address, &Path::Resource(tag.clone()));
+            // the third address holds nothing, and the first is asked for twice
+            let access_paths = vec![path(addresses[0]), path(addresses[2]), path(addresses[1]), path(addresses[0])];
+
+            let (batching, single) = (sql_state(&db), sql_state(&db));
+            let (batched, one_by_one, batching) = tokio::task::spawn_blocking(move || {
+                let batched = batching.multi_get(&access_paths);
+                let one_by_one = access_paths.iter().map(|access_path| single.get(access_path)).collect::<Result<Vec<_>>>();
+                (batched, one_by_one, batching)
+            })
+            .await
+            .unwrap();
+            // every resource of one type is read with a single query
+            assert_eq!(batching.queries(), 1);
+            let batched = batched.unwrap();
+            assert_eq!(batched, one_by_one.unwrap());
+            assert_eq!(batched[1], None);
+            assert_eq!(batched[0], Some(bcs::to_bytes(&test_util::counter(0)).unwrap()));
+            assert_eq!(batched[0], batched[3]);
+        }
+    }
+
+    #[tokio::test]
+    async fn kept_raw_bytes_match_the_decoded_resource() {
+        let db = TestDb::new(db::Config { keep_raw: true, ..test_util::config() }).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::module_bytes("M", &[TestStruct::resource("R", vec![
+            ("value", SignatureToken::U64),
+            ("bytes", test_util::vector_token(SignatureToken::U8)),
+        ])])).await;
+        let access_path = test_util::access_path(ADDRESS, &Path::Resource(tag.clone()));
+        let resource = |value: u64| {
+            let bytes = (0..value as u8).map(MoveValue::U8).collect();
+            MoveStruct::new(vec![MoveValue::U64(value), MoveValue::Vector(bytes)])
+        };
+
+        // an insert, then an update in place
+        for value in &[3, 5] {
+            db.store(*value, &tag, resource(*value)).await;
+            let expected = bcs::to_bytes(&resource(*value)).unwrap();
+
+            // the same tables read without raw bytes, reconstructing the
+            // resource from its struct rows
+            let decoding = SqlState::from_pool(
+                db.pool.clone(),
+                Arc::new(db::Config { keep_raw: false, ..test_util::config() }),
+                db.modules.clone(),
+            );
+            let decoded = decoding.fetch(&access_path).await.unwrap();
+            assert_eq!(decoded, Some(expected.clone()));
+
+            let state = sql_state(&db).with_root_ids(db.db.root_ids());
+            assert_eq!(state.fetch_many(&[access_path.clone()]).await.unwrap(), vec![decoded.clone()]);
+            let path = access_path.clone();
+            let served = tokio::task::spawn_blocking(move || state.get(&path)).await.unwrap().unwrap();
+            assert_eq!(served, decoded);
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_state_matches_sql_state() {
+        let db = TestDb::new(test_util::config()).await;
+        let txs = vec![Transaction::BlockMetadata(BlockMetadata::new(HashValue::zero(), 1, 1, vec![], ADDRESS))];
+
+        let block = txs.clone();
+        let state = sql_state(&db);
+        let direct = tokio::task::spawn_blocking(move || DiemVM::execute_block(block, &state)).await.unwrap();
+        let in_memory = execute_block_in_memory(&sql_state(&db), txs.clone(), MAX_MEMORY_PASSES).await;
+        match direct {
+            Ok(outputs) => assert_eq!(format!("{:?}", in_memory.unwrap().unwrap()), format!("{:?}", outputs)),
+            Err(status) => {
+                let error = in_memory.unwrap_err().to_string();
+                assert!(error.contains(&format!("{:?}", status)), "{}", error);
+            },
+        }
+        // the first pass only finds the reads it made against empty memory,
+        // so with no more passes allowed the caller falls back to SQL
+        assert!(execute_block_in_memory(&sql_state(&db), txs, 1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn prefetched_state_matches_sql_state() {
+        let db = TestDb::new(test_util::config()).await;
+        let tag = test_util::struct_tag("M", "R", vec![]);
+        db.publish(&test_util::counter_module()).await;
+        db.store(1, &tag, test_util::counter(7)).await;
+        let txs = vec![Transaction::BlockMetadata(BlockMetadata::new(HashValue::zero(), 1, 1, vec![], ADDRESS))];
+
+        // a stored resource alongside whatever the block reads up front
+        let counter_path = test_util::access_path(ADDRESS, &Path::Resource(tag));
+        let mut read_set = speculative_read_set(txs.clone()).await.unwrap();
+        read_set.push(counter_path.clone());
+        let mut prefetched = sql_state(&db);
+        prefetched.prefetch(&read_set).await.unwrap();
+
+        let block = txs.clone();
+        let state = sql_state(&db);
+        let direct = tokio::task::spawn_blocking(move || DiemVM::execute_block(block, &state)).await.unwrap();
+        let (with_prefetch, prefetched) = tokio::task::spawn_blocking(move || {
+            let outputs = DiemVM::execute_block(txs, &prefetched);
+            (outputs, prefetched)
+        }).await.unwrap();
+        assert_eq!(format!("{:?}", with_prefetch), format!("{:?}", direct));
+
+        // prefetched values are served as SQL has them, without a query
+        let queries = prefetched.queries();
+        let (value, prefetched) = tokio::task::spawn_blocking(move || {
+            let value = prefetched.get(&counter_path).unwrap();
+            (value, prefetched)
+        }).await.unwrap();
+        assert_eq!(value, Some(bcs::to_bytes(&test_util::counter(7)).unwrap()));
+        assert_eq!(prefetched.queries(), queries);
+    }
+}
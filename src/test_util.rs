@@ -0,0 +1,238 @@
+//! Helpers for tests that write to and read from a real SQLite database.
+
+use anyhow::Result;
+use diem_types::{
+    access_path::{AccessPath, Path},
+    write_set::WriteOp,
+};
+use move_core_types::{
+    account_address::AccountAddress,
+    identifier::Identifier,
+    language_storage::{StructTag, TypeTag},
+    value::{MoveStruct, MoveValue},
+};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use tempfile::TempDir;
+use vm::file_format::{
+    empty_module, CompiledModule, CompiledModuleMut, FieldDefinition, IdentifierIndex, Kind, ModuleHandleIndex,
+    SignatureToken, StructDefinition, StructFieldInformation, StructHandle, StructHandleIndex, TableIndex,
+    TypeSignature,
+};
+
+use crate::{
+    annotator::MoveValueAnnotator,
+    db::{Config, DB},
+    resolver::{ModuleCache, Resolver, DEFAULT_MODULE_CACHE_SIZE},
+};
+
+/// The address test modules are published at and resources written to.
+pub const ADDRESS: AccountAddress = AccountAddress::new([0x42; AccountAddress::LENGTH]);
+
+/// A struct declared by `module_bytes`. Fields refer to other structs of the
+/// same module by their position in the list.
+pub struct TestStruct {
+    pub name: &'static str,
+    pub is_resource: bool,
+    pub type_parameters: Vec<Kind>,
+    pub fields: Vec<(&'static str, SignatureToken)>,
+}
+
+impl TestStruct {
+    pub fn resource(name: &'static str, fields: Vec<(&'static str, SignatureToken)>) -> Self {
+        TestStruct { name, is_resource: true, type_parameters: vec![], fields }
+    }
+
+    pub fn plain(name: &'static str, fields: Vec<(&'static str, SignatureToken)>) -> Self {
+        TestStruct { name, is_resource: false, type_parameters: vec![], fields }
+    }
+}
+
+/// The token for a field holding the `index`th struct of the module.
+pub fn struct_token(index: usize) -> SignatureToken {
+    SignatureToken::Struct(StructHandleIndex(index as TableIndex))
+}
+
+pub fn vector_token(elem: SignatureToken) -> SignatureToken {
+    SignatureToken::Vector(Box::new(elem))
+}
+
+fn identifier(module: &mut CompiledModuleMut, name: &str) -> IdentifierIndex {
+    let name = Identifier::new(name).unwrap();
+    let index = match module.identifiers.iter().position(|i| *i == name) {
+        Some(index) => index,
+        None => {
+            module.identifiers.push(name);
+            module.identifiers.len() - 1
+        },
+    };
+    IdentifierIndex(index as TableIndex)
+}
+
+/// The serialized bytes of a module declaring `structs`, and nothing else.
+pub fn module_bytes(module_name: &str, structs: &[TestStruct]) -> Vec<u8> {
+    module_bytes_at(ADDRESS, module_name, structs)
+}
+
+/// `module_bytes` for a module published at `address`, such as one standing
+/// in for a module of the standard library.
+pub fn module_bytes_at(address: AccountAddress, module_name: &str, structs: &[TestStruct]) -> Vec<u8> {
+    let mut module = empty_module();
+    module.identifiers[0] = Identifier::new(module_name).unwrap();
+    module.address_identifiers[0] = address;
+    for (i, s) in structs.iter().enumerate() {
+        let name = identifier(&mut module, s.name);
+        module.struct_handles.push(StructHandle {
+            module: ModuleHandleIndex(0),
+            name,
+            is_nominal_resource: s.is_resource,
+            type_parameters: s.type_parameters.clone(),
+        });
+        let fields = s
+            .fields
+            .iter()
+            .map(|(field_name, ty)| FieldDefinition {
+                name: identifier(&mut module, field_name),
+                signature: TypeSignature(ty.clone()),
+            })
+            .collect();
+        module.struct_defs.push(StructDefinition {
+            struct_handle: StructHandleIndex(i as TableIndex),
+            field_information: StructFieldInformation::Declared(fields),
+        });
+    }
+    let module: CompiledModule = module.freeze().unwrap();
+    let mut bytes = vec![];
+    module.serialize(&mut bytes).unwrap();
+    bytes
+}
+
+/// A module `M` with one resource, `R { value: u64 }`.
+pub fn counter_module() -> Vec<u8> {
+    module_bytes("M", &[TestStruct::resource("R", vec![("value", SignatureToken::U64)])])
+}
+
+pub fn counter(value: u64) -> MoveStruct {
+    MoveStruct::new(vec![MoveValue::U64(value)])
+}
+
+/// The tag of a struct declared by `module_bytes`.
+pub fn struct_tag(module_name: &str, name: &str, type_params: Vec<TypeTag>) -> StructTag {
+    StructTag {
+        address: ADDRESS,
+        module: Identifier::new(module_name).unwrap(),
+        name: Identifier::new(name).unwrap(),
+        type_params,
+    }
+}
+
+pub fn access_path(address: AccountAddress, path: &Path) -> AccessPath {
+    AccessPath::new(address, bcs::to_bytes(path).unwrap())
+}
+
+/// The settings indexing uses by default.
+pub fn config() -> Config {
+    Config::default()
+}
+
+/// A pool on a new database file, removed along with the `TempDir`. Tests
+/// use files rather than `:memory:` so that every pooled connection sees
+/// the same database.
+pub async fn pool() -> (TempDir, SqlitePool) {
+    let dir = tempfile::tempdir().unwrap();
+    let options = SqliteConnectOptions::new()
+        .filename(dir.path().join("chain.db"))
+        .create_if_missing(true);
+    let pool = SqlitePoolOptions::new().connect_with(options).await.unwrap();
+    (dir, pool)
+}
+
+/// What is logged through `tracing` on the current thread while the guard
+/// `capture` returns is held. Tests run on a single threaded runtime, so this
+/// includes what their tasks log, but not what blocking threads do.
+#[derive(Clone, Default)]
+pub struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Logs {
+    pub fn capture() -> (Self, tracing::subscriber::DefaultGuard) {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An initialized database and what is needed to write to it.
+pub struct TestDb {
+    // dropping the last of them removes the database file
+    pub _dir: Arc<TempDir>,
+    pub pool: SqlitePool,
+    pub config: Arc<Config>,
+    pub modules: Arc<ModuleCache>,
+    pub db: DB,
+}
+
+impl TestDb {
+    pub async fn new(config: Config) -> Self {
+        let (dir, pool) = pool().await;
+        let config = Arc::new(config);
+        let modules = Arc::new(ModuleCache::new(DEFAULT_MODULE_CACHE_SIZE));
+        let db = DB::from_pool(pool.clone(), config.clone(), modules.clone());
+        db.initialize().await;
+        TestDb { _dir: Arc::new(dir), pool, config, modules, db }
+    }
+
+    pub fn resolver(&self) -> Resolver {
+        Resolver::from_shared_cache(self.pool.clone(), self.config.clone(), self.modules.clone())
+    }
+
+    pub fn annotator(&self) -> MoveValueAnnotator {
+        MoveValueAnnotator::new(self.resolver())
+    }
+
+    /// Publish a module built by `module_bytes`.
+    pub async fn publish(&self, module: &[u8]) {
+        let id = CompiledModule::deserialize(module).unwrap().self_id();
+        self.write(0, ADDRESS, Path::Code(id), WriteOp::Value(module.to_vec())).await;
+    }
+
+    /// Write a resource at `ADDRESS`, as a transaction at `version` would.
+    pub async fn store(&self, version: u64, tag: &StructTag, value: MoveStruct) {
+        self.try_store(version, tag, value).await.unwrap();
+    }
+
+    pub async fn try_store(&self, version: u64, tag: &StructTag, value: MoveStruct) -> Result<()> {
+        let op = WriteOp::Value(bcs::to_bytes(&value).unwrap());
+        self.try_write(version, ADDRESS, Path::Resource(tag.clone()), op).await
+    }
+
+    pub async fn write(&self, version: u64, address: AccountAddress, path: Path, op: WriteOp) {
+        self.try_write(version, address, path, op).await.unwrap();
+    }
+
+    pub async fn try_write(&self, version: u64, address: AccountAddress, path: Path, op: WriteOp) -> Result<()> {
+        self.db.execute_with_annotator(version, &access_path(address, &path), &op, &self.annotator()).await
+    }
+}
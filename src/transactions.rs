@@ -0,0 +1,374 @@
+use anyhow::{anyhow, Result};
+use diem_json_rpc_client::{
+    async_client::{Client, Retry},
+    views::EventView,
+};
+use diem_types::transaction::Transaction;
+use serde::Serialize;
+use std::{
+    convert::TryFrom,
+    fs::File,
+    future::Future,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use url::Url;
+
+/// Where the transactions to replay come from.
+#[derive(Clone)]
+pub enum TransactionSource {
+    Endpoint(Arc<Endpoints>),
+    File(Arc<TransactionFile>),
+}
+
+impl TransactionSource {
+    /// Fetch from the first of `endpoints`, failing over to the next on any
+    /// error. Every endpoint that can be reached must be on the same chain.
+    pub async fn from_endpoints(endpoints: &[Url]) -> Result<Self> {
+        let endpoints = Endpoints {
+            clients: endpoints
+                .iter()
+                .map(|url| (url.clone(), Client::from_url(url.clone(), Retry::default()).unwrap()))
+                .collect(),
+            current: AtomicUsize::new(0),
+        };
+        if endpoints.clients.len() > 1 {
+            endpoints.check_chain_ids().await?;
+        }
+        Ok(TransactionSource::Endpoint(Arc::new(endpoints)))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(TransactionSource::File(Arc::new(TransactionFile::open(path)?)))
+    }
+
+    /// Whether more transactions may appear later, so that the replay
+    /// should wait for them rather than stop once it has caught up.
+    pub fn is_live(&self) -> bool {
+        matches!(self, TransactionSource::Endpoint(_))
+    }
+
+    /// Transactions before this version can be fetched.
+    pub async fn end_version(&self) -> Result<u64> {
+        match self {
+            TransactionSource::Endpoint(endpoints) => {
+                endpoints.call(|client| async move { Ok(client.get_metadata().await?.version) }).await
+            },
+            TransactionSource::File(file) => Ok(file.first_version + file.offsets.len() as u64),
+        }
+    }
+
+    /// Fetch `count` transactions starting at `first_version`, each with the
+    /// events the endpoint reports for it. A file has no events, so there is
+    /// nothing to check them against.
+    pub async fn get_transactions(
+        &self,
+        first_version: u64,
+        count: u64,
+        with_events: bool,
+    ) -> Result<Vec<(Transaction, Option<Vec<EventView>>)>> {
+        match self {
+            TransactionSource::Endpoint(endpoints) => {
+                endpoints.call(|client| async move {
+                    let txs = client.get_transactions(first_version, count, with_events).await?;
+                    txs.iter()
+                        .map(|t| {
+                            let bytes = hex::decode(&t.bytes)?;
+                            Ok((bcs::from_bytes::<Transaction>(&bytes)?, Some(t.events.clone())))
+                        })
+                        .collect()
+                }).await
+            },
+            TransactionSource::File(file) => {
+                let txs = file.read(first_version, count)?;
+                Ok(txs.into_iter().map(|tx| (tx, None)).collect())
+            },
+        }
+    }
+}
+
+/// Redundant JSON-RPC endpoints for one chain. Requests go to the current
+/// endpoint, which only changes when a request to it fails. The client is
+/// only generic so that failing over can be tested without a node.
+pub struct Endpoints<C = Client<Retry>> {
+    clients: Vec<(Url, C)>,
+    current: AtomicUsize,
+}
+
+impl<C: Clone> Endpoints<C> {
+    /// Make a request, trying each endpoint in turn from the current one
+    /// until one succeeds. The last error is returned if none does.
+    async fn call<T, F, Fut>(&self, request: F) -> Result<T>
+    where
+        F: Fn(C) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let first = self.current.load(Ordering::SeqCst);
+        let mut error = None;
+        for i in 0..self.clients.len() {
+            let index = (first + i) % self.clients.len();
+            let (url, client) = &self.clients[index];
+            match request(client.clone()).await {
+                Ok(result) => {
+                    if index != first {
+                        self.current.store(index, Ordering::SeqCst);
+                    }
+                    return Ok(result);
+                },
+                Err(e) => {
+                    if self.clients.len() > 1 {
+                        tracing::warn!(endpoint = %url, "request failed: {}", e);
+                    }
+                    error = Some(e);
+                },
+            }
+        }
+        Err(error.expect("there is always at least one endpoint"))
+    }
+}
+
+impl Endpoints<Client<Retry>> {
+    /// Fail if two endpoints report different chains. An endpoint that can't
+    /// be reached now is only warned about, since failing over to it later
+    /// is the point of having it.
+    async fn check_chain_ids(&self) -> Result<()> {
+        let mut chain_id = None;
+        for (url, client) in &self.clients {
+            let id = match client.get_metadata().await {
+                Ok(metadata) => metadata.chain_id,
+                Err(e) => {
+                    tracing::warn!(endpoint = %url, "cannot check the chain of the endpoint: {}", e);
+                    continue;
+                },
+            };
+            match chain_id {
+                None => chain_id = Some((url, id)),
+                Some((first_url, first_id)) if first_id != id => {
+                    return Err(anyhow!(
+                        "{} is on chain {} but {} is on chain {}",
+                        first_url,
+                        first_id,
+                        url,
+                        id,
+                    ));
+                },
+                Some(_) => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transactions recorded for replaying without a node. The file is the
+/// version of the first transaction as a big-endian u64, followed by the
+/// transactions of consecutive versions, each bcs encoded and prefixed with
+/// its length as a big-endian u32 as in a `Backup`.
+pub struct TransactionFile {
+    first_version: u64,
+    /// Where each transaction's length prefix starts.
+    offsets: Vec<u64>,
+    reader: Mutex<BufReader<File>>,
+}
+
+impl TransactionFile {
+    fn open(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut version_buf = [0u8; 8];
+        reader
+            .read_exact(&mut version_buf)
+            .map_err(|e| anyhow!("transactions file {} has no header: {}", path.display(), e))?;
+        let first_version = u64::from_be_bytes(version_buf);
+
+        // find every transaction up front so that reads can seek to them
+        let len = reader.get_ref().metadata()?.len();
+        let mut offsets = vec![];
+        let mut offset = version_buf.len() as u64;
+        while offset < len {
+            let mut len_buf = [0u8; 4];
+            reader
+                .read_exact(&mut len_buf)
+                .map_err(|e| anyhow!("transactions file truncated in length at offset {}: {}", offset, e))?;
+            let tx_len = u32::from_be_bytes(len_buf) as u64;
+            if offset + len_buf.len() as u64 + tx_len > len {
+                return Err(anyhow!("transactions file truncated in transaction at offset {}", offset));
+            }
+            offsets.push(offset);
+            offset += len_buf.len() as u64 + tx_len;
+            reader.seek(SeekFrom::Start(offset))?;
+        }
+
+        Ok(TransactionFile {
+            first_version,
+            offsets,
+            reader: Mutex::new(reader),
+        })
+    }
+
+    fn read(&self, first_version: u64, count: u64) -> Result<Vec<Transaction>> {
+        let end_version = self.first_version + self.offsets.len() as u64;
+        if first_version < self.first_version || first_version + count > end_version {
+            return Err(anyhow!(
+                "versions {} to {} are not in the transactions file, which has {} to {}",
+                first_version,
+                first_version + count,
+                self.first_version,
+                end_version,
+            ));
+        }
+
+        let mut reader = self.reader.lock().unwrap();
+        let start = (first_version - self.first_version) as usize;
+        reader.seek(SeekFrom::Start(self.offsets[start]))?;
+        let mut txs = vec![];
+        for _ in 0..count {
+            let mut len_buf = [0u8; 4];
+            reader.read_exact(&mut len_buf)?;
+            let mut buffer = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+            reader.read_exact(&mut buffer)?;
+            txs.push(bcs::from_bytes(&buffer)?);
+        }
+        Ok(txs)
+    }
+}
+
+/// Writes a file laid out like a `TransactionFile`, but of any bcs encoded
+/// values, one per version, e.g. the write sets `export-writesets` records.
+pub struct FramedWriter {
+    writer: BufWriter<File>,
+}
+
+impl FramedWriter {
+    pub fn create(path: &Path, first_version: u64) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&first_version.to_be_bytes())?;
+        Ok(FramedWriter { writer })
+    }
+
+    /// Append the value of the next version.
+    pub fn append<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let bytes = bcs::to_bytes(value)?;
+        let len = u32::try_from(bytes.len()).map_err(|_| anyhow!("a value of {} bytes is too long to frame", bytes.len()))?;
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diem_crypto::HashValue;
+    use diem_types::block_metadata::BlockMetadata;
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::ADDRESS;
+
+    fn block(round: u64) -> Transaction {
+        Transaction::BlockMetadata(BlockMetadata::new(HashValue::zero(), round, round, vec![], ADDRESS))
+    }
+
+    /// Record a block of each of `rounds`, starting at `first_version`.
+    fn record(path: &Path, first_version: u64, rounds: u64) {
+        let mut writer = FramedWriter::create(path, first_version).unwrap();
+        for round in 0..rounds {
+            writer.append(&block(round)).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn open_error(path: &Path) -> String {
+        match TransactionFile::open(path) {
+            Ok(_) => panic!("{} opened", path.display()),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn recorded_transactions_are_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions");
+        record(&path, 5, 3);
+
+        let source = TransactionSource::from_file(&path).unwrap();
+        assert!(!source.is_live());
+        assert_eq!(source.end_version().await.unwrap(), 8);
+        let txs = source.get_transactions(6, 2, true).await.unwrap();
+        assert!(txs.iter().all(|(_, events)| events.is_none()));
+        let txs = txs.into_iter().map(|(tx, _)| tx).collect::<Vec<_>>();
+        assert_eq!(txs, vec![block(1), block(2)]);
+
+        for (first_version, count) in &[(4, 1), (7, 2)] {
+            let error = source.get_transactions(*first_version, *count, false).await.unwrap_err().to_string();
+            let expected = format!(
+                "versions {} to {} are not in the transactions file, which has 5 to 8",
+                first_version,
+                first_version + count,
+            );
+            assert_eq!(error, expected);
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions");
+        record(&path, 0, 2);
+        let bytes = fs::read(&path).unwrap();
+        let second = 8 + 4 + bcs::to_bytes(&block(0)).unwrap().len();
+
+        fs::write(&path, &bytes[..4]).unwrap();
+        let error = open_error(&path);
+        assert!(error.contains("has no header"), "{}", error);
+
+        fs::write(&path, &bytes[..second + 2]).unwrap();
+        let error = open_error(&path);
+        assert!(error.starts_with(&format!("transactions file truncated in length at offset {}", second)), "{}", error);
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let error = open_error(&path);
+        assert_eq!(error, format!("transactions file truncated in transaction at offset {}", second));
+    }
+
+    #[tokio::test]
+    async fn requests_fail_over_to_the_next_endpoint() {
+        let url = |port| Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let endpoints = Endpoints { clients: vec![(url(1), 0), (url(2), 1), (url(3), 2)], current: AtomicUsize::new(0) };
+        let tried = Mutex::new(vec![]);
+        let tried_ref = &tried;
+        let request = move |down: &'static [u32]| {
+            move |client: u32| async move {
+                tried_ref.lock().unwrap().push(client);
+                if down.contains(&client) {
+                    Err(anyhow!("endpoint {} is down", client))
+                } else {
+                    Ok(client)
+                }
+            }
+        };
+
+        assert_eq!(endpoints.call(request(&[0])).await.unwrap(), 1);
+        assert_eq!(*tried.lock().unwrap(), vec![0, 1]);
+        // the endpoint that answered stays current
+        tried.lock().unwrap().clear();
+        assert_eq!(endpoints.call(request(&[0])).await.unwrap(), 1);
+        assert_eq!(*tried.lock().unwrap(), vec![1]);
+        // and failing over wraps around to the first
+        tried.lock().unwrap().clear();
+        assert_eq!(endpoints.call(request(&[1, 2])).await.unwrap(), 0);
+        assert_eq!(*tried.lock().unwrap(), vec![1, 2, 0]);
+
+        tried.lock().unwrap().clear();
+        let error = endpoints.call(request(&[0, 1, 2])).await.unwrap_err();
+        assert_eq!(error.to_string(), "endpoint 2 is down");
+        assert_eq!(*tried.lock().unwrap(), vec![0, 1, 2]);
+    }
+}
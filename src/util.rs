@@ -1,8 +1,12 @@
+use anyhow::{anyhow, Result};
 use diem_types::access_path::{AccessPath, Path};
 use move_core_types::account_address::AccountAddress;
 
-pub fn decode_access_path(access_path: &AccessPath) -> (AccountAddress, Path) {
+/// Split an access path into its address and path. A path that isn't code
+/// or a resource, e.g. a kind added to Diem later, is an error.
+pub fn decode_access_path(access_path: &AccessPath) -> Result<(AccountAddress, Path)> {
     let address = access_path.address.clone();
-    let path = bcs::from_bytes(&access_path.path).unwrap();
-    (address, path)
+    let path = bcs::from_bytes(&access_path.path)
+        .map_err(|e| anyhow!("unknown access path {}: {}", hex::encode(&access_path.path), e))?;
+    Ok((address, path))
 }
@@ -0,0 +1,81 @@
+//! Indexes the start of a real chain, to catch changes in the JSON-RPC
+//! responses that stubbed sources can't. It needs a node to fetch from, e.g.
+//! a local test validator, and does nothing unless one is named:
+//!
+//! ```text
+//! DIEM_SQLIZE_TEST_ENDPOINT=http://127.0.0.1:8080 cargo test --test endpoint
+//! ```
+//!
+//! `DIEM_SQLIZE_TEST_VERSIONS` sets how many versions to index, 200 unless
+//! given. The chain must have at least that many. The indexed database is
+//! checked with plain SQL, so only the binary is needed.
+
+use diem_types::account_config;
+use move_core_types::language_storage::CORE_CODE_ADDRESS;
+use sqlx::{sqlite::SqlitePoolOptions, Row};
+use std::{
+    env,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+/// How long indexing may take before the test gives up.
+const TIMEOUT: Duration = Duration::from_secs(600);
+
+#[tokio::test]
+async fn indexes_the_start_of_a_chain() {
+    let endpoint = match env::var("DIEM_SQLIZE_TEST_ENDPOINT") {
+        Ok(endpoint) => endpoint,
+        Err(_) => {
+            eprintln!("DIEM_SQLIZE_TEST_ENDPOINT is not set, so no chain is indexed");
+            return;
+        },
+    };
+    let versions: u64 = env::var("DIEM_SQLIZE_TEST_VERSIONS").map_or(200, |v| v.parse().unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    let mut indexer = Command::new(env!("CARGO_BIN_EXE_diem-sqlize"))
+        .current_dir(dir.path())
+        .arg("--endpoint")
+        .arg(&endpoint)
+        .spawn()
+        .unwrap();
+
+    // indexing tails the chain until stopped, so it is stopped once the
+    // sync state is past the versions asked for
+    let pool = loop {
+        let path = dir.path().join("chain.db");
+        if path.exists() {
+            break SqlitePoolOptions::new().connect(&format!("sqlite:{}", path.display())).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    // the table only appears once indexing has set the database up
+    let select_sql = "SELECT next_version FROM __sync_state WHERE id = 0";
+    let start = Instant::now();
+    loop {
+        if let Some(status) = indexer.try_wait().unwrap() {
+            panic!("indexing exited early with {}", status);
+        }
+        if let Ok(row) = sqlx::query(select_sql).fetch_one(&pool).await {
+            if row.get::<i64, _>(0) as u64 >= versions {
+                break;
+            }
+        }
+        assert!(start.elapsed() < TIMEOUT, "indexing {} versions took over {:?}", versions, TIMEOUT);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    indexer.kill().unwrap();
+    indexer.wait().unwrap();
+
+    // genesis gives the root account the info of every currency, stored as
+    // a root row pointing at a row of the struct's table
+    let core = hex::encode(CORE_CODE_ADDRESS);
+    let currency_info = format!("x{0}__Diem__CurrencyInfo__t_x{0}__XUS__XUS_t", core);
+    let select_sql = format!(
+        "SELECT COUNT(*) FROM __root__{0} r JOIN {0} s ON s.__id = r.id WHERE r.address = x'{1}'",
+        currency_info,
+        hex::encode(account_config::diem_root_address()),
+    );
+    let row = sqlx::query(&select_sql).fetch_one(&pool).await.unwrap();
+    assert_eq!(row.get::<i64, _>(0), 1, "the root account holds the XUS currency info");
+}